@@ -22,11 +22,15 @@ impl BIOS {
         }
     }
 
-    pub fn read32(&self, offset: u32) -> u32 {
-        u32::from_le_bytes(*self.data[(offset as usize)..].first_chunk().unwrap())
+    pub fn from_image(data: Vec<u8>) -> Result<BIOS, anyhow::Error> {
+        if data.len() != BIOS_SIZE as usize {
+            return Err(anyhow!("Invalid BIOS"));
+        }
+
+        Ok(BIOS { data })
     }
 
-    pub fn read8(&self, offset: u32) -> u8 {
-        *self.data.get(offset as usize).unwrap()
+    pub fn data(&self) -> &[u8] {
+        &self.data
     }
 }
\ No newline at end of file
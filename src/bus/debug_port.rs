@@ -0,0 +1,52 @@
+const PCSX_ID: u32 = u32::from_le_bytes(*b"PCSX");
+
+#[derive(Debug, Default)]
+pub struct DebugPort {
+    tty: String,
+    exit_code: Option<u32>,
+    break_requested: bool,
+    pub echo: bool,
+}
+
+impl DebugPort {
+    pub fn new() -> Self {
+        Self { echo: true, ..Default::default() }
+    }
+
+    pub fn read32(&self, offset: u32) -> u32 {
+        match offset {
+            0 => PCSX_ID,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            0 => self.putchar(value as u8),
+            1 => self.break_requested = true,
+            2 => {
+                if self.echo {println!("Exited with code {value:04X}")};
+                self.exit_code = Some(value);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn putchar(&mut self, byte: u8) {
+        let ch = byte as char;
+        if self.echo {print!("{ch}")};
+        self.tty.push(ch);
+    }
+
+    pub fn tty(&self) -> &str {
+        &self.tty
+    }
+
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    pub fn take_break(&mut self) -> bool {
+        std::mem::take(&mut self.break_requested)
+    }
+}
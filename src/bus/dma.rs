@@ -1,8 +1,10 @@
-use std::{cell::RefCell, ops::{Index, IndexMut}, rc::Rc};
+use std::ops::{Index, IndexMut};
 
-use crate::{bus::{interface::Interface, interrupt::{Interrupt, IRQ}}, Registers};
+use crate::{bus::{interface::Bus, interrupt::{Interrupt, IRQ}}, scheduler::{Event, Scheduler}, state::{Savestate, StateReader, StateWriter}, Registers};
 
 const CHANNELS: [u8; 7] = [0x00, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60];
+const CD_ROM_WORD_CYCLES: u64 = 40;
+const SLICE_CYCLES: u64 = 0x1_0000;
 
 pub struct DMA {
     channels: Channels,
@@ -10,15 +12,11 @@ pub struct DMA {
     current_addr: [Option<u32>; 7],
     header: u32,
 
-    interface: Rc<RefCell<Interface>>,
-    interrupt: Rc<RefCell<Interrupt>>,
-
-    clock: usize,
-    running: Rc<RefCell<bool>>,
+    running: bool,
 }
 
 impl DMA {
-    pub fn new(interface: Rc<RefCell<Interface>>, interrupt: Rc<RefCell<Interrupt>>, running: Rc<RefCell<bool>>) -> Self {
+    pub fn new() -> Self {
         let mut channels = Channels {channels: [Registers{R: [0; 4]}; 8]};
         channels[0x70] = 0x0765_4321;
         Self {
@@ -27,42 +25,25 @@ impl DMA {
             current_addr: [None; 7],
             header: 0x00FF_FFFF,
 
-            interface,
-            interrupt,
-            
-            clock: 0,
-            running,
+            running: false,
         }
     }
 
-    pub fn tick(&mut self) {
-        self.clock += 1;
-        if self.clock == 120 {
-            self.clock = 0;
-        }
+    pub fn running(&self) -> bool {
+        self.running
+    }
 
-        if let Some(channel) = self.active_channel() {
-            self.running.replace(true);
-            match channel >> 4 {
-                0 => panic!("MDEC-IN not implemented."),
-                1 => panic!("MDEC-OUT not implemented."),
-                2 => match self.channels.sync_type(channel) {
-                    2 => self.linked_list_transfer(channel),
-                    _ => self.block_transfer(channel),
-                }
-                3 => if self.clock % 40 == 0 {self.block_transfer(channel)},
-                4 => panic!("SPU not implemented."),
-                5 => panic!("PIO not implemented."),
-                6 => self.block_transfer(channel),
-                _ => panic!("Unreachable channel: {channel}"),
-            }
-        }
+    pub fn complete(&mut self, interrupt: &mut Interrupt) {
+        self.running = false;
+        self.update_irq(interrupt);
+    }
 
+    fn update_irq(&mut self, interrupt: &mut Interrupt) {
         if self.bus_error() || (self.master_interrupt_enabled() && self.channels[0x74] & 0x3F00_0000 != 0) {
             let old_irq = self.master_interrupt();
             self.channels[0x74] |= 0x8000_0000;
             if !old_irq && self.master_interrupt() {
-                self.interrupt.borrow_mut().request(IRQ::DMA);
+                interrupt.request(IRQ::DMA);
             }
         }
     }
@@ -86,7 +67,7 @@ impl DMA {
         self.channels[offset]
     }
 
-    pub fn write_register(&mut self, offset: u32, value: u32) {
+    pub fn write_register(&mut self, offset: u32, value: u32, scheduler: &mut Scheduler) {
         // println!("DMA[{:02X}] <- {:08X}", offset, value);
         match offset {
             0x00 | 0x10 | 0x20 | 0x30 | 0x40 | 0x50 | 0x60 => {
@@ -100,6 +81,8 @@ impl DMA {
             }
             _ => self.channels[offset] = value,
         }
+
+        scheduler.schedule(Event::DmaStart, 0);
     }
 
     pub fn bus_error(&self) -> bool {
@@ -113,42 +96,84 @@ impl DMA {
     pub fn master_interrupt(&self) -> bool {
         self.channels[0x74] & (1 << 31) != 0
     }
+}
+
+impl Bus {
+    // The CPU is stalled while a transfer runs, so the words are moved up front and the
+    // transfer only completes once the time it would have taken has passed. Long transfers
+    // are split into slices so that other events still get to run in between.
+    pub fn run_dma(&mut self) {
+        let mut cycles = 0;
+        while cycles < SLICE_CYCLES {
+            let Some(channel) = self.dma.active_channel() else {break};
+
+            let progress = match channel >> 4 {
+                0 => panic!("MDEC-IN not implemented."),
+                1 => panic!("MDEC-OUT not implemented."),
+                2 => match self.dma.channels.sync_type(channel) {
+                    2 => self.linked_list_transfer(channel),
+                    _ => self.block_transfer(channel),
+                }
+                3 => {
+                    cycles += CD_ROM_WORD_CYCLES - 1;
+                    self.block_transfer(channel)
+                }
+                4 => panic!("SPU not implemented."),
+                5 => panic!("PIO not implemented."),
+                6 => self.block_transfer(channel),
+                _ => panic!("Unreachable channel: {channel}"),
+            };
+
+            if !progress {
+                self.dma.running = true;
+                return;
+            }
+            cycles += 1;
+        }
+
+        if cycles == 0 {
+            self.dma.update_irq(&mut self.interrupt);
+            return;
+        }
+
+        self.dma.running = true;
+        let event = if self.dma.active_channel().is_some() {Event::DmaStart} else {Event::DmaComplete};
+        self.scheduler.schedule(event, cycles);
+    }
 
-    fn block_transfer(&mut self, index: u32) {
+    fn block_transfer(&mut self, index: u32) -> bool {
         let channel = (index >> 4) as usize;
-        let increment = self.channels.increment_size(index);
+        let increment = self.dma.channels.increment_size(index);
 
-        self.current_addr[channel] = Some(self.current_addr[channel].unwrap_or_else(|| self.channels.base_address(index)));
-        let addr = unsafe { self.current_addr[channel].unwrap_unchecked() };
+        self.dma.current_addr[channel] = Some(self.dma.current_addr[channel].unwrap_or_else(|| self.dma.channels.base_address(index)));
+        let addr = unsafe { self.dma.current_addr[channel].unwrap_unchecked() };
 
-        if self.remaining_size[channel] == 0 {
-            self.remaining_size[channel] = match self.channels.sync_type(index) {
-                0 => self.channels.word_num(index),
-                1 => self.channels.block_amount(index) * self.channels.block_size(index),
+        if self.dma.remaining_size[channel] == 0 {
+            self.dma.remaining_size[channel] = match self.dma.channels.sync_type(index) {
+                0 => self.dma.channels.word_num(index),
+                1 => self.dma.channels.block_amount(index) * self.dma.channels.block_size(index),
                 _ => panic!("Unknown block sync mode"),
             }
-            // println!("Remaining size: {}", self.remaining_size[channel]);
+            // println!("Remaining size: {}", self.dma.remaining_size[channel]);
         }
-        let mut remaining_size = self.remaining_size[channel];
+        let mut remaining_size = self.dma.remaining_size[channel];
+        let moved = remaining_size > 0;
 
-        if remaining_size > 0 {
-            if self.channels.transfer_direction(index) {
+        if moved {
+            if self.dma.channels.transfer_direction(index) {
                 match channel {
                     2 => {
-                        let value = self.interface.borrow_mut().read32(addr & 0x001F_FFFC);
-                        self.interface.borrow_mut().write32(0x1F80_1810, value);
+                        let value = self.read32(addr & 0x001F_FFFC);
+                        self.write32(0x1F80_1810, value);
                     }
                     _ => panic!("Unhandled DMA channel {channel} RAM -> Device"),
                 };
             } else {
                 let value = match channel {
-                    2 => self.interface.borrow_mut().read32(0x1F80_1810),
-                    3 => {
-                        let mut borrowed = self.interface.borrow_mut();
-                        u32::from_le_bytes(std::array::from_fn(|_| {
-                            borrowed.read8(0x1F80_1802)
-                        }))
-                    }
+                    2 => self.read32(0x1F80_1810),
+                    3 => u32::from_le_bytes(std::array::from_fn(|_| {
+                        self.read8(0x1F80_1802)
+                    })),
                     6 => match remaining_size {
                         1 => 0x00FF_FFFF,
                         _ => addr.wrapping_sub(4) & 0x001F_FFFF,
@@ -157,61 +182,81 @@ impl DMA {
                 };
 
                 // if channel == 3 {println!("DMA: [{:08X}] <- ${:08X}", addr, value)};
-                self.interface.borrow_mut().write32(addr & 0x001F_FFFC, value);
+                self.write32(addr & 0x001F_FFFC, value);
             }
 
             remaining_size -= 1;
-            self.remaining_size[channel] = remaining_size;
+            self.dma.remaining_size[channel] = remaining_size;
             if remaining_size == 0 {
-                self.current_addr[channel] = None;
-                self.channels.done(index);
-                if self.active_channel().is_none() {self.running.replace(false);}
+                self.dma.current_addr[channel] = None;
+                self.dma.channels.done(index);
             } else {
-                self.current_addr[channel] = Some(addr.wrapping_add(increment));
+                self.dma.current_addr[channel] = Some(addr.wrapping_add(increment));
             }
         }
+
+        moved
     }
 
-    fn linked_list_transfer(&mut self, index: u32) {
-        if !self.channels.transfer_direction(index) {panic!("Linked list mode cannot transfer to RAM")}
+    fn linked_list_transfer(&mut self, index: u32) -> bool {
+        if !self.dma.channels.transfer_direction(index) {panic!("Linked list mode cannot transfer to RAM")}
 
         let channel = (index >> 4) as usize;
         if channel != 2 {panic!("Attempting linked-list DMA on non-GPU channel: {}", channel)}
 
-        let mut remaining_size = self.remaining_size[channel];
-        if self.remaining_size[channel] == 0 {
-            if self.current_addr[channel] == None {
-                let first_header_addr = self.channels.base_address(index);
-                self.current_addr[channel] = Some(first_header_addr);
+        let mut remaining_size = self.dma.remaining_size[channel];
+        if self.dma.remaining_size[channel] == 0 {
+            if self.dma.current_addr[channel] == None {
+                let first_header_addr = self.dma.channels.base_address(index);
+                self.dma.current_addr[channel] = Some(first_header_addr);
                 // println!("base: {:08X}", first_header_addr);
-                self.header = self.interface.borrow_mut().read32(first_header_addr);
+                self.dma.header = self.read32(first_header_addr);
             }
 
-            remaining_size = (self.header >> 24) as u16;
-            self.remaining_size[channel] = remaining_size;
+            remaining_size = (self.dma.header >> 24) as u16;
+            self.dma.remaining_size[channel] = remaining_size;
         }
-        let mut addr = (self.current_addr[channel].unwrap() + 4) & 0x001F_FFFC;
+        let mut addr = (self.dma.current_addr[channel].unwrap() + 4) & 0x001F_FFFC;
 
         if remaining_size > 0 {
-            let command = self.interface.borrow_mut().read32(addr);
-            self.interface.borrow_mut().write32(0x1F80_1810, command);
+            let command = self.read32(addr);
+            self.write32(0x1F80_1810, command);
         }
 
         remaining_size = remaining_size.saturating_sub(1);
-        self.remaining_size[channel] = remaining_size;
+        self.dma.remaining_size[channel] = remaining_size;
         if remaining_size == 0 {
-            if self.header & 0x0080_0000 != 0 {
-                self.current_addr[channel] = None;
-                self.channels.done(index);
-                self.running.replace(false);
-                return;
+            if self.dma.header & 0x0080_0000 != 0 {
+                self.dma.current_addr[channel] = None;
+                self.dma.channels.done(index);
+                return true;
             }
-            addr = self.header & 0x00FF_FFFF;
-            self.header = self.interface.borrow_mut().read32(addr);
-            // println!("Header: {:08X} addr: {:08X}", self.header, addr);
+            addr = self.dma.header & 0x00FF_FFFF;
+            self.dma.header = self.read32(addr);
+            // println!("Header: {:08X} addr: {:08X}", self.dma.header, addr);
         }
 
-        self.current_addr[channel] = Some(addr);
+        self.dma.current_addr[channel] = Some(addr);
+        true
+    }
+}
+
+impl Savestate for DMA {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.channels.channels);
+        w.write(&self.remaining_size);
+        w.write(&self.current_addr);
+        w.write(&self.header);
+        w.write(&self.running);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.channels.channels = r.read()?;
+        self.remaining_size = r.read()?;
+        self.current_addr = r.read()?;
+        self.header = r.read()?;
+        self.running = r.read()?;
+        Ok(())
     }
 }
 
@@ -1,6 +1,6 @@
-use std::{cell::RefCell, path::Path, rc::{Rc, Weak}};
+use std::path::Path;
 
-use crate::{bios::BIOS, bus::{dma::DMA, interrupt::Interrupt, timer::Timer}, cd_rom::CD_ROM, gpu::GPU, peripheral::ports::sio0::SIO0, ram::RAM, spu::SPU};
+use crate::{bios::BIOS, bus::{debug_port::DebugPort, dma::DMA, interrupt::Interrupt, mem_control::MemControl, timer::Timer}, cd_rom::CD_ROM, debugger::{Access, WatchHit, Watchpoint}, gpu::{GPU, FIRST_VBLANK, FRAME_CYCLES}, peripheral::ports::sio0::SIO0, ram::RAM, scheduler::{Event, Scheduler}, spu::SPU, state::{Savestate, StateReader, StateWriter}};
 
 const DRAM_SIZE: usize = 2 * 1024 * 1024;
 const DRAM_START: u32 = 0x0000_0000;
@@ -61,53 +61,290 @@ const BIOS_END: u32 = BIOS_START + (512 * 1024);
 const CACHE_CONTROL_START: u32 = 0xFFFE_0130;
 const CACHE_CONTROL_END: u32 = 0xFFFF_FFFF;
 
-pub struct Interface {
+// DRAM, and the I/O ports that aren't on a configurable bus, answer in a fixed time
+const DRAM_ACCESS_CYCLES: u64 = 4;
+const IO_ACCESS_CYCLES: u64 = 2;
+
+// The scratchpad is the data cache used as fast RAM, it only answers with both enable bits set
+const SCRATCHPAD_ENABLE: u32 = 0x88;
+
+const PAGE_BITS: u32 = 16;
+const PAGE_MASK: usize = (1 << PAGE_BITS) - 1;
+const PAGE_COUNT: usize = 0x2000_0000 >> PAGE_BITS;
+
+pub struct Bus {
     bios: BIOS,
-    pub(crate) dma: Weak<RefCell<DMA>>,
+    pub dma: DMA,
     pub dram: RAM,
     pub scratchpad: RAM,
     pub gpu: GPU,
-    spu: Rc<RefCell<SPU>>,
-    pub interrupt: Rc<RefCell<Interrupt>>,
-    cd_rom: Rc<RefCell<CD_ROM>>,
-    timer: Rc<RefCell<Timer>>,
-    sio0: Rc<RefCell<SIO0>>,
+    spu: SPU,
+    pub interrupt: Interrupt,
+    pub cd_rom: CD_ROM,
+    pub timer: Timer,
+    pub sio0: SIO0,
+    pub debug_port: DebugPort,
+    pub scheduler: Scheduler,
+    mem_control: MemControl,
+    cache_control: u32,
+
+    // Pointers straight into the DRAM and BIOS buffers for every 64KB page they cover, null
+    // for pages that need the I/O match. The buffers are never resized, so the pointers stay
+    // valid for as long as the bus lives.
+    read_pages: Box<[*const u8]>,
+    write_pages: Box<[*mut u8]>,
+
+    // One bit per DRAM word the CPU has decoded into its block cache. A write that hits one
+    // clears the bits of its whole 4KB page and queues the page so the CPU can drop the
+    // blocks compiled from it.
+    code_words: Box<[u64]>,
+    dirty_code_pages: Vec<u32>,
+
+    // Only the CPU's own loads and stores are checked, not DMA or instruction fetches
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
 }
 
-impl Interface {
-    pub fn new(path: &Path, interrupt: Rc<RefCell<Interrupt>>, cd_rom: Rc<RefCell<CD_ROM>>, timer: Rc<RefCell<Timer>>, sio0: Rc<RefCell<SIO0>>, spu: Rc<RefCell<SPU>>) -> Result<Self, anyhow::Error> {
-        let bios = BIOS::new(path)?;
+impl Bus {
+    pub fn new(path: &Path, cd_rom: CD_ROM, sio0: SIO0) -> Result<Self, anyhow::Error> {
+        Ok(Self::with_bios(BIOS::new(path)?, cd_rom, sio0))
+    }
+
+    pub(crate) fn with_bios(bios: BIOS, cd_rom: CD_ROM, sio0: SIO0) -> Self {
         let dram = RAM::new(DRAM_SIZE);
         let scratchpad = RAM::new(SCRATCHPAD_SIZE);
-        let gpu = GPU::new(interrupt.clone(), timer.clone());
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_at(Event::VBlank, FIRST_VBLANK);
+
+        let mut bus = Self {
+            bios,
+            dma: DMA::new(),
+            dram,
+            scratchpad,
+            gpu: GPU::new(),
+            spu: SPU::new(),
+            interrupt: Interrupt::new(),
+            cd_rom,
+            timer: Timer::new(),
+            sio0,
+            debug_port: DebugPort::new(),
+            scheduler,
+            mem_control: MemControl::new(),
+            cache_control: 0,
+
+            read_pages: vec![std::ptr::null(); PAGE_COUNT].into_boxed_slice(),
+            write_pages: vec![std::ptr::null_mut(); PAGE_COUNT].into_boxed_slice(),
+
+            code_words: vec![0; DRAM_SIZE / 4 / 64].into_boxed_slice(),
+            dirty_code_pages: Vec::new(),
+
+            watchpoints: Vec::new(),
+            watch_hit: None,
+        };
+        bus.map_pages();
+
+        bus
+    }
+
+    // A bus with nothing plugged in, running the given BIOS image
+    #[cfg(test)]
+    pub(crate) fn with_bios_image(image: Vec<u8>) -> Self {
+        Self::with_bios(BIOS::from_image(image).unwrap(), CD_ROM::new(None::<&Path>).unwrap(), SIO0::new([const { None }; 2]))
+    }
+
+    fn map_pages(&mut self) {
+        let dram = self.dram.data.as_mut_ptr();
+        for page in (DRAM_START >> PAGE_BITS)..(DRAM_END >> PAGE_BITS) {
+            let offset = ((page << PAGE_BITS) - DRAM_START) as usize & (DRAM_SIZE - 1);
+            let ptr = unsafe { dram.add(offset) };
+            self.read_pages[page as usize] = ptr;
+            self.write_pages[page as usize] = ptr;
+        }
+
+        let bios = self.bios.data().as_ptr();
+        for page in (BIOS_START >> PAGE_BITS)..(BIOS_END >> PAGE_BITS) {
+            let offset = ((page << PAGE_BITS) - BIOS_START) as usize;
+            self.read_pages[page as usize] = unsafe { bios.add(offset) };
+        }
+    }
+
+    // KSEG2 is left unmasked, so its pages fall past the end of the tables
+    #[inline(always)]
+    fn read_page(&self, addr: u32) -> Option<*const u8> {
+        let page = *self.read_pages.get((addr >> PAGE_BITS) as usize)?;
+        (!page.is_null()).then(|| unsafe { page.add(addr as usize & PAGE_MASK) })
+    }
+
+    #[inline(always)]
+    fn write_page(&self, addr: u32) -> Option<*mut u8> {
+        let page = *self.write_pages.get((addr >> PAGE_BITS) as usize)?;
+        (!page.is_null()).then(|| unsafe { page.add(addr as usize & PAGE_MASK) })
+    }
+
+    #[inline(always)]
+    fn check_code(&mut self, addr: u32) {
+        let word = (addr as usize & (DRAM_SIZE - 1)) >> 2;
+        if self.code_words[word >> 6] & (1 << (word & 63)) != 0 {
+            self.invalidate_code_page(word >> 10);
+        }
+    }
+
+    #[cold]
+    fn invalidate_code_page(&mut self, page: usize) {
+        self.code_words[page * 16..(page + 1) * 16].fill(0);
+        self.dirty_code_pages.push(page as u32);
+    }
+
+    pub fn mark_code(&mut self, addr: u32) {
+        let word = (addr as usize & (DRAM_SIZE - 1)) >> 2;
+        self.code_words[word >> 6] |= 1 << (word & 63);
+    }
+
+    pub fn has_dirty_code(&self) -> bool {
+        !self.dirty_code_pages.is_empty()
+    }
+
+    pub fn take_dirty_code_page(&mut self) -> Option<u32> {
+        self.dirty_code_pages.pop()
+    }
+
+    pub fn clear_code(&mut self) {
+        self.code_words.fill(0);
+        self.dirty_code_pages.clear();
+    }
+
+    pub fn run_events(&mut self) -> bool {
+        let mut vblank = false;
+        while let Some(event) = self.scheduler.pop_due() {
+            match event {
+                Event::VBlank => {
+                    self.gpu.vblank(&mut self.interrupt);
+                    self.timer.enter_vblank(&mut self.scheduler, &mut self.interrupt);
+                    self.scheduler.schedule(Event::VBlank, FRAME_CYCLES);
+                    vblank = true;
+                }
+                Event::CdRomInterrupt => self.cd_rom.fire_interrupt(&mut self.scheduler, &mut self.interrupt),
+                Event::SioTransfer => self.sio0.transfer(&mut self.scheduler),
+                Event::SioAck => self.sio0.acknowledge(&mut self.interrupt),
+                Event::Timer => self.timer.sync(&mut self.scheduler, &mut self.interrupt),
+                Event::DmaStart => self.run_dma(),
+                Event::DmaComplete => self.dma.complete(&mut self.interrupt),
+            }
+        }
+
+        vblank
+    }
+
+    #[inline(always)]
+    pub fn watch(&mut self, addr: u32, bytes: u32, access: Access, pc: u32) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, bytes, access, pc);
+        }
+    }
+
+    #[cold]
+    fn check_watchpoints(&mut self, addr: u32, bytes: u32, access: Access, pc: u32) {
+        let addr = mask_region(addr);
+        if self.watchpoints.iter().any(|watchpoint| watchpoint.hit(addr, bytes, access)) {
+            self.watch_hit = Some(WatchHit { addr, access, pc });
+        }
+    }
+
+    pub fn watching(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    pub fn set_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.remove_watchpoint(watchpoint.addr);
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| mask_region(watchpoint.addr) != mask_region(addr));
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
 
-        Ok(Self { bios, dma: Weak::new(), dram, scratchpad, gpu, spu, interrupt, timer, cd_rom, sio0 })
+    pub fn cache_control(&self) -> u32 {
+        self.cache_control
+    }
+
+    // Cycles a load of the given width stalls the CPU for. Stores go through the write buffer
+    // and aren't charged.
+    pub fn access_cycles(&self, addr: u32, bytes: u32) -> u64 {
+        let addr = mask_region(addr);
+        match addr {
+            DRAM_START..DRAM_END => DRAM_ACCESS_CYCLES,
+            SCRATCHPAD_START..SCRATCHPAD_END if self.scratchpad_offset(addr).is_some() => 0,
+            BIOS_START..BIOS_END => self.mem_control.bios.cycles(bytes),
+            EXPANSION_1_START..EXPANSION_1_END => self.mem_control.expansion_1.cycles(bytes),
+            CD_ROM_START..CD_ROM_END => self.mem_control.cd_rom.cycles(bytes),
+            VOICE_START..REVERB_END => self.mem_control.spu.cycles(bytes),
+            EXPANSION_2_START..PCSX_END => self.mem_control.expansion_2.cycles(bytes),
+            _ => IO_ACCESS_CYCLES,
+        }
+    }
+
+    // The slowest access the dynarec can make without going through the I/O match
+    pub fn max_memory_access_cycles(&self) -> u64 {
+        DRAM_ACCESS_CYCLES.max(self.mem_control.bios.cycles(4))
+    }
+
+    #[inline(always)]
+    fn scratchpad_offset(&self, addr: u32) -> Option<u32> {
+        if self.cache_control & SCRATCHPAD_ENABLE != SCRATCHPAD_ENABLE {
+            return None;
+        }
+
+        (SCRATCHPAD_START..SCRATCHPAD_END).contains(&addr).then_some(addr - SCRATCHPAD_START)
     }
 
     pub fn read32(&mut self, addr: u32) -> u32 {
         if addr & 0b11 != 0 {panic!("Unaligned read at {:08X}", addr)}
         
+        if let Some(value) = self.read_memory32(addr) {
+            return value;
+        }
+
+        self.read32_io(mask_region(addr))
+    }
+
+    #[inline(always)]
+    pub fn read_memory32(&self, addr: u32) -> Option<u32> {
         let addr = mask_region(addr);
+        if let Some(page) = self.read_page(addr) {
+            return Some(u32::from_le_bytes(unsafe { page.cast::<[u8; 4]>().read() }));
+        }
+
+        self.scratchpad_offset(addr).map(|offset| self.scratchpad.read32(offset))
+    }
+
+    fn read32_io(&mut self, addr: u32) -> u32 {
         match addr {
-            DRAM_START..DRAM_END => self.dram.read32((addr - DRAM_START) & 0x1FFFFF),
             EXPANSION_1_START..EXPANSION_1_END => 0,
-            SCRATCHPAD_START..SCRATCHPAD_END => self.scratchpad.read32(addr - SCRATCHPAD_START),
-            BIOS_START..BIOS_END => self.bios.read32(addr - BIOS_START),
-            MEM_CTRL_START..MEM_CTRL_END => 0,
-            SIO0_START..SIO0_END => self.sio0.borrow_mut().read32(addr - SIO0_START),
+            MEM_CTRL_START..MEM_CTRL_END => self.mem_control.read32(addr - MEM_CTRL_START),
+            SIO0_START..SIO0_END => self.sio0.read32(addr - SIO0_START, &self.scheduler),
             SIO1_START..SIO1_END => 0xFF,
-            MEM_CTRL_2_START..MEM_CTRL_2_END => 0,
-            TIMER_START..TIMER_END => self.timer.borrow_mut().read32(addr - TIMER_START),
+            MEM_CTRL_2_START..MEM_CTRL_2_END => self.mem_control.read_ram_size(),
+            TIMER_START..TIMER_END => self.timer.read32(addr - TIMER_START, &mut self.scheduler, &mut self.interrupt),
             CD_ROM_START..CD_ROM_END => 0,
             IQR_START..IRQ_END => {
                 let offset = addr - IQR_START;
                 match offset {
-                    0 => self.interrupt.borrow_mut().read_status32(),
-                    4 => self.interrupt.borrow_mut().read_mask32(),
+                    0 => self.interrupt.read_status32(),
+                    4 => self.interrupt.read_mask32(),
                     _ => unreachable!(),
                 }
             }
-            DMA_START..DMA_END => self.dma.upgrade().unwrap().borrow().read_register(addr - DMA_START),
+            DMA_START..DMA_END => self.dma.read_register(addr - DMA_START),
             GPU_START..GPU_END => {
                 let offset = addr - GPU_START;
                 match offset {
@@ -118,17 +355,22 @@ impl Interface {
             }
             VOICE_START..VOICE_END => {
                 println!("Read 32-bit voice address: {addr:08X}");
-                self.spu.borrow_mut().read_voice32(addr - VOICE_START)
+                self.spu.read_voice32(addr - VOICE_START)
             },
             SPU_START..SPU_END => {
                 println!("Read 32-bit SPU address: {addr:08X}");
-                self.spu.borrow_mut().read_control32(addr - SPU_START)
+                self.spu.read_control32(addr - SPU_START)
             },
             REVERB_START..REVERB_END => {
                 println!("Read 32-bit reverb address: {addr:08X}");
-                self.spu.borrow_mut().read_reverb32(addr - REVERB_START)
+                self.spu.read_reverb32(addr - REVERB_START)
+            },
+            PCSX_START..PCSX_END => self.debug_port.read32(addr - PCSX_START),
+            SCRATCHPAD_START..SCRATCHPAD_END => 0,
+            CACHE_CONTROL_START..=CACHE_CONTROL_END => match addr {
+                CACHE_CONTROL_START => self.cache_control,
+                _ => 0,
             },
-            CACHE_CONTROL_START..=CACHE_CONTROL_END => 0,
             _ => panic!("Read access at unmapped address: {:08X}", addr),
         }
     }
@@ -136,69 +378,100 @@ impl Interface {
     pub fn read16(&mut self, addr: u32) -> u16 {
         if addr & 0b1 != 0 {panic!("Unaligned read at {:08X}", addr)}
         
+        if let Some(value) = self.read_memory16(addr) {
+            return value;
+        }
+
+        self.read16_io(mask_region(addr))
+    }
+
+    #[inline(always)]
+    pub fn read_memory16(&self, addr: u32) -> Option<u16> {
         let addr = mask_region(addr);
+        if let Some(page) = self.read_page(addr) {
+            return Some(u16::from_le_bytes(unsafe { page.cast::<[u8; 2]>().read() }));
+        }
+
+        self.scratchpad_offset(addr).map(|offset| self.scratchpad.read16(offset))
+    }
+
+    fn read16_io(&mut self, addr: u32) -> u16 {
         match addr {
-            DRAM_START..DRAM_END => self.dram.read16((addr - DRAM_START) & 0x1FFFFF),
-            SCRATCHPAD_START..SCRATCHPAD_END => self.scratchpad.read16(addr - SCRATCHPAD_START),
-            MEM_CTRL_START..MEM_CTRL_END => 0,
-            SIO0_START..SIO0_END => self.sio0.borrow_mut().read16(addr - SIO0_START),
+            MEM_CTRL_START..MEM_CTRL_END => (self.mem_control.read32(addr - MEM_CTRL_START) >> (8 * (addr & 2))) as u16,
+            SIO0_START..SIO0_END => self.sio0.read16(addr - SIO0_START, &self.scheduler),
             SIO1_START..SIO1_END => 0xFF,
-            MEM_CTRL_2_START..MEM_CTRL_2_END => 0,
-            TIMER_START..TIMER_END => self.timer.borrow_mut().read16(addr - TIMER_START),
+            MEM_CTRL_2_START..MEM_CTRL_2_END => (self.mem_control.read_ram_size() >> (8 * (addr & 2))) as u16,
+            TIMER_START..TIMER_END => self.timer.read16(addr - TIMER_START, &mut self.scheduler, &mut self.interrupt),
             CD_ROM_START..CD_ROM_END => 0,
             IQR_START..IRQ_END => {
                 let offset = addr - IQR_START;
                 match offset {
-                    0 => self.interrupt.borrow_mut().read_status16(),
-                    4 => self.interrupt.borrow_mut().read_mask16(),
+                    0 => self.interrupt.read_status16(),
+                    4 => self.interrupt.read_mask16(),
                     _ => unreachable!(),
                 }
             }
             VOICE_START..VOICE_END => {
                 println!("Read 16-bit voice address: {addr:08X}");
-                self.spu.borrow_mut().read_voice16(addr - VOICE_START)
+                self.spu.read_voice16(addr - VOICE_START)
             },
             SPU_START..SPU_END => {
                 println!("Read 16-bit SPU address: {addr:08X}");
                 match addr {
                     0x1F80_1DAC => 0x0004,
-                    _ => self.spu.borrow_mut().read_control16(addr - SPU_START),
+                    _ => self.spu.read_control16(addr - SPU_START),
                 }
             },
             REVERB_START..REVERB_END => {
                 println!("Read 16-bit reverb address: {addr:08X}");
-                self.spu.borrow_mut().read_reverb16(addr - REVERB_START)
+                self.spu.read_reverb16(addr - REVERB_START)
             },
+            SCRATCHPAD_START..SCRATCHPAD_END => 0,
             CACHE_CONTROL_START..=CACHE_CONTROL_END => 0,
             _ => panic!("Read 16-bit access at unmapped address: {:08X}", addr),
         }
     }
 
     pub fn read8(&mut self, addr: u32) -> u8 {
+        if let Some(value) = self.read_memory8(addr) {
+            return value;
+        }
+
+        self.read8_io(mask_region(addr))
+    }
+
+    #[inline(always)]
+    pub fn read_memory8(&self, addr: u32) -> Option<u8> {
         let addr = mask_region(addr);
+        if let Some(page) = self.read_page(addr) {
+            return Some(u8::from_le_bytes(unsafe { page.cast::<[u8; 1]>().read() }));
+        }
+
+        self.scratchpad_offset(addr).map(|offset| self.scratchpad.read8(offset))
+    }
+
+    fn read8_io(&mut self, addr: u32) -> u8 {
         match addr {
-            DRAM_START..DRAM_END => self.dram.read8((addr - DRAM_START) & 0x1FFFFF),
-            SCRATCHPAD_START..SCRATCHPAD_END => self.scratchpad.read8(addr - SCRATCHPAD_START),
             EXPANSION_1_START..EXPANSION_1_END => 0xFF,
-            BIOS_START..BIOS_END => self.bios.read8(addr - BIOS_START),
-            MEM_CTRL_START..MEM_CTRL_END => 0,
-            SIO0_START..SIO0_END => self.sio0.borrow_mut().read8(addr - SIO0_START),
+            MEM_CTRL_START..MEM_CTRL_END => (self.mem_control.read32(addr - MEM_CTRL_START) >> (8 * (addr & 3))) as u8,
+            SIO0_START..SIO0_END => self.sio0.read8(addr - SIO0_START, &self.scheduler),
             SIO1_START..SIO1_END => 0xFF,
-            MEM_CTRL_2_START..MEM_CTRL_2_END => 0,
-            CD_ROM_START..CD_ROM_END => self.cd_rom.borrow_mut().read8(addr - CD_ROM_START),
+            MEM_CTRL_2_START..MEM_CTRL_2_END => (self.mem_control.read_ram_size() >> (8 * (addr & 3))) as u8,
+            CD_ROM_START..CD_ROM_END => self.cd_rom.read8(addr - CD_ROM_START),
             VOICE_START..VOICE_END => {
                 println!("Read 8-bit voice address: {addr:08X}");
-                self.spu.borrow_mut().read_voice8(addr - VOICE_START)
+                self.spu.read_voice8(addr - VOICE_START)
             },
             SPU_START..SPU_END => {
                 println!("Read 8-bit SPU address: {addr:08X}");
-                self.spu.borrow_mut().read_control8(addr - SPU_START)
+                self.spu.read_control8(addr - SPU_START)
             },
             REVERB_START..REVERB_END => {
                 println!("Read 8-bit reverb address: {addr:08X}");
-                self.spu.borrow_mut().read_reverb8(addr - REVERB_START)
+                self.spu.read_reverb8(addr - REVERB_START)
             },
             EXPANSION_2_START..EXPANSION_2_END => 0,
+            SCRATCHPAD_START..SCRATCHPAD_END => 0,
             CACHE_CONTROL_START..=CACHE_CONTROL_END => 0,
             _ => panic!("Read 8-bit access at unmapped address: {:08X}", addr),
         }
@@ -207,39 +480,64 @@ impl Interface {
     pub fn write32(&mut self, addr: u32, value: u32) {
         if addr & 0b11 != 0 {panic!("Unaligned write at {:08X}", addr)}
 
+        if self.write_memory32(addr, value) {
+            return;
+        }
+
+        self.write32_io(mask_region(addr), value);
+    }
+
+    #[inline(always)]
+    pub fn write_memory32(&mut self, addr: u32, value: u32) -> bool {
         let addr = mask_region(addr);
+        if let Some(page) = self.write_page(addr) {
+            unsafe { page.cast::<[u8; 4]>().write(value.to_le_bytes()) };
+            self.check_code(addr);
+            return true;
+        }
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            self.scratchpad.write32(offset, value);
+            return true;
+        }
+
+        false
+    }
+
+    fn write32_io(&mut self, addr: u32, value: u32) {
         match addr {
-            DRAM_START..DRAM_END => self.dram.write32((addr - DRAM_START) & 0x1FFFFF, value),
-            SCRATCHPAD_START..SCRATCHPAD_END => self.scratchpad.write32(addr - SCRATCHPAD_START, value),
             BIOS_START..BIOS_END => {},
-            MEM_CTRL_START..MEM_CTRL_END => {},
-            SIO0_START..SIO0_END => self.sio0.borrow_mut().write32(addr - SIO0_START, value),
+            MEM_CTRL_START..MEM_CTRL_END => self.mem_control.write32(addr - MEM_CTRL_START, value),
+            SIO0_START..SIO0_END => self.sio0.write32(addr - SIO0_START, value, &mut self.scheduler),
             SIO1_START..SIO1_END => {},
-            MEM_CTRL_2_START..MEM_CTRL_2_END => {},
-            TIMER_START..TIMER_END => self.timer.borrow_mut().write32(addr - TIMER_START, value),
+            MEM_CTRL_2_START..MEM_CTRL_2_END => self.mem_control.write_ram_size(value),
+            TIMER_START..TIMER_END => self.timer.write32(addr - TIMER_START, value, &mut self.scheduler, &mut self.interrupt),
             CD_ROM_START..CD_ROM_END => {},
             IQR_START..IRQ_END => {
                 let offset = addr - IQR_START;
                 match offset {
-                    0 => self.interrupt.borrow_mut().acknowledge32(value),
-                    4 => self.interrupt.borrow_mut().write_mask32(value),
+                    0 => self.interrupt.acknowledge32(value),
+                    4 => self.interrupt.write_mask32(value),
                     _ => unreachable!(),
                 }
             }
-            DMA_START..DMA_END => self.dma.upgrade().unwrap().borrow_mut().write_register(addr - DMA_START, value),
+            DMA_START..DMA_END => self.dma.write_register(addr - DMA_START, value, &mut self.scheduler),
             GPU_START..GPU_END => {
                 let offset = addr - GPU_START;
                 match offset {
-                    0 => self.gpu.write_gp0(value),
+                    0 => self.gpu.write_gp0(value, &mut self.interrupt),
                     4 => self.gpu.write_gp1(value),
                     _ => unreachable!(),
                 }
             }
-            VOICE_START..VOICE_END => self.spu.borrow_mut().write_voice32(addr - VOICE_START, value),
-            SPU_START..SPU_END => self.spu.borrow_mut().write_control32(addr - SPU_START, value),
-            REVERB_START..REVERB_END => self.spu.borrow_mut().write_reverb32(addr - REVERB_START, value),
+            VOICE_START..VOICE_END => self.spu.write_voice32(addr - VOICE_START, value),
+            SPU_START..SPU_END => self.spu.write_control32(addr - SPU_START, value),
+            REVERB_START..REVERB_END => self.spu.write_reverb32(addr - REVERB_START, value),
+            PCSX_START..PCSX_END => self.debug_port.write(addr - PCSX_START, value),
+            SCRATCHPAD_START..SCRATCHPAD_END => {},
             CACHE_CONTROL_START..=CACHE_CONTROL_END => {
-                // println!("Write to CACHE_CONTROL")
+                if addr == CACHE_CONTROL_START {
+                    self.cache_control = value;
+                }
             }
             _ => panic!("Write access at unmapped address: {:08X}", addr),
         }
@@ -248,58 +546,131 @@ impl Interface {
     pub fn write16(&mut self, addr: u32, value: u16) {
         if addr & 1 != 0 {panic!("Unaligned 16-bit write at: {:08X}", addr)}
 
+        if self.write_memory16(addr, value) {
+            return;
+        }
+
+        self.write16_io(mask_region(addr), value);
+    }
+
+    #[inline(always)]
+    pub fn write_memory16(&mut self, addr: u32, value: u16) -> bool {
         let addr = mask_region(addr);
+        if let Some(page) = self.write_page(addr) {
+            unsafe { page.cast::<[u8; 2]>().write(value.to_le_bytes()) };
+            self.check_code(addr);
+            return true;
+        }
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            self.scratchpad.write16(offset, value);
+            return true;
+        }
+
+        false
+    }
+
+    fn write16_io(&mut self, addr: u32, value: u16) {
         match addr {
-            DRAM_START..DRAM_END => self.dram.write16((addr - DRAM_START) & 0x1FFFFF, value),
-            SCRATCHPAD_START..SCRATCHPAD_END => self.scratchpad.write16(addr - SCRATCHPAD_START, value),
             BIOS_START..BIOS_END => {},
             MEM_CTRL_START..MEM_CTRL_END => {},
-            SIO0_START..SIO0_END => self.sio0.borrow_mut().write16(addr - SIO0_START, value),
+            SIO0_START..SIO0_END => self.sio0.write16(addr - SIO0_START, value, &mut self.scheduler),
             SIO1_START..SIO1_END => {},
             MEM_CTRL_2_START..MEM_CTRL_2_END => {},
-            TIMER_START..TIMER_END => self.timer.borrow_mut().write16(addr - TIMER_START, value),
+            TIMER_START..TIMER_END => self.timer.write16(addr - TIMER_START, value, &mut self.scheduler, &mut self.interrupt),
             CD_ROM_START..CD_ROM_END => {},
             IQR_START..IRQ_END => {
                 let offset = addr - IQR_START;
                 match offset {
-                    0 => self.interrupt.borrow_mut().acknowledge16(value),
-                    4 => self.interrupt.borrow_mut().write_mask16(value),
+                    0 => self.interrupt.acknowledge16(value),
+                    4 => self.interrupt.write_mask16(value),
                     _ => unreachable!(),
                 }
             }
-            VOICE_START..VOICE_END => self.spu.borrow_mut().write_voice16(addr - VOICE_START, value),
-            SPU_START..SPU_END => self.spu.borrow_mut().write_control16(addr - SPU_START, value),
-            REVERB_START..REVERB_END => self.spu.borrow_mut().write_reverb16(addr - REVERB_START, value),
-            PCSX_START..PCSX_END => {
-                match addr - PCSX_START {
-                    2 => println!("Exited with code {value:04X}"),
-                    _ => {}
-                }
-            }
+            VOICE_START..VOICE_END => self.spu.write_voice16(addr - VOICE_START, value),
+            SPU_START..SPU_END => self.spu.write_control16(addr - SPU_START, value),
+            REVERB_START..REVERB_END => self.spu.write_reverb16(addr - REVERB_START, value),
+            PCSX_START..PCSX_END => self.debug_port.write(addr - PCSX_START, value as u32),
+            SCRATCHPAD_START..SCRATCHPAD_END => {},
             _ => panic!("Write 16-bit access at unmapped address: {:08X}", addr),
         }
     }
 
     pub fn write8(&mut self, addr: u32, value: u8) {
+        if self.write_memory8(addr, value) {
+            return;
+        }
+
+        self.write8_io(mask_region(addr), value);
+    }
+
+    #[inline(always)]
+    pub fn write_memory8(&mut self, addr: u32, value: u8) -> bool {
         let addr = mask_region(addr);
+        if let Some(page) = self.write_page(addr) {
+            unsafe { page.cast::<[u8; 1]>().write(value.to_le_bytes()) };
+            self.check_code(addr);
+            return true;
+        }
+        if let Some(offset) = self.scratchpad_offset(addr) {
+            self.scratchpad.write8(offset, value);
+            return true;
+        }
+
+        false
+    }
+
+    fn write8_io(&mut self, addr: u32, value: u8) {
         match addr {
-            DRAM_START..DRAM_END => self.dram.write8((addr - DRAM_START) & 0x1FFFFF, value),
-            SCRATCHPAD_START..SCRATCHPAD_END => self.scratchpad.write8(addr - SCRATCHPAD_START, value),
             BIOS_START..BIOS_END => {},
             MEM_CTRL_START..MEM_CTRL_END => {},
-            SIO0_START..SIO0_END => self.sio0.borrow_mut().write8(addr - SIO0_START, value),
+            SIO0_START..SIO0_END => self.sio0.write8(addr - SIO0_START, value, &mut self.scheduler),
             SIO1_START..SIO1_END => {},
             MEM_CTRL_2_START..MEM_CTRL_2_END => {},
-            CD_ROM_START..CD_ROM_END => self.cd_rom.borrow_mut().write8(addr - CD_ROM_START, value),
-            VOICE_START..VOICE_END => self.spu.borrow_mut().write_voice8(addr - VOICE_START, value),
-            SPU_START..SPU_END => self.spu.borrow_mut().write_control8(addr - SPU_START, value),
-            REVERB_START..REVERB_END => self.spu.borrow_mut().write_reverb8(addr - REVERB_START, value),
+            CD_ROM_START..CD_ROM_END => self.cd_rom.write8(addr - CD_ROM_START, value, &mut self.scheduler),
+            VOICE_START..VOICE_END => self.spu.write_voice8(addr - VOICE_START, value),
+            SPU_START..SPU_END => self.spu.write_control8(addr - SPU_START, value),
+            REVERB_START..REVERB_END => self.spu.write_reverb8(addr - REVERB_START, value),
             EXPANSION_2_START..EXPANSION_2_END => {}
+            PCSX_START..PCSX_END => self.debug_port.write(addr - PCSX_START, value as u32),
+            SCRATCHPAD_START..SCRATCHPAD_END => {},
             _ => panic!("Write 8-bit access at unmapped address: {:08X}", addr),
         }
     }
 }
 
+impl Savestate for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.dram.save_state(w);
+        self.scratchpad.save_state(w);
+        self.gpu.save_state(w);
+        self.spu.save_state(w);
+        self.interrupt.save_state(w);
+        self.dma.save_state(w);
+        self.timer.save_state(w);
+        self.cd_rom.save_state(w);
+        self.sio0.save_state(w);
+        self.scheduler.save_state(w);
+        self.mem_control.save_state(w);
+        w.write(&self.cache_control);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.dram.load_state(r)?;
+        self.scratchpad.load_state(r)?;
+        self.gpu.load_state(r)?;
+        self.spu.load_state(r)?;
+        self.interrupt.load_state(r)?;
+        self.dma.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.cd_rom.load_state(r)?;
+        self.sio0.load_state(r)?;
+        self.scheduler.load_state(r)?;
+        self.mem_control.load_state(r)?;
+        self.cache_control = r.read()?;
+        Ok(())
+    }
+}
+
 const REGION_MASK: [u32; 8] = [
     // KUSEG: 2048MB
     0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF,
@@ -315,4 +686,43 @@ pub fn mask_region(addr: u32) -> u32 {
     let index = (addr >> 29) as usize;
 
     addr & REGION_MASK[index]
-}
\ No newline at end of file
+}
+
+// Physical address the block cache keys instructions by, with DRAM mirrors folded. Code
+// anywhere but DRAM and BIOS is left to the interpreter.
+pub fn code_address(addr: u32) -> Option<u32> {
+    match mask_region(addr) {
+        addr @ DRAM_START..DRAM_END => Some(addr & (DRAM_SIZE as u32 - 1)),
+        addr @ BIOS_START..BIOS_END => Some(addr),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::bus::interface::Bus;
+
+    #[test]
+    fn page_mirrors() {
+        let mut bios = vec![0; 512 * 1024];
+        bios[0x7FFFC..].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        let mut bus = Bus::with_bios_image(bios);
+
+        bus.write32(0x8000_1230, 0xDEAD_BEEF);
+        assert_eq!(bus.read32(0xA000_1230), 0xDEAD_BEEF);
+        assert_eq!(bus.read16(0x0060_1232), 0xDEAD);
+        assert_eq!(bus.read8(0x8020_1231), 0xBE);
+
+        bus.write32(0x1F80_03FC, 0x0BAD_F00D);
+        assert_eq!(bus.read32(0x1F80_03FC), 0);
+
+        bus.write32(0xFFFE_0130, 0x0001_E988);
+        bus.write32(0x1F80_03FC, 0x0BAD_F00D);
+        assert_eq!(bus.read32(0x9F80_03FC), 0x0BAD_F00D);
+
+        bus.write32(0xBFC7_FFFC, 0);
+        assert_eq!(bus.read32(0xBFC7_FFFC), 0x1234_5678);
+        assert_eq!(bus.read8(0x9FC7_FFFF), 0x12);
+    }
+}
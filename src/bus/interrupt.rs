@@ -1,18 +1,22 @@
-use std::{cell::RefCell, rc::Rc};
-
-use crate::cpu::system_control::SystemControl;
+use crate::state::{Savestate, StateReader, StateWriter};
 
 #[derive(Debug)]
 pub struct Interrupt {
     I_STAT: u32,
     I_MASK: u32,
 
-    system_control: Rc<RefCell<SystemControl>>,
+    // The COP0 interrupt line only changes on requests and acknowledges, the CPU picks up
+    // the change before its next instruction.
+    line_change: Option<bool>,
 }
 
 impl Interrupt {
-    pub fn new(system_control: Rc<RefCell<SystemControl>>) -> Self {
-        Self { I_STAT: 0, I_MASK: 0, system_control }
+    pub fn new() -> Self {
+        Self { I_STAT: 0, I_MASK: 0, line_change: None }
+    }
+
+    pub fn take_line_change(&mut self) -> Option<bool> {
+        self.line_change.take()
     }
 
     pub fn read_status32(&self) -> u32 {
@@ -64,7 +68,7 @@ impl Interrupt {
         self.I_STAT |= irq as u32;
         if (self.I_STAT & self.I_MASK) & 0x7FF != 0 {
             // println!("IRQ: {irq:#?}");
-            self.system_control.borrow_mut().request_interrupt();
+            self.line_change = Some(true);
         }
         // self.set_cop0(old_irq);
     }
@@ -72,14 +76,29 @@ impl Interrupt {
     fn set_cop0(&mut self, old_irq: u32) {
         if self.I_STAT & self.I_MASK & 0x7FF == 0 {
             // println!("Clearing interrupt!");
-            self.system_control.borrow_mut().clear_interrupt();
+            self.line_change = Some(false);
         } else if self.I_STAT & self.I_MASK & 0x7FF != old_irq {
             // println!("Requesting interrupt!");
-            self.system_control.borrow_mut().request_interrupt();
+            self.line_change = Some(true);
         }
     }
 }
 
+impl Savestate for Interrupt {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.I_STAT);
+        w.write(&self.I_MASK);
+        w.write(&self.line_change);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.I_STAT = r.read()?;
+        self.I_MASK = r.read()?;
+        self.line_change = r.read()?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum IRQ {
     VBLANK        = 0x001,
@@ -0,0 +1,162 @@
+use crate::state::{Savestate, StateReader, StateWriter};
+
+const EXPANSION_1_DELAY: usize = 2;
+const EXPANSION_3_DELAY: usize = 3;
+const BIOS_DELAY: usize = 4;
+const SPU_DELAY: usize = 5;
+const CD_ROM_DELAY: usize = 6;
+const EXPANSION_2_DELAY: usize = 7;
+const COMMON_DELAY: usize = 8;
+
+// What the BIOS sets up during boot
+const RESET_VALUES: [u32; 9] = [
+    0x1F00_0000, 0x1F80_2000, 0x0013_243F, 0x0000_3022, 0x0013_243F,
+    0x2009_31E1, 0x0002_0843, 0x0007_0777, 0x0003_1125,
+];
+const RESET_RAM_SIZE: u32 = 0x0000_0B88;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    byte: u64,
+    halfword: u64,
+    word: u64,
+}
+
+impl Timing {
+    pub fn cycles(&self, bytes: u32) -> u64 {
+        match bytes {
+            1 => self.byte,
+            2 => self.halfword,
+            _ => self.word,
+        }
+    }
+}
+
+pub struct MemControl {
+    registers: [u32; 9],
+    ram_size: u32,
+
+    pub expansion_1: Timing,
+    pub expansion_3: Timing,
+    pub bios: Timing,
+    pub spu: Timing,
+    pub cd_rom: Timing,
+    pub expansion_2: Timing,
+}
+
+impl MemControl {
+    pub fn new() -> Self {
+        let mut mem_control = Self {
+            registers: RESET_VALUES,
+            ram_size: RESET_RAM_SIZE,
+
+            expansion_1: Timing::default(),
+            expansion_3: Timing::default(),
+            bios: Timing::default(),
+            spu: Timing::default(),
+            cd_rom: Timing::default(),
+            expansion_2: Timing::default(),
+        };
+        mem_control.update_timings();
+
+        mem_control
+    }
+
+    pub fn read32(&self, offset: u32) -> u32 {
+        self.registers[(offset >> 2) as usize]
+    }
+
+    pub fn write32(&mut self, offset: u32, value: u32) {
+        let register = (offset >> 2) as usize;
+        self.registers[register] = match register {
+            // Only the address bits of the expansion bases are writable
+            0 | 1 => 0x1F00_0000 | (value & 0x00FF_FFFF),
+            _ => value,
+        };
+        self.update_timings();
+    }
+
+    pub fn read_ram_size(&self) -> u32 {
+        self.ram_size
+    }
+
+    pub fn write_ram_size(&mut self, value: u32) {
+        self.ram_size = value;
+    }
+
+    fn update_timings(&mut self) {
+        let common = self.registers[COMMON_DELAY];
+        let timing = |register| access_timing(self.registers[register], common);
+
+        self.expansion_1 = timing(EXPANSION_1_DELAY);
+        self.expansion_3 = timing(EXPANSION_3_DELAY);
+        self.bios = timing(BIOS_DELAY);
+        self.spu = timing(SPU_DELAY);
+        self.cd_rom = timing(CD_ROM_DELAY);
+        self.expansion_2 = timing(EXPANSION_2_DELAY);
+    }
+}
+
+// Bits 4-7 of a delay/size register give the access time, bits 8, 10 and 11 add the COM0, COM2
+// and COM3 periods of the common delay register, and bit 12 selects a 16-bit bus. Wider
+// accesses are split into as many sequential bus cycles as the bus needs.
+fn access_timing(delay: u32, common: u32) -> Timing {
+    let access = (delay >> 4 & 0xF) as u64;
+    let com0 = (common & 0xF) as u64;
+    let com2 = (common >> 8 & 0xF) as u64;
+    let com3 = (common >> 12 & 0xF) as u64;
+
+    let (mut first, mut sequential, mut min) = (0, 0, 0);
+    if delay & (1 << 8) != 0 {
+        first += com0.saturating_sub(1);
+        sequential += com0.saturating_sub(1);
+    }
+    if delay & (1 << 10) != 0 {
+        first += com2;
+        sequential += com2;
+    }
+    if delay & (1 << 11) != 0 {
+        min = com3;
+    }
+
+    if first < 6 {first += 1}
+    first = (first + access + 2).max(min + 6);
+    sequential = (sequential + access + 2).max(min + 2);
+
+    if delay & (1 << 12) != 0 {
+        Timing { byte: first, halfword: first, word: first + sequential }
+    } else {
+        Timing { byte: first, halfword: first + sequential, word: first + 3 * sequential }
+    }
+}
+
+impl Savestate for MemControl {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.registers);
+        w.write(&self.ram_size);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.registers = r.read()?;
+        self.ram_size = r.read()?;
+        self.update_timings();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::mem_control::MemControl;
+
+    #[test]
+    fn bios_timing() {
+        let mut mem_control = MemControl::new();
+        assert_eq!(mem_control.bios.cycles(4), 25);
+        assert_eq!(mem_control.bios.cycles(1), 7);
+
+        // 16-bit bus
+        mem_control.write32(0x10, 0x0013_343F);
+        assert_eq!(mem_control.bios.cycles(2), 7);
+        assert_eq!(mem_control.bios.cycles(4), 13);
+    }
+}
@@ -1,4 +1,6 @@
 pub mod interface;
 pub mod dma;
 pub mod interrupt;
-pub mod timer;
\ No newline at end of file
+pub mod mem_control;
+pub mod timer;
+pub mod debug_port;
\ No newline at end of file
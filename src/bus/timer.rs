@@ -1,6 +1,8 @@
-use std::{cell::RefCell, hint::unreachable_unchecked, rc::Rc};
+use std::hint::unreachable_unchecked;
 
-use crate::bus::interrupt::{Interrupt, IRQ};
+use crate::{bus::interrupt::{Interrupt, IRQ}, scheduler::{Event, Scheduler}, state::{Savestate, StateReader, StateWriter}};
+
+const TIMER_IRQ: [IRQ; 3] = [IRQ::TMR0, IRQ::TMR1, IRQ::TMR2];
 
 #[derive(Debug)]
 pub struct Timer {
@@ -12,12 +14,11 @@ pub struct Timer {
 
     sysclock_8: usize,
     vblank: bool,
-
-    interrupt: Rc<RefCell<Interrupt>>,
+    last_sync: u64,
 }
 
 impl Timer {
-    pub fn new(interrupt: Rc<RefCell<Interrupt>>) -> Self {
+    pub fn new() -> Self {
         Self {
             counter: [0; 3],
             mode: [0; 3],
@@ -27,125 +28,148 @@ impl Timer {
 
             sysclock_8: 0,
             vblank: false,
-
-            interrupt
+            last_sync: 0,
         }
     }
 
-    pub fn tick(&mut self) {
-        self.tick_counter_0();
-        self.tick_counter_1();
-        self.tick_counter_2();
+    pub fn sync(&mut self, scheduler: &mut Scheduler, interrupt: &mut Interrupt) {
+        let now = scheduler.now();
+        let mut cycles = now - self.last_sync;
+        self.last_sync = now;
 
-        self.vblank = false;
+        if self.vblank && cycles > 0 {
+            self.advance(1, true, interrupt);
+            self.vblank = false;
+            cycles -= 1;
+        }
+        self.advance(cycles, false, interrupt);
 
-        // println!("Timers [{:04X}, {:04X}, {:04X}]", self.counter[0], self.counter[1], self.counter[2]);
+        self.schedule_next_irq(scheduler);
     }
 
-    pub fn enter_vblank(&mut self) {
+    pub fn enter_vblank(&mut self, scheduler: &mut Scheduler, interrupt: &mut Interrupt) {
+        self.sync(scheduler, interrupt);
         self.vblank = true;
+        self.schedule_next_irq(scheduler);
     }
 
-    fn tick_counter_0(&mut self) {
-        let counter = &mut self.counter[0];
-        let mode = self.mode[0];
-        let target = self.target[0];
-        let target_enabled = mode & 0x08 != 0;
-        let enabled = &mut self.irq_enabled[0];
+    fn advance(&mut self, cycles: u64, vblank: bool, interrupt: &mut Interrupt) {
+        if cycles == 0 {return}
 
-        if mode & 1 != 0 {
+        if self.mode[0] & 1 != 0 {
             panic!("Timer 0 sync modes not implemented")
         } else {
-            *counter += 1;
-            if *counter == 0xFFFF {
-                *counter = 0;
-                if mode & 0x20 != 0 && *enabled {
-                    self.interrupt.borrow_mut().request(IRQ::TMR0);
-                    *enabled = mode & 0x40 != 0;
-                }
-            } else if target_enabled && *counter == target {
-                *counter = 0;
-                if mode & 0x10 != 0 && *enabled {
-                    self.interrupt.borrow_mut().request(IRQ::TMR0);
-                    *enabled = mode & 0x40 != 0;
-                }
-            }
+            self.count(0, cycles, interrupt);
         }
-    }
 
-    fn tick_counter_1(&mut self) {
-        let counter = &mut self.counter[1];
         let mode = &mut self.mode[1];
-        let target = self.target[1];
-        let target_enabled = *mode & 0x08 != 0;
-        let enabled = &mut self.irq_enabled[1];
-
         if *mode & 1 != 0 {
             let sync = (*mode >> 1) & 3;
             match sync {
                 3 => {
                     *mode &= !1;
-                    *mode |= !(self.vblank as u32);
+                    *mode |= !(vblank as u32);
                 }
                 0 | 1 | 2 => panic!("Timer 1 sync mode {sync} not implemented!"),
                 _ => unsafe { unreachable_unchecked() }
             }
         } else {
-            *counter += 1;
-            if *counter == 0xFFFF {
-                *counter = 0;
-                if *mode & 0x20 != 0 && *enabled {
-                    self.interrupt.borrow_mut().request(IRQ::TMR1);
-                    *enabled = *mode & 0x40 != 0;
-                }
-            } else if target_enabled && *counter == target {
-                *counter = 0;
-                if *mode & 0x10 != 0 && *enabled {
-                    self.interrupt.borrow_mut().request(IRQ::TMR1);
-                    *enabled = *mode & 0x40 != 0;
-                }
-            }
+            self.count(1, cycles, interrupt);
         }
-    }
 
-    fn tick_counter_2(&mut self) {
-        let counter = &mut self.counter[2];
-        let mode = &mut self.mode[2];
-        let target = self.target[2];
-        let target_enabled = *mode & 0x10 != 0;
-        let enabled = &mut self.irq_enabled[2];
-        let source = *mode & 0x200 != 0;
-        let sync = (*mode >> 1) & 3;
+        let mode = self.mode[2];
+        let source = mode & 0x200 != 0;
+        let sync = (mode >> 1) & 3;
 
-        if *mode & 1 != 0 && (sync == 0 || sync == 3) {
+        if mode & 1 != 0 && (sync == 0 || sync == 3) {
             /* Sync Mode 0 and 3 stall the timer */
-        } else if !source || self.sysclock_8 == 0 {
+        } else if !source {
             self.sysclock_8 = 8;
-            *counter += 1;
-            // let old_counter_2 = *counter;
-            if *counter == 0xFFFF {
-                *counter = 0;
-                if *mode & 0x20 != 0 && *enabled {
-                    self.interrupt.borrow_mut().request(IRQ::TMR2);
-                    *enabled = *mode & 0x40 != 0;
-                    *mode |= 0x1000;
-                    // println!("Timer 2 IRQ. Timer 2 before: {:04X}", old_counter_2);
-                }
-            } else if target_enabled && *counter == target {
-                *counter = 0;
-                if *mode & 0x10 != 0 && *enabled {
-                    self.interrupt.borrow_mut().request(IRQ::TMR2);
-                    *enabled = *mode & 0x40 != 0;
-                    *mode |= 0x0800;
-                    // println!("Timer 2 IRQ. Timer 2 target: {:04X}. Timer 2 before: {:04X}", self.target[2], old_counter_2);
-                }
+            self.count(2, cycles, interrupt);
+        } else {
+            // Counts on the cycle where the divider is already 0, then reloads it with 8
+            let first = self.sysclock_8 as u64 + 1;
+            if cycles < first {
+                self.sysclock_8 -= cycles as usize;
+            } else {
+                let rest = cycles - first;
+                self.sysclock_8 = 8 - (rest % 9) as usize;
+                self.count(2, 1 + rest / 9, interrupt);
+            }
+        }
+    }
+
+    fn count(&mut self, idx: usize, mut ticks: u64, interrupt: &mut Interrupt) {
+        while ticks > 0 {
+            let counter = self.counter[idx];
+            let (steps, overflow) = self.ticks_until_reset(idx, counter);
+            if ticks < steps {
+                self.counter[idx] = counter.wrapping_add(ticks as u32);
+                return;
+            }
+
+            ticks -= steps;
+            self.counter[idx] = 0;
+
+            let (irq_bit, reached_flag) = if overflow {(0x20, 0x1000)} else {(0x10, 0x0800)};
+            if self.mode[idx] & irq_bit != 0 && self.irq_enabled[idx] {
+                interrupt.request(TIMER_IRQ[idx]);
+                self.irq_enabled[idx] = self.mode[idx] & 0x40 != 0;
+                if idx == 2 {self.mode[idx] |= reached_flag}
             }
+        }
+    }
+
+    // Timer 2 checks bit 4 of its mode for the target instead of bit 3
+    fn ticks_until_reset(&self, idx: usize, counter: u32) -> (u64, bool) {
+        let target_enabled = self.mode[idx] & if idx == 2 {0x10} else {0x08} != 0;
+        let target = self.target[idx];
+
+        let to_overflow = if counter < 0xFFFF {(0xFFFF - counter) as u64} else {u64::MAX};
+        let to_target = if target_enabled && target > counter {(target - counter) as u64} else {u64::MAX};
+
+        (to_overflow.min(to_target), to_overflow <= to_target)
+    }
+
+    fn ticks_until_irq(&self, idx: usize) -> Option<u64> {
+        let raises = |overflow: bool| self.irq_enabled[idx] && self.mode[idx] & if overflow {0x20} else {0x10} != 0;
+
+        let (first, overflow) = self.ticks_until_reset(idx, self.counter[idx]);
+        if first == u64::MAX {return None}
+        if raises(overflow) {return Some(first)}
+
+        let (period, overflow) = self.ticks_until_reset(idx, 0);
+        if period == u64::MAX || !raises(overflow) {return None}
+        Some(first + period)
+    }
+
+    fn schedule_next_irq(&self, scheduler: &mut Scheduler) {
+        if self.vblank {
+            scheduler.schedule(Event::Timer, 1);
+            return;
+        }
+
+        let timer_0 = if self.mode[0] & 1 != 0 {None} else {self.ticks_until_irq(0)};
+        let timer_1 = if self.mode[1] & 1 != 0 {None} else {self.ticks_until_irq(1)};
+
+        let mode = self.mode[2];
+        let sync = (mode >> 1) & 3;
+        let timer_2 = if mode & 1 != 0 && (sync == 0 || sync == 3) {
+            None
+        } else if mode & 0x200 == 0 {
+            self.ticks_until_irq(2)
         } else {
-            self.sysclock_8 -= 1;
+            self.ticks_until_irq(2).map(|ticks| self.sysclock_8 as u64 + 1 + (ticks - 1) * 9)
+        };
+
+        match [timer_0, timer_1, timer_2].into_iter().flatten().min() {
+            Some(cycles) => scheduler.schedule(Event::Timer, cycles),
+            None => scheduler.cancel(Event::Timer),
         }
     }
 
-    pub fn read32(&mut self, offset: u32) -> u32 {
+    pub fn read32(&mut self, offset: u32, scheduler: &mut Scheduler, interrupt: &mut Interrupt) -> u32 {
+        self.sync(scheduler, interrupt);
         let timer_idx = ((offset & 0x30) >> 4) as usize;
         match offset & 0xF {
             0x0 => {
@@ -163,7 +187,8 @@ impl Timer {
         }
     }
 
-    pub fn read16(&mut self, offset: u32) -> u16 {
+    pub fn read16(&mut self, offset: u32, scheduler: &mut Scheduler, interrupt: &mut Interrupt) -> u16 {
+        self.sync(scheduler, interrupt);
         let timer_idx = (offset >> 4) as usize;
         match offset & 0xF {
             0x0 => {
@@ -181,7 +206,8 @@ impl Timer {
         }
     }
 
-    pub fn write32(&mut self, offset: u32, value: u32) {
+    pub fn write32(&mut self, offset: u32, value: u32, scheduler: &mut Scheduler, interrupt: &mut Interrupt) {
+        self.sync(scheduler, interrupt);
         let timer_idx = ((offset & 0x30) >> 4) as usize;
         match offset & 0xF {
             0x0 => self.counter[timer_idx] = value & 0xFFFF,
@@ -194,9 +220,12 @@ impl Timer {
             0xC => {},
             _ => unreachable!()
         }
+
+        self.schedule_next_irq(scheduler);
     }
 
-    pub fn write16(&mut self, offset: u32, value: u16) {
+    pub fn write16(&mut self, offset: u32, value: u16, scheduler: &mut Scheduler, interrupt: &mut Interrupt) {
+        self.sync(scheduler, interrupt);
         let timer_idx = ((offset & 0x30) >> 4) as usize;
         match offset & 0xF {
             0x0 => self.counter[timer_idx] = value as u32,
@@ -209,5 +238,30 @@ impl Timer {
             0xC => {},
             _ => unreachable!()
         }
+
+        self.schedule_next_irq(scheduler);
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.counter);
+        w.write(&self.mode);
+        w.write(&self.target);
+        w.write(&self.irq_enabled);
+        w.write(&self.sysclock_8);
+        w.write(&self.vblank);
+        w.write(&self.last_sync);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.counter = r.read()?;
+        self.mode = r.read()?;
+        self.target = r.read()?;
+        self.irq_enabled = r.read()?;
+        self.sysclock_8 = r.read()?;
+        self.vblank = r.read()?;
+        self.last_sync = r.read()?;
+        Ok(())
+    }
+}
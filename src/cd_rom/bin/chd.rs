@@ -0,0 +1,598 @@
+use std::{fs::File, io::{Cursor, Read}, path::Path};
+
+use anyhow::anyhow;
+use flate2::read::DeflateDecoder;
+
+use crate::cd_rom::bin::{cue::Span, ecc, read_at, sector::{Sector, RAW_SECTOR_SIZE, SYNC}, SectorSource, Track, TrackMode};
+
+const HEADER_SIZE: usize = 124;
+// Each frame is a raw sector followed by its subchannel data
+const FRAME_SIZE: usize = RAW_SECTOR_SIZE + SUBCODE_SIZE;
+const SUBCODE_SIZE: usize = 96;
+// Tracks are padded out to a multiple of this many frames
+const TRACK_PADDING: u32 = 4;
+
+// Only the CD flavours of each codec are used for discs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Zlib,
+    Lzma,
+    Flac,
+}
+
+impl Codec {
+    fn from_tag(tag: &[u8]) -> anyhow::Result<Option<Codec>> {
+        match tag {
+            [0, 0, 0, 0] => Ok(None),
+            b"cdzl" => Ok(Some(Codec::Zlib)),
+            b"cdlz" => Ok(Some(Codec::Lzma)),
+            b"cdfl" => Ok(Some(Codec::Flac)),
+            _ => Err(anyhow!("Unsupported CHD codec {}", String::from_utf8_lossy(tag))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Hunk {
+    // Compressed with one of the header's codecs
+    Compressed { codec: usize, offset: u64, length: u32 },
+    Uncompressed { offset: u64 },
+    // A copy of an earlier hunk
+    Copy { hunk: u32 },
+    Zero,
+}
+
+// A disc compressed with MAME's chdman. The image is cut into hunks of a few frames each,
+// compressed on their own and decoded when one of their sectors is read.
+pub struct ChdImage {
+    file: File,
+    hunk_bytes: usize,
+    codecs: [Option<Codec>; 4],
+    map: Vec<Hunk>,
+    spans: Vec<Span>,
+    // The last hunk decoded, since sectors are mostly read in order
+    hunk: Option<(u32, Vec<u8>)>,
+}
+
+impl ChdImage {
+    pub fn open<P>(path: P) -> anyhow::Result<Self>
+    where P: AsRef<Path> {
+        let mut file = File::open(path)?;
+        let header = read_at(&mut file, 0, HEADER_SIZE)?;
+        if &header[..8] != b"MComprHD" {
+            return Err(anyhow!("Not a CHD file"));
+        }
+        if be(&header[12..16]) != 5 {
+            return Err(anyhow!("Only version 5 CHD files are supported"));
+        }
+
+        let mut codecs = [None; 4];
+        for (index, codec) in codecs.iter_mut().enumerate() {
+            *codec = Codec::from_tag(&header[16 + index * 4..20 + index * 4])?;
+        }
+        let logical_bytes = be(&header[32..40]);
+        let map_offset = be(&header[40..48]);
+        let meta_offset = be(&header[48..56]);
+        let hunk_bytes = be(&header[56..60]) as usize;
+        let unit_bytes = be(&header[60..64]) as usize;
+        if unit_bytes != FRAME_SIZE || hunk_bytes == 0 || !hunk_bytes.is_multiple_of(FRAME_SIZE) {
+            return Err(anyhow!("The CHD doesn't hold a CD image"));
+        }
+        if header[104..124].iter().any(|&byte| byte != 0) {
+            return Err(anyhow!("CHD files with a parent aren't supported"));
+        }
+
+        let hunks = logical_bytes.div_ceil(hunk_bytes as u64) as u32;
+        let map = match codecs[0] {
+            Some(_) => read_compressed_map(&mut file, map_offset, hunks, hunk_bytes)?,
+            None => read_uncompressed_map(&mut file, map_offset, hunks, hunk_bytes)?,
+        };
+        let spans = layout(&read_tracks(&mut file, meta_offset)?);
+
+        Ok(Self { file, hunk_bytes, codecs, map, spans, hunk: None })
+    }
+
+    pub fn tracks(&self) -> Vec<Track> {
+        self.spans.iter().map(|span| span.track).collect()
+    }
+
+    fn load_hunk(&mut self, hunk: u32) -> anyhow::Result<&[u8]> {
+        if self.hunk.as_ref().is_none_or(|(index, _)| *index != hunk) {
+            let data = self.decode_hunk(hunk)?;
+            self.hunk = Some((hunk, data));
+        }
+
+        Ok(&self.hunk.as_ref().unwrap().1)
+    }
+
+    fn decode_hunk(&mut self, hunk: u32) -> anyhow::Result<Vec<u8>> {
+        match *self.map.get(hunk as usize).ok_or_else(|| anyhow!("Read past the end of the CHD"))? {
+            Hunk::Compressed { codec, offset, length } => {
+                let codec = self.codecs[codec].ok_or_else(|| anyhow!("Invalid CHD codec"))?;
+                let compressed = read_at(&mut self.file, offset, length as usize)?;
+                decompress_cd(codec, &compressed, self.hunk_bytes)
+            }
+            Hunk::Uncompressed { offset } => read_at(&mut self.file, offset, self.hunk_bytes),
+            Hunk::Copy { hunk: source } if source < hunk => self.decode_hunk(source),
+            Hunk::Copy { .. } => Err(anyhow!("Invalid CHD hunk reference")),
+            Hunk::Zero => Ok(vec![0; self.hunk_bytes]),
+        }
+    }
+}
+
+impl SectorSource for ChdImage {
+    fn read(&mut self, lba: u32) -> Option<Sector> {
+        let span = *self.spans.iter().find(|span| (span.lba..span.lba + span.count).contains(&lba))?;
+        let frame = (span.file_sector + lba - span.lba) as usize;
+        let offset = frame * FRAME_SIZE % self.hunk_bytes;
+        let hunk = self.load_hunk((frame * FRAME_SIZE / self.hunk_bytes) as u32).ok()?;
+
+        let mut raw = [0; RAW_SECTOR_SIZE];
+        raw.copy_from_slice(&hunk[offset..offset + RAW_SECTOR_SIZE]);
+        // CHD keeps CD audio big endian
+        if span.track.mode == TrackMode::Audio {
+            raw.chunks_exact_mut(2).for_each(|sample| sample.swap(0, 1));
+        }
+
+        Some(span.track.mode.sector(lba, &raw[..span.track.mode.sector_size()]))
+    }
+}
+
+// The CD codecs compress the sector data of every frame in the hunk with the base codec, then the
+// subchannel data with deflate. Data sectors may have had their sync and ECC taken out.
+fn decompress_cd(codec: Codec, src: &[u8], hunk_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let frames = hunk_bytes / FRAME_SIZE;
+    let ecc_bytes = frames.div_ceil(8);
+    let length_bytes = if hunk_bytes < 0x10000 {2} else {3};
+    let header_bytes = ecc_bytes + length_bytes;
+    if src.len() < header_bytes {
+        return Err(anyhow!("Truncated CHD hunk"));
+    }
+
+    let base_length = src[ecc_bytes..header_bytes].iter().fold(0, |length, &byte| (length << 8) | byte as usize);
+    let base = src.get(header_bytes..header_bytes + base_length).ok_or_else(|| anyhow!("Truncated CHD hunk"))?;
+    let sectors_size = frames * RAW_SECTOR_SIZE;
+    let sectors = match codec {
+        Codec::Zlib => inflate(base, sectors_size)?,
+        Codec::Lzma => unlzma(base, sectors_size)?,
+        Codec::Flac => unflac(base, sectors_size)?,
+    };
+
+    // The subchannel isn't used, so it's left blank
+    let mut hunk = vec![0; hunk_bytes];
+    for frame in 0..frames {
+        let sector = &mut hunk[frame * FRAME_SIZE..frame * FRAME_SIZE + RAW_SECTOR_SIZE];
+        sector.copy_from_slice(&sectors[frame * RAW_SECTOR_SIZE..(frame + 1) * RAW_SECTOR_SIZE]);
+        if src[frame / 8] & (1 << (frame % 8)) != 0 {
+            sector[..12].copy_from_slice(&SYNC);
+            ecc::generate_ecc(sector);
+        }
+    }
+
+    Ok(hunk)
+}
+
+fn inflate(src: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    DeflateDecoder::new(src).read_exact(&mut data)?;
+    Ok(data)
+}
+
+// The stream has no header, so one is made up from the properties chdman always uses
+fn unlzma(src: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut dictionary = 1 << 26;
+    for bits in 11..=30 {
+        if len <= 2 << bits {dictionary = 2 << bits; break}
+        if len <= 3 << bits {dictionary = 3 << bits; break}
+    }
+
+    let mut header = vec![0x5D];
+    header.extend_from_slice(&(dictionary as u32).to_le_bytes());
+    let options = lzma_rs::decompress::Options {
+        unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(len as u64)),
+        ..Default::default()
+    };
+
+    let mut data = Vec::with_capacity(len);
+    lzma_rs::lzma_decompress_with_options(&mut (&header[..]).chain(src), &mut data, &options)
+        .map_err(|err| anyhow!("Invalid CHD hunk: {err:?}"))?;
+    Ok(data)
+}
+
+// Only the frames are stored, so a stream header is made up for 16-bit stereo at 44.1 kHz
+fn unflac(src: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut block_size = len / 4;
+    while block_size > 2048 {block_size /= 2}
+
+    let mut stream = b"fLaC\x80\x00\x00\x22".to_vec();
+    stream.extend_from_slice(&(block_size as u16).to_be_bytes());
+    stream.extend_from_slice(&(block_size as u16).to_be_bytes());
+    stream.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+    stream.extend_from_slice(&[0; 16]);
+    stream.extend_from_slice(src);
+
+    let mut reader = claxon::FlacReader::new(Cursor::new(stream)).map_err(|err| anyhow!("Invalid CHD hunk: {err}"))?;
+    let mut data = Vec::with_capacity(len);
+    for sample in reader.samples().take(len / 2) {
+        let sample = sample.map_err(|err| anyhow!("Invalid CHD hunk: {err}"))?;
+        data.extend_from_slice(&(sample as i16).to_be_bytes());
+    }
+
+    if data.len() != len {
+        return Err(anyhow!("Truncated CHD hunk"));
+    }
+    Ok(data)
+}
+
+fn read_uncompressed_map(file: &mut File, offset: u64, hunks: u32, hunk_bytes: usize) -> anyhow::Result<Vec<Hunk>> {
+    let map = read_at(file, offset, hunks as usize * 4)?;
+    Ok(map.chunks_exact(4).map(|entry| match be(entry) {
+        0 => Hunk::Zero,
+        block => Hunk::Uncompressed { offset: block * hunk_bytes as u64 },
+    }).collect())
+}
+
+// The map of a compressed CHD is itself compressed: a Huffman coded, run length encoded list of
+// how each hunk is stored, followed by the lengths and references that go with them
+fn read_compressed_map(file: &mut File, offset: u64, hunks: u32, hunk_bytes: usize) -> anyhow::Result<Vec<Hunk>> {
+    const TYPE_NONE: u8 = 4;
+    const TYPE_SELF: u8 = 5;
+    const TYPE_PARENT: u8 = 6;
+    const RLE_SMALL: u8 = 7;
+    const RLE_LARGE: u8 = 8;
+    const SELF_0: u8 = 9;
+    const SELF_1: u8 = 10;
+
+    let header = read_at(file, offset, 16)?;
+    let map_bytes = be(&header[0..4]) as usize;
+    let mut next_offset = be(&header[4..10]);
+    let (length_bits, self_bits) = (header[12] as u32, header[13] as u32);
+    let data = read_at(file, offset + 16, map_bytes)?;
+    let mut bits = BitReader::new(&data);
+
+    let huffman = Huffman::read(&mut bits)?;
+    let mut types = Vec::with_capacity(hunks as usize);
+    let (mut last, mut repeat) = (0, 0);
+    while types.len() < hunks as usize {
+        if repeat > 0 {
+            types.push(last);
+            repeat -= 1;
+            continue;
+        }
+        match huffman.decode(&mut bits) {
+            RLE_SMALL => repeat = 2 + huffman.decode(&mut bits) as u32,
+            RLE_LARGE => repeat = 2 + 16 + ((huffman.decode(&mut bits) as u32) << 4) + huffman.decode(&mut bits) as u32,
+            value => last = value,
+        }
+        types.push(last);
+    }
+
+    let mut last_self = 0;
+    types.into_iter().map(|kind| Ok(match kind {
+        0..=3 => {
+            let length = bits.read(length_bits);
+            bits.read(16);
+            let hunk = Hunk::Compressed { codec: kind as usize, offset: next_offset, length };
+            next_offset += length as u64;
+            hunk
+        }
+        TYPE_NONE => {
+            bits.read(16);
+            let hunk = Hunk::Uncompressed { offset: next_offset };
+            next_offset += hunk_bytes as u64;
+            hunk
+        }
+        TYPE_SELF => {
+            last_self = bits.read(self_bits);
+            Hunk::Copy { hunk: last_self }
+        }
+        SELF_0 => Hunk::Copy { hunk: last_self },
+        SELF_1 => {
+            last_self += 1;
+            Hunk::Copy { hunk: last_self }
+        }
+        TYPE_PARENT.. => return Err(anyhow!("CHD files with a parent aren't supported")),
+    })).collect()
+}
+
+// Track metadata is stored as text, one entry per track
+fn read_tracks(file: &mut File, mut offset: u64) -> anyhow::Result<Vec<(Track, u32)>> {
+    let mut tracks = Vec::new();
+    while offset != 0 {
+        let header = read_at(file, offset, 16)?;
+        let length = be(&header[5..8]) as usize;
+        if &header[0..4] == b"CHT2" || &header[0..4] == b"CHTR" {
+            let text = read_at(file, offset + 16, length)?;
+            tracks.push(parse_track(String::from_utf8_lossy(&text).trim_end_matches('\0'))?);
+        }
+        offset = be(&header[8..16]);
+    }
+
+    if tracks.is_empty() {
+        return Err(anyhow!("The CHD has no CD track metadata"));
+    }
+    tracks.sort_by_key(|(track, _)| track.number);
+    Ok(tracks)
+}
+
+// Returns the track and its postgap. The pregap is part of the track's frames if its type starts
+// with a V.
+fn parse_track(text: &str) -> anyhow::Result<(Track, u32)> {
+    let error = || anyhow!("Invalid CHD track metadata: {text}");
+    let field = |name: &str| text.split_whitespace().find_map(|field| field.strip_prefix(name)?.strip_prefix(':'));
+    let number = |name: &str| field(name).map_or(Ok(0), |value| value.parse::<u32>().map_err(|_| error()));
+
+    let mode = match field("TYPE").ok_or_else(error)? {
+        "AUDIO" => TrackMode::Audio,
+        "MODE1" => TrackMode::Mode1(2048),
+        "MODE1_RAW" => TrackMode::Mode1(RAW_SECTOR_SIZE),
+        "MODE2" | "MODE2_FORM_MIX" => TrackMode::Mode2(2336),
+        "MODE2_FORM1" => TrackMode::Mode2(2048),
+        "MODE2_FORM2" => TrackMode::Mode2(2324),
+        "MODE2_RAW" => TrackMode::Mode2(RAW_SECTOR_SIZE),
+        _ => return Err(error()),
+    };
+    let frames = number("FRAMES")?;
+    let pregap = number("PREGAP")?;
+    let stored_pregap = if field("PGTYPE").is_some_and(|kind| kind.starts_with('V')) {pregap.min(frames)} else {0};
+
+    let track = Track { number: number("TRACK")? as u8, mode, start: stored_pregap, pregap, length: frames - stored_pregap };
+    Ok((track, number("POSTGAP")?))
+}
+
+// Places the tracks on the disc. Track starts are relative to their own frames until now.
+fn layout(tracks: &[(Track, u32)]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let (mut lba, mut frame) = (0, 0);
+    for &(track, postgap) in tracks {
+        let stored_pregap = track.start;
+        lba += track.pregap - stored_pregap;
+        let count = track.length + stored_pregap;
+        spans.push(Span { track: Track { start: lba + stored_pregap, ..track }, file: 0, file_sector: frame, lba, count });
+
+        lba += count + postgap;
+        frame += count.div_ceil(TRACK_PADDING) * TRACK_PADDING;
+    }
+
+    spans
+}
+
+// Canonical Huffman codes for the 16 values of the map, at most 8 bits long
+struct Huffman {
+    lookup: Vec<(u8, u32)>,
+}
+
+impl Huffman {
+    const CODES: usize = 16;
+    const MAX_BITS: u32 = 8;
+
+    // The code lengths are run length encoded, with 1 as the escape
+    fn read(bits: &mut BitReader) -> anyhow::Result<Self> {
+        let mut lengths = Vec::with_capacity(Self::CODES);
+        while lengths.len() < Self::CODES {
+            match bits.read(4) {
+                1 => match bits.read(4) {
+                    1 => lengths.push(1),
+                    length => {
+                        let repeat = bits.read(4) as usize + 3;
+                        lengths.extend(std::iter::repeat_n(length, repeat));
+                    }
+                },
+                length => lengths.push(length),
+            }
+        }
+        if lengths.len() != Self::CODES || lengths.iter().any(|&length| length > Self::MAX_BITS) {
+            return Err(anyhow!("Invalid CHD map"));
+        }
+
+        // Codes are handed out from the longest down
+        let mut starts = [0; 33];
+        let mut start = 0;
+        for length in (1..=32).rev() {
+            let count = lengths.iter().filter(|&&other| other == length).count() as u32;
+            starts[length as usize] = start;
+            start = (start + count) >> 1;
+        }
+
+        let mut lookup = vec![(0, 0); 1 << Self::MAX_BITS];
+        for (value, &length) in lengths.iter().enumerate() {
+            if length == 0 {continue}
+            let code = starts[length as usize];
+            starts[length as usize] += 1;
+
+            let shift = Self::MAX_BITS - length;
+            let first = (code << shift) as usize;
+            for entry in lookup.iter_mut().skip(first).take(1 << shift) {
+                *entry = (value as u8, length);
+            }
+        }
+
+        Ok(Self { lookup })
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> u8 {
+        let (value, length) = self.lookup[bits.peek(Self::MAX_BITS) as usize];
+        bits.skip(length);
+        value
+    }
+}
+
+// Reads bits from the most significant down. Reading past the end gives zeroes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn peek(&self, count: u32) -> u32 {
+        (0..count as usize).fold(0, |value, bit| {
+            let position = self.position + bit;
+            let byte = self.data.get(position / 8).copied().unwrap_or(0);
+            (value << 1) | ((byte >> (7 - position % 8)) & 1) as u32
+        })
+    }
+
+    fn skip(&mut self, count: u32) {
+        self.position += count as usize;
+    }
+
+    fn read(&mut self, count: u32) -> u32 {
+        let value = self.peek(count);
+        self.skip(count);
+        value
+    }
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::DeflateEncoder, Compression};
+
+    use crate::cd_rom::bin::{chd::{ChdImage, FRAME_SIZE, HEADER_SIZE}, ecc, sector::{RAW_SECTOR_SIZE, SYNC}, SectorSource, TrackMode};
+
+    #[test]
+    fn uncompressed_map_and_track_layout() {
+        const HUNK_FRAMES: usize = 4;
+        const HUNK_BYTES: usize = HUNK_FRAMES * FRAME_SIZE;
+        const MAP: usize = HEADER_SIZE;
+        const META: usize = MAP + 16;
+
+        // A 6 frame data track padded out to 8, then a 2 frame audio track padded out to 4. Each
+        // data frame is filled with its number, and the audio holds big endian 0x0102 samples.
+        let mut frames = Vec::new();
+        for frame in 0..12 {
+            let mut bytes = vec![0; FRAME_SIZE];
+            match frame {
+                0..6 => bytes[..RAW_SECTOR_SIZE].fill(frame as u8),
+                8..10 => bytes[..RAW_SECTOR_SIZE].chunks_exact_mut(2).for_each(|sample| sample.copy_from_slice(&[0x01, 0x02])),
+                _ => {}
+            }
+            frames.extend_from_slice(&bytes);
+        }
+
+        let mut file = vec![0; HUNK_BYTES];
+        file[..8].copy_from_slice(b"MComprHD");
+        file[12..16].copy_from_slice(&5u32.to_be_bytes());
+        file[32..40].copy_from_slice(&(frames.len() as u64).to_be_bytes());
+        file[40..48].copy_from_slice(&(MAP as u64).to_be_bytes());
+        file[48..56].copy_from_slice(&(META as u64).to_be_bytes());
+        file[56..60].copy_from_slice(&(HUNK_BYTES as u32).to_be_bytes());
+        file[60..64].copy_from_slice(&(FRAME_SIZE as u32).to_be_bytes());
+
+        // The hunks are stored in the blocks after the header, the last one in reverse order
+        for (hunk, block) in [1u32, 3, 2].into_iter().enumerate() {
+            file[MAP + hunk * 4..MAP + hunk * 4 + 4].copy_from_slice(&block.to_be_bytes());
+        }
+        let tracks = [
+            "TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:6 PREGAP:0 PGTYPE:MODE1 PGSUB:RW POSTGAP:0\0",
+            "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:2 PREGAP:0 PGTYPE:MODE1 PGSUB:RW POSTGAP:0\0",
+        ];
+        let mut offset = META;
+        for (index, text) in tracks.iter().enumerate() {
+            let next = if index + 1 < tracks.len() {offset + 16 + text.len()} else {0};
+            file[offset..offset + 4].copy_from_slice(b"CHT2");
+            file[offset + 4..offset + 8].copy_from_slice(&(text.len() as u32).to_be_bytes());
+            file[offset + 8..offset + 16].copy_from_slice(&(next as u64).to_be_bytes());
+            file[offset + 16..offset + 16 + text.len()].copy_from_slice(text.as_bytes());
+            offset += 16 + text.len();
+        }
+        file.extend_from_slice(&frames[..HUNK_BYTES]);
+        file.extend_from_slice(&frames[2 * HUNK_BYTES..]);
+        file.extend_from_slice(&frames[HUNK_BYTES..2 * HUNK_BYTES]);
+
+        let path = std::env::temp_dir().join("psx_chd_test.chd");
+        std::fs::write(&path, file).unwrap();
+        let mut chd = ChdImage::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let tracks = chd.tracks();
+        assert_eq!(tracks.iter().map(|track| (track.number, track.start, track.length)).collect::<Vec<_>>(), [(1, 0, 6), (2, 6, 2)]);
+        assert_eq!(tracks[1].mode, TrackMode::Audio);
+
+        for lba in [5, 0, 4] {
+            assert_eq!(chd.read(lba).unwrap()[0], lba as u8);
+        }
+        // Audio comes out little endian
+        let audio = chd.read(7).unwrap();
+        assert_eq!((audio[0], audio[1]), (0x02, 0x01));
+        assert!(chd.read(8).is_none());
+    }
+
+    #[test]
+    fn compressed_map_and_zlib_hunk() {
+        const HUNK_BYTES: usize = 4 * FRAME_SIZE;
+        const MAP: usize = HEADER_SIZE;
+
+        // A Mode 1 sector, stored without its sync and ECC for the decoder to work out again
+        let mut raw = [0; RAW_SECTOR_SIZE];
+        raw[..12].copy_from_slice(&SYNC);
+        raw[12..16].copy_from_slice(&[0x00, 0x02, 0x00, 0x01]);
+        raw[16..0x810].iter_mut().enumerate().for_each(|(index, byte)| *byte = (index * 3) as u8);
+        ecc::generate_mode1(&mut raw);
+        let mut sectors = vec![0; 4 * RAW_SECTOR_SIZE];
+        sectors[12..0x81C].copy_from_slice(&raw[12..0x81C]);
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&sectors).unwrap();
+        let base = encoder.finish().unwrap();
+        let mut hunk = vec![0x01];
+        hunk.extend_from_slice(&(base.len() as u16).to_be_bytes());
+        hunk.extend_from_slice(&base);
+
+        // Codes 0 for a cdzl hunk and 1 for a copy of another hunk, then the first hunk's length
+        // and CRC and the second one's source. Code lengths go 4 bits at a time, with 1 escaped.
+        let mut bits = String::new();
+        for length in [1, 0, 0, 0, 0, 1].into_iter().chain([0; 10]) {
+            bits += if length == 1 {"00010001"} else {"0000"};
+        }
+        bits += "01";
+        bits += &format!("{:016b}{:016b}{:08b}", hunk.len(), 0, 0);
+        let map: Vec<u8> = bits.as_bytes().chunks(8)
+            .map(|byte| byte.iter().enumerate().fold(0, |value, (bit, &digit)| value | ((digit - b'0') << (7 - bit))))
+            .collect();
+
+        let text = "TRACK:1 TYPE:MODE1_RAW SUBTYPE:NONE FRAMES:8 PREGAP:0 PGTYPE:MODE1 PGSUB:RW POSTGAP:0\0";
+        let meta = MAP + 16 + map.len();
+        let data = meta + 16 + text.len();
+
+        let mut file = vec![0; HEADER_SIZE];
+        file[..8].copy_from_slice(b"MComprHD");
+        file[12..16].copy_from_slice(&5u32.to_be_bytes());
+        file[16..20].copy_from_slice(b"cdzl");
+        file[32..40].copy_from_slice(&(2 * HUNK_BYTES as u64).to_be_bytes());
+        file[40..48].copy_from_slice(&(MAP as u64).to_be_bytes());
+        file[48..56].copy_from_slice(&(meta as u64).to_be_bytes());
+        file[56..60].copy_from_slice(&(HUNK_BYTES as u32).to_be_bytes());
+        file[60..64].copy_from_slice(&(FRAME_SIZE as u32).to_be_bytes());
+        file.extend_from_slice(&(map.len() as u32).to_be_bytes());
+        file.extend_from_slice(&(data as u64).to_be_bytes()[2..]);
+        file.extend_from_slice(&[0, 0, 16, 8, 0, 0]);
+        file.extend_from_slice(&map);
+        file.extend_from_slice(b"CHT2");
+        file.extend_from_slice(&(text.len() as u32).to_be_bytes());
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(text.as_bytes());
+        file.extend_from_slice(&hunk);
+
+        let path = std::env::temp_dir().join("psx_chd_zlib_test.chd");
+        std::fs::write(&path, file).unwrap();
+        let mut chd = ChdImage::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The second hunk is the first one again
+        for lba in [0, 4] {
+            let sector = chd.read(lba).unwrap();
+            assert!((0..RAW_SECTOR_SIZE - 12).all(|index| sector[index] == raw[index + 12]));
+        }
+        assert!((0..RAW_SECTOR_SIZE - 12).all(|index| chd.read(1).unwrap()[index] == 0));
+    }
+}
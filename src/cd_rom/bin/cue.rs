@@ -0,0 +1,177 @@
+use anyhow::anyhow;
+
+use crate::cd_rom::bin::{Track, TrackMode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CueTrack {
+    number: u8,
+    mode: TrackMode,
+    file: usize,
+    // Gaps the sheet asks for that aren't stored in the file
+    pregap: u32,
+    postgap: u32,
+    // Index positions within the file, in sectors
+    index0: Option<u32>,
+    index1: u32,
+}
+
+// Where a track's sectors come from, and where they go on the disc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub track: Track,
+    pub file: usize,
+    pub file_sector: u32,
+    pub lba: u32,
+    pub count: u32,
+}
+
+pub struct CueSheet {
+    files: Vec<String>,
+    tracks: Vec<CueTrack>,
+}
+
+impl CueSheet {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut sheet = CueSheet { files: Vec::new(), tracks: Vec::new() };
+        for (line_number, line) in text.lines().enumerate() {
+            let error = || anyhow!("Invalid CUE sheet line {}: {}", line_number + 1, line.trim());
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    // The name may be quoted, and is followed by the file type
+                    let name = match rest.strip_prefix('"') {
+                        Some(quoted) => quoted.split_once('"').ok_or_else(error)?.0,
+                        None => rest.rsplit_once(char::is_whitespace).map_or(rest, |(name, _)| name),
+                    };
+                    sheet.files.push(name.to_string());
+                }
+                "TRACK" => {
+                    let (number, mode) = rest.split_once(char::is_whitespace).ok_or_else(error)?;
+                    let mode = match mode.trim().to_ascii_uppercase().as_str() {
+                        "AUDIO" => TrackMode::Audio,
+                        "MODE1/2048" => TrackMode::Mode1(2048),
+                        "MODE1/2352" => TrackMode::Mode1(2352),
+                        "MODE2/2048" => TrackMode::Mode2(2048),
+                        "MODE2/2336" => TrackMode::Mode2(2336),
+                        "MODE2/2352" => TrackMode::Mode2(2352),
+                        _ => return Err(error()),
+                    };
+                    let file = sheet.files.len().checked_sub(1).ok_or_else(error)?;
+                    let number = number.parse().map_err(|_| error())?;
+                    sheet.tracks.push(CueTrack { number, mode, file, pregap: 0, postgap: 0, index0: None, index1: 0 });
+                }
+                "INDEX" | "PREGAP" | "POSTGAP" => {
+                    let track = sheet.tracks.last_mut().ok_or_else(error)?;
+                    let (index, time) = match rest.split_once(char::is_whitespace) {
+                        Some((index, time)) => (index.parse::<u32>().ok(), time.trim()),
+                        None => (None, rest),
+                    };
+                    let frames = parse_msf(time).ok_or_else(error)?;
+
+                    match (command.to_ascii_uppercase().as_str(), index) {
+                        ("PREGAP", _) => track.pregap = frames,
+                        ("POSTGAP", _) => track.postgap = frames,
+                        (_, Some(0)) => track.index0 = Some(frames),
+                        (_, Some(1)) => track.index1 = frames,
+                        (_, Some(_)) => {}
+                        (_, None) => return Err(error()),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if sheet.tracks.is_empty() {
+            return Err(anyhow!("CUE sheet has no tracks"));
+        }
+
+        Ok(sheet)
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    // Lays the tracks out one after the other, given each file's length in bytes. A file's
+    // tracks are assumed to share a sector size.
+    pub fn layout(&self, file_lengths: &[usize]) -> Vec<Span> {
+        let mut spans = Vec::new();
+        let mut file_base = 0;
+        let mut gaps = 0;
+
+        for (file, &length) in file_lengths.iter().enumerate() {
+            let tracks: Vec<&CueTrack> = self.tracks.iter().filter(|track| track.file == file).collect();
+            let Some(first) = tracks.first() else { continue };
+            let file_sectors = (length / first.mode.sector_size()) as u32;
+
+            for (index, track) in tracks.iter().enumerate() {
+                gaps += track.pregap;
+                let first = track.index0.unwrap_or(track.index1).min(file_sectors);
+                let end = tracks.get(index + 1).map_or(file_sectors, |next| next.index0.unwrap_or(next.index1)).clamp(first, file_sectors);
+
+                spans.push(Span {
+                    track: Track {
+                        number: track.number,
+                        mode: track.mode,
+                        start: file_base + gaps + track.index1,
+                        pregap: track.pregap + track.index1.saturating_sub(first),
+                        length: end.saturating_sub(track.index1),
+                    },
+                    file,
+                    file_sector: first,
+                    lba: file_base + gaps + first,
+                    count: end - first,
+                });
+                gaps += track.postgap;
+            }
+
+            file_base += file_sectors;
+        }
+
+        spans
+    }
+}
+
+fn parse_msf(time: &str) -> Option<u32> {
+    let mut fields = time.split(':').map(|field| field.trim().parse::<u32>().ok());
+    let (min, sec, frame) = (fields.next()??, fields.next()??, fields.next()??);
+    (fields.next().is_none() && sec < 60 && frame < 75).then_some((min * 60 + sec) * 75 + frame)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cd_rom::bin::{cue::CueSheet, Track, TrackMode};
+
+    #[test]
+    fn multi_file_layout() {
+        let sheet = CueSheet::parse(concat!(
+            "REM a comment\r\n",
+            "FILE \"Game (Track 1).bin\" BINARY\r\n",
+            "  TRACK 01 MODE2/2352\r\n",
+            "    INDEX 01 00:00:00\r\n",
+            "FILE \"Game (Track 2).bin\" BINARY\r\n",
+            "  TRACK 02 AUDIO\r\n",
+            "    INDEX 00 00:00:00\r\n",
+            "    INDEX 01 00:02:00\r\n",
+            "  TRACK 03 AUDIO\r\n",
+            "    PREGAP 00:02:00\r\n",
+            "    INDEX 01 00:30:00\r\n",
+        )).unwrap();
+        assert_eq!(sheet.files(), ["Game (Track 1).bin", "Game (Track 2).bin"]);
+
+        let spans = sheet.layout(&[1000 * 2352, 5000 * 2352]);
+        let tracks: Vec<Track> = spans.iter().map(|span| span.track).collect();
+        assert_eq!(tracks, [
+            Track { number: 1, mode: TrackMode::Mode2(2352), start: 0, pregap: 0, length: 1000 },
+            Track { number: 2, mode: TrackMode::Audio, start: 1150, pregap: 150, length: 2100 },
+            Track { number: 3, mode: TrackMode::Audio, start: 3400, pregap: 150, length: 2750 },
+        ]);
+        assert_eq!((spans[1].file, spans[1].file_sector, spans[1].lba, spans[1].count), (1, 0, 1000, 2250));
+        assert_eq!((spans[2].file_sector, spans[2].lba, spans[2].count), (2250, 3400, 2750));
+
+        assert!(CueSheet::parse("TRACK 01 AUDIO").is_err());
+    }
+}
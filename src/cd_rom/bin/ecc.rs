@@ -0,0 +1,103 @@
+// The error detection and correction codes of data sectors, for images that leave them out.
+// EDC is a CRC-32 with the polynomial 0x8001801B, ECC is the pair of Reed-Solomon product
+// codes P and Q over GF(2^8).
+
+const EDC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut edc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            edc = (edc >> 1) ^ if edc & 1 != 0 {0xD801_8001} else {0};
+            bit += 1;
+        }
+        table[i] = edc;
+        i += 1;
+    }
+    table
+};
+
+// Multiplication by alpha, and division by 1 + alpha
+const ECC_F: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = ((i << 1) ^ if i & 0x80 != 0 {0x11D} else {0}) as u8;
+        i += 1;
+    }
+    table
+};
+
+const ECC_B: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i ^ ECC_F[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+pub fn edc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |edc, &byte| (edc >> 8) ^ EDC_TABLE[((edc ^ byte as u32) & 0xFF) as usize])
+}
+
+// Fills in the EDC and ECC of a raw sector whose sync, header and user data are in place
+pub fn generate_mode1(sector: &mut [u8]) {
+    let edc = edc(&sector[..0x810]);
+    sector[0x810..0x814].copy_from_slice(&edc.to_le_bytes());
+    sector[0x814..0x81C].fill(0);
+    generate_ecc(sector);
+}
+
+pub fn generate_form1(sector: &mut [u8]) {
+    let edc = edc(&sector[0x10..0x818]);
+    sector[0x818..0x81C].copy_from_slice(&edc.to_le_bytes());
+
+    // Mode 2 leaves the header out of the ECC, so it can be changed without redoing it
+    let header: [u8; 4] = sector[12..16].try_into().unwrap();
+    sector[12..16].fill(0);
+    generate_ecc(sector);
+    sector[12..16].copy_from_slice(&header);
+}
+
+pub fn generate_form2(sector: &mut [u8]) {
+    let edc = edc(&sector[0x10..0x92C]);
+    sector[0x92C..0x930].copy_from_slice(&edc.to_le_bytes());
+}
+
+// P and Q over everything from the header on, as Mode 1 does it
+pub fn generate_ecc(sector: &mut [u8]) {
+    let (data, parity) = sector[12..].split_at_mut(0x810);
+    let p = compute_block(data, 86, 24, 2, 86);
+    parity[..172].copy_from_slice(&p);
+
+    // Q also covers P
+    let mut with_p = [0; 0x810 + 172];
+    with_p[..0x810].copy_from_slice(data);
+    with_p[0x810..].copy_from_slice(&p);
+    let q = compute_block(&with_p, 52, 43, 86, 88);
+    parity[172..276].copy_from_slice(&q);
+}
+
+fn compute_block(src: &[u8], major_count: usize, minor_count: usize, major_mult: usize, minor_inc: usize) -> Vec<u8> {
+    let size = major_count * minor_count;
+    let mut parity = vec![0; major_count * 2];
+    for major in 0..major_count {
+        let mut index = (major >> 1) * major_mult + (major & 1);
+        let (mut ecc_a, mut ecc_b) = (0u8, 0u8);
+        for _ in 0..minor_count {
+            let byte = src[index];
+            index += minor_inc;
+            if index >= size {index -= size}
+            ecc_a ^= byte;
+            ecc_b ^= byte;
+            ecc_a = ECC_F[ecc_a as usize];
+        }
+        ecc_a = ECC_B[(ECC_F[ecc_a as usize] ^ ecc_b) as usize];
+        parity[major] = ecc_a;
+        parity[major + major_count] = ecc_a ^ ecc_b;
+    }
+    parity
+}
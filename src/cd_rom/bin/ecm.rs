@@ -0,0 +1,182 @@
+use std::{fs::File, io::{BufReader, Read, Seek, SeekFrom}, path::Path};
+
+use anyhow::anyhow;
+
+use crate::cd_rom::bin::{ecc, sector::{Sector, RAW_SECTOR_SIZE, SYNC}, SectorSource};
+
+// Each record type, with how many bytes an item takes up in the file and once decoded. Type 0 is
+// literal bytes, the rest are sectors stripped of whatever can be worked out again.
+const SIZES: [(u64, u64); 4] = [(1, 1), (0x803, 0x930), (0x804, 0x920), (0x918, 0x920)];
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    kind: usize,
+    count: u64,
+    // Where the record starts in the file, and in the decoded image
+    input: u64,
+    output: u64,
+}
+
+impl Record {
+    fn output_len(&self) -> u64 {
+        self.count * SIZES[self.kind].1
+    }
+}
+
+// An image packed with ECM. The records are indexed when the file is opened, so a sector can be
+// decoded on its own.
+pub struct EcmImage {
+    file: File,
+    records: Vec<Record>,
+    len: u64,
+}
+
+impl EcmImage {
+    pub fn open<P>(path: P) -> anyhow::Result<Self>
+    where P: AsRef<Path> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"ECM\0" {
+            return Err(anyhow!("Not an ECM file"));
+        }
+
+        let mut records = Vec::new();
+        let mut output = 0;
+        loop {
+            let byte = read_byte(&mut reader)?;
+            let kind = (byte & 3) as usize;
+            let mut count = ((byte >> 2) & 0x1F) as u64;
+            let (mut more, mut shift) = (byte & 0x80 != 0, 5);
+            while more {
+                let byte = read_byte(&mut reader)?;
+                if shift > 32 {
+                    return Err(anyhow!("Invalid ECM record count"));
+                }
+                count |= ((byte & 0x7F) as u64) << shift;
+                more = byte & 0x80 != 0;
+                shift += 7;
+            }
+            if count == 0xFFFF_FFFF {break}
+
+            let record = Record { kind, count: count + 1, input: reader.stream_position()?, output };
+            reader.seek_relative((record.count * SIZES[kind].0) as i64)?;
+            output += record.output_len();
+            records.push(record);
+        }
+
+        Ok(Self { file: reader.into_inner(), records, len: output })
+    }
+
+    // The length of the decoded image
+    pub fn image_len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> anyhow::Result<()> {
+        let mut index = self.records.partition_point(|record| record.output + record.output_len() <= offset);
+        while !buf.is_empty() {
+            let record = *self.records.get(index).ok_or_else(|| anyhow!("Read past the end of the ECM image"))?;
+            let (input_size, output_size) = SIZES[record.kind];
+            let item = (offset - record.output) / output_size;
+            let skip = ((offset - record.output) % output_size) as usize;
+            self.file.seek(SeekFrom::Start(record.input + item * input_size))?;
+
+            let len = if record.kind == 0 {
+                let len = buf.len().min((record.output_len() - (offset - record.output)) as usize);
+                self.file.read_exact(&mut buf[..len])?;
+                len
+            } else {
+                let sector = self.decode_sector(record.kind)?;
+                let decoded = if record.kind == 1 {&sector[..]} else {&sector[0x10..]};
+                let len = buf.len().min(decoded.len() - skip);
+                buf[..len].copy_from_slice(&decoded[skip..skip + len]);
+                len
+            };
+
+            offset += len as u64;
+            buf = &mut buf[len..];
+            if offset == record.output + record.output_len() {index += 1}
+        }
+
+        Ok(())
+    }
+
+    // Mode 2 sectors come out without their sync and header, which ECM leaves as literal bytes
+    fn decode_sector(&mut self, kind: usize) -> anyhow::Result<[u8; RAW_SECTOR_SIZE]> {
+        let mut sector = [0; RAW_SECTOR_SIZE];
+        match kind {
+            1 => {
+                sector[..12].copy_from_slice(&SYNC);
+                self.file.read_exact(&mut sector[12..15])?;
+                sector[15] = 1;
+                self.file.read_exact(&mut sector[0x10..0x810])?;
+                ecc::generate_mode1(&mut sector);
+            }
+            2 => {
+                self.file.read_exact(&mut sector[0x14..0x818])?;
+                sector.copy_within(0x14..0x18, 0x10);
+                ecc::generate_form1(&mut sector);
+            }
+            _ => {
+                self.file.read_exact(&mut sector[0x14..0x92C])?;
+                sector.copy_within(0x14..0x18, 0x10);
+                ecc::generate_form2(&mut sector);
+            }
+        }
+
+        Ok(sector)
+    }
+}
+
+impl SectorSource for EcmImage {
+    fn read(&mut self, lba: u32) -> Option<Sector> {
+        let offset = lba as u64 * RAW_SECTOR_SIZE as u64;
+        if offset + RAW_SECTOR_SIZE as u64 > self.len {return None}
+
+        let mut raw = [0; RAW_SECTOR_SIZE];
+        self.read_at(offset, &mut raw).ok()?;
+        Some(Sector::from_raw(&raw))
+    }
+}
+
+fn read_byte(reader: &mut impl Read) -> anyhow::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cd_rom::bin::{ecc, ecm::EcmImage, sector::{RAW_SECTOR_SIZE, SYNC}};
+
+    #[test]
+    fn form1_sector_round_trip() {
+        assert_eq!(ecc::edc(b"123456789"), 0x6EC2_EDC4);
+
+        let mut raw = [0; RAW_SECTOR_SIZE];
+        raw[..12].copy_from_slice(&SYNC);
+        raw[12..16].copy_from_slice(&[0x00, 0x02, 0x16, 0x02]);
+        raw[16..24].copy_from_slice(&[0, 0, 0x08, 0, 0, 0, 0x08, 0]);
+        raw[24..0x818].iter_mut().enumerate().for_each(|(index, byte)| *byte = (index * 7) as u8);
+        ecc::generate_form1(&mut raw);
+
+        // Sync and header as 16 literal bytes, then the rest of the sector as a Form 1 record
+        let mut file = b"ECM\0".to_vec();
+        file.push(15 << 2);
+        file.extend_from_slice(&raw[..16]);
+        file.push(2);
+        file.extend_from_slice(&raw[0x14..0x818]);
+        file.extend_from_slice(&[0xFC, 0xFF, 0xFF, 0xFF, 0x3F, 0, 0, 0, 0]);
+
+        let path = std::env::temp_dir().join("psx_ecm_test.ecm");
+        std::fs::write(&path, file).unwrap();
+        let mut ecm = EcmImage::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ecm.image_len(), RAW_SECTOR_SIZE as u64);
+        let mut decoded = [0; RAW_SECTOR_SIZE];
+        ecm.read_at(0, &mut decoded).unwrap();
+        assert_eq!(decoded, raw);
+    }
+}
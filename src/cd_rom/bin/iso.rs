@@ -0,0 +1,60 @@
+use std::{fs::File, path::Path};
+
+use crate::cd_rom::bin::{read_at_padded, sector::Sector, DiskAddress, SectorSource};
+
+const USER_DATA_SIZE: u64 = 0x800;
+
+// A plain ISO 9660 image, holding only the 2048 data bytes of each sector. The rest of the sector
+// is made up on the way out.
+pub struct IsoImage {
+    file: File,
+    sectors: u32,
+}
+
+impl IsoImage {
+    pub fn open<P>(path: P) -> anyhow::Result<Self>
+    where P: AsRef<Path> {
+        let file = File::open(path)?;
+        let sectors = file.metadata()?.len().div_ceil(USER_DATA_SIZE) as u32;
+        Ok(Self { file, sectors })
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+}
+
+impl SectorSource for IsoImage {
+    fn read(&mut self, lba: u32) -> Option<Sector> {
+        if lba >= self.sectors {return None}
+
+        let mut data = [0; USER_DATA_SIZE as usize];
+        read_at_padded(&mut self.file, lba as u64 * USER_DATA_SIZE, &mut data).ok()?;
+
+        Some(Sector::from_user_data(DiskAddress::from_lba(lba), &data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cd_rom::{bin::{iso::IsoImage, SectorSource}, iso9660, CD_ROM};
+
+    #[test]
+    fn sectors_get_headers_and_files_are_found() {
+        let path = std::env::temp_dir().join("psx_iso_test.iso");
+        let text = b"Made up on the way out".repeat(100);
+        std::fs::write(&path, iso9660::test::image(&[("README.TXT;1", &text)])).unwrap();
+        let mut iso = IsoImage::open(&path).unwrap();
+        let mut cd_rom = CD_ROM::new(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 00:02:16, Mode 2, then a Form 1 data subheader
+        let sector = iso.read(16).unwrap();
+        assert_eq!((0..12).map(|index| sector[index]).collect::<Vec<u8>>(), [0x00, 0x02, 0x16, 0x02, 0, 0, 0x08, 0, 0, 0, 0x08, 0]);
+        assert_eq!((13..18).map(|index| sector[index]).collect::<Vec<u8>>(), b"CD001");
+        assert!(iso.read(iso.sectors()).is_none());
+
+        assert_eq!(cd_rom.read_file("cdrom:\\readme.txt"), Some(text));
+        assert!(cd_rom.find_file("cdrom:\\MISSING.TXT;1").is_none());
+    }
+}
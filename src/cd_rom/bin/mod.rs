@@ -1,30 +1,239 @@
-use std::{collections::HashMap, fmt::Debug, path::Path};
+use std::{collections::VecDeque, fmt::Debug, fs::File, io::{Read, Seek, SeekFrom}, path::Path};
 
-use crate::cd_rom::{bin::sector::Sector};
+use anyhow::anyhow;
 
+use crate::{cd_rom::bin::{chd::ChdImage, cue::{CueSheet, Span}, ecm::EcmImage, iso::IsoImage, pbp::PbpImage, raw::RawImage, sector::{Sector, RAW_SECTOR_SIZE}}, state::{StateReader, StateValue, StateWriter}};
+
+pub mod chd;
+pub mod cue;
+pub mod ecc;
+pub mod ecm;
+pub mod iso;
+pub mod pbp;
+pub mod raw;
 pub mod sector;
 
-pub type DiskMap = HashMap<DiskAddress, Sector>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackMode {
+    Audio,
+    // The number is how many bytes each sector takes up in the image
+    Mode1(usize),
+    Mode2(usize),
+}
+
+impl TrackMode {
+    pub fn sector_size(self) -> usize {
+        match self {
+            TrackMode::Audio => RAW_SECTOR_SIZE,
+            TrackMode::Mode1(size) | TrackMode::Mode2(size) => size,
+        }
+    }
+
+    // Turns a sector as stored in an image into a raw one
+    pub fn sector(self, lba: u32, bytes: &[u8]) -> Sector {
+        match self {
+            TrackMode::Mode1(size) | TrackMode::Mode2(size) if size < RAW_SECTOR_SIZE => Sector::from_user_data(DiskAddress::from_lba(lba), bytes),
+            _ => Sector::from_raw(bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Track {
+    pub number: u8,
+    pub mode: TrackMode,
+    // The LBA of INDEX 01, and how many sectors of pregap come before it
+    pub start: u32,
+    pub pregap: u32,
+    pub length: u32,
+}
+
+// Where the sectors of a disc come from. Images that need decoding do it as sectors are asked
+// for, rather than all at once when the disc goes in.
+pub trait SectorSource {
+    // None past the end of the disc, or if the image can't be read there
+    fn read(&mut self, lba: u32) -> Option<Sector>;
+}
+
+// How many recently read sectors are kept around
+const CACHE_SECTORS: usize = 32;
+
+// The sectors of a disc, along with its track table
+pub struct DiskMap {
+    source: Box<dyn SectorSource>,
+    tracks: Vec<Track>,
+    // Most recently read first
+    cache: VecDeque<(u32, Sector)>,
+}
+
+impl Default for DiskMap {
+    fn default() -> Self {
+        Self::with_source(RawImage::default(), Vec::new())
+    }
+}
+
+impl DiskMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_source(source: impl SectorSource + 'static, tracks: Vec<Track>) -> Self {
+        Self { source: Box::new(source), tracks, cache: VecDeque::with_capacity(CACHE_SECTORS) }
+    }
+
+    pub fn get(&mut self, address: &DiskAddress) -> Option<Sector> {
+        let lba = address.to_lba()?;
+        if let Some(index) = self.cache.iter().position(|&(cached, _)| cached == lba) {
+            let entry = self.cache.remove(index)?;
+            self.cache.push_front(entry);
+            return Some(entry.1);
+        }
+
+        let sector = self.source.read(lba)?;
+        if self.cache.len() == CACHE_SECTORS {
+            self.cache.pop_back();
+        }
+        self.cache.push_front((lba, sector));
+        Some(sector)
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+}
 
 pub trait DiskTrait {
     fn from_bin<P>(bin_path: P) -> anyhow::Result<DiskMap>
     where P: AsRef<Path>;
+
+    fn from_cue<P>(cue_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path>;
+
+    fn from_iso<P>(iso_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path>;
+
+    fn from_chd<P>(chd_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path>;
+
+    fn from_ecm<P>(ecm_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path>;
+
+    // disc counts from 0, and picks one disc out of a multi-disc EBOOT
+    fn from_pbp<P>(pbp_path: P, disc: usize) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path>;
+
+    // Picks the format from the file extension
+    fn open<P>(path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path>;
 }
 
 impl DiskTrait for DiskMap {
+    // A lone .bin is taken to be a single data track
     fn from_bin<P>(bin_path: P) -> anyhow::Result<DiskMap>
     where P: AsRef<Path> {
-        let disk = std::fs::read(bin_path)?;
-        let chunks = disk.chunks_exact(2352);
-        let mut sectors: HashMap<DiskAddress, Sector> = HashMap::new();
+        let file = File::open(bin_path)?;
+        let track = single_track(TrackMode::Mode2(RAW_SECTOR_SIZE), (file.metadata()?.len() / RAW_SECTOR_SIZE as u64) as u32);
+        let span = Span { track, file: 0, file_sector: 0, lba: 0, count: track.length };
+
+        Ok(DiskMap::with_source(RawImage::new(vec![file], vec![span]), vec![track]))
+    }
+
+    fn from_cue<P>(cue_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path> {
+        let cue_path = cue_path.as_ref();
+        let sheet = CueSheet::parse(&std::fs::read_to_string(cue_path)?)?;
+        let directory = cue_path.parent().unwrap_or(Path::new(""));
+
+        let files = sheet.files().iter()
+            .map(|name| File::open(directory.join(name)).map_err(|err| anyhow!("{name}: {err}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let lengths = files.iter()
+            .map(|file| Ok(file.metadata()?.len() as usize))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let spans = sheet.layout(&lengths);
+        let tracks = spans.iter().map(|span| span.track).collect();
+        Ok(DiskMap::with_source(RawImage::new(files, spans), tracks))
+    }
+
+    fn from_iso<P>(iso_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path> {
+        let iso = IsoImage::open(iso_path)?;
+        let tracks = vec![single_track(TrackMode::Mode2(0x800), iso.sectors())];
+        Ok(DiskMap::with_source(iso, tracks))
+    }
+
+    fn from_chd<P>(chd_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path> {
+        let chd = ChdImage::open(chd_path)?;
+        let tracks = chd.tracks();
+        Ok(DiskMap::with_source(chd, tracks))
+    }
 
-        for chunk in chunks {
-            let (address, sector) = Sector::from_bytes(chunk);
-            sectors.insert(address, sector);
+    fn from_ecm<P>(ecm_path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path> {
+        let ecm = EcmImage::open(ecm_path)?;
+        let sectors = (ecm.image_len() / RAW_SECTOR_SIZE as u64) as u32;
+        Ok(DiskMap::with_source(ecm, vec![single_track(TrackMode::Mode2(RAW_SECTOR_SIZE), sectors)]))
+    }
+
+    fn from_pbp<P>(pbp_path: P, disc: usize) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path> {
+        let pbp = PbpImage::open(pbp_path, disc)?;
+        let tracks = pbp.tracks();
+        Ok(DiskMap::with_source(pbp, tracks))
+    }
+
+    // Later discs of an EBOOT are asked for as game.pbp#2, game.pbp#3 and so on
+    fn open<P>(path: P) -> anyhow::Result<DiskMap>
+    where P: AsRef<Path> {
+        let path = path.as_ref();
+        if let Some((pbp, disc)) = path.to_str().and_then(|path| path.rsplit_once('#')) {
+            if let (true, Ok(disc @ 1..)) = (has_extension(Path::new(pbp), "pbp"), disc.parse::<usize>()) {
+                return DiskMap::from_pbp(pbp, disc - 1);
+            }
         }
 
-        Ok(sectors)
+        match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("cue") => DiskMap::from_cue(path),
+            Some("iso") => DiskMap::from_iso(path),
+            Some("chd") => DiskMap::from_chd(path),
+            Some("ecm") => DiskMap::from_ecm(path),
+            Some("pbp") => DiskMap::from_pbp(path, 0),
+            _ => DiskMap::from_bin(path),
+        }
+    }
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+// Images without a track table hold one data track
+fn single_track(mode: TrackMode, length: u32) -> Track {
+    Track { number: 1, mode, start: 0, pregap: 0, length }
+}
+
+// For the headers and blocks of compressed images, which have to be there in full
+fn read_at(file: &mut File, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// For sectors stored as they are, where the last one may be cut short. Whatever's missing is left
+// as it was in the buffer.
+fn read_at_padded(file: &mut File, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            read => len += read,
+        }
     }
+    Ok(())
 }
 
 #[derive(Default, Clone, Copy, Hash, PartialEq, Eq)]
@@ -39,6 +248,23 @@ impl DiskAddress {
         Self { min: bytes[0], sec: bytes[1], frame: bytes[2] }
     }
 
+    pub fn to_bytes(self) -> [u8; 3] {
+        [self.min, self.sec, self.frame]
+    }
+
+    // Logical block addresses start after the two second pregap
+    pub fn from_lba(lba: u32) -> DiskAddress {
+        let bcd = |value: u32| (((value / 10) << 4) | (value % 10)) as u8;
+        let frames = lba + 150;
+        Self { min: bcd(frames / (75 * 60)), sec: bcd(frames / 75 % 60), frame: bcd(frames % 75) }
+    }
+
+    // None for addresses inside the two second pregap
+    pub fn to_lba(self) -> Option<u32> {
+        let bin = |value: u8| (value >> 4) as u32 * 10 + (value & 0xF) as u32;
+        ((bin(self.min) * 60 + bin(self.sec)) * 75 + bin(self.frame)).checked_sub(150)
+    }
+
     pub fn increment(&mut self) {
         fn carry_lo(x: &mut u8) {
             let carry = (((*x & 0x0F) + 6) & 0x10) >> 4;
@@ -60,6 +286,16 @@ impl DiskAddress {
     }
 }
 
+impl StateValue for DiskAddress {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&[self.min, self.sec, self.frame]);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(DiskAddress::from_bytes(&r.read::<[u8; 3]>()?))
+    }
+}
+
 impl Debug for DiskAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DiskAddress")
@@ -68,4 +304,33 @@ impl Debug for DiskAddress {
             .field("frame", &format!("{:02X}",  self.frame))
             .finish()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use crate::cd_rom::bin::{DiskAddress, DiskMap, DiskTrait};
+
+    #[test]
+    fn cue_sectors_come_from_their_file() {
+        let directory = std::env::temp_dir().join("psx_cue_test");
+        std::fs::create_dir_all(&directory).unwrap();
+        // Each sector is filled with its own number within the file, plus 10 for the second file
+        let sectors = |range: std::ops::Range<u8>| range.flat_map(|index| [index; 2352]).collect::<Vec<u8>>();
+        std::fs::write(directory.join("data.bin"), sectors(0..3)).unwrap();
+        std::fs::write(directory.join("audio.bin"), sectors(10..14)).unwrap();
+        std::fs::write(directory.join("game.cue"), concat!(
+            "FILE \"data.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n",
+            "FILE \"audio.bin\" BINARY\n  TRACK 02 AUDIO\n    INDEX 00 00:00:00\n    INDEX 01 00:00:02\n",
+        )).unwrap();
+
+        let mut disk = DiskMap::open(directory.join("game.cue")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let tracks = disk.tracks();
+        assert_eq!(tracks.iter().map(|track| (track.number, track.start, track.pregap, track.length)).collect::<Vec<_>>(), [(1, 0, 0, 3), (2, 5, 2, 2)]);
+        // Setloc addresses, the second file's pregap included
+        for (address, expected) in [([0x00, 0x02, 0x02], 2), ([0x00, 0x02, 0x03], 10), ([0x00, 0x02, 0x05], 12), ([0x00, 0x02, 0x06], 13)] {
+            assert_eq!(disk.get(&DiskAddress::from_bytes(&address)).map(|sector| sector[0]), Some(expected));
+        }
+        assert!(disk.get(&DiskAddress::from_bytes(&[0x00, 0x02, 0x07])).is_none());
+    }
+}
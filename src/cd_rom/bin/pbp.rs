@@ -0,0 +1,177 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::anyhow;
+use flate2::read::DeflateDecoder;
+
+use crate::cd_rom::bin::{read_at, sector::{Sector, RAW_SECTOR_SIZE}, Track, TrackMode, SectorSource};
+
+// The image is split into blocks of 16 sectors, each deflated on its own
+const BLOCK_SECTORS: u32 = 16;
+const BLOCK_SIZE: usize = BLOCK_SECTORS as usize * RAW_SECTOR_SIZE;
+// Offsets within a PSISOIMG
+const TOC: u64 = 0x800;
+const INDEX: u64 = 0x4000;
+const DATA: u64 = 0x10_0000;
+const INDEX_ENTRY_SIZE: usize = 32;
+
+// A PlayStation disc converted for the PSP, found in the DATA.PSAR section of an EBOOT.PBP. A
+// multi-disc PSTITLEIMG holds several PSISOIMGs, one per disc.
+pub struct PbpImage {
+    file: File,
+    // Where each block is in the file, and how long it is
+    blocks: Vec<(u64, usize)>,
+    tracks: Vec<Track>,
+    // The last block read, since sectors are mostly read in order
+    block: Option<(usize, Vec<u8>)>,
+}
+
+impl PbpImage {
+    pub fn open<P>(path: P, disc: usize) -> anyhow::Result<Self>
+    where P: AsRef<Path> {
+        let mut file = File::open(path)?;
+        let header = read_at(&mut file, 0, 0x28)?;
+        if &header[..4] != b"\0PBP" {
+            return Err(anyhow!("Not a PBP file"));
+        }
+
+        let psar = u32::from_le_bytes(header[0x24..0x28].try_into().unwrap()) as u64;
+        let image = if read_at(&mut file, psar, 16)? == b"PSTITLEIMG000000" {
+            let table = read_at(&mut file, psar + 0x200, 20)?;
+            let offsets: Vec<u32> = table.chunks_exact(4).map(|offset| u32::from_le_bytes(offset.try_into().unwrap())).take_while(|&offset| offset != 0).collect();
+            let offset = offsets.get(disc).ok_or_else(|| anyhow!("The PBP has only {} discs", offsets.len()))?;
+            psar + *offset as u64
+        } else if disc == 0 {
+            psar
+        } else {
+            return Err(anyhow!("The PBP has only one disc"));
+        };
+
+        if read_at(&mut file, image, 12)? != b"PSISOIMG0000" {
+            return Err(anyhow!("The PBP holds no PlayStation disc image"));
+        }
+
+        let index = read_at(&mut file, image + INDEX, (DATA - INDEX) as usize)?;
+        let blocks: Vec<(u64, usize)> = index.chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|entry| (
+                image + DATA + u32::from_le_bytes(entry[0..4].try_into().unwrap()) as u64,
+                u16::from_le_bytes(entry[4..6].try_into().unwrap()) as usize,
+            ))
+            .take_while(|&(_, length)| length != 0)
+            .collect();
+
+        let toc = read_at(&mut file, image + TOC, 0x400)?;
+        let tracks = parse_toc(&toc, blocks.len() as u32 * BLOCK_SECTORS);
+
+        Ok(Self { file, blocks, tracks, block: None })
+    }
+
+    pub fn tracks(&self) -> Vec<Track> {
+        self.tracks.clone()
+    }
+
+    fn load_block(&mut self, block: usize) -> anyhow::Result<&[u8]> {
+        if self.block.as_ref().is_none_or(|(index, _)| *index != block) {
+            let &(offset, length) = self.blocks.get(block).ok_or_else(|| anyhow!("Read past the end of the PBP image"))?;
+            let compressed = read_at(&mut self.file, offset, length)?;
+
+            // Blocks that don't shrink are stored as they are
+            let data = if length == BLOCK_SIZE {
+                compressed
+            } else {
+                let mut data = Vec::with_capacity(BLOCK_SIZE);
+                DeflateDecoder::new(&compressed[..]).read_to_end(&mut data)?;
+                data
+            };
+            self.block = Some((block, data));
+        }
+
+        Ok(&self.block.as_ref().unwrap().1)
+    }
+}
+
+impl SectorSource for PbpImage {
+    fn read(&mut self, lba: u32) -> Option<Sector> {
+        let block = self.load_block((lba / BLOCK_SECTORS) as usize).ok()?;
+        let offset = (lba % BLOCK_SECTORS) as usize * RAW_SECTOR_SIZE;
+        Some(Sector::from_raw(block.get(offset..)?))
+    }
+}
+
+// The table of contents is laid out like the Q subchannel of the lead-in: points A0 to A2 for the
+// first track, last track and lead-out, then a 10 byte entry for each track.
+fn parse_toc(toc: &[u8], sectors: u32) -> Vec<Track> {
+    let bin = |value: u8| (value >> 4) as u32 * 10 + (value & 0xF) as u32;
+    let lba = |entry: &[u8]| ((bin(entry[7]) * 60 + bin(entry[8])) * 75 + bin(entry[9])).saturating_sub(150);
+
+    let entries: Vec<&[u8]> = toc.chunks_exact(10).collect();
+    let last = bin(entries[1][7]) as usize;
+    let lead_out = match lba(entries[2]) {
+        0 => sectors,
+        lead_out => lead_out,
+    };
+
+    let mut tracks: Vec<Track> = entries[3..].iter().take(last).map(|entry| Track {
+        number: bin(entry[2]) as u8,
+        mode: if entry[0] & 0x40 != 0 {TrackMode::Mode2(RAW_SECTOR_SIZE)} else {TrackMode::Audio},
+        start: lba(entry),
+        pregap: 0,
+        length: 0,
+    }).collect();
+
+    if tracks.is_empty() {
+        tracks.push(Track { number: 1, mode: TrackMode::Mode2(RAW_SECTOR_SIZE), start: 0, pregap: 0, length: 0 });
+    }
+    for index in 0..tracks.len() {
+        let end = tracks.get(index + 1).map_or(lead_out, |next| next.start);
+        tracks[index].length = end.saturating_sub(tracks[index].start);
+    }
+
+    tracks
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::{write::DeflateEncoder, Compression};
+
+    use crate::cd_rom::bin::{pbp::{PbpImage, BLOCK_SECTORS, DATA, INDEX, TOC}, sector::RAW_SECTOR_SIZE, SectorSource, TrackMode};
+
+    #[test]
+    fn one_block_image() {
+        const PSAR: usize = 0x28;
+
+        // Each sector is filled with its own LBA
+        let sectors: Vec<u8> = (0..BLOCK_SECTORS as u8).flat_map(|lba| [lba; RAW_SECTOR_SIZE]).collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&sectors).unwrap();
+        let block = encoder.finish().unwrap();
+
+        let mut file = vec![0; PSAR + DATA as usize];
+        file[..4].copy_from_slice(b"\0PBP");
+        file[0x24..0x28].copy_from_slice(&(PSAR as u32).to_le_bytes());
+        file[PSAR..PSAR + 12].copy_from_slice(b"PSISOIMG0000");
+        // Points A1 and A2 give the last track and the lead-out at 00:02:16, then track 1 at 00:02:00
+        let toc = PSAR + TOC as usize;
+        file[toc + 10 + 7] = 0x01;
+        file[toc + 20 + 7..toc + 20 + 10].copy_from_slice(&[0x00, 0x02, 0x16]);
+        file[toc + 30..toc + 40].copy_from_slice(&[0x41, 0x00, 0x01, 0, 0, 0, 0, 0x00, 0x02, 0x00]);
+        let index = PSAR + INDEX as usize;
+        file[index + 4..index + 6].copy_from_slice(&(block.len() as u16).to_le_bytes());
+        file.extend_from_slice(&block);
+
+        let path = std::env::temp_dir().join("psx_pbp_test.pbp");
+        std::fs::write(&path, file).unwrap();
+        let mut pbp = PbpImage::open(&path, 0).unwrap();
+        assert!(PbpImage::open(&path, 1).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let tracks = pbp.tracks();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!((tracks[0].mode, tracks[0].start, tracks[0].length), (TrackMode::Mode2(RAW_SECTOR_SIZE), 0, 16));
+        for lba in [3, 15, 0] {
+            assert_eq!(pbp.read(lba).unwrap()[0], lba as u8);
+        }
+        assert!(pbp.read(16).is_none());
+    }
+}
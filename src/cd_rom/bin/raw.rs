@@ -0,0 +1,58 @@
+use std::fs::File;
+
+use crate::cd_rom::bin::{cue::Span, read_at_padded, sector::{Sector, RAW_SECTOR_SIZE}, SectorSource};
+
+// Images that store sectors as they are, in one or more files. Nothing is read upfront: the file
+// offset of a sector is worked out from its position when it's asked for.
+#[derive(Default)]
+pub struct RawImage {
+    files: Vec<File>,
+    spans: Vec<Span>,
+}
+
+impl RawImage {
+    pub fn new(files: Vec<File>, spans: Vec<Span>) -> Self {
+        Self { files, spans }
+    }
+}
+
+impl SectorSource for RawImage {
+    fn read(&mut self, lba: u32) -> Option<Sector> {
+        let span = self.spans.iter().find(|span| (span.lba..span.lba + span.count).contains(&lba))?;
+        let size = span.track.mode.sector_size();
+        let offset = (span.file_sector + lba - span.lba) as u64 * size as u64;
+
+        let mut bytes = [0; RAW_SECTOR_SIZE];
+        read_at_padded(&mut self.files[span.file], offset, &mut bytes[..size]).ok()?;
+
+        Some(span.track.mode.sector(lba, &bytes[..size]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cd_rom::bin::{sector::RAW_SECTOR_SIZE, DiskAddress, DiskMap, DiskTrait};
+
+    #[test]
+    fn reads_sectors_from_each_file() {
+        let directory = std::env::temp_dir().join("psx_raw_test");
+        std::fs::create_dir_all(&directory).unwrap();
+        // Each sector is filled with its own LBA
+        let sectors = |range: std::ops::Range<u8>| range.flat_map(|lba| [lba; RAW_SECTOR_SIZE]).collect::<Vec<u8>>();
+        std::fs::write(directory.join("data.bin"), sectors(0..3)).unwrap();
+        std::fs::write(directory.join("audio.bin"), sectors(3..5)).unwrap();
+        std::fs::write(directory.join("game.cue"), concat!(
+            "FILE \"data.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n",
+            "FILE \"audio.bin\" BINARY\n  TRACK 02 AUDIO\n    INDEX 01 00:00:00\n",
+        )).unwrap();
+
+        let mut disk = DiskMap::open(directory.join("game.cue")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        for lba in [4, 0, 2, 3, 4] {
+            assert_eq!(disk.get(&DiskAddress::from_lba(lba)).map(|sector| sector[0]), Some(lba as u8));
+        }
+        assert!(disk.get(&DiskAddress::from_lba(5)).is_none());
+        assert!(disk.get(&DiskAddress::from_bytes(&[0x00, 0x01, 0x00])).is_none());
+    }
+}
@@ -2,30 +2,49 @@ use std::ops::Index;
 
 use bitflags::bitflags;
 
-use crate::cd_rom::bin::DiskAddress;
+use crate::{cd_rom::bin::{ecc, DiskAddress}, state::{StateReader, StateValue, StateWriter}};
 
+pub const RAW_SECTOR_SIZE: usize = 0x930;
+
+pub const SYNC: [u8; 12] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+// The whole raw sector is kept so audio sectors, which have no sync or header, survive
+// intact. Indexing skips the sync pattern, the way the drive delivers data sectors.
 #[derive(Clone, Copy, Debug)]
 pub struct Sector {
     sub_header: SubHeader,
-    data: [u8; 0x924],
+    data: [u8; RAW_SECTOR_SIZE],
 }
 
 impl Sector {
-    pub fn from_bytes(bytes: &[u8]) -> (DiskAddress, Sector) {
-        let mut data = [0; 0x924];
-        (
-            DiskAddress::from_bytes(&bytes[12..=15]),
-            Sector {
-                sub_header: SubHeader::from_bytes(&bytes[16..=23]),
-                data: *bytes[12..].first_chunk().unwrap_or({
-                    for i in 12..bytes.len() {
-                        data[i - 12] = bytes[i];
-                    }
-
-                    &data
-                })
-            }
-        )
+    // Short sectors are padded with zeroes
+    pub fn from_raw(bytes: &[u8]) -> Sector {
+        let mut data = [0; RAW_SECTOR_SIZE];
+        let len = bytes.len().min(RAW_SECTOR_SIZE);
+        data[..len].copy_from_slice(&bytes[..len]);
+
+        Sector { sub_header: SubHeader::from_bytes(&data[16..=23]), data }
+    }
+
+    // Rebuilds the sync pattern and header that images without them leave out. user_data is
+    // everything from the subheader on, or just the 2048 data bytes of a Form 1 sector, in which
+    // case the subheader, EDC and ECC are made up too.
+    pub fn from_user_data(address: DiskAddress, user_data: &[u8]) -> Sector {
+        let mut data = [0; RAW_SECTOR_SIZE];
+        data[..12].copy_from_slice(&SYNC);
+        data[12..15].copy_from_slice(&address.to_bytes());
+        data[15] = 2;
+
+        let offset = if user_data.len() <= 0x800 {24} else {16};
+        let len = user_data.len().min(RAW_SECTOR_SIZE - offset);
+        data[offset..offset + len].copy_from_slice(&user_data[..len]);
+        if offset == 24 {
+            data[18] = SubMode::DATA.bits();
+            data[22] = SubMode::DATA.bits();
+            ecc::generate_form1(&mut data);
+        }
+
+        Sector::from_raw(&data)
     }
 
     pub fn get_sub_header(&self) -> SubHeader {
@@ -37,7 +56,23 @@ impl Index<usize> for Sector {
     type Output = u8;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.data[index]
+        &self.data[index + 12]
+    }
+}
+
+impl StateValue for Sector {
+    fn write(&self, w: &mut StateWriter) {
+        let sub_header = self.sub_header;
+        w.write(&[sub_header.file_num, sub_header.channel_num, sub_header.sub_mode.bits(), sub_header.coding_info.bits()]);
+        w.write_bytes(&self.data);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let sub_header = SubHeader::from_bytes(&r.read::<[u8; 4]>()?);
+        let mut data = [0; RAW_SECTOR_SIZE];
+        r.read_bytes_into(&mut data)?;
+
+        Ok(Sector { sub_header, data })
     }
 }
 
@@ -1,9 +1,117 @@
-use crate::cd_rom::CD_ROM;
+use crate::cd_rom::{bin::DiskAddress, command::{read::INT1_RATE, toc::bcd}, CD_ROM, CD_ROM_INT, CD_ROM_MODE, CD_ROM_RESPONSE, CD_ROM_STATUS, ERROR_INVALID_PARAMETER, ERROR_NOT_READY};
+
+// Sectors skipped per step while fast forwarding or rewinding
+const SCAN_STEP: i32 = 10;
 
 impl CD_ROM {
+    pub fn mute(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.mute = true;
+
+        self.send_status(3, None, None);
+    }
+
     pub fn demute(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
         self.mute = false;
 
         self.send_status(3, None, None);
     }
-}
\ No newline at end of file
+
+    pub fn setfilter(&mut self) {
+        let Some(filter) = self.take_parameters() else { return };
+        self.filter = filter;
+
+        self.send_status(3, None, None);
+    }
+
+    // Plays from the start of the given track, or from the Setloc position without one. There's no
+    // CD audio output, so playing only moves the drive's position along.
+    pub fn play(&mut self) {
+        let track = self.parameters.pop_front().unwrap_or(0);
+        if !self.motor_ready() {return}
+        let start = match track {
+            0 => self.seek_target,
+            _ => match self.disk.tracks().iter().find(|candidate| bcd(candidate.number) == track) {
+                Some(track) => DiskAddress::from_lba(track.start),
+                None => return self.send_error(ERROR_INVALID_PARAMETER),
+            },
+        };
+
+        self.stop_activity();
+        self.read_addr = start;
+        self.play_step = 1;
+
+        self.status.insert(CD_ROM_STATUS::PLAY);
+        self.status.remove(CD_ROM_STATUS::SEEK);
+        self.status.remove(CD_ROM_STATUS::READ);
+
+        self.send_status(3, None, None);
+        self.queue_play_sector(None);
+    }
+
+    pub fn forward(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.scan(SCAN_STEP);
+    }
+
+    pub fn backward(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.scan(-SCAN_STEP);
+    }
+
+    fn scan(&mut self, step: i32) {
+        if !self.status.contains(CD_ROM_STATUS::PLAY) {
+            return self.send_error(ERROR_NOT_READY);
+        }
+
+        self.play_step = step;
+        self.send_status(3, None, None);
+    }
+
+    // Moves on to the next sector of audio. With Report set, an INT1 with the position goes out
+    // every ten frames, alternating between the time on the disc and the time within the track.
+    pub fn play_sector(&mut self) {
+        let tracks = self.disk.tracks();
+        let lba = self.read_addr.to_lba().unwrap_or(0);
+        let lead_out = tracks.last().map_or(0, |track| track.start + track.length);
+        let track_end = tracks.iter().find(|track| lba < track.start + track.length).map_or(lead_out, |track| track.start + track.length);
+
+        let next = (lba as i64 + self.play_step as i64).max(0) as u32;
+        if next == 0 {self.play_step = 1}
+        let auto_pause = self.mode.contains(CD_ROM_MODE::AUTO_PAUSE) && next >= track_end;
+        if next >= lead_out || auto_pause {
+            self.status.remove(CD_ROM_STATUS::PLAY);
+            self.send_status(4, None, None);
+            return;
+        }
+
+        let previous = self.read_addr;
+        self.read_addr = DiskAddress::from_lba(next);
+        let [min, sec, frame] = self.read_addr.to_bytes();
+        if !self.mode.contains(CD_ROM_MODE::REPORT) || frame >> 4 == previous.to_bytes()[2] >> 4 {
+            return self.queue_play_sector(None);
+        }
+
+        let (track, index, relative) = self.position();
+        let time = match frame & 0x10 {
+            0 => [min, sec, frame],
+            _ => [relative[0], relative[1] | 0x80, relative[2]],
+        };
+        // There's no audio to measure the peak level of
+        let report = [self.status.bits(), track, index, time[0], time[1], time[2], 0, 0];
+        self.queue_play_sector(Some(report));
+    }
+
+    fn queue_play_sector(&mut self, report: Option<[u8; 8]>) {
+        let delay = INT1_RATE[self.mode.contains(CD_ROM_MODE::SPEED) as usize];
+        match report {
+            Some(report) => self.send_response(1, &report, Some(delay), Some(CD_ROM_RESPONSE::PlaySector)),
+            None => self.int_queue.push_back(CD_ROM_INT {
+                num: 0,
+                delay,
+                func: Some(CD_ROM_RESPONSE::PlaySector),
+            }),
+        }
+    }
+}
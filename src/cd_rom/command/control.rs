@@ -1,20 +1,22 @@
-use crate::cd_rom::{CD_ROM, CD_ROM_MODE, CD_ROM_STATUS};
+use crate::cd_rom::{CD_ROM, CD_ROM_INT, CD_ROM_MODE, CD_ROM_RESPONSE, CD_ROM_STATUS, ERROR_WRONG_PARAMETER_COUNT};
 
 impl CD_ROM {
     pub fn setmode(&mut self) {
-        self.mode = CD_ROM_MODE::from_bits_truncate(self.parameters.pop_front().unwrap());
+        let Some([mode]) = self.take_parameters() else {return};
+        self.mode = CD_ROM_MODE::from_bits_truncate(mode);
 
         self.send_status(3, None, None);
     }
 
     pub fn init(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
         const INIT_FIRST_DELAY: usize = 0x0001_3CCE;
 
         self.mode = CD_ROM_MODE::from_bits_truncate(0x20);
         
         self.int_queue.clear();
         self.pending_int = None;
-        self.send_status(3, Some(INIT_FIRST_DELAY), Some(Self::init_second_response));
+        self.send_status(3, Some(INIT_FIRST_DELAY), Some(CD_ROM_RESPONSE::InitSecond));
     }
 
     pub fn init_second_response(&mut self) {
@@ -24,16 +26,10 @@ impl CD_ROM {
     }
 
     pub fn pause(&mut self) {
-        self.int_queue = self.int_queue.iter()
-            .filter(|int| {int.num != 1})
-            .map(|int| *int)
-            .collect();
+        if self.take_parameters::<0>().is_none() {return}
+        self.stop_activity();
 
-        if let Some(int) = self.pending_int {
-            if int.num == 1 {self.pending_int = None}
-        }
-
-        self.send_status(3, None, Some(Self::pause_second_response));
+        self.send_status(3, None, Some(CD_ROM_RESPONSE::PauseSecond));
     }
 
     pub fn pause_second_response(&mut self) {
@@ -48,6 +44,68 @@ impl CD_ROM {
 
         self.send_status(2, Some(PAUSE_SECOND_DELAY[paused + speed]), None);
     }
+
+    pub fn motor_on(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        if self.status.contains(CD_ROM_STATUS::SPINDLE) {
+            return self.send_error(ERROR_WRONG_PARAMETER_COUNT);
+        }
+
+        self.status.insert(CD_ROM_STATUS::SPINDLE);
+        self.send_status(3, None, Some(CD_ROM_RESPONSE::MotorOnSecond));
+    }
+
+    pub fn motor_on_second_response(&mut self) {
+        // Roughly how long the disc takes to spin up
+        const MOTOR_ON_SECOND_DELAY: usize = 33_000_000 / 2;
+
+        self.send_status(2, Some(MOTOR_ON_SECOND_DELAY), None);
+    }
+
+    pub fn stop(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.stop_activity();
+        self.sector_buffer = [None; 2];
+
+        self.status.remove(CD_ROM_STATUS::PLAY);
+        self.status.remove(CD_ROM_STATUS::SEEK);
+        self.status.remove(CD_ROM_STATUS::READ);
+
+        self.send_status(3, None, Some(CD_ROM_RESPONSE::StopSecond));
+    }
+
+    // The motor takes a while to spin down, unless it was already stopped
+    pub fn stop_second_response(&mut self) {
+        let spinning = self.status.contains(CD_ROM_STATUS::SPINDLE);
+        let speed = self.mode.contains(CD_ROM_MODE::SPEED) as usize;
+        let delay = if spinning {STOP_SECOND_DELAY[speed]} else {STOPPED_SECOND_DELAY};
+
+        self.status.remove(CD_ROM_STATUS::SPINDLE);
+        self.send_status(2, Some(delay), None);
+    }
+
+    // Like Init, but without the second response
+    pub fn reset(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.mode = CD_ROM_MODE::empty();
+        self.filter = [0; 2];
+        self.play_step = 1;
+        self.sector_buffer = [None; 2];
+        self.status = CD_ROM_STATUS::SPINDLE;
+
+        self.int_queue.clear();
+        self.pending_int = None;
+        self.send_status(3, None, None);
+    }
+
+    // Drops the interrupts still to come from a read or audio play
+    pub fn stop_activity(&mut self) {
+        let activity = |int: &CD_ROM_INT| int.num == 1 || int.func == Some(CD_ROM_RESPONSE::PlaySector);
+        self.int_queue.retain(|int| !activity(int));
+        if self.pending_int.as_ref().is_some_and(activity) {
+            self.pending_int = None;
+        }
+    }
 }
 
 const PAUSE_SECOND_DELAY: [usize; 4] = [
@@ -55,4 +113,7 @@ const PAUSE_SECOND_DELAY: [usize; 4] = [
     0x0010_BD93,
     0x0000_1DF2,
     0x0000_1DF2,
-];
\ No newline at end of file
+];
+
+const STOP_SECOND_DELAY: [usize; 2] = [0x00D3_8ACA, 0x018A_6076];
+const STOPPED_SECOND_DELAY: usize = 0x0000_1D7B;
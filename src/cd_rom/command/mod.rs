@@ -3,4 +3,5 @@ pub mod test;
 pub mod status;
 pub mod control;
 pub mod read;
-pub mod audio;
\ No newline at end of file
+pub mod audio;
+pub mod toc;
\ No newline at end of file
@@ -1,13 +1,21 @@
-use crate::cd_rom::{bin::sector::Sector, CD_ROM, CD_ROM_INT, CD_ROM_MODE, CD_ROM_STATUS};
+use crate::cd_rom::{bin::sector::Sector, CD_ROM, CD_ROM_INT, CD_ROM_MODE, CD_ROM_RESPONSE, CD_ROM_STATUS, RDDATA_READER};
 
 impl CD_ROM {
     pub fn readN(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        if !self.motor_ready() {return}
+        self.stop_activity();
         self.sector_pointer = 0;
         
         let delay = if self.seek_target == self.read_addr {Some(444000)} else {None};
         self.read_addr = self.seek_target;
 
-        self.send_status(3, delay, Some(Self::readN_second_response));
+        self.send_status(3, delay, Some(CD_ROM_RESPONSE::ReadNSecond));
+    }
+
+    // ReadS skips the retries ReadN makes on bad sectors, and sectors here are never bad
+    pub fn readS(&mut self) {
+        self.readN();
     }
 
     pub fn readN_second_response(&mut self) {
@@ -15,14 +23,14 @@ impl CD_ROM {
         self.sector_pointer = 0;
         match self.disk.get(&self.read_addr) {
             Some(sector) => {
-                self.load_sector(*sector);
+                self.load_sector(sector);
 
                 self.status.insert(CD_ROM_STATUS::READ);
                 self.status.remove(CD_ROM_STATUS::SEEK);
                 self.status.remove(CD_ROM_STATUS::PLAY);
 
                 let speed = INT1_RATE[self.mode.contains(CD_ROM_MODE::SPEED) as usize];
-                self.send_status(1, Some(speed), Some(Self::readN_second_response));
+                self.send_status(1, Some(speed), Some(CD_ROM_RESPONSE::ReadNSecond));
 
                 self.read_addr.increment();
             }
@@ -48,5 +56,5 @@ impl CD_ROM {
     }
 }
 
-const INT1_RATE: [usize; 2] = [0x0006_E1CD, 0x0003_6CD2];
-const RDDATA_READ: [fn(&mut CD_ROM) -> u8; 2] = [CD_ROM::read_0x800, CD_ROM::read_0x924];
\ No newline at end of file
+pub const INT1_RATE: [usize; 2] = [0x0006_E1CD, 0x0003_6CD2];
+const RDDATA_READ: [RDDATA_READER; 2] = [RDDATA_READER::Read0x800, RDDATA_READER::Read0x924];
\ No newline at end of file
@@ -1,24 +1,35 @@
-use crate::cd_rom::{DiskAddress, CD_ROM, CD_ROM_STATUS};
+use crate::cd_rom::{DiskAddress, CD_ROM, CD_ROM_RESPONSE, CD_ROM_STATUS, ERROR_INVALID_PARAMETER};
 
 impl CD_ROM {
     pub fn setloc(&mut self) {
-        self.seek_target = DiskAddress::from_bytes(&[
-            self.parameters.pop_front().unwrap(),
-            self.parameters.pop_front().unwrap(),
-            self.parameters.pop_front().unwrap()
-        ]);
-
+        let Some(address) = self.take_parameters::<3>() else {return};
+        // Each part has to be BCD, and seconds and frames within their range
+        let valid = address.iter().zip([0xA0, 0x60, 0x75]).all(|(&byte, limit)| byte & 0xF < 10 && byte < limit);
+        if !valid {
+            return self.send_error(ERROR_INVALID_PARAMETER);
+        }
+
+        self.seek_target = DiskAddress::from_bytes(&address);
         self.send_status(3, None, None);
     }
 
     pub fn seekL(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        if !self.motor_ready() {return}
+        self.stop_activity();
         self.read_addr = self.seek_target;
 
         self.status.insert(CD_ROM_STATUS::SEEK);
         self.status.remove(CD_ROM_STATUS::READ);
         self.status.remove(CD_ROM_STATUS::PLAY);
 
-        self.send_status(3, None, Some(Self::seekL_second_response));
+        self.send_status(3, None, Some(CD_ROM_RESPONSE::SeekLSecond));
+    }
+
+    // SeekP finds its place by the subchannel rather than the sector headers, which comes to the
+    // same thing here
+    pub fn seekP(&mut self) {
+        self.seekL();
     }
 
     pub fn seekL_second_response(&mut self) {
@@ -1,9 +1,9 @@
-use crate::cd_rom::{AVERAGE_IRQ_DELAY, CD_ROM_INT};
+use crate::cd_rom::{command::toc::{bcd, msf}, AVERAGE_IRQ_DELAY, CD_ROM_INT, CD_ROM_RESPONSE, ERROR_NOT_READY, ERROR_WRONG_PARAMETER_COUNT};
 #[allow(unused)]
 use crate::cd_rom::{CD_ROM, CD_ROM_STATUS};
 
 impl CD_ROM {
-    pub fn send_status(&mut self, int: u8, delay: Option<usize>, func: Option<fn(&mut CD_ROM)>) {
+    pub fn send_status(&mut self, int: u8, delay: Option<usize>, func: Option<CD_ROM_RESPONSE>) {
         self.result_fifo[self.result_idx] = self.status.bits();
         self.result_size = 0;
         self.result_fifo_empty = false;
@@ -17,8 +17,96 @@ impl CD_ROM {
         });
     }
 
+    // For responses that carry more than the status byte
+    pub fn send_response(&mut self, int: u8, response: &[u8], delay: Option<usize>, func: Option<CD_ROM_RESPONSE>) {
+        self.result_idx = 0;
+        self.result_fifo[..response.len()].copy_from_slice(response);
+        self.result_size = response.len();
+        self.result_fifo_empty = false;
+
+        self.int_queue.push_back(CD_ROM_INT {
+            num: int,
+            delay: delay.unwrap_or(AVERAGE_IRQ_DELAY),
+            func,
+        });
+    }
+
+    pub fn send_error(&mut self, code: u8) {
+        let status = self.status.bits() | CD_ROM_STATUS::ERROR.bits();
+        self.send_response(5, &[status, code], None, None);
+    }
+
+    // Answers with an error instead if the command was given the wrong number of parameters
+    pub fn take_parameters<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.parameters.len() != N {
+            self.parameters.clear();
+            self.send_error(ERROR_WRONG_PARAMETER_COUNT);
+            return None;
+        }
+
+        Some(std::array::from_fn(|_| self.parameters.pop_front().unwrap()))
+    }
+
+    // Reading, seeking and playing all need the disc spinning, which it isn't after Stop
+    pub fn motor_ready(&mut self) -> bool {
+        if !self.status.contains(CD_ROM_STATUS::SPINDLE) {
+            self.send_error(ERROR_NOT_READY);
+            return false;
+        }
+
+        true
+    }
+
+    pub fn get_stat(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.send_status(3, None, None);
+    }
+
+    pub fn get_param(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        let response = [self.status.bits(), self.mode.bits(), 0, self.filter[0], self.filter[1]];
+        self.send_response(3, &response, None, None);
+    }
+
+    // The header and subheader of the last sector read
+    pub fn get_loc_l(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        match self.sector_buffer[1] {
+            Some((sector, _)) => {
+                let response: [u8; 8] = std::array::from_fn(|index| sector[index]);
+                self.send_response(3, &response, None, None);
+            }
+            None => self.send_error(ERROR_NOT_READY),
+        }
+    }
+
+    // The position from the subchannel: track, index, time within the track and time on the disc
+    pub fn get_loc_p(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        let (track, index, relative) = self.position();
+        let [min, sec, frame] = self.read_addr.to_bytes();
+        let response = [track, index, relative[0], relative[1], relative[2], min, sec, frame];
+        self.send_response(3, &response, None, None);
+    }
+
+    // The track and index of the drive's position, and the time from the start of the track. The
+    // time counts down through the pregap.
+    pub fn position(&self) -> (u8, u8, [u8; 3]) {
+        let lba = self.read_addr.to_lba().unwrap_or(0);
+        let tracks = self.disk.tracks();
+        match tracks.iter().find(|track| lba < track.start + track.length) {
+            Some(track) if lba >= track.start => (bcd(track.number), 1, msf(lba - track.start)),
+            Some(track) => (bcd(track.number), 0, msf(track.start - lba)),
+            None => {
+                let lead_out = tracks.last().map_or(0, |track| track.start + track.length);
+                (0xAA, 1, msf(lba.saturating_sub(lead_out)))
+            }
+        }
+    }
+
     pub fn get_id(&mut self) {
-        self.send_status(3, None, Some(Self::get_id_second_response));
+        if self.take_parameters::<0>().is_none() {return}
+        self.send_status(3, None, Some(CD_ROM_RESPONSE::GetIDSecond));
     }
 
     pub fn get_id_second_response(&mut self) {
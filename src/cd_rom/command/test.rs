@@ -1,15 +1,23 @@
-use crate::cd_rom::{AVERAGE_IRQ_DELAY, CD_ROM, CD_ROM_INT};
+use crate::cd_rom::{AVERAGE_IRQ_DELAY, CD_ROM, CD_ROM_INT, ERROR_INVALID_COMMAND, ERROR_INVALID_PARAMETER, ERROR_WRONG_PARAMETER_COUNT};
 
 impl CD_ROM {
     pub fn test(&mut self) {
-        let sub_op = self.parameters.pop_front().unwrap();
+        let Some(sub_op) = self.parameters.pop_front() else {
+            return self.send_error(ERROR_WRONG_PARAMETER_COUNT);
+        };
         // println!("CD-ROM test sub-op: {sub_op:02X}");
         match sub_op {
-            0x20 => self.test_version(),
-            _ => panic!("CD-ROM test sub-op not yet implemented. {sub_op:02X}"),
+            0x20 => if self.take_parameters::<0>().is_some() {self.test_version()},
+            _ => self.send_error(ERROR_INVALID_PARAMETER),
         }
     }
 
+    // Drives outside Japan answer each step of the unlock sequence as if they didn't know the
+    // command. Region checks aren't emulated, so there's nothing for it to unlock.
+    pub fn secret_unlock(&mut self) {
+        self.send_error(ERROR_INVALID_COMMAND);
+    }
+
     pub fn test_version(&mut self) {
         *self.result_fifo[self.result_idx..].first_chunk_mut().unwrap() = VERSION;
         self.result_size = 3;
@@ -0,0 +1,77 @@
+use crate::cd_rom::{bin::DiskAddress, CD_ROM, CD_ROM_RESPONSE, CD_ROM_STATUS, ERROR_INVALID_COMMAND, ERROR_INVALID_PARAMETER};
+
+impl CD_ROM {
+    pub fn get_tn(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        let tracks = self.disk.tracks();
+        let first = tracks.first().map_or(1, |track| track.number);
+        let last = tracks.last().map_or(1, |track| track.number);
+
+        self.send_status(3, None, None);
+        self.result_fifo[1] = bcd(first);
+        self.result_fifo[2] = bcd(last);
+        self.result_size = 3;
+    }
+
+    // Track 0 is the lead-out, right after the last track
+    pub fn get_td(&mut self) {
+        let Some([track]) = self.take_parameters() else {return};
+        let tracks = self.disk.tracks();
+        let lba = match track {
+            0 => tracks.last().map_or(0, |track| track.start + track.length),
+            _ => match tracks.iter().find(|candidate| bcd(candidate.number) == track) {
+                Some(track) => track.start,
+                None => return self.send_error(ERROR_INVALID_PARAMETER),
+            },
+        };
+
+        let [min, sec, _] = DiskAddress::from_lba(lba).to_bytes();
+        self.send_status(3, None, None);
+        self.result_fifo[1] = min;
+        self.result_fifo[2] = sec;
+        self.result_size = 3;
+    }
+
+    pub fn read_toc(&mut self) {
+        if self.take_parameters::<0>().is_none() {return}
+        self.send_status(3, None, Some(CD_ROM_RESPONSE::ReadTOCSecond));
+    }
+
+    pub fn read_toc_second_response(&mut self) {
+        // The drive rereads the lead-in, which takes about half a second
+        const READ_TOC_SECOND_DELAY: usize = 33_000_000 / 2;
+
+        self.send_status(2, Some(READ_TOC_SECOND_DELAY), None);
+    }
+
+    // Discs are single session, so only session 1 can be found
+    pub fn set_session(&mut self) {
+        let Some([session]) = self.take_parameters() else { return };
+        match session {
+            0 => self.send_error(ERROR_INVALID_PARAMETER),
+            1 => self.send_status(3, None, Some(CD_ROM_RESPONSE::SetSessionSecond)),
+            _ => self.send_status(3, None, Some(CD_ROM_RESPONSE::SetSessionMissing)),
+        }
+    }
+
+    pub fn set_session_second_response(&mut self) {
+        self.send_status(2, Some(SET_SESSION_SECOND_DELAY), None);
+    }
+
+    pub fn set_session_missing_response(&mut self) {
+        let status = self.status.bits() | CD_ROM_STATUS::SEEK_ERR.bits() | CD_ROM_STATUS::ERROR.bits();
+        self.send_response(5, &[status, ERROR_INVALID_COMMAND], Some(SET_SESSION_SECOND_DELAY), None);
+    }
+}
+
+const SET_SESSION_SECOND_DELAY: usize = 33_000_000 / 2;
+
+pub fn bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+// A length of time in frames, as BCD minutes, seconds and frames
+pub fn msf(frames: u32) -> [u8; 3] {
+    let [min, sec, frame] = [frames / (75 * 60), frames / 75 % 60, frames % 75].map(|value| bcd(value as u8));
+    [min, sec, frame]
+}
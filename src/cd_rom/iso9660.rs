@@ -0,0 +1,197 @@
+use crate::cd_rom::{bin::DiskAddress, CD_ROM};
+
+const SECTOR_SIZE: usize = 0x800;
+const PRIMARY_VOLUME_DESCRIPTOR: u32 = 16;
+// Nested directories are searched no deeper than this
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsoFile {
+    pub lba: u32,
+    pub size: u32,
+}
+
+// Just enough of the ISO 9660 filesystem to find files by path, the way the BIOS does when it
+// boots a disc. Sectors are read straight from the image, without going through the drive.
+impl CD_ROM {
+    // The 2048 bytes of user data in a Mode 2 Form 1 sector
+    pub fn data_sector(&mut self, lba: u32) -> Option<[u8; SECTOR_SIZE]> {
+        let sector = self.disk.get(&DiskAddress::from_lba(lba))?;
+        Some(std::array::from_fn(|index| sector[index + 12]))
+    }
+
+    // Paths are relative to the root and separated by backslashes, as in cdrom:\DIR\FILE.EXE;1.
+    // Names are matched without case, and the version suffix is optional.
+    pub fn find_file(&mut self, path: &str) -> Option<IsoFile> {
+        let path = strip_device(path);
+        let descriptor = self.data_sector(PRIMARY_VOLUME_DESCRIPTOR)?;
+        if descriptor[0] != 1 || &descriptor[1..6] != b"CD001" {
+            return None;
+        }
+
+        let mut entry = directory_record(&descriptor[156..])?.0;
+        for (depth, name) in path.split(['\\', '/']).filter(|name| !name.is_empty()).enumerate() {
+            if depth == MAX_DEPTH {return None}
+            entry = self.find_in_directory(entry, name)?;
+        }
+
+        Some(entry)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> Option<Vec<u8>> {
+        let file = self.find_file(path)?;
+        self.read_file_range(file, 0, file.size)
+    }
+
+    // Reads len bytes starting offset bytes into the file, cut short at its end
+    pub fn read_file_range(&mut self, file: IsoFile, offset: u32, len: u32) -> Option<Vec<u8>> {
+        let end = offset.saturating_add(len).min(file.size);
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let sector = self.data_sector(file.lba + pos / SECTOR_SIZE as u32)?;
+            let start = pos as usize % SECTOR_SIZE;
+            let count = (SECTOR_SIZE - start).min((end - pos) as usize);
+            data.extend_from_slice(&sector[start..start + count]);
+            pos += count as u32;
+        }
+
+        Some(data)
+    }
+
+    fn find_in_directory(&mut self, directory: IsoFile, name: &str) -> Option<IsoFile> {
+        let sectors = (directory.size as usize).div_ceil(SECTOR_SIZE) as u32;
+        for index in 0..sectors {
+            let sector = self.data_sector(directory.lba + index)?;
+            let mut offset = 0;
+            while let Some((entry, record_name, len)) = directory_record(&sector[offset..]) {
+                if names_match(record_name, name) {
+                    return Some(entry);
+                }
+                offset += len;
+            }
+        }
+
+        None
+    }
+}
+
+// Returns the entry, its name and the length of the record. A zero length record pads out the
+// rest of the sector.
+fn directory_record(bytes: &[u8]) -> Option<(IsoFile, &[u8], usize)> {
+    let len = *bytes.first()? as usize;
+    if len < 34 || len > bytes.len() {
+        return None;
+    }
+
+    let lba = u32::from_le_bytes(*bytes[2..].first_chunk()?);
+    let size = u32::from_le_bytes(*bytes[10..].first_chunk()?);
+    let name_len = bytes[32] as usize;
+    let name = bytes.get(33..33 + name_len)?;
+
+    Some((IsoFile { lba, size }, name, len))
+}
+
+fn names_match(record: &[u8], name: &str) -> bool {
+    let record = record.split(|&byte| byte == b';').next().unwrap_or(record);
+    let name = name.split(';').next().unwrap_or(name);
+    record.eq_ignore_ascii_case(name.as_bytes())
+}
+
+fn strip_device(path: &str) -> &str {
+    match path.get(..6) {
+        Some(device) if device.eq_ignore_ascii_case("cdrom:") => &path[6..],
+        _ => path,
+    }
+}
+
+// The boot settings from SYSTEM.CNF, with the BIOS defaults for anything missing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemCnf {
+    pub boot: String,
+    pub tcb: u32,
+    pub event: u32,
+    pub stack: u32,
+}
+
+impl SystemCnf {
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut cnf = SystemCnf { boot: String::new(), tcb: 4, event: 16, stack: 0x801F_FF00 };
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else { continue };
+            let value = value.trim();
+            match key.trim().to_ascii_uppercase().as_str() {
+                "BOOT" => cnf.boot = value.split_whitespace().next().unwrap_or_default().to_string(),
+                "TCB" => cnf.tcb = parse_hex(value)?,
+                "EVENT" => cnf.event = parse_hex(value)?,
+                "STACK" => cnf.stack = parse_hex(value)?,
+                _ => {}
+            }
+        }
+
+        (!cnf.boot.is_empty()).then_some(cnf)
+    }
+
+    // Reads SYSTEM.CNF from the disc, falling back to PSX.EXE like the BIOS does
+    pub fn from_disc(cd_rom: &mut CD_ROM) -> Option<Self> {
+        match cd_rom.read_file("SYSTEM.CNF;1") {
+            Some(text) => Self::parse(&String::from_utf8_lossy(&text)),
+            None => cd_rom.find_file("PSX.EXE;1").map(|_| Self::parse("BOOT = cdrom:PSX.EXE;1").unwrap()),
+        }
+    }
+}
+
+// Values are always hexadecimal, with or without a 0x prefix
+fn parse_hex(value: &str) -> Option<u32> {
+    let value = value.split_whitespace().next()?;
+    let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::cd_rom::iso9660::{SystemCnf, PRIMARY_VOLUME_DESCRIPTOR, SECTOR_SIZE};
+
+    // A plain ISO image with the files in its root directory, which sits right after the volume
+    // descriptors. Only the fields find_file looks at are filled in.
+    pub fn image(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let root = PRIMARY_VOLUME_DESCRIPTOR as usize + 2;
+        let record = |lba: usize, size: usize, name: &[u8]| {
+            let mut record = vec![0; (33 + name.len()).next_multiple_of(2)];
+            record[0] = record.len() as u8;
+            record[2..6].copy_from_slice(&(lba as u32).to_le_bytes());
+            record[10..14].copy_from_slice(&(size as u32).to_le_bytes());
+            record[32] = name.len() as u8;
+            record[33..33 + name.len()].copy_from_slice(name);
+            record
+        };
+
+        let mut image = vec![0; (root + 1) * SECTOR_SIZE];
+        let descriptor = PRIMARY_VOLUME_DESCRIPTOR as usize * SECTOR_SIZE;
+        image[descriptor] = 1;
+        image[descriptor + 1..descriptor + 6].copy_from_slice(b"CD001");
+        let root_record = record(root, SECTOR_SIZE, &[0]);
+        image[descriptor + 156..descriptor + 156 + root_record.len()].copy_from_slice(&root_record);
+
+        let mut offset = root * SECTOR_SIZE;
+        for (name, data) in files {
+            let file_record = record(image.len() / SECTOR_SIZE, data.len(), name.as_bytes());
+            image[offset..offset + file_record.len()].copy_from_slice(&file_record);
+            offset += file_record.len();
+            image.extend_from_slice(data);
+            image.resize(image.len().next_multiple_of(SECTOR_SIZE), 0);
+        }
+
+        image
+    }
+
+    #[test]
+    fn system_cnf() {
+        let cnf = SystemCnf::parse("BOOT = cdrom:\\SLUS_000.67;1\r\nTCB = 4\r\nEVENT = 10\r\nSTACK = 801FFFF0\r\n").unwrap();
+        assert_eq!(cnf, SystemCnf { boot: String::from("cdrom:\\SLUS_000.67;1"), tcb: 4, event: 0x10, stack: 0x801F_FFF0 });
+
+        let cnf = SystemCnf::parse("BOOT=cdrom:\\MAIN.EXE;1 arg\n").unwrap();
+        assert_eq!((cnf.boot.as_str(), cnf.tcb, cnf.event), ("cdrom:\\MAIN.EXE;1", 4, 16));
+        assert!(SystemCnf::parse("TCB = 4").is_none());
+    }
+}
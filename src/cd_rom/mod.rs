@@ -1,14 +1,22 @@
-use std::{cell::RefCell, collections::VecDeque, path::Path, rc::Rc};
+use std::{collections::VecDeque, path::Path};
 
+use anyhow::anyhow;
 use bitflags::bitflags;
 
-use crate::{bus::interrupt::{Interrupt, IRQ}, cd_rom::bin::{sector::Sector, DiskAddress, DiskMap, DiskTrait}};
+use crate::{bus::interrupt::{Interrupt, IRQ}, cd_rom::bin::{sector::Sector, DiskAddress, DiskMap, DiskTrait}, scheduler::{Event, Scheduler}, state::{Savestate, StateReader, StateValue, StateWriter}};
 
 mod command;
 mod bin;
+pub mod iso9660;
 
 const AVERAGE_IRQ_DELAY: usize = 0xC4E1;
 
+// The second byte of an INT5 response
+const ERROR_INVALID_PARAMETER: u8 = 0x10;
+const ERROR_WRONG_PARAMETER_COUNT: u8 = 0x20;
+const ERROR_INVALID_COMMAND: u8 = 0x40;
+const ERROR_NOT_READY: u8 = 0x80;
+
 bitflags! {
     pub struct CD_ROM_STATUS: u8 {
         const PLAY     = 0x80;
@@ -37,12 +45,16 @@ bitflags! {
 
 pub struct CD_ROM {
     disk: DiskMap,
-    sector_buffer: [Option<(Sector, fn(&mut CD_ROM) -> u8)>; 2],
+    sector_buffer: [Option<(Sector, RDDATA_READER)>; 2],
     sector_pointer: usize,
 
     status: CD_ROM_STATUS,
     mode: CD_ROM_MODE,
     mute: bool,
+    // The XA file and channel set by Setfilter
+    filter: [u8; 2],
+    // How many sectors each step of audio play moves, more while fast forwarding or rewinding
+    play_step: i32,
 
     registers: [u8; 16],
     current_bank: usize,
@@ -58,21 +70,26 @@ pub struct CD_ROM {
 
     seek_target: DiskAddress,
     read_addr: DiskAddress,
-
-    interrupt: Rc<RefCell<Interrupt>>,
 }
 
 impl CD_ROM {
-    pub fn new<P>(interrupt: Rc<RefCell<Interrupt>>, bin_path: P) -> anyhow::Result<CD_ROM>
+    pub fn new<P>(bin_path: Option<P>) -> anyhow::Result<CD_ROM>
     where P: AsRef<Path> {
+        let disk = match bin_path {
+            Some(bin_path) => DiskMap::open(bin_path)?,
+            None => DiskMap::new(),
+        };
+
         Ok(Self {
-            disk: DiskMap::from_bin(bin_path)?,
+            disk,
             sector_buffer: [None; 2],
             sector_pointer: 0,
 
             status: CD_ROM_STATUS::from_bits_truncate(0x02),
             mode: CD_ROM_MODE::from_bits_truncate(0),
             mute: false,
+            filter: [0; 2],
+            play_step: 1,
 
             registers: [0; 16],
             current_bank: 0,
@@ -88,27 +105,46 @@ impl CD_ROM {
 
             seek_target: DiskAddress::default(),
             read_addr: DiskAddress::default(),
-            
-            interrupt,
         })
     }
 
-    pub fn tick(&mut self) {
-        if let Some(int) = &mut self.pending_int {
-            int.delay -= 1;
-            if int.delay == 0 {
+    pub fn swap_disk<P>(&mut self, bin_path: Option<P>) -> anyhow::Result<()>
+    where P: AsRef<Path> {
+        self.disk = match bin_path {
+            Some(bin_path) => DiskMap::open(bin_path)?,
+            None => DiskMap::new(),
+        };
+        self.sector_buffer = [None; 2];
+
+        Ok(())
+    }
+
+    pub fn fire_interrupt(&mut self, scheduler: &mut Scheduler, interrupt: &mut Interrupt) {
+        if let Some(int) = self.pending_int.take() {
+            // INT0 only keeps time for the drive, the CPU never sees it
+            if int.num != 0 {
                 self.registers[HINTSTS] = (self.registers[HINTSTS] & !7) | int.num;
                 if self.registers[HINTMSK] & self.registers[HINTSTS] != 0 {
                     println!("Firing CD-ROM INT{}", self.registers[HINTSTS] & 7);
-                    self.interrupt.borrow_mut().request(IRQ::CDROM);
+                    interrupt.request(IRQ::CDROM);
                 }
-                if let Some(func) = int.func {
-                    func(self);
-                }
-                self.pending_int = None;
             }
-        } else {
-            self.pending_int = self.int_queue.pop_front();
+            if let Some(func) = int.func {
+                func.call(self);
+            }
+        }
+
+        self.schedule_interrupt(1, scheduler);
+    }
+
+    // Taking the next interrupt off the queue costs a cycle once the previous one has fired
+    fn schedule_interrupt(&mut self, pop_delay: u64, scheduler: &mut Scheduler) {
+        if self.pending_int.is_some() {return}
+
+        self.pending_int = self.int_queue.pop_front();
+        match self.pending_int {
+            Some(int) => scheduler.schedule(Event::CdRomInterrupt, pop_delay + int.delay as u64),
+            None => scheduler.cancel(Event::CdRomInterrupt),
         }
     }
 
@@ -133,7 +169,7 @@ impl CD_ROM {
                 result
             }
             RDDATA => {
-                (self.sector_buffer[0].unwrap().1)(self)
+                self.sector_buffer[0].unwrap().1.call(self)
             },
             _ => self.registers[register]
         };
@@ -142,7 +178,7 @@ impl CD_ROM {
     }
 
     fn read_0x800(&mut self) -> u8 {
-        const RDDATA_0X800: [fn(&mut CD_ROM) -> u8; 2] = [CD_ROM::read_0x800, CD_ROM::pad_0x800];
+        const RDDATA_0X800: [RDDATA_READER; 2] = [RDDATA_READER::Read0x800, RDDATA_READER::Pad0x800];
 
         let byte = self.sector_buffer[0].unwrap().0[self.sector_pointer + 12];
         
@@ -159,7 +195,7 @@ impl CD_ROM {
     }
 
     fn read_0x924(&mut self) -> u8 {
-        const RDDATA_0X924: [fn(&mut CD_ROM) -> u8; 2] = [CD_ROM::read_0x924, CD_ROM::pad_0x924];
+        const RDDATA_0X924: [RDDATA_READER; 2] = [RDDATA_READER::Read0x924, RDDATA_READER::Pad0x924];
 
         let byte = self.sector_buffer[0].unwrap().0[self.sector_pointer];
         
@@ -175,7 +211,7 @@ impl CD_ROM {
         self.sector_buffer[0].unwrap().0[const {0x924 - 4}]
     }
 
-    pub fn write8(&mut self, offset: u32, value: u8) {
+    pub fn write8(&mut self, offset: u32, value: u8, scheduler: &mut Scheduler) {
         // println!("CDROM bank {} [{offset}] <- {value:02X}", self.current_bank);
         let register = WRITE_BANKS[self.current_bank][offset as usize];
         match register {
@@ -184,7 +220,10 @@ impl CD_ROM {
                 self.parameters.push_back(value);
                 self.registers[ADDRESS] |= 0x10;
             }
-            COMMAND => self.execute(value),
+            COMMAND => {
+                self.execute(value);
+                self.schedule_interrupt(0, scheduler);
+            }
             HCLRCTL => {
                 self.registers[HINTSTS] &= !(value & 0x1F);
                 if value & 0x40 != 0 {
@@ -201,18 +240,38 @@ impl CD_ROM {
         self.result_idx = 0;
         println!("CD-ROM command: {command:02X}");
         match command {
-            0x01 => self.send_status(3, None, None),
+            0x01 => self.get_stat(),
             0x02 => self.setloc(),
+            0x03 => self.play(),
+            0x04 => self.forward(),
+            0x05 => self.backward(),
             0x06 => self.readN(),
+            0x07 => self.motor_on(),
+            0x08 => self.stop(),
             0x09 => self.pause(),
             0x0A => self.init(),
+            0x0B => self.mute(),
             0x0C => self.demute(),
+            0x0D => self.setfilter(),
             0x0E => self.setmode(),
+            0x0F => self.get_param(),
+            0x10 => self.get_loc_l(),
+            0x11 => self.get_loc_p(),
+            0x12 => self.set_session(),
+            0x13 => self.get_tn(),
+            0x14 => self.get_td(),
             0x15 => self.seekL(),
+            0x16 => self.seekP(),
             0x19 => self.test(),
             0x1A => self.get_id(),
-            _ => panic!("CD-ROM command not yet implemented. {command:02X}"),
+            0x1B => self.readS(),
+            0x1C => self.reset(),
+            0x1E => self.read_toc(),
+            0x50..=0x56 => self.secret_unlock(),
+            _ => self.send_error(ERROR_INVALID_COMMAND),
         }
+        // Whatever a command didn't use is dropped rather than left for the next one
+        self.parameters.clear();
     }
 }
 
@@ -252,5 +311,276 @@ const ADPCTL:    usize = 15;
 pub struct CD_ROM_INT {
     num: u8,
     delay: usize,
-    func: Option<fn(&mut CD_ROM)>,
-}
\ No newline at end of file
+    func: Option<CD_ROM_RESPONSE>,
+}
+
+// Deferred responses are stored as plain values rather than function pointers so that the
+// interrupt queue can be written to and restored from save states.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CD_ROM_RESPONSE {
+    InitSecond,
+    PauseSecond,
+    ReadNSecond,
+    SeekLSecond,
+    GetIDSecond,
+    MotorOnSecond,
+    StopSecond,
+    SetSessionSecond,
+    SetSessionMissing,
+    ReadTOCSecond,
+    PlaySector,
+}
+
+impl CD_ROM_RESPONSE {
+    fn call(self, cd_rom: &mut CD_ROM) {
+        match self {
+            CD_ROM_RESPONSE::InitSecond => cd_rom.init_second_response(),
+            CD_ROM_RESPONSE::PauseSecond => cd_rom.pause_second_response(),
+            CD_ROM_RESPONSE::ReadNSecond => cd_rom.readN_second_response(),
+            CD_ROM_RESPONSE::SeekLSecond => cd_rom.seekL_second_response(),
+            CD_ROM_RESPONSE::GetIDSecond => cd_rom.get_id_second_response(),
+            CD_ROM_RESPONSE::MotorOnSecond => cd_rom.motor_on_second_response(),
+            CD_ROM_RESPONSE::StopSecond => cd_rom.stop_second_response(),
+            CD_ROM_RESPONSE::SetSessionSecond => cd_rom.set_session_second_response(),
+            CD_ROM_RESPONSE::SetSessionMissing => cd_rom.set_session_missing_response(),
+            CD_ROM_RESPONSE::ReadTOCSecond => cd_rom.read_toc_second_response(),
+            CD_ROM_RESPONSE::PlaySector => cd_rom.play_sector(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RDDATA_READER {
+    Read0x800,
+    Pad0x800,
+    Read0x924,
+    Pad0x924,
+}
+
+impl RDDATA_READER {
+    fn call(self, cd_rom: &mut CD_ROM) -> u8 {
+        match self {
+            RDDATA_READER::Read0x800 => cd_rom.read_0x800(),
+            RDDATA_READER::Pad0x800 => cd_rom.pad_0x800(),
+            RDDATA_READER::Read0x924 => cd_rom.read_0x924(),
+            RDDATA_READER::Pad0x924 => cd_rom.pad_0x924(),
+        }
+    }
+}
+
+impl Savestate for CD_ROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.sector_buffer);
+        w.write(&self.sector_pointer);
+        w.write(&self.status.bits());
+        w.write(&self.mode.bits());
+        w.write(&self.mute);
+        w.write(&self.filter);
+        w.write(&self.play_step);
+        w.write(&self.registers);
+        w.write(&self.current_bank);
+        w.write(&self.parameters);
+        w.write(&self.result_fifo);
+        w.write(&self.result_idx);
+        w.write(&self.result_size);
+        w.write(&self.result_fifo_empty);
+        w.write(&self.int_queue);
+        w.write(&self.pending_int);
+        w.write(&self.seek_target);
+        w.write(&self.read_addr);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.sector_buffer = r.read()?;
+        self.sector_pointer = r.read()?;
+        self.status = CD_ROM_STATUS::from_bits_truncate(r.read()?);
+        self.mode = CD_ROM_MODE::from_bits_truncate(r.read()?);
+        self.mute = r.read()?;
+        self.filter = r.read()?;
+        self.play_step = r.read()?;
+        self.registers = r.read()?;
+        self.current_bank = r.read()?;
+        self.parameters = r.read()?;
+        self.result_fifo = r.read()?;
+        self.result_idx = r.read()?;
+        self.result_size = r.read()?;
+        self.result_fifo_empty = r.read()?;
+        self.int_queue = r.read()?;
+        self.pending_int = r.read()?;
+        self.seek_target = r.read()?;
+        self.read_addr = r.read()?;
+        Ok(())
+    }
+}
+
+impl StateValue for CD_ROM_INT {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.num);
+        w.write(&self.delay);
+        w.write(&self.func);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(Self { num: r.read()?, delay: r.read()?, func: r.read()? })
+    }
+}
+
+impl StateValue for CD_ROM_RESPONSE {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        const RESPONSES: [CD_ROM_RESPONSE; 11] = [
+            CD_ROM_RESPONSE::InitSecond,
+            CD_ROM_RESPONSE::PauseSecond,
+            CD_ROM_RESPONSE::ReadNSecond,
+            CD_ROM_RESPONSE::SeekLSecond,
+            CD_ROM_RESPONSE::GetIDSecond,
+            CD_ROM_RESPONSE::MotorOnSecond,
+            CD_ROM_RESPONSE::StopSecond,
+            CD_ROM_RESPONSE::SetSessionSecond,
+            CD_ROM_RESPONSE::SetSessionMissing,
+            CD_ROM_RESPONSE::ReadTOCSecond,
+            CD_ROM_RESPONSE::PlaySector,
+        ];
+
+        let tag: u8 = r.read()?;
+        RESPONSES.get(tag as usize).copied().ok_or_else(|| anyhow!("Invalid CD-ROM response in save state: {tag}"))
+    }
+}
+
+impl StateValue for RDDATA_READER {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        const READERS: [RDDATA_READER; 4] = [
+            RDDATA_READER::Read0x800,
+            RDDATA_READER::Pad0x800,
+            RDDATA_READER::Read0x924,
+            RDDATA_READER::Pad0x924,
+        ];
+
+        let tag: u8 = r.read()?;
+        READERS.get(tag as usize).copied().ok_or_else(|| anyhow!("Invalid RDDATA reader in save state: {tag}"))
+    }
+}
+#[cfg(test)]
+mod test {
+    use crate::{bus::interrupt::Interrupt, cd_rom::CD_ROM, scheduler::Scheduler};
+
+    struct Drive {
+        cd_rom: CD_ROM,
+        scheduler: Scheduler,
+        interrupt: Interrupt,
+    }
+
+    impl Drive {
+        fn command(&mut self, command: u8, parameters: &[u8]) -> (u8, Vec<u8>) {
+            self.cd_rom.write8(0, 0, &mut self.scheduler);
+            for &parameter in parameters {
+                self.cd_rom.write8(2, parameter, &mut self.scheduler);
+            }
+            self.cd_rom.write8(1, command, &mut self.scheduler);
+            self.response()
+        }
+
+        // Runs the drive until the next interrupt the CPU would see, then acknowledges it
+        fn response(&mut self) -> (u8, Vec<u8>) {
+            self.cd_rom.write8(0, 1, &mut self.scheduler);
+            for _ in 0..1000 {
+                if self.cd_rom.read8(3) & 7 != 0 {break}
+                self.scheduler.skip_to_next_event();
+                while self.scheduler.pop_due().is_some() {
+                    self.cd_rom.fire_interrupt(&mut self.scheduler, &mut self.interrupt);
+                }
+            }
+
+            let int = self.cd_rom.read8(3) & 7;
+            let response = (0..self.cd_rom.result_size.max(1)).map(|_| self.cd_rom.read8(1)).collect();
+            self.cd_rom.write8(3, 0x1F, &mut self.scheduler);
+            (int, response)
+        }
+    }
+
+    // A data track of 3 sectors, then an audio track of 40
+    fn two_track_drive(name: &str) -> Drive {
+        let directory = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("data.bin"), vec![0; 3 * 2352]).unwrap();
+        std::fs::write(directory.join("audio.bin"), vec![0; 40 * 2352]).unwrap();
+        std::fs::write(directory.join("game.cue"), concat!(
+            "FILE \"data.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n",
+            "FILE \"audio.bin\" BINARY\n  TRACK 02 AUDIO\n    INDEX 01 00:00:00\n",
+        )).unwrap();
+        let cd_rom = CD_ROM::new(Some(directory.join("game.cue"))).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        Drive { cd_rom, scheduler: Scheduler::new(), interrupt: Interrupt::new() }
+    }
+
+    #[test]
+    fn track_numbers_and_starts() {
+        let mut drive = two_track_drive("psx_cd_rom_toc_test");
+
+        assert_eq!(drive.command(0x13, &[]), (3, vec![0x02, 0x01, 0x02]));
+        assert_eq!(drive.command(0x14, &[0x01]), (3, vec![0x02, 0x00, 0x02]));
+        // The lead-out starts at 00:02:43
+        assert_eq!(drive.command(0x14, &[0x00]), (3, vec![0x02, 0x00, 0x02]));
+        assert_eq!(drive.command(0x14, &[0x03]), (5, vec![0x03, 0x10]));
+    }
+
+    #[test]
+    fn play_reports_and_errors() {
+        let mut drive = two_track_drive("psx_cd_rom_test");
+
+        assert_eq!(drive.command(0x14, &[0x05]), (5, vec![0x03, 0x10]));
+        assert_eq!(drive.command(0x12, &[0x00]), (5, vec![0x03, 0x10]));
+        assert_eq!(drive.command(0x0D, &[0x01]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x04, &[]), (5, vec![0x03, 0x80]));
+
+        // Report on, then play track 2, which starts at 00:02:03
+        assert_eq!(drive.command(0x0E, &[0x04]), (3, vec![0x02]));
+        assert_eq!(drive.command(0x03, &[0x02]), (3, vec![0x82]));
+        // The first report comes at frame 10, and gives the time within the track
+        assert_eq!(drive.response(), (1, vec![0x82, 0x02, 0x01, 0x00, 0x80, 0x07, 0x00, 0x00]));
+        // Each response waits for the drive to move on a sector
+        assert_eq!(drive.command(0x0E, &[0x00]), (3, vec![0x82]));
+        assert_eq!(drive.command(0x11, &[]), (3, vec![0x02, 0x01, 0x00, 0x00, 0x09, 0x00, 0x02, 0x12]));
+
+        assert_eq!(drive.command(0x04, &[]), (3, vec![0x82]));
+        // The second response is queued as the first fires, and takes over the result fifo
+        assert_eq!(drive.command(0x08, &[]).0, 3);
+        assert_eq!(drive.response().0, 2);
+        assert!(drive.cd_rom.status.is_empty());
+    }
+
+    #[test]
+    fn bad_commands_get_errors() {
+        let cd_rom = CD_ROM::new(None::<&str>).unwrap();
+        let mut drive = Drive { cd_rom, scheduler: Scheduler::new(), interrupt: Interrupt::new() };
+
+        assert_eq!(drive.command(0x1F, &[]), (5, vec![0x03, 0x40]));
+        assert_eq!(drive.command(0x02, &[0x00, 0x02]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x02, &[0x00, 0x61, 0x00]), (5, vec![0x03, 0x10]));
+        assert_eq!(drive.command(0x0E, &[]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x14, &[]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x14, &[0x01, 0x02]), (5, vec![0x03, 0x20]));
+        // Commands that take no parameters refuse any they're given
+        assert_eq!(drive.command(0x01, &[0x00]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x06, &[0x00]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x13, &[0x01]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x1A, &[0x00, 0x00]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x19, &[]), (5, vec![0x03, 0x20]));
+        assert_eq!(drive.command(0x19, &[0x99]), (5, vec![0x03, 0x10]));
+        // The parameters of a bad command don't carry over to the next one
+        assert_eq!(drive.command(0x02, &[0x00, 0x02, 0x00]), (3, vec![0x02]));
+
+        // Nothing can be read once the motor is stopped
+        assert_eq!(drive.command(0x08, &[]).0, 3);
+        assert_eq!(drive.response().0, 2);
+        assert_eq!(drive.command(0x06, &[]), (5, vec![0x01, 0x80]));
+        assert_eq!(drive.command(0x15, &[]), (5, vec![0x01, 0x80]));
+    }
+}
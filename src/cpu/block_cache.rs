@@ -0,0 +1,139 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{bus::interface::{code_address, Bus}, cpu::decoder::{decode, is_branch, Handler, Instruction}};
+
+const PAGE_BITS: u32 = 12;
+const DRAM_PAGES: usize = (2 * 1024 * 1024) >> PAGE_BITS;
+const BIOS_START: u32 = 0x1FC0_0000;
+const MAX_BLOCK_LEN: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct Op {
+    pub instruction: u32,
+    pub handler: Handler,
+}
+
+impl Op {
+    pub fn new(instruction: u32) -> Self {
+        Self { instruction, handler: decode(instruction) }
+    }
+}
+
+struct Cursor {
+    ops: Rc<[Op]>,
+    index: usize,
+    addr: u32,
+}
+
+// Decoded basic blocks keyed by the physical address of their first instruction. A block runs
+// up to and including the delay slot of its first branch, and never crosses a 4KB page so a
+// write to DRAM only has to invalidate the blocks of a single page.
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<[Op]>>,
+    pages: Vec<Vec<u32>>,
+    cursor: Option<Cursor>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            pages: vec![Vec::new(); DRAM_PAGES],
+            cursor: None,
+        }
+    }
+
+    pub fn fetch(&mut self, pc: u32, bus: &mut Bus) -> Option<Op> {
+        let addr = code_address(pc)?;
+
+        if let Some(cursor) = &mut self.cursor {
+            if cursor.addr == addr && cursor.index < cursor.ops.len() {
+                let op = cursor.ops[cursor.index];
+                cursor.index += 1;
+                cursor.addr += 4;
+                return Some(op);
+            }
+        }
+
+        let ops = match self.blocks.get(&addr) {
+            Some(ops) => ops.clone(),
+            None => self.compile(addr, bus),
+        };
+        let op = ops[0];
+        self.cursor = Some(Cursor { ops, index: 1, addr: addr + 4 });
+
+        Some(op)
+    }
+
+    fn compile(&mut self, start: u32, bus: &mut Bus) -> Rc<[Op]> {
+        let mut ops = Vec::new();
+        let mut addr = start;
+        let mut delay_slot = false;
+
+        loop {
+            let instruction = bus.read32(addr);
+            ops.push(Op::new(instruction));
+            addr += 4;
+
+            if delay_slot || ends_block(instruction) || ops.len() == MAX_BLOCK_LEN || addr.trailing_zeros() >= PAGE_BITS {
+                break;
+            }
+            delay_slot = is_branch(instruction);
+        }
+
+        if start < BIOS_START {
+            for word in (start..addr).step_by(4) {
+                bus.mark_code(word);
+            }
+            self.pages[(start >> PAGE_BITS) as usize].push(start);
+        }
+
+        let ops: Rc<[Op]> = ops.into();
+        self.blocks.insert(start, ops.clone());
+        ops
+    }
+
+    pub fn invalidate_page(&mut self, page: u32) {
+        for start in self.pages[page as usize].drain(..) {
+            self.blocks.remove(&start);
+        }
+        self.cursor = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.iter_mut().for_each(Vec::clear);
+        self.cursor = None;
+    }
+}
+
+// Exceptions always leave the block, so nothing after a syscall or break is worth decoding.
+fn ends_block(instruction: u32) -> bool {
+    instruction.op() == 0 && matches!(instruction.funct(), 0b001100 | 0b001101)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{bus::interface::Bus, cpu::CPU};
+
+    #[test]
+    fn ram_writes_invalidate_blocks() {
+        let mut bus = Bus::with_bios_image(vec![0; 512 * 1024]);
+        let mut cpu = CPU::new();
+
+        let run = |cpu: &mut CPU, bus: &mut Bus| {
+            cpu.pc = 0x8000_1000;
+            cpu.next_pc = 0x8000_1004;
+            for _ in 0..3 {cpu.tick(bus);}
+            cpu.R[1]
+        };
+
+        // ori r1, r0, 1
+        bus.write32(0x0000_1000, 0x3401_0001);
+        assert_eq!(run(&mut cpu, &mut bus), 1);
+
+        // ori r1, r0, 2 through a mirror
+        bus.write32(0xA060_1000, 0x3401_0002);
+        assert_eq!(run(&mut cpu, &mut bus), 2);
+    }
+}
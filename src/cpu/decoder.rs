@@ -1,3 +1,5 @@
+use crate::{bus::interface::Bus, cpu::CPU};
+
 pub trait Instruction {
     fn op(&self) -> Self;
     fn rs(&self) -> Self;
@@ -60,4 +62,101 @@ pub enum Cause {
     RI = 0x0A,
     CpU = 0x0B,
     Ovf = 0x0C,
-}
\ No newline at end of file
+}
+
+pub type Handler = fn(&mut CPU, u32, &mut Bus);
+
+// Maps an instruction word to the op handler that executes it, so the block cache only has
+// to decode each instruction once.
+pub fn decode(instruction: u32) -> Handler {
+    let op = instruction.op();
+    match op {
+        0b000000 => {
+            let funct = instruction.funct();
+            match funct {
+                0b000000 => |cpu, instruction, _| cpu.sll(instruction),
+                0b000010 => |cpu, instruction, _| cpu.srl(instruction),
+                0b000011 => |cpu, instruction, _| cpu.sra(instruction),
+                0b000100 => |cpu, instruction, _| cpu.sllv(instruction),
+                0b000110 => |cpu, instruction, _| cpu.srlv(instruction),
+                0b000111 => |cpu, instruction, _| cpu.srav(instruction),
+                0b001100 => |cpu, _, _| cpu.raise_exception(Cause::Sys),
+                0b001101 => |cpu, _, _| cpu.raise_exception(Cause::Bp),
+                0b001000 => |cpu, instruction, _| cpu.jr(instruction),
+                0b001001 => |cpu, instruction, _| cpu.jalr(instruction),
+                0b010000 => |cpu, instruction, _| cpu.mfhi(instruction),
+                0b010001 => |cpu, instruction, _| cpu.mthi(instruction),
+                0b010010 => |cpu, instruction, _| cpu.mflo(instruction),
+                0b010011 => |cpu, instruction, _| cpu.mtlo(instruction),
+                0b011000 => |cpu, instruction, _| cpu.mult(instruction),
+                0b011001 => |cpu, instruction, _| cpu.multu(instruction),
+                0b011010 => |cpu, instruction, _| cpu.div(instruction),
+                0b011011 => |cpu, instruction, _| cpu.divu(instruction),
+                0b100000 => |cpu, instruction, _| cpu.add(instruction),
+                0b100001 => |cpu, instruction, _| cpu.addu(instruction),
+                0b100010 => |cpu, instruction, _| cpu.sub(instruction),
+                0b100011 => |cpu, instruction, _| cpu.subu(instruction),
+                0b100100 => |cpu, instruction, _| cpu.and(instruction),
+                0b100101 => |cpu, instruction, _| cpu.or(instruction),
+                0b100110 => |cpu, instruction, _| cpu.xor(instruction),
+                0b100111 => |cpu, instruction, _| cpu.nor(instruction),
+                0b101010 => |cpu, instruction, _| cpu.slt(instruction),
+                0b101011 => |cpu, instruction, _| cpu.sltu(instruction),
+                _ => illegal,
+            }
+        },
+        0b000001 => |cpu, instruction, _| cpu.bxx(instruction),
+        0b000010 => |cpu, instruction, _| cpu.j(instruction),
+        0b000011 => |cpu, instruction, _| cpu.jal(instruction),
+        0b000100 => |cpu, instruction, _| cpu.beq(instruction),
+        0b000101 => |cpu, instruction, _| cpu.bne(instruction),
+        0b000110 => |cpu, instruction, _| cpu.blez(instruction),
+        0b000111 => |cpu, instruction, _| cpu.bgtz(instruction),
+        0b001000 => |cpu, instruction, _| cpu.addi(instruction),
+        0b001001 => |cpu, instruction, _| cpu.addiu(instruction),
+        0b001010 => |cpu, instruction, _| cpu.slti(instruction),
+        0b001011 => |cpu, instruction, _| cpu.sltiu(instruction),
+        0b001100 => |cpu, instruction, _| cpu.andi(instruction),
+        0b001101 => |cpu, instruction, _| cpu.ori(instruction),
+        0b001110 => |cpu, instruction, _| cpu.xori(instruction),
+        0b001111 => |cpu, instruction, _| cpu.lui(instruction),
+        0b010000 => |cpu, instruction, _| cpu.cop0(instruction),
+        0b010001 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b010010 => |cpu, instruction, _| cpu.cop2(instruction),
+        0b010011 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b100000 => |cpu, instruction, bus| cpu.lb(instruction, bus),
+        0b100001 => |cpu, instruction, bus| cpu.lh(instruction, bus),
+        0b100010 => |cpu, instruction, bus| cpu.lwl(instruction, bus),
+        0b100011 => |cpu, instruction, bus| cpu.lw(instruction, bus),
+        0b100100 => |cpu, instruction, bus| cpu.lbu(instruction, bus),
+        0b100101 => |cpu, instruction, bus| cpu.lhu(instruction, bus),
+        0b100110 => |cpu, instruction, bus| cpu.lwr(instruction, bus),
+        0b101000 => |cpu, instruction, bus| cpu.sb(instruction, bus),
+        0b101001 => |cpu, instruction, bus| cpu.sh(instruction, bus),
+        0b101010 => |cpu, instruction, bus| cpu.swl(instruction, bus),
+        0b101011 => |cpu, instruction, bus| cpu.sw(instruction, bus),
+        0b101110 => |cpu, instruction, bus| cpu.swr(instruction, bus),
+        0b110000 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b110001 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b110010 => |cpu, instruction, bus| cpu.lwc2(instruction, bus),
+        0b110011 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b111000 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b111001 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        0b111010 => |cpu, instruction, bus| cpu.swc2(instruction, bus),
+        0b111011 => |cpu, _, _| cpu.raise_exception(Cause::CpU),
+        _ => illegal,
+    }
+}
+
+pub fn is_branch(instruction: u32) -> bool {
+    match instruction.op() {
+        0b000000 => matches!(instruction.funct(), 0b001000 | 0b001001),
+        0b000001..=0b000111 => true,
+        _ => false,
+    }
+}
+
+fn illegal(cpu: &mut CPU, instruction: u32, _: &mut Bus) {
+    println!("Illegal instruction: {:08X}", instruction);
+    cpu.raise_exception(Cause::RI);
+}
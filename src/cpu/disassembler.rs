@@ -0,0 +1,224 @@
+use crate::cpu::{decoder::Instruction, gte::command::GTE_Command};
+
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+const COP0_NAMES: [&str; 16] = [
+    "cop0r0", "cop0r1", "cop0r2", "bpc", "cop0r4", "bda", "jumpdest", "dcic",
+    "badvaddr", "bdam", "cop0r10", "bpcm", "sr", "cause", "epc", "prid",
+];
+
+const GTE_DATA_NAMES: [&str; 32] = [
+    "vxy0", "vz0", "vxy1", "vz1", "vxy2", "vz2", "rgbc", "otz",
+    "ir0", "ir1", "ir2", "ir3", "sxy0", "sxy1", "sxy2", "sxyp",
+    "sz0", "sz1", "sz2", "sz3", "rgb0", "rgb1", "rgb2", "res1",
+    "mac0", "mac1", "mac2", "mac3", "irgb", "orgb", "lzcs", "lzcr",
+];
+
+const GTE_CONTROL_NAMES: [&str; 32] = [
+    "rt11rt12", "rt13rt21", "rt22rt23", "rt31rt32", "rt33", "trx", "try", "trz",
+    "l11l12", "l13l21", "l22l23", "l31l32", "l33", "rbk", "gbk", "bbk",
+    "lr1lr2", "lr3lg1", "lg2lg3", "lb1lb2", "lb3", "rfc", "gfc", "bfc",
+    "ofx", "ofy", "h", "dqa", "dqb", "zsf3", "zsf4", "flag",
+];
+
+// Turns an instruction word into assembly, with branch and jump targets resolved against the
+// address it was fetched from.
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    let (rs, rt, rd) = (reg(instruction.rs()), reg(instruction.rt()), reg(instruction.rd()));
+    let simm = instruction.imm_se() as i32;
+    let imm = instruction.imm();
+    let branch = pc.wrapping_add(4).wrapping_add((simm << 2) as u32);
+    let memory = |name| format_op(name, format!("{rt}, {simm}({rs})"));
+
+    match instruction.op() {
+        0b000000 => match instruction.funct() {
+            _ if instruction == 0 => String::from("nop"),
+            0b000000 => format_op("sll", format!("{rd}, {rt}, {}", instruction.shamt())),
+            0b000010 => format_op("srl", format!("{rd}, {rt}, {}", instruction.shamt())),
+            0b000011 => format_op("sra", format!("{rd}, {rt}, {}", instruction.shamt())),
+            0b000100 => format_op("sllv", format!("{rd}, {rt}, {rs}")),
+            0b000110 => format_op("srlv", format!("{rd}, {rt}, {rs}")),
+            0b000111 => format_op("srav", format!("{rd}, {rt}, {rs}")),
+            0b001000 => format_op("jr", rs.to_string()),
+            0b001001 if instruction.rd() == 31 => format_op("jalr", rs.to_string()),
+            0b001001 => format_op("jalr", format!("{rd}, {rs}")),
+            0b001100 => format_op("syscall", format!("0x{:X}", instruction >> 6 & 0xF_FFFF)),
+            0b001101 => format_op("break", format!("0x{:X}", instruction >> 6 & 0xF_FFFF)),
+            0b010000 => format_op("mfhi", rd.to_string()),
+            0b010001 => format_op("mthi", rs.to_string()),
+            0b010010 => format_op("mflo", rd.to_string()),
+            0b010011 => format_op("mtlo", rs.to_string()),
+            funct @ 0b011000..=0b011011 => {
+                let name = ["mult", "multu", "div", "divu"][(funct & 3) as usize];
+                format_op(name, format!("{rs}, {rt}"))
+            }
+            funct @ (0b100000..=0b100111 | 0b101010 | 0b101011) => {
+                let name = match funct {
+                    0b100000 => "add",
+                    0b100001 => "addu",
+                    0b100010 => "sub",
+                    0b100011 => "subu",
+                    0b100100 => "and",
+                    0b100101 => "or",
+                    0b100110 => "xor",
+                    0b100111 => "nor",
+                    0b101010 => "slt",
+                    _ => "sltu",
+                };
+                format_op(name, format!("{rd}, {rs}, {rt}"))
+            }
+            _ => word(instruction),
+        },
+        0b000001 => {
+            let link = instruction.rt() & 0x1E == 0x10;
+            let name = match (instruction.rt() & 1 != 0, link) {
+                (false, false) => "bltz",
+                (true, false) => "bgez",
+                (false, true) => "bltzal",
+                (true, true) => "bgezal",
+            };
+            format_op(name, format!("{rs}, 0x{branch:08X}"))
+        }
+        op @ (0b000010 | 0b000011) => {
+            let target = (pc.wrapping_add(4) & 0xF000_0000) | instruction.target() << 2;
+            format_op(if op == 0b000010 {"j"} else {"jal"}, format!("0x{target:08X}"))
+        }
+        0b000100 => format_op("beq", format!("{rs}, {rt}, 0x{branch:08X}")),
+        0b000101 => format_op("bne", format!("{rs}, {rt}, 0x{branch:08X}")),
+        0b000110 => format_op("blez", format!("{rs}, 0x{branch:08X}")),
+        0b000111 => format_op("bgtz", format!("{rs}, 0x{branch:08X}")),
+        0b001000 => format_op("addi", format!("{rt}, {rs}, {simm}")),
+        0b001001 => format_op("addiu", format!("{rt}, {rs}, {simm}")),
+        0b001010 => format_op("slti", format!("{rt}, {rs}, {simm}")),
+        0b001011 => format_op("sltiu", format!("{rt}, {rs}, {simm}")),
+        0b001100 => format_op("andi", format!("{rt}, {rs}, 0x{imm:X}")),
+        0b001101 => format_op("ori", format!("{rt}, {rs}, 0x{imm:X}")),
+        0b001110 => format_op("xori", format!("{rt}, {rs}, 0x{imm:X}")),
+        0b001111 => format_op("lui", format!("{rt}, 0x{imm:X}")),
+        0b010000 => match instruction.rs() {
+            0b00000 => format_op("mfc0", format!("{rt}, {}", COP0_NAMES[instruction.rd() as usize & 0xF])),
+            0b00100 => format_op("mtc0", format!("{rt}, {}", COP0_NAMES[instruction.rd() as usize & 0xF])),
+            0b10000 if instruction.funct() == 0b010000 => String::from("rfe"),
+            _ => word(instruction),
+        },
+        0b010010 if instruction & (1 << 25) != 0 => gte_command(instruction),
+        0b010010 => match instruction.rs() {
+            0b00000 => format_op("mfc2", format!("{rt}, {}", GTE_DATA_NAMES[instruction.rd() as usize])),
+            0b00010 => format_op("cfc2", format!("{rt}, {}", GTE_CONTROL_NAMES[instruction.rd() as usize])),
+            0b00100 => format_op("mtc2", format!("{rt}, {}", GTE_DATA_NAMES[instruction.rd() as usize])),
+            0b00110 => format_op("ctc2", format!("{rt}, {}", GTE_CONTROL_NAMES[instruction.rd() as usize])),
+            0b01000 => {
+                let name = if instruction & 0x0001_0000 != 0 {"bc2t"} else {"bc2f"};
+                format_op(name, format!("0x{branch:08X}"))
+            }
+            _ => word(instruction),
+        },
+        0b100000 => memory("lb"),
+        0b100001 => memory("lh"),
+        0b100010 => memory("lwl"),
+        0b100011 => memory("lw"),
+        0b100100 => memory("lbu"),
+        0b100101 => memory("lhu"),
+        0b100110 => memory("lwr"),
+        0b101000 => memory("sb"),
+        0b101001 => memory("sh"),
+        0b101010 => memory("swl"),
+        0b101011 => memory("sw"),
+        0b101110 => memory("swr"),
+        op @ (0b110010 | 0b111010) => {
+            let name = if op == 0b110010 {"lwc2"} else {"swc2"};
+            format_op(name, format!("{}, {simm}({rs})", GTE_DATA_NAMES[instruction.rt() as usize]))
+        }
+        _ => word(instruction),
+    }
+}
+
+fn gte_command(instruction: u32) -> String {
+    let name = match instruction.num() {
+        0x01 => "rtps",
+        0x06 => "nclip",
+        0x0C => "op",
+        0x10 => "dpcs",
+        0x11 => "intpl",
+        0x12 => "mvmva",
+        0x13 => "ncds",
+        0x14 => "cdp",
+        0x16 => "ncdt",
+        0x1B => "nccs",
+        0x1C => "cc",
+        0x1E => "ncs",
+        0x20 => "nct",
+        0x28 => "sqr",
+        0x29 => "dcpl",
+        0x2A => "dpct",
+        0x2D => "avsz3",
+        0x2E => "avsz4",
+        0x30 => "rtpt",
+        0x3D => "gpf",
+        0x3E => "gpl",
+        0x3F => "ncct",
+        _ => return format_op("cop2", format!("0x{:07X}", instruction & 0x1FF_FFFF)),
+    };
+
+    let mut operands = Vec::new();
+    if instruction.sf() {operands.push("sf")}
+    if instruction.lm() {operands.push("lm")}
+    if instruction.num() == 0x12 {
+        operands.push(["rt", "llm", "lcm", "bad"][instruction.mx() as usize]);
+        operands.push(["v0", "v1", "v2", "ir"][instruction.v() as usize]);
+        operands.push(["tr", "bk", "fc", "none"][instruction.cv() as usize]);
+    }
+
+    format_op(name, operands.join(", "))
+}
+
+fn reg(register: u32) -> &'static str {
+    REGISTER_NAMES[register as usize]
+}
+
+fn format_op(name: &str, operands: String) -> String {
+    if operands.is_empty() {
+        return name.to_string();
+    }
+    format!("{name:<8}{operands}")
+}
+
+fn word(instruction: u32) -> String {
+    format_op(".word", format!("0x{instruction:08X}"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::disassembler::disassemble;
+
+    #[test]
+    fn mnemonics() {
+        let pc = 0x8001_0000;
+        let cases = [
+            (0x0000_0000, "nop"),
+            (0x27BD_FFE8, "addiu   sp, sp, -24"),
+            (0x3C01_1F80, "lui     at, 0x1F80"),
+            (0x8FBF_0014, "lw      ra, 20(sp)"),
+            (0x03E0_0008, "jr      ra"),
+            (0x0C00_4000, "jal     0x80010000"),
+            (0x1480_FFFF, "bne     a0, zero, 0x80010000"),
+            (0x0411_0003, "bgezal  zero, 0x80010010"),
+            (0x4080_6000, "mtc0    zero, sr"),
+            (0x4200_0010, "rfe"),
+            (0x4A28_0030, "rtpt    sf"),
+            (0x4A48_0012, "mvmva   sf, rt, v0, tr"),
+            (0x4802_6000, "mfc2    v0, sxy0"),
+            (0xC9A1_0004, "lwc2    vz0, 4(t5)"),
+            (0xFC00_0000, ".word   0xFC000000"),
+        ];
+
+        for (instruction, text) in cases {
+            assert_eq!(disassemble(instruction, pc), text, "{instruction:08X}");
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{bus::interface::{code_address, Bus}, cpu::{decoder::is_branch, icache::ICache, CPU, CYCLES_PER_INSTRUCTION}};
+
+mod x64;
+
+const PAGE_BITS: u32 = 12;
+const DRAM_PAGES: usize = (2 * 1024 * 1024) >> PAGE_BITS;
+const BIOS_START: u32 = 0x1FC0_0000;
+const MAX_BLOCK_LEN: usize = 32;
+
+// Shared between the native code and the exit handling: the values of the loads still in
+// their delay slot, where the block's branch went and how long its loads took.
+#[repr(C)]
+#[derive(Default)]
+struct Scratch {
+    slots: [u32; 2],
+    next_pc: u32,
+    taken: u32,
+    access_cycles: u32,
+}
+
+type Entry = unsafe extern "sysv64" fn(*mut CPU, *mut Bus, *mut Scratch) -> u32;
+
+// Interpreter state to restore when the native code returns before a given instruction.
+#[derive(Debug, Clone, Copy)]
+struct Exit {
+    pending: Option<(u32, usize)>,
+    delay_slot: bool,
+}
+
+struct Block {
+    entry: Entry,
+    start: u32,
+    exits: Vec<Exit>,
+    branch: bool,
+}
+
+impl Block {
+    fn len(&self) -> usize {
+        self.exits.len() - 1
+    }
+}
+
+// Native blocks keyed by the virtual PC they start at, since link addresses and jump targets
+// depend on the segment the code runs from. A block is only entered when nothing the
+// interpreter checks between instructions could happen inside it: no interrupt can become
+// pending, no event is due and only plain memory is accessed. Anything else returns to the
+// interpreter, which also runs every COP0, GTE, division and unaligned access instruction.
+pub struct Dynarec {
+    blocks: HashMap<u32, Option<Rc<Block>>>,
+    pages: Vec<Vec<u32>>,
+    code: x64::CodeBuffer,
+}
+
+impl Dynarec {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+            pages: vec![Vec::new(); DRAM_PAGES],
+            code: x64::CodeBuffer::new(),
+        }
+    }
+
+    pub fn invalidate_page(&mut self, page: u32) {
+        for pc in self.pages[page as usize].drain(..) {
+            self.blocks.remove(&pc);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.iter_mut().for_each(Vec::clear);
+        self.code.clear();
+    }
+
+    fn block(&mut self, pc: u32, bus: &mut Bus) -> Option<Rc<Block>> {
+        if let Some(block) = self.blocks.get(&pc) {
+            return block.clone();
+        }
+
+        let addr = code_address(pc)?;
+        let block = self.compile(pc, addr, bus);
+        self.blocks.insert(pc, block.clone());
+
+        if addr < BIOS_START {
+            let len = block.as_ref().map_or(1, |block| block.len());
+            for word in 0..len as u32 {
+                bus.mark_code(addr + word * 4);
+            }
+            self.pages[(addr >> PAGE_BITS) as usize].push(pc);
+        }
+
+        block
+    }
+
+    fn compile(&mut self, pc: u32, addr: u32, bus: &mut Bus) -> Option<Rc<Block>> {
+        let mut instructions = Vec::new();
+        let mut branch = false;
+
+        while instructions.len() < MAX_BLOCK_LEN {
+            let word_addr = addr + instructions.len() as u32 * 4;
+            let instruction = bus.read32(word_addr);
+            if !x64::supported(instruction) {
+                break;
+            }
+
+            if is_branch(instruction) {
+                // The delay slot has to come along and live on the same page
+                let slot_addr = word_addr + 4;
+                if slot_addr.trailing_zeros() >= PAGE_BITS {
+                    break;
+                }
+                let slot = bus.read32(slot_addr);
+                if x64::supported(slot) && !is_branch(slot) {
+                    instructions.extend([instruction, slot]);
+                    branch = true;
+                }
+                break;
+            }
+
+            instructions.push(instruction);
+            if (word_addr + 4).trailing_zeros() >= PAGE_BITS {
+                break;
+            }
+        }
+
+        if instructions.is_empty() {
+            return None;
+        }
+
+        let (code, exits) = x64::translate(pc, &instructions, self.code.base());
+        let entry = match self.code.push(&code) {
+            Some(entry) => entry,
+            None => {
+                self.clear();
+                let (code, _) = x64::translate(pc, &instructions, self.code.base());
+                self.code.push(&code).expect("Block larger than the dynarec code buffer")
+            }
+        };
+
+        Some(Rc::new(Block { entry, start: pc, exits, branch }))
+    }
+}
+
+impl CPU {
+    // Runs a whole native block if the interpreter would have run the same instructions back
+    // to back, returning how many cycles they took.
+    pub(super) fn run_native(&mut self, bus: &mut Bus) -> Option<u64> {
+        self.dynarec.as_ref()?;
+        self.update_interrupt_line(bus);
+
+        if self.trace
+            || bus.watching()
+            || bus.dma.running()
+            || self.pc & 0b11 != 0
+            || self.branch
+            || self.next_pc != self.pc.wrapping_add(4)
+            || self.pending_writes[0].is_some()
+            || self.system_control.trigger_interrupt()
+        {
+            return None;
+        }
+
+        // The interpreter handles events after every instruction, so no event may come due
+        // before the last one even if every fetch misses the I-cache and every load is slow
+        let budget = bus.scheduler.cycles_until_next_event();
+        let cache_control = bus.cache_control();
+        let fetch_cycles = ICache::max_fetch_words(self.pc, cache_control) * bus.access_cycles(self.pc, 4);
+        let worst_case = CYCLES_PER_INSTRUCTION + fetch_cycles + bus.max_memory_access_cycles();
+
+        self.invalidate_code(bus);
+        let block = self.dynarec.as_mut()?.block(self.pc, bus)?;
+        if (block.len() as u64 - 1) * worst_case >= budget {
+            return None;
+        }
+
+        self.stalled = false;
+        let mut scratch = Scratch::default();
+        let executed = unsafe { (block.entry)(self, bus, &mut scratch) } as usize;
+        if executed == 0 {
+            return None;
+        }
+
+        let fetch_cycles = bus.access_cycles(block.start, 4);
+        let cycles = (0..executed as u32)
+            .map(|index| CYCLES_PER_INSTRUCTION + self.icache.fetch(block.start.wrapping_add(index * 4), cache_control) * fetch_cycles)
+            .sum::<u64>() + scratch.access_cycles as u64;
+        self.gte.elapse(cycles);
+
+        let exit = block.exits[executed];
+        let addr = block.start.wrapping_add(executed as u32 * 4);
+        self.current_pc = addr.wrapping_sub(4);
+        self.pending_writes = [exit.pending.map(|(register, slot)| (register, scratch.slots[slot])), None];
+
+        if executed == block.len() && block.branch {
+            self.pc = scratch.next_pc;
+            self.next_pc = self.pc.wrapping_add(4);
+            self.branch = false;
+            self.delay_slot = scratch.taken != 0;
+        } else if exit.delay_slot {
+            self.pc = addr;
+            self.next_pc = scratch.next_pc;
+            self.branch = scratch.taken != 0;
+            self.delay_slot = false;
+        } else {
+            self.pc = addr;
+            self.next_pc = addr.wrapping_add(4);
+            self.branch = false;
+            self.delay_slot = false;
+        }
+
+        self.check_for_kernel_call(bus);
+
+        Some(cycles)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{bus::interface::Bus, cpu::CPU, state::{Savestate, StateWriter}};
+
+    const BASE: u32 = 0x8000_1000;
+    const END: u32 = BASE + 35 * 4;
+
+    fn i(op: u32, rs: u32, rt: u32, imm: u16) -> u32 {
+        op << 26 | rs << 21 | rt << 16 | imm as u32
+    }
+
+    fn r(funct: u32, rs: u32, rt: u32, rd: u32, shamt: u32) -> u32 {
+        rs << 21 | rt << 16 | rd << 11 | shamt << 6 | funct
+    }
+
+    fn program() -> Vec<u32> {
+        let jump = |op: u32, index: u32| op << 26 | ((BASE + index * 4) >> 2 & 0x03FF_FFFF);
+        vec![
+            i(0x0F, 0, 1, 0x8000),            // lui r1, 0x8000
+            i(0x0D, 1, 1, 0x2000),            // ori r1, r1, 0x2000
+            i(0x09, 0, 2, 10),                // addiu r2, r0, 10
+            i(0x09, 0, 3, 0x1234),            // addiu r3, r0, 0x1234
+            i(0x0F, 0, 23, 0x26D6),           // lui r23, 0x26D6
+            i(0x0D, 23, 23, 42),              // ori r23, r23, 42 (addiu r22, r22, 42)
+            i(0x0F, 0, 24, 0x8000),           // lui r24, 0x8000
+            // loop:
+            i(0x23, 1, 4, 0),                 // lw r4, 0(r1)
+            r(0x21, 3, 4, 3, 0),              // addu r3, r3, r4
+            i(0x2B, 1, 3, 4),                 // sw r3, 4(r1)
+            i(0x23, 1, 4, 4),                 // lw r4, 4(r1)
+            i(0x23, 1, 4, 0),                 // lw r4, 0(r1)
+            r(0x00, 0, 4, 5, 3),              // sll r5, r4, 3
+            i(0x09, 6, 6, 7),                 // addiu r6, r6, 7
+            r(0x18, 6, 5, 0, 0),              // mult r6, r5
+            r(0x12, 0, 0, 7, 0),              // mflo r7
+            r(0x10, 0, 0, 8, 0),              // mfhi r8
+            r(0x26, 7, 3, 9, 0),              // xor r9, r7, r3
+            r(0x2A, 9, 7, 10, 0),             // slt r10, r9, r7
+            i(0x0B, 9, 11, 100),              // sltiu r11, r9, 100
+            i(0x28, 1, 9, 8),                 // sb r9, 8(r1)
+            i(0x21, 1, 12, 8),                // lh r12, 8(r1)
+            i(0x24, 1, 13, 9),                // lbu r13, 9(r1)
+            i(0x2B, 1, 9, 0),                 // sw r9, 0(r1)
+            i(0x2B, 24, 23, 0x1000 + 26 * 4), // sw r23, patch(r24)
+            r(0x23, 3, 4, 21, 0),             // subu r21, r3, r4
+            0,                                // patch: nop
+            i(0x09, 2, 2, 0xFFFF),            // addiu r2, r2, -1
+            i(0x07, 2, 0, (-22i16) as u16),   // bgtz r2, loop
+            i(0x09, 14, 14, 1),               // addiu r14, r14, 1
+            jump(0x03, 37),                   // jal func
+            i(0x23, 1, 15, 0),                // lw r15, 0(r1)
+            i(0x01, 0, 0x11, 2),              // bgezal r0, end
+            r(0x27, 15, 0, 16, 0),            // nor r16, r15, r0
+            i(0x09, 0, 17, 99),               // addiu r17, r0, 99
+            // end:
+            jump(0x02, 35),                   // j end
+            0,                                // nop
+            // func:
+            r(0x21, 15, 31, 18, 0),           // addu r18, r15, r31
+            r(0x08, 31, 0, 0, 0),             // jr r31
+            i(0x09, 0, 20, 5),                // addiu r20, r0, 5
+        ]
+    }
+
+    fn run(dynarec: bool) -> (Vec<u8>, Vec<u8>, u64, usize) {
+        let mut bus = Bus::with_bios_image(vec![0; 512 * 1024]);
+
+        let mut cpu = CPU::new();
+        cpu.set_dynarec(dynarec).unwrap();
+        for (index, word) in program().into_iter().enumerate() {
+            bus.write32(BASE + index as u32 * 4, word);
+        }
+        bus.write32(0x8000_2000, 0x1357_9BDF);
+        bus.write32(0xFFFE_0130, 0x0001_E988);
+        cpu.pc = BASE;
+        cpu.next_pc = BASE + 4;
+
+        let mut steps = 0;
+        while cpu.pc != END {
+            let cycles = cpu.step(&mut bus);
+            bus.scheduler.advance(cycles);
+            bus.run_events();
+            steps += 1;
+        }
+
+        let mut w = StateWriter::new();
+        cpu.save_state(&mut w);
+        (w.into_inner(), bus.dram.data.clone(), bus.scheduler.now(), steps)
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let (interpreter_cpu, interpreter_ram, interpreter_cycles, interpreter_steps) = run(false);
+        let (dynarec_cpu, dynarec_ram, dynarec_cycles, dynarec_steps) = run(true);
+
+        assert!(dynarec_steps < interpreter_steps / 4);
+        assert_eq!(dynarec_cycles, interpreter_cycles);
+        assert!(dynarec_cpu == interpreter_cpu);
+        assert!(dynarec_ram == interpreter_ram);
+    }
+}
@@ -0,0 +1,477 @@
+use std::mem::offset_of;
+
+use dynasmrt::{dynasm, mmap::MutableBuffer, x64::X64Relocation, AssemblyOffset, DynamicLabel, DynasmApi, DynasmLabelApi, ExecutableBuffer, VecAssembler};
+
+use crate::{bus::interface::Bus, cpu::{decoder::{is_branch, Instruction}, dynarec::{Entry, Exit, Scratch}, CPU}, Registers};
+
+const CODE_SIZE: usize = 16 * 1024 * 1024;
+
+const R: i32 = (offset_of!(CPU, R) + offset_of!(Registers<32>, R)) as i32;
+const HI: i32 = offset_of!(CPU, hi) as i32;
+const LO: i32 = offset_of!(CPU, lo) as i32;
+
+const SLOTS: i32 = 0;
+const NEXT_PC: i32 = 8;
+const TAKEN: i32 = 12;
+
+const LOAD_FAILED: u64 = 1 << 32;
+const STORE_OK: u32 = 0;
+const STORE_FAILED: u32 = 1;
+const STORE_DIRTY: u32 = 2;
+
+// One executable mapping that blocks are appended to. It is only ever reset as a whole, once
+// it fills up.
+pub struct CodeBuffer {
+    buffer: Option<ExecutableBuffer>,
+    len: usize,
+}
+
+impl CodeBuffer {
+    pub fn new() -> Self {
+        let buffer = ExecutableBuffer::new(CODE_SIZE).expect("Failed to map dynarec code buffer");
+        Self { buffer: Some(buffer), len: 0 }
+    }
+
+    pub fn base(&self) -> usize {
+        self.buffer.as_ref().unwrap().as_ptr() as usize + self.len
+    }
+
+    pub fn push(&mut self, code: &[u8]) -> Option<Entry> {
+        if self.len + code.len() > CODE_SIZE {
+            return None;
+        }
+
+        let mut buffer: MutableBuffer = self.buffer.take().unwrap().make_mut().expect("Failed to unprotect dynarec code");
+        buffer.set_len(self.len + code.len());
+        buffer[self.len..].copy_from_slice(code);
+        let buffer = buffer.make_exec().expect("Failed to protect dynarec code");
+
+        let entry = unsafe { std::mem::transmute::<*const u8, Entry>(buffer.ptr(AssemblyOffset(self.len))) };
+        self.len += code.len();
+        self.buffer = Some(buffer);
+
+        Some(entry)
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+pub fn supported(instruction: u32) -> bool {
+    match instruction.op() {
+        0b000000 => matches!(instruction.funct(),
+            0b000000 | 0b000010 | 0b000011 | 0b000100 | 0b000110 | 0b000111 |
+            0b001000 | 0b001001 | 0b010000..=0b010011 | 0b011000 | 0b011001 |
+            0b100000..=0b100111 | 0b101010 | 0b101011
+        ),
+        0b000001..=0b001111 => true,
+        0b100000 | 0b100001 | 0b100011 | 0b100100 | 0b100101 => true,
+        0b101000 | 0b101001 | 0b101011 => true,
+        _ => false,
+    }
+}
+
+// rbx holds the CPU, r12 the bus and r13 the scratch area for the whole block. Guest registers
+// are never cached in host registers, so the CPU is up to date at every exit.
+pub fn translate(start: u32, instructions: &[u32], base: usize) -> (Vec<u8>, Vec<Exit>) {
+    let mut emitter = Emitter {
+        ops: VecAssembler::new(base),
+        exits: Vec::with_capacity(instructions.len() + 1),
+        exit_labels: Vec::new(),
+        pending: None,
+    };
+
+    dynasm!(emitter.ops
+        ; .arch x64
+        ; push rbx
+        ; push r12
+        ; push r13
+        ; mov rbx, rdi
+        ; mov r12, rsi
+        ; mov r13, rdx
+    );
+
+    for (index, &instruction) in instructions.iter().enumerate() {
+        let pc = start.wrapping_add(index as u32 * 4);
+        let delay_slot = index > 0 && is_branch(instructions[index - 1]);
+        emitter.exits.push(Exit { pending: emitter.pending, delay_slot });
+        emitter.instruction(index, pc, instruction);
+    }
+    emitter.exits.push(Exit { pending: emitter.pending, delay_slot: false });
+
+    let len = instructions.len() as i32;
+    dynasm!(emitter.ops
+        ; .arch x64
+        ; mov eax, len
+        ; ->exit:
+        ; pop r13
+        ; pop r12
+        ; pop rbx
+        ; ret
+    );
+
+    for (label, index) in std::mem::take(&mut emitter.exit_labels) {
+        dynasm!(emitter.ops
+            ; .arch x64
+            ; =>label
+            ; mov eax, index as i32
+            ; jmp ->exit
+        );
+    }
+
+    (emitter.ops.finalize().expect("Failed to assemble dynarec block"), emitter.exits)
+}
+
+struct Emitter {
+    ops: VecAssembler<X64Relocation>,
+    exits: Vec<Exit>,
+    exit_labels: Vec<(DynamicLabel, usize)>,
+    // Guest register and scratch slot of a load whose delay slot is the current instruction
+    pending: Option<(u32, usize)>,
+}
+
+impl Emitter {
+    // Jumping here leaves the block with `index` instructions executed
+    fn exit(&mut self, index: usize) -> DynamicLabel {
+        let label = self.ops.new_dynamic_label();
+        self.exit_labels.push((label, index));
+        label
+    }
+
+    fn instruction(&mut self, index: usize, pc: u32, instruction: u32) {
+        let rs = reg(instruction.rs());
+        let rt = reg(instruction.rt());
+        let imm = instruction.imm_se() as i32;
+
+        match instruction.op() {
+            0b000000 => self.special(index, pc, instruction),
+            0b000001 => {
+                let greater = (instruction >> 16) & 1 != 0;
+                let link = (instruction >> 17) & 0xF == 8;
+                let target = branch_target(pc, instruction);
+
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov ecx, DWORD [rbx + rs]
+                    ; mov DWORD [r13 + NEXT_PC], pc.wrapping_add(8) as i32
+                    ; mov DWORD [r13 + TAKEN], 0
+                    ; test ecx, ecx
+                );
+                if greater {
+                    dynasm!(self.ops ; .arch x64 ; js >skip);
+                } else {
+                    dynasm!(self.ops ; .arch x64 ; jns >skip);
+                }
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov DWORD [r13 + NEXT_PC], target as i32
+                    ; mov DWORD [r13 + TAKEN], 1
+                    ; skip:
+                );
+
+                if link {
+                    dynasm!(self.ops ; .arch x64 ; mov eax, pc.wrapping_add(8) as i32);
+                    self.finish(Some(31));
+                } else {
+                    self.finish(None);
+                }
+            }
+            0b000010 | 0b000011 => {
+                let target = (pc.wrapping_add(4) & 0xF000_0000) | (instruction.target() << 2);
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov DWORD [r13 + NEXT_PC], target as i32
+                    ; mov DWORD [r13 + TAKEN], 1
+                );
+
+                if instruction.op() == 0b000011 {
+                    dynasm!(self.ops ; .arch x64 ; mov eax, pc.wrapping_add(8) as i32);
+                    self.finish(Some(31));
+                } else {
+                    self.finish(None);
+                }
+            }
+            op @ 0b000100..=0b000111 => {
+                let target = branch_target(pc, instruction);
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov eax, DWORD [rbx + rs]
+                    ; mov DWORD [r13 + NEXT_PC], pc.wrapping_add(8) as i32
+                    ; mov DWORD [r13 + TAKEN], 0
+                );
+                match op {
+                    0b000100 => dynasm!(self.ops ; .arch x64 ; cmp eax, DWORD [rbx + rt] ; jne >skip),
+                    0b000101 => dynasm!(self.ops ; .arch x64 ; cmp eax, DWORD [rbx + rt] ; je >skip),
+                    0b000110 => dynasm!(self.ops ; .arch x64 ; cmp eax, 0 ; jg >skip),
+                    _ => dynasm!(self.ops ; .arch x64 ; cmp eax, 0 ; jle >skip),
+                }
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov DWORD [r13 + NEXT_PC], target as i32
+                    ; mov DWORD [r13 + TAKEN], 1
+                    ; skip:
+                );
+                self.finish(None);
+            }
+            0b001000 => {
+                let exit = self.exit(index);
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; add eax, imm ; jo =>exit);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001001 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; add eax, imm);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001010 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; cmp eax, imm ; setl al ; movzx eax, al);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001011 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; cmp eax, imm ; setb al ; movzx eax, al);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001100 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; and eax, instruction.imm() as i32);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001101 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; or eax, instruction.imm() as i32);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001110 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; xor eax, instruction.imm() as i32);
+                self.finish(Some(instruction.rt()));
+            }
+            0b001111 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, (instruction.imm() << 16) as i32);
+                self.finish(Some(instruction.rt()));
+            }
+            op @ (0b100000 | 0b100001 | 0b100011 | 0b100100 | 0b100101) => {
+                let helper = match op {
+                    0b100000 | 0b100100 => load8 as *const (),
+                    0b100001 | 0b100101 => load16 as *const (),
+                    _ => load32 as *const (),
+                };
+
+                let exit = self.exit(index);
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov esi, DWORD [rbx + rs]
+                    ; add esi, imm
+                    ; mov rdi, r12
+                    ; mov rdx, r13
+                    ; mov rax, QWORD helper as i64
+                    ; call rax
+                    ; bt rax, 32
+                    ; jc =>exit
+                );
+                match op {
+                    0b100000 => dynasm!(self.ops ; .arch x64 ; movsx eax, al),
+                    0b100001 => dynasm!(self.ops ; .arch x64 ; movsx eax, ax),
+                    _ => {}
+                }
+                self.load(instruction.rt());
+            }
+            op @ (0b101000 | 0b101001 | 0b101011) => {
+                let helper = match op {
+                    0b101000 => store8 as *const (),
+                    0b101001 => store16 as *const (),
+                    _ => store32 as *const (),
+                };
+
+                let failed = self.exit(index);
+                let dirty = self.exit(index + 1);
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov rdi, rbx
+                    ; mov esi, DWORD [rbx + rs]
+                    ; add esi, imm
+                    ; mov edx, DWORD [rbx + rt]
+                    ; mov rcx, r12
+                    ; mov rax, QWORD helper as i64
+                    ; call rax
+                    ; cmp eax, STORE_FAILED as i32
+                    ; je =>failed
+                );
+                self.finish(None);
+                dynasm!(self.ops ; .arch x64 ; cmp eax, STORE_DIRTY as i32 ; je =>dirty);
+            }
+            _ => unreachable!("Unsupported instruction {instruction:08X} in dynarec block"),
+        }
+    }
+
+    fn special(&mut self, index: usize, pc: u32, instruction: u32) {
+        let rs = reg(instruction.rs());
+        let rt = reg(instruction.rt());
+        let rd = instruction.rd();
+        let shamt = instruction.shamt() as i8;
+
+        match instruction.funct() {
+            0b000000 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rt] ; shl eax, shamt),
+            0b000010 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rt] ; shr eax, shamt),
+            0b000011 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rt] ; sar eax, shamt),
+            0b000100 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rt] ; mov ecx, DWORD [rbx + rs] ; shl eax, cl),
+            0b000110 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rt] ; mov ecx, DWORD [rbx + rs] ; shr eax, cl),
+            0b000111 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rt] ; mov ecx, DWORD [rbx + rs] ; sar eax, cl),
+            0b001000 => {
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov eax, DWORD [rbx + rs]
+                    ; mov DWORD [r13 + NEXT_PC], eax
+                    ; mov DWORD [r13 + TAKEN], 1
+                );
+                return self.finish(None);
+            }
+            0b001001 => {
+                // The link goes through write_register, which commits an older load before
+                // the target register is read
+                self.commit_pending();
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov eax, DWORD [rbx + rs]
+                    ; mov DWORD [r13 + NEXT_PC], eax
+                    ; mov DWORD [r13 + TAKEN], 1
+                    ; mov eax, pc.wrapping_add(8) as i32
+                );
+            }
+            0b010000 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + HI]),
+            0b010001 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; mov DWORD [rbx + HI], eax);
+                return self.finish(None);
+            }
+            0b010010 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + LO]),
+            0b010011 => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; mov DWORD [rbx + LO], eax);
+                return self.finish(None);
+            }
+            funct @ (0b011000 | 0b011001) => {
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs]);
+                if funct == 0b011000 {
+                    dynasm!(self.ops ; .arch x64 ; imul DWORD [rbx + rt]);
+                } else {
+                    dynasm!(self.ops ; .arch x64 ; mul DWORD [rbx + rt]);
+                }
+                dynasm!(self.ops ; .arch x64 ; mov DWORD [rbx + LO], eax ; mov DWORD [rbx + HI], edx);
+                return self.finish(None);
+            }
+            0b100000 => {
+                let exit = self.exit(index);
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; add eax, DWORD [rbx + rt] ; jo =>exit);
+            }
+            0b100001 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; add eax, DWORD [rbx + rt]),
+            0b100010 => {
+                let exit = self.exit(index);
+                dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; sub eax, DWORD [rbx + rt] ; jo =>exit);
+            }
+            0b100011 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; sub eax, DWORD [rbx + rt]),
+            0b100100 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; and eax, DWORD [rbx + rt]),
+            0b100101 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; or eax, DWORD [rbx + rt]),
+            0b100110 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; xor eax, DWORD [rbx + rt]),
+            0b100111 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; or eax, DWORD [rbx + rt] ; not eax),
+            0b101010 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; cmp eax, DWORD [rbx + rt] ; setl al ; movzx eax, al),
+            0b101011 => dynasm!(self.ops ; .arch x64 ; mov eax, DWORD [rbx + rs] ; cmp eax, DWORD [rbx + rt] ; setb al ; movzx eax, al),
+            _ => unreachable!("Unsupported instruction {instruction:08X} in dynarec block"),
+        }
+
+        self.finish(Some(rd));
+    }
+
+    // Mirrors write_register followed by commit_writes: the older load lands first, then eax
+    fn finish(&mut self, register: Option<u32>) {
+        self.commit_pending();
+        if let Some(register) = register.filter(|&register| register != 0) {
+            dynasm!(self.ops ; .arch x64 ; mov DWORD [rbx + reg(register)], eax);
+        }
+    }
+
+    // Mirrors schedule_write followed by commit_writes: a load to the same register replaces
+    // the older one, otherwise the older one lands and eax waits in a slot for one instruction
+    fn load(&mut self, register: u32) {
+        let slot = match self.pending {
+            Some((_, slot)) => slot ^ 1,
+            None => 0,
+        };
+        let offset = SLOTS + slot as i32 * 4;
+        dynasm!(self.ops ; .arch x64 ; mov DWORD [r13 + offset], eax);
+
+        if self.pending.is_some_and(|(pending, _)| pending == register) {
+            self.pending = None;
+        }
+        self.commit_pending();
+        self.pending = (register != 0).then_some((register, slot));
+    }
+
+    fn commit_pending(&mut self) {
+        if let Some((register, slot)) = self.pending.take() {
+            if register != 0 {
+                let offset = SLOTS + slot as i32 * 4;
+                dynasm!(self.ops
+                    ; .arch x64
+                    ; mov ecx, DWORD [r13 + offset]
+                    ; mov DWORD [rbx + reg(register)], ecx
+                );
+            }
+        }
+    }
+}
+
+fn reg(register: u32) -> i32 {
+    R + register as i32 * 4
+}
+
+fn branch_target(pc: u32, instruction: u32) -> u32 {
+    pc.wrapping_add(4).wrapping_add(instruction.imm_se() << 2)
+}
+
+// Loads charge the bus timing to the block, which adds it up once the native code returns
+fn load<T: Into<u64>>(bus: *mut Bus, addr: u32, scratch: *mut Scratch, read: impl FnOnce(&Bus) -> Option<T>) -> u64 {
+    let (bus, scratch) = unsafe { (&*bus, &mut *scratch) };
+    match read(bus) {
+        Some(value) => {
+            scratch.access_cycles += bus.access_cycles(addr, size_of::<T>() as u32) as u32;
+            value.into()
+        }
+        None => LOAD_FAILED,
+    }
+}
+
+extern "sysv64" fn load8(bus: *mut Bus, addr: u32, scratch: *mut Scratch) -> u64 {
+    load(bus, addr, scratch, |bus| bus.read_memory8(addr))
+}
+
+extern "sysv64" fn load16(bus: *mut Bus, addr: u32, scratch: *mut Scratch) -> u64 {
+    if addr & 0b1 != 0 {return LOAD_FAILED}
+    load(bus, addr, scratch, |bus| bus.read_memory16(addr))
+}
+
+extern "sysv64" fn load32(bus: *mut Bus, addr: u32, scratch: *mut Scratch) -> u64 {
+    if addr & 0b11 != 0 {return LOAD_FAILED}
+    load(bus, addr, scratch, |bus| bus.read_memory32(addr))
+}
+
+// Isolated cache writes are left to the interpreter along with everything that isn't memory
+fn store(cpu: *mut CPU, bus: *mut Bus, write: impl FnOnce(&mut Bus) -> bool) -> u32 {
+    let (cpu, bus) = unsafe { (&*cpu, &mut *bus) };
+    if cpu.system_control.read_register(12) & 0x10000 != 0 || !write(bus) {
+        STORE_FAILED
+    } else if bus.has_dirty_code() {
+        STORE_DIRTY
+    } else {
+        STORE_OK
+    }
+}
+
+extern "sysv64" fn store8(cpu: *mut CPU, addr: u32, value: u32, bus: *mut Bus) -> u32 {
+    store(cpu, bus, |bus| bus.write_memory8(addr, value as u8))
+}
+
+extern "sysv64" fn store16(cpu: *mut CPU, addr: u32, value: u32, bus: *mut Bus) -> u32 {
+    if addr & 0b1 != 0 {return STORE_FAILED}
+    store(cpu, bus, |bus| bus.write_memory16(addr, value as u16))
+}
+
+extern "sysv64" fn store32(cpu: *mut CPU, addr: u32, value: u32, bus: *mut Bus) -> u32 {
+    if addr & 0b11 != 0 {return STORE_FAILED}
+    store(cpu, bus, |bus| bus.write_memory32(addr, value))
+}
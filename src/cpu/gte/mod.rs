@@ -1,6 +1,6 @@
-use crate::{cpu::gte::command::GTE_Command, Registers};
+use crate::{cpu::gte::command::GTE_Command, state::{Savestate, StateReader, StateWriter}, Registers};
 
-mod command;
+pub(crate) mod command;
 mod register;
 
 pub struct GTE {
@@ -57,6 +57,14 @@ impl GTE {
         }
     }
 
+    pub fn elapse(&mut self, cycles: u64) {
+        self.cycles = self.cycles.saturating_sub(cycles as usize);
+    }
+
+    pub fn take_busy_cycles(&mut self) -> u64 {
+        std::mem::take(&mut self.cycles) as u64
+    }
+
     pub fn read_data_register(&self, register: u32) -> u32 {
         match register {
             1 | 3 | 5 | 8..=11 => self.R[register] as i16 as u32,
@@ -145,4 +153,17 @@ impl GTE {
         self.R[28] = (self.R[28] & !(0x1F << 10)) | ((blue as u16 as u32) << 10);
         self.R[28] &= 0x7FFF;
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for GTE {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.R);
+        w.write(&self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.R = r.read()?;
+        self.cycles = r.read()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{bus::interface::mask_region, state::{Savestate, StateReader, StateWriter}};
+
+const LINES: usize = 256;
+
+pub const CODE_CACHE_ENABLE: u32 = 1 << 11;
+const TAG_TEST: u32 = 1 << 2;
+
+// The 4KB direct-mapped instruction cache: 256 lines of four words, with a valid bit per
+// word. It only decides fetch timing; the instructions themselves always come from memory, so
+// the data half of the cache isn't kept.
+pub struct ICache {
+    tags: [u32; LINES],
+    valid: [u8; LINES],
+}
+
+impl ICache {
+    pub fn new() -> Self {
+        Self {
+            tags: [0; LINES],
+            valid: [0; LINES],
+        }
+    }
+
+    // Returns how many words the fetch reads from memory. A miss refills the line from the
+    // missed word to its end, the words before it are left invalid.
+    pub fn fetch(&mut self, addr: u32, cache_control: u32) -> u64 {
+        if !cached(addr, cache_control) {
+            return 1;
+        }
+
+        let (line, word, tag) = split(addr);
+        if self.tags[line] == tag && self.valid[line] & (1 << word) != 0 {
+            return 0;
+        }
+
+        self.tags[line] = tag;
+        self.valid[line] = 0xF & (0xF << word);
+        (4 - word) as u64
+    }
+
+    pub fn max_fetch_words(addr: u32, cache_control: u32) -> u64 {
+        if cached(addr, cache_control) {4} else {1}
+    }
+
+    // Stores with SR.IsC set land here instead of memory. In tag test mode they set the tag of
+    // the line and invalidate it, which is how the BIOS flushes the cache.
+    pub fn isolated_write(&mut self, addr: u32, cache_control: u32) {
+        if cache_control & CODE_CACHE_ENABLE == 0 {
+            return;
+        }
+
+        let (line, _, tag) = split(addr);
+        if cache_control & TAG_TEST != 0 {
+            self.tags[line] = tag;
+            self.valid[line] = 0;
+        }
+    }
+}
+
+fn cached(addr: u32, cache_control: u32) -> bool {
+    cache_control & CODE_CACHE_ENABLE != 0 && addr < 0xA000_0000
+}
+
+fn split(addr: u32) -> (usize, u32, u32) {
+    let line = (addr >> 4) as usize & (LINES - 1);
+    let word = (addr >> 2) & 3;
+    let tag = mask_region(addr) & !0xFFF;
+
+    (line, word, tag)
+}
+
+impl Savestate for ICache {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.tags);
+        w.write(&self.valid);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.tags = r.read()?;
+        self.valid = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::icache::{ICache, CODE_CACHE_ENABLE};
+
+    #[test]
+    fn flush_invalidates_lines() {
+        let mut icache = ICache::new();
+        let enabled = CODE_CACHE_ENABLE;
+
+        assert_eq!(icache.fetch(0x8003_0004, enabled), 3);
+        assert_eq!(icache.fetch(0x8003_0008, enabled), 0);
+        assert_eq!(icache.fetch(0x0003_000C, enabled), 0);
+        assert_eq!(icache.fetch(0x8003_0000, enabled), 4);
+        assert_eq!(icache.fetch(0xA003_0000, enabled), 1);
+
+        icache.isolated_write(0x0000_0000, enabled);
+        assert_eq!(icache.fetch(0x8003_0000, enabled), 0);
+
+        icache.isolated_write(0x0000_0000, enabled | 0b100);
+        assert_eq!(icache.fetch(0x8003_0000, enabled), 4);
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::anyhow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    A,
+    B,
+    C,
+}
+
+impl Table {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "A" | "a" => Some(Table::A),
+            "B" | "b" => Some(Table::B),
+            "C" | "c" => Some(Table::C),
+            _ => None,
+        }
+    }
+
+    fn functions(self) -> &'static [(u32, &'static str, &'static str)] {
+        match self {
+            Table::A => A_FUNCTIONS,
+            Table::B => B_FUNCTIONS,
+            Table::C => C_FUNCTIONS,
+        }
+    }
+}
+
+// Calls are made by jumping to A0h, B0h or C0h with the function number in r9
+pub fn kernel_call(pc: u32, r9: u32) -> Option<(Table, u32)> {
+    match pc & 0x1FFF_FFFF {
+        0xA0 => Some((Table::A, r9)),
+        0xB0 => Some((Table::B, r9)),
+        0xC0 => Some((Table::C, r9)),
+        _ => None,
+    }
+}
+
+// Arguments are described one character each: s is a string pointer, d a signed integer, c a
+// character, and x anything else.
+const A_FUNCTIONS: &[(u32, &str, &str)] = &[
+    (0x00, "open", "sx"), (0x01, "lseek", "ddd"), (0x02, "read", "dxd"), (0x03, "write", "dxd"),
+    (0x04, "close", "d"), (0x05, "ioctl", "dxx"), (0x06, "exit", "d"), (0x07, "isatty", "d"),
+    (0x08, "getc", "d"), (0x09, "putc", "cd"), (0x0A, "todigit", "c"), (0x0B, "atof", "s"),
+    (0x0C, "strtoul", "sxd"), (0x0D, "strtol", "sxd"), (0x0E, "abs", "d"), (0x0F, "labs", "d"),
+    (0x10, "atoi", "s"), (0x11, "atol", "s"), (0x12, "atob", "sx"), (0x13, "SaveState", "x"),
+    (0x14, "RestoreState", "xx"), (0x15, "strcat", "xs"), (0x16, "strncat", "xsd"), (0x17, "strcmp", "ss"),
+    (0x18, "strncmp", "ssd"), (0x19, "strcpy", "xs"), (0x1A, "strncpy", "xsd"), (0x1B, "strlen", "s"),
+    (0x1C, "index", "sc"), (0x1D, "rindex", "sc"), (0x1E, "strchr", "sc"), (0x1F, "strrchr", "sc"),
+    (0x20, "strpbrk", "ss"), (0x21, "strspn", "ss"), (0x22, "strcspn", "ss"), (0x23, "strtok", "ss"),
+    (0x24, "strstr", "ss"), (0x25, "toupper", "c"), (0x26, "tolower", "c"), (0x27, "bcopy", "xxd"),
+    (0x28, "bzero", "xd"), (0x29, "bcmp", "xxd"), (0x2A, "memcpy", "xxd"), (0x2B, "memset", "xxd"),
+    (0x2C, "memmove", "xxd"), (0x2D, "memcmp", "xxd"), (0x2E, "memchr", "xxd"), (0x2F, "rand", ""),
+    (0x30, "srand", "x"), (0x31, "qsort", "xddx"), (0x32, "strtod", "sx"), (0x33, "malloc", "d"),
+    (0x34, "free", "x"), (0x35, "lsearch", "xxddx"), (0x36, "bsearch", "xxddx"), (0x37, "calloc", "dd"),
+    (0x38, "realloc", "xd"), (0x39, "InitHeap", "xd"), (0x3A, "_exit", "d"), (0x3B, "getchar", ""),
+    (0x3C, "putchar", "c"), (0x3D, "gets", "x"), (0x3E, "puts", "s"), (0x3F, "printf", "sxxx"),
+    (0x40, "SystemErrorUnresolvedException", ""), (0x41, "LoadTest", "sx"), (0x42, "Load", "sx"),
+    (0x43, "Exec", "xxx"), (0x44, "FlushCache", ""), (0x45, "init_a0_b0_c0_vectors", ""),
+    (0x46, "GPU_dw", "dddd"), (0x47, "gpu_send_dma", "dddd"), (0x48, "SendGP1Command", "x"),
+    (0x49, "GPU_cw", "x"), (0x4A, "GPU_cwp", "xd"), (0x4B, "send_gpu_linked_list", "x"),
+    (0x4C, "gpu_abort_dma", ""), (0x4D, "GetGPUStatus", ""), (0x4E, "gpu_sync", ""),
+    (0x51, "LoadExec", "sxx"), (0x52, "GetSysSp", ""), (0x54, "CdInit", ""), (0x55, "_bu_init", ""),
+    (0x56, "CdRemove", ""), (0x5B, "dev_tty_init", ""), (0x5C, "dev_tty_open", "xsx"),
+    (0x5D, "dev_tty_in_out", "xx"), (0x5E, "dev_tty_ioctl", "xxx"), (0x5F, "dev_cd_open", "xsx"),
+    (0x60, "dev_cd_read", "xxd"), (0x61, "dev_cd_close", "x"), (0x62, "dev_cd_firstfile", "xsx"),
+    (0x63, "dev_cd_nextfile", "xx"), (0x64, "dev_cd_chdir", "xs"), (0x65, "dev_card_open", "xsx"),
+    (0x66, "dev_card_read", "xxd"), (0x67, "dev_card_write", "xxd"), (0x68, "dev_card_close", "x"),
+    (0x69, "dev_card_firstfile", "xsx"), (0x6A, "dev_card_nextfile", "xx"), (0x6B, "dev_card_erase", "xs"),
+    (0x6C, "dev_card_undelete", "xs"), (0x6D, "dev_card_format", "x"), (0x6E, "dev_card_rename", "xsxs"),
+    (0x6F, "card_clear_error", "x"), (0x70, "_bu_init", ""), (0x71, "CdInit", ""), (0x72, "CdRemove", ""),
+    (0x78, "CdAsyncSeekL", "x"), (0x7C, "CdAsyncGetStatus", "x"), (0x7E, "CdAsyncReadSector", "dxx"),
+    (0x81, "CdAsyncSetMode", "x"), (0x90, "CdromIoIrqFunc1", ""), (0x91, "CdromDmaIrqFunc1", ""),
+    (0x92, "CdromIoIrqFunc2", ""), (0x93, "CdromDmaIrqFunc2", ""), (0x94, "CdromGetInt5errCode", "xx"),
+    (0x95, "CdInitSubFunc", ""), (0x96, "AddCDROMDevice", ""), (0x97, "AddMemCardDevice", ""),
+    (0x98, "AddDuartTtyDevice", ""), (0x99, "AddDummyTtyDevice", ""), (0x9C, "SetConf", "ddx"),
+    (0x9D, "GetConf", "xxx"), (0x9E, "SetCdromIrqAutoAbort", "xx"), (0x9F, "SetMemSize", "d"),
+    (0xA0, "WarmBoot", ""), (0xA1, "SystemErrorBootOrDiskFailure", "cx"), (0xA2, "EnqueueCdIntr", ""),
+    (0xA3, "DequeueCdIntr", ""), (0xA4, "CdGetLbn", "s"), (0xA5, "CdReadSector", "ddx"),
+    (0xA6, "CdGetStatus", ""), (0xA7, "bufs_cb_0", ""), (0xA8, "bufs_cb_1", ""), (0xA9, "bufs_cb_2", ""),
+    (0xAA, "bufs_cb_3", ""), (0xAB, "_card_info", "x"), (0xAC, "_card_load", "x"),
+    (0xAD, "set_card_auto_format", "x"), (0xAE, "bufs_cb_4", ""), (0xAF, "card_write_test", "x"),
+    (0xB2, "ioabort_raw", "x"), (0xB4, "GetSystemInfo", "x"),
+];
+
+const B_FUNCTIONS: &[(u32, &str, &str)] = &[
+    (0x00, "alloc_kernel_memory", "d"), (0x01, "free_kernel_memory", "x"), (0x02, "init_timer", "dxx"),
+    (0x03, "get_timer", "d"), (0x04, "enable_timer_irq", "d"), (0x05, "disable_timer_irq", "d"),
+    (0x06, "restart_timer", "d"), (0x07, "DeliverEvent", "xx"), (0x08, "OpenEvent", "xxxx"),
+    (0x09, "CloseEvent", "x"), (0x0A, "WaitEvent", "x"), (0x0B, "TestEvent", "x"), (0x0C, "EnableEvent", "x"),
+    (0x0D, "DisableEvent", "x"), (0x0E, "OpenThread", "xxx"), (0x0F, "CloseThread", "x"),
+    (0x10, "ChangeThread", "x"), (0x11, "jump_to_00000000", ""), (0x12, "InitPad", "xdxd"),
+    (0x13, "StartPad", ""), (0x14, "StopPad", ""), (0x15, "OutdatedPadInitAndStart", "xxxx"),
+    (0x16, "OutdatedPadGetButtons", ""), (0x17, "ReturnFromException", ""),
+    (0x18, "SetDefaultExitFromException", ""), (0x19, "SetCustomExitFromException", "x"),
+    (0x20, "UnDeliverEvent", "xx"), (0x32, "open", "sx"), (0x33, "lseek", "ddd"), (0x34, "read", "dxd"),
+    (0x35, "write", "dxd"), (0x36, "close", "d"), (0x37, "ioctl", "dxx"), (0x38, "exit", "d"),
+    (0x39, "isatty", "d"), (0x3A, "getc", "d"), (0x3B, "putc", "cd"), (0x3C, "getchar", ""),
+    (0x3D, "putchar", "c"), (0x3E, "gets", "x"), (0x3F, "puts", "s"), (0x40, "cd", "s"), (0x41, "format", "s"),
+    (0x42, "firstfile", "sx"), (0x43, "nextfile", "x"), (0x44, "rename", "ss"), (0x45, "erase", "s"),
+    (0x46, "undelete", "s"), (0x47, "AddDrv", "x"), (0x48, "DelDrv", "s"), (0x49, "PrintInstalledDevices", ""),
+    (0x4A, "InitCard", "x"), (0x4B, "StartCard", ""), (0x4C, "StopCard", ""), (0x4D, "_card_info_subfunc", "x"),
+    (0x4E, "write_card_sector", "xdx"), (0x4F, "read_card_sector", "xdx"), (0x50, "allow_new_card", ""),
+    (0x51, "Krom2RawAdd", "x"), (0x53, "Krom2Offset", "x"), (0x54, "GetLastError", ""),
+    (0x55, "GetLastFileError", "d"), (0x56, "GetC0Table", ""), (0x57, "GetB0Table", ""),
+    (0x58, "get_bu_callback_port", ""), (0x59, "testdevice", "s"), (0x5B, "ChangeClearPad", "x"),
+    (0x5C, "get_card_status", "d"), (0x5D, "wait_card_status", "d"),
+];
+
+const C_FUNCTIONS: &[(u32, &str, &str)] = &[
+    (0x00, "EnqueueTimerAndVblankIrqs", "d"), (0x01, "EnqueueSyscallHandler", "d"), (0x02, "SysEnqIntRP", "dx"),
+    (0x03, "SysDeqIntRP", "dx"), (0x04, "get_free_EvCB_slot", ""), (0x05, "get_free_TCB_slot", ""),
+    (0x06, "ExceptionHandler", ""), (0x07, "InstallExceptionHandlers", ""), (0x08, "SysInitMemory", "xd"),
+    (0x09, "SysInitKernelVariables", ""), (0x0A, "ChangeClearRCnt", "dx"), (0x0C, "InitDefInt", "d"),
+    (0x0D, "SetIrqAutoAck", "dx"), (0x0E, "dev_sio_init", ""), (0x0F, "dev_sio_open", "xsx"),
+    (0x10, "dev_sio_in_out", "xx"), (0x11, "dev_sio_ioctl", "xxx"), (0x12, "InstallDevices", "x"),
+    (0x13, "FlushStdInOutPut", ""), (0x15, "tty_cdevinput", "xc"), (0x16, "tty_cdevscan", ""),
+    (0x17, "tty_circgetc", "x"), (0x18, "tty_circputc", "cx"), (0x19, "ioabort", "ss"),
+    (0x1A, "set_card_find_mode", "x"), (0x1B, "KernelRedirect", "x"), (0x1C, "AdjustA0Table", ""),
+    (0x1D, "get_card_find_mode", ""),
+];
+
+// These never come back to their caller, so no return value is waited for
+const NO_RETURN: [&str; 8] = [
+    "exit", "_exit", "LoadExec", "WarmBoot", "ReturnFromException", "jump_to_00000000", "ChangeThread",
+    "SystemErrorUnresolvedException",
+];
+
+// Calls nested deeper than this are assumed to have been abandoned
+const MAX_DEPTH: usize = 32;
+const MAX_STRING: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Rule {
+    Table(Table),
+    Call(Table, u32),
+    Name(String),
+}
+
+impl Rule {
+    fn parse(rule: &str) -> anyhow::Result<Self> {
+        if let Some(table) = Table::parse(rule) {
+            return Ok(Rule::Table(table));
+        }
+
+        if let Some((table, number)) = rule.strip_suffix(')').and_then(|rule| rule.split_once('(')) {
+            let table = Table::parse(table).ok_or_else(|| anyhow!("Unknown kernel table {table}"))?;
+            let number = u32::from_str_radix(number.trim_end_matches(['h', 'H']), 16)?;
+            return Ok(Rule::Call(table, number));
+        }
+
+        Ok(Rule::Name(rule.to_string()))
+    }
+
+    fn matches(&self, table: Table, number: u32, name: &str) -> bool {
+        match self {
+            Rule::Table(rule_table) => *rule_table == table,
+            Rule::Call(rule_table, rule_number) => (*rule_table, *rule_number) == (table, number),
+            Rule::Name(rule_name) => rule_name.eq_ignore_ascii_case(name),
+        }
+    }
+}
+
+struct PendingReturn {
+    addr: u32,
+    call: String,
+}
+
+// Logs kernel calls with their arguments, and their return values once the caller's return
+// address is reached. The filter is a comma separated list of tables (B), calls (A(3Fh)) or
+// names (open), where a leading - excludes the call instead.
+pub struct KernelTracer {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+    returns: Vec<PendingReturn>,
+}
+
+impl KernelTracer {
+    pub fn new(filter: &str) -> anyhow::Result<Self> {
+        let mut include = Vec::new();
+        let mut exclude = Vec::new();
+        for rule in filter.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            match rule.strip_prefix('-') {
+                Some(rule) => exclude.push(Rule::parse(rule)?),
+                None => include.push(Rule::parse(rule)?),
+            }
+        }
+
+        Ok(Self { include, exclude, returns: Vec::new() })
+    }
+
+    // Returns the line to log for a call, if the filter lets it through. read8 peeks at memory
+    // for string arguments.
+    pub fn call(&mut self, table: Table, number: u32, args: [u32; 4], ra: u32, read8: impl Fn(u32) -> Option<u8>) -> Option<String> {
+        let (name, kinds) = table.functions().iter()
+            .find(|(function, _, _)| *function == number)
+            .map_or(("?", "xxxx"), |(_, name, kinds)| (*name, *kinds));
+
+        let allowed = self.include.is_empty() || self.include.iter().any(|rule| rule.matches(table, number, name));
+        if !allowed || self.exclude.iter().any(|rule| rule.matches(table, number, name)) {
+            return None;
+        }
+
+        let args: Vec<String> = kinds.chars().zip(args).map(|(kind, arg)| format_arg(kind, arg, &read8)).collect();
+        let call = format!("{table:?}({number:02X}) {name}");
+        let line = format!("{}{call}({}) from {ra:08X}", self.indent(), args.join(", "));
+
+        if !NO_RETURN.contains(&name) {
+            if self.returns.len() == MAX_DEPTH {
+                self.returns.remove(0);
+            }
+            self.returns.push(PendingReturn { addr: ra, call });
+        }
+
+        Some(line)
+    }
+
+    // Returns the line to log if pc is where a traced call returns to. Calls nested inside it
+    // that never returned are dropped.
+    pub fn ret(&mut self, pc: u32, v0: u32) -> Option<String> {
+        let index = self.returns.iter().rposition(|pending| pending.addr == pc)?;
+        let pending = self.returns.drain(index..).next()?;
+        Some(format!("{}{} -> {v0:08X}", self.indent(), pending.call))
+    }
+
+    fn indent(&self) -> String {
+        "  ".repeat(self.returns.len())
+    }
+}
+
+fn format_arg(kind: char, arg: u32, read8: &impl Fn(u32) -> Option<u8>) -> String {
+    match kind {
+        'd' => (arg as i32).to_string(),
+        'c' => format!("{:?}", arg as u8 as char),
+        's' if arg == 0 => String::from("NULL"),
+        's' => {
+            let mut string = String::new();
+            for addr in arg..arg.saturating_add(MAX_STRING as u32) {
+                match read8(addr) {
+                    Some(0) => return format!("{string:?}"),
+                    Some(byte) => string.push(byte as char),
+                    None => break,
+                }
+            }
+            format!("{string:?}...")
+        }
+        _ => format!("{arg:08X}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::kernel::{KernelTracer, Table};
+
+    #[test]
+    fn calls_and_filters() {
+        let memory = b"cdrom:\\SLUS_000.01;1\0";
+        let read8 = |addr: u32| memory.get(addr.wrapping_sub(0x8000_1000) as usize).copied();
+
+        let mut tracer = KernelTracer::new("B, open, -putchar").unwrap();
+        assert_eq!(
+            tracer.call(Table::A, 0x00, [0x8000_1000, 1, 0, 0], 0x8001_0010, read8).as_deref(),
+            Some("A(00) open(\"cdrom:\\\\SLUS_000.01;1\", 00000001) from 80010010"),
+        );
+        assert_eq!(tracer.call(Table::A, 0x3C, [0x41, 0, 0, 0], 0xBFC0_1000, read8), None);
+        assert_eq!(tracer.call(Table::B, 0x3D, [0x41, 0, 0, 0], 0xBFC0_1000, read8), None);
+        assert_eq!(
+            tracer.call(Table::B, 0x5B, [1, 0, 0, 0], 0xBFC0_2000, read8).as_deref(),
+            Some("  B(5B) ChangeClearPad(00000001) from BFC02000"),
+        );
+
+        // The nested call never returns, so it's dropped along with the outer one
+        assert_eq!(tracer.ret(0x8001_0010, 4).as_deref(), Some("A(00) open -> 00000004"));
+        assert_eq!(tracer.ret(0xBFC0_2000, 0), None);
+
+        let mut tracer = KernelTracer::new("C(1Ch)").unwrap();
+        assert!(tracer.call(Table::C, 0x1C, [0; 4], 0, read8).is_some());
+        assert!(tracer.call(Table::C, 0x1D, [0; 4], 0, read8).is_none());
+        assert!(KernelTracer::new("D(00)").is_err());
+    }
+}
@@ -1,12 +1,18 @@
-use std::{cell::RefCell, rc::Rc};
-
-use crate::{bus::interface::Interface, cpu::{decoder::{Cause, Instruction}, gte::GTE, system_control::SystemControl}, Registers};
+use crate::{bus::interface::Bus, debugger::Access, cpu::{block_cache::{BlockCache, Op}, decoder::Cause, disassembler::disassemble, gte::GTE, icache::ICache, kernel::{kernel_call, KernelTracer, Table}, system_control::SystemControl}, state::{Savestate, StateReader, StateWriter}, Registers};
 
+pub mod block_cache;
 pub mod decoder;
+pub mod disassembler;
+#[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+mod dynarec;
+pub mod icache;
+pub mod kernel;
 pub mod system_control;
 mod gte;
 mod op_codes;
 
+pub const CYCLES_PER_INSTRUCTION: u64 = 2;
+
 pub struct CPU {
     pub R: Registers<32>,
     pub pc: u32,
@@ -19,18 +25,23 @@ pub struct CPU {
     branch: bool,
     delay_slot: bool,
 
-    pub system_control: Rc<RefCell<SystemControl>>,
+    pub system_control: SystemControl,
     gte: GTE,
+    icache: ICache,
+    block_cache: BlockCache,
+    #[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+    dynarec: Option<dynarec::Dynarec>,
 
-    interface: Rc<RefCell<Interface>>,
-    pub dma_running: Rc<RefCell<bool>>,
     stalled: bool,
+    // Cycles the current instruction waited on loads and the GTE
+    stall_cycles: u64,
 
     pub trace: bool,
+    pub kernel_tracer: Option<KernelTracer>,
 }
 
 impl CPU {
-    pub fn new(interface: Rc<RefCell<Interface>>, dma_running: Rc<RefCell<bool>>, system_control: Rc<RefCell<SystemControl>>) -> Self {
+    pub fn new() -> Self {
         let R = Registers {R: [0; 32]};
         let pc = 0xBFC0_0000;
         let (hi, lo) = (0, 0);
@@ -47,20 +58,52 @@ impl CPU {
             branch: false,
             delay_slot: false,
 
-            system_control,
+            system_control: SystemControl::new(),
             gte: GTE::new(),
+            icache: ICache::new(),
+            block_cache: BlockCache::new(),
+            #[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+            dynarec: None,
 
-            interface,
-            dma_running,
             stalled: false,
+            stall_cycles: 0,
+
+            trace: false,
+            kernel_tracer: None,
+        }
+    }
 
-            trace: false
+    // Runs the next instruction, or a whole recompiled block when the dynarec can take it,
+    // and returns how many cycles that took.
+    pub fn step(&mut self, bus: &mut Bus) -> u64 {
+        #[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+        if let Some(cycles) = self.run_native(bus) {
+            return cycles;
         }
+
+        self.tick(bus)
     }
 
-    pub fn tick(&mut self) {
-        self.stalled &= *self.dma_running.borrow();
-        if self.stalled {return}
+    pub fn set_dynarec(&mut self, enabled: bool) -> anyhow::Result<()> {
+        #[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+        {
+            self.dynarec = enabled.then(dynarec::Dynarec::new);
+            Ok(())
+        }
+
+        #[cfg(not(all(feature = "dynarec", target_arch = "x86_64")))]
+        if enabled {
+            Err(anyhow::anyhow!("Built without the x86-64 dynarec"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn tick(&mut self, bus: &mut Bus) -> u64 {
+        self.update_interrupt_line(bus);
+
+        self.stalled &= bus.dma.running();
+        if self.stalled {return CYCLES_PER_INSTRUCTION}
 
         self.current_pc = self.pc;
 
@@ -68,11 +111,13 @@ impl CPU {
             self.raise_exception(Cause::AdEL);
         }
 
-        let instruction = self.read32(self.pc);
+        let op = self.fetch(bus);
         if self.stalled {
             // println!("Stalled!");
-            return
+            return CYCLES_PER_INSTRUCTION
         }
+        let words = self.icache.fetch(self.current_pc, bus.cache_control());
+        let mut cycles = CYCLES_PER_INSTRUCTION + words * bus.access_cycles(self.current_pc, 4);
 
         self.delay_slot = self.branch;
         self.branch = false;
@@ -80,106 +125,97 @@ impl CPU {
         self.pc = self.next_pc;
         self.next_pc = self.next_pc.wrapping_add(4);
 
-        if self.system_control.borrow().trigger_interrupt() {
+        if self.system_control.trigger_interrupt() {
             // println!("IRQ triggered!");
             self.raise_exception(Cause::INT);
-            return;
+            self.gte.elapse(cycles);
+            return cycles;
         }
 
-        self.execute(instruction);
+        self.execute(op, bus);
         self.commit_writes();
         
-        self.check_for_tty_output();
+        self.check_for_kernel_call(bus);
+
+        cycles += std::mem::take(&mut self.stall_cycles);
+        self.gte.elapse(cycles);
+        cycles
     }
 
-    pub fn execute(&mut self, instruction: u32) {
-        if self.trace {println!("instruction: {:08X}, pc: {:08X}, R31: {:08X}", instruction, self.pc, self.R[31])};
-
-        let op = instruction.op();
-        match op {
-            0b000000 => {
-                let funct = instruction.funct();
-                match funct {
-                    0b000000 => self.sll(instruction),
-                    0b000010 => self.srl(instruction),
-                    0b000011 => self.sra(instruction),
-                    0b000100 => self.sllv(instruction),
-                    0b000110 => self.srlv(instruction),
-                    0b000111 => self.srav(instruction),
-                    0b001100 => self.raise_exception(Cause::Sys),
-                    0b001101 => self.raise_exception(Cause::Bp),
-                    0b001000 => self.jr(instruction),
-                    0b001001 => self.jalr(instruction),
-                    0b010000 => self.mfhi(instruction),
-                    0b010001 => self.mthi(instruction),
-                    0b010010 => self.mflo(instruction),
-                    0b010011 => self.mtlo(instruction),
-                    0b011000 => self.mult(instruction),
-                    0b011001 => self.multu(instruction),
-                    0b011010 => self.div(instruction),
-                    0b011011 => self.divu(instruction),
-                    0b100000 => self.add(instruction),
-                    0b100001 => self.addu(instruction),
-                    0b100010 => self.sub(instruction),
-                    0b100011 => self.subu(instruction),
-                    0b100100 => self.and(instruction),
-                    0b100101 => self.or(instruction),
-                    0b100110 => self.xor(instruction),
-                    0b100111 => self.nor(instruction),
-                    0b101010 => self.slt(instruction),
-                    0b101011 => self.sltu(instruction),
-                    _ => {
-                        println!("Illegal instruction: {:08X}", instruction);
-                        self.raise_exception(Cause::RI);
-                    },
-                }
-            },
-            0b000001 => self.bxx(instruction),
-            0b000010 => self.j(instruction),
-            0b000011 => self.jal(instruction),
-            0b000100 => self.beq(instruction),
-            0b000101 => self.bne(instruction),
-            0b000110 => self.blez(instruction),
-            0b000111 => self.bgtz(instruction),
-            0b001000 => self.addi(instruction),
-            0b001001 => self.addiu(instruction),
-            0b001010 => self.slti(instruction),
-            0b001011 => self.sltiu(instruction),
-            0b001100 => self.andi(instruction),
-            0b001101 => self.ori(instruction),
-            0b001110 => self.xori(instruction),
-            0b001111 => self.lui(instruction),
-            0b010000 => self.cop0(instruction),
-            0b010001 => self.raise_exception(Cause::CpU),
-            0b010010 => self.cop2(instruction),
-            0b010011 => self.raise_exception(Cause::CpU),
-            0b100000 => self.lb(instruction),
-            0b100001 => self.lh(instruction),
-            0b100010 => self.lwl(instruction),
-            0b100011 => self.lw(instruction),
-            0b100100 => self.lbu(instruction),
-            0b100101 => self.lhu(instruction),
-            0b100110 => self.lwr(instruction),
-            0b101000 => self.sb(instruction),
-            0b101001 => self.sh(instruction),
-            0b101010 => self.swl(instruction),
-            0b101011 => self.sw(instruction),
-            0b101110 => self.swr(instruction),
-            0b110000 => self.raise_exception(Cause::CpU),
-            0b110001 => self.raise_exception(Cause::CpU),
-            0b110010 => self.lwc2(instruction),
-            0b110011 => self.raise_exception(Cause::CpU),
-            0b111000 => self.raise_exception(Cause::CpU),
-            0b111001 => self.raise_exception(Cause::CpU),
-            0b111010 => self.swc2(instruction),
-            0b111011 => self.raise_exception(Cause::CpU),
-            _ => {
-                println!("Illegal instruction: {:08X}", instruction);
-                self.raise_exception(Cause::RI);
-            },
+    fn update_interrupt_line(&mut self, bus: &mut Bus) {
+        match bus.interrupt.take_line_change() {
+            Some(true) => self.system_control.request_interrupt(),
+            Some(false) => self.system_control.clear_interrupt(),
+            None => {}
         }
     }
 
+    fn fetch(&mut self, bus: &mut Bus) -> Op {
+        self.invalidate_code(bus);
+
+        self.stalled = bus.dma.running();
+        match self.block_cache.fetch(self.pc, bus) {
+            Some(op) => op,
+            None => Op::new(self.read32(self.pc, bus)),
+        }
+    }
+
+    fn invalidate_code(&mut self, bus: &mut Bus) {
+        while let Some(page) = bus.take_dirty_code_page() {
+            self.block_cache.invalidate_page(page);
+            #[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+            if let Some(dynarec) = &mut self.dynarec {
+                dynarec.invalidate_page(page);
+            }
+        }
+    }
+
+    // Anything that writes DRAM behind the bus's back has to drop the decoded blocks.
+    pub fn flush_block_cache(&mut self, bus: &mut Bus) {
+        self.block_cache.clear();
+        #[cfg(all(feature = "dynarec", target_arch = "x86_64"))]
+        if let Some(dynarec) = &mut self.dynarec {
+            dynarec.clear();
+        }
+        bus.clear_code();
+    }
+
+    pub fn hi(&self) -> u32 {
+        self.hi
+    }
+
+    pub fn lo(&self) -> u32 {
+        self.lo
+    }
+
+    pub fn set_hi(&mut self, value: u32) {
+        self.hi = value;
+    }
+
+    pub fn set_lo(&mut self, value: u32) {
+        self.lo = value;
+    }
+
+    // Lets a load still in its delay slot land, for code that takes over between instructions
+    pub fn flush_load_delay(&mut self) {
+        self.commit_writes();
+        self.commit_writes();
+    }
+
+    // Moves execution to pc as if it had been jumped to, for debuggers writing the PC
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.branch = false;
+        self.delay_slot = false;
+    }
+
+    pub fn execute(&mut self, op: Op, bus: &mut Bus) {
+        if self.trace {println!("{:08X}: {:08X}  {}", self.current_pc, op.instruction, disassemble(op.instruction, self.current_pc))};
+
+        (op.handler)(self, op.instruction, bus);
+    }
+
     fn raise_exception(&mut self, cause: Cause) {
         // if cause != Cause::INT {println!("Raised exception on cause: {:#?}", cause)};
 
@@ -188,7 +224,7 @@ impl CPU {
             self.next_pc = self.next_pc.wrapping_sub(4);
         }
 
-        self.pc = if self.system_control.borrow_mut().raise_exception(cause as u32, self.current_pc, self.pc, self.delay_slot) {
+        self.pc = if self.system_control.raise_exception(cause as u32, self.current_pc, self.pc, self.delay_slot) {
             0xBFC0_0180
         } else {
             0x8000_0080
@@ -197,49 +233,67 @@ impl CPU {
         self.next_pc = self.pc.wrapping_add(4);
     }
 
-    fn read32(&mut self, addr: u32) -> u32 {
-        self.stalled = *self.dma_running.borrow();
-        self.interface.borrow_mut().read32(addr)
+    fn read32(&mut self, addr: u32, bus: &mut Bus) -> u32 {
+        self.stalled = bus.dma.running();
+        self.stall_cycles += bus.access_cycles(addr, 4);
+        bus.watch(addr, 4, Access::Read, self.current_pc);
+        bus.read32(addr)
     }
 
-    fn read16(&mut self, addr: u32) -> u16 {
-        self.stalled = *self.dma_running.borrow();
-        self.interface.borrow_mut().read16(addr)
+    fn read16(&mut self, addr: u32, bus: &mut Bus) -> u16 {
+        self.stalled = bus.dma.running();
+        self.stall_cycles += bus.access_cycles(addr, 2);
+        bus.watch(addr, 2, Access::Read, self.current_pc);
+        bus.read16(addr)
     }
 
-    fn read8(&mut self, addr: u32) -> u8 {
-        self.stalled = *self.dma_running.borrow();
-        self.interface.borrow_mut().read8(addr)
+    fn read8(&mut self, addr: u32, bus: &mut Bus) -> u8 {
+        self.stalled = bus.dma.running();
+        self.stall_cycles += bus.access_cycles(addr, 1);
+        bus.watch(addr, 1, Access::Read, self.current_pc);
+        bus.read8(addr)
     }
 
-    fn write32(&mut self, addr: u32, value: u32) {
-        if self.system_control.borrow().read_register(12) & 0x10000 != 0 {
-            // println!("Cache not implemented");
+    fn write32(&mut self, addr: u32, value: u32, bus: &mut Bus) {
+        if self.cache_isolated() {
+            self.icache.isolated_write(addr, bus.cache_control());
             return;
         }
-        self.stalled = *self.dma_running.borrow();
+        self.stalled = bus.dma.running();
+        bus.watch(addr, 4, Access::Write, self.current_pc);
 
-        self.interface.borrow_mut().write32(addr, value);
+        bus.write32(addr, value);
     }
 
-    fn write16(&mut self, addr: u32, value: u16) {
-        if self.system_control.borrow().read_register(12) & 0x10000 != 0 {
-            // println!("Cache not implemented");
+    fn write16(&mut self, addr: u32, value: u16, bus: &mut Bus) {
+        if self.cache_isolated() {
+            self.icache.isolated_write(addr, bus.cache_control());
             return;
         }
-        self.stalled = *self.dma_running.borrow();
+        self.stalled = bus.dma.running();
+        bus.watch(addr, 2, Access::Write, self.current_pc);
 
-        self.interface.borrow_mut().write16(addr, value);
+        bus.write16(addr, value);
     }
 
-    fn write8(&mut self, addr: u32, value: u8) {
-        if self.system_control.borrow().read_register(12) & 0x10000 != 0 {
-            // println!("Cache not implemented");
+    fn write8(&mut self, addr: u32, value: u8, bus: &mut Bus) {
+        if self.cache_isolated() {
+            self.icache.isolated_write(addr, bus.cache_control());
             return;
         }
-        self.stalled = *self.dma_running.borrow();
+        self.stalled = bus.dma.running();
+        bus.watch(addr, 1, Access::Write, self.current_pc);
 
-        self.interface.borrow_mut().write8(addr, value);
+        bus.write8(addr, value);
+    }
+
+    // GTE commands run alongside the CPU, which only waits for one when it touches the GTE again
+    fn wait_for_gte(&mut self) {
+        self.stall_cycles += self.gte.take_busy_cycles();
+    }
+
+    fn cache_isolated(&self) -> bool {
+        self.system_control.read_register(12) & 0x10000 != 0
     }
 
     fn write_register(&mut self, register: u32, value: u32) {
@@ -269,12 +323,59 @@ impl CPU {
         self.pending_writes[1] = None;
     }
 
-    #[allow(unused)]
-    fn check_for_tty_output(&self) {
-        let pc = self.pc & 0x1FFF_FFFF;
-        if (pc == 0xA0 && self.R[9] == 0x3C) || (pc == 0xB0 && self.R[9] == 0x3D) {
-            let ch = self.R[4] as u8 as char;
-            print!("{ch}");
+    // Hooks the BIOS A0h, B0h and C0h function tables as they're entered
+    fn check_for_kernel_call(&mut self, bus: &mut Bus) {
+        if let Some(tracer) = &mut self.kernel_tracer {
+            if let Some(line) = tracer.ret(self.pc, self.R[2]) {println!("{line}")}
+        }
+
+        let Some((table, number)) = kernel_call(self.pc, self.R[9]) else { return };
+        match (table, number) {
+            (Table::A, 0x3C) | (Table::B, 0x3D) => bus.debug_port.putchar(self.R[4] as u8),
+            // FlushCache
+            (Table::A, 0x44) => self.flush_block_cache(bus),
+            _ => {}
+        }
+
+        if let Some(tracer) = &mut self.kernel_tracer {
+            let args = [self.R[4], self.R[5], self.R[6], self.R[7]];
+            if let Some(line) = tracer.call(table, number, args, self.R[31], |addr| bus.read_memory8(addr)) {println!("{line}")}
         }
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.R);
+        w.write(&self.pc);
+        w.write(&self.hi);
+        w.write(&self.lo);
+        w.write(&self.current_pc);
+        w.write(&self.next_pc);
+        w.write(&self.pending_writes);
+        w.write(&self.branch);
+        w.write(&self.delay_slot);
+        w.write(&self.stalled);
+
+        self.system_control.save_state(w);
+        self.gte.save_state(w);
+        self.icache.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.R = r.read()?;
+        self.pc = r.read()?;
+        self.hi = r.read()?;
+        self.lo = r.read()?;
+        self.current_pc = r.read()?;
+        self.next_pc = r.read()?;
+        self.pending_writes = r.read()?;
+        self.branch = r.read()?;
+        self.delay_slot = r.read()?;
+        self.stalled = r.read()?;
+
+        self.system_control.load_state(r)?;
+        self.gte.load_state(r)?;
+        self.icache.load_state(r)
+    }
+}
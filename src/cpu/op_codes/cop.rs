@@ -1,4 +1,4 @@
-use crate::cpu::{decoder::{Cause, Instruction}, CPU};
+use crate::{bus::interface::Bus, cpu::{decoder::{Cause, Instruction}, CPU}};
 
 impl CPU {
     pub fn cop0(&mut self, instruction: u32) {
@@ -6,7 +6,7 @@ impl CPU {
         match cop_instruction {
             0b00000 => self.mfc0(instruction),
             0b00100 => self.mtc0(instruction),
-            0b10000 => self.system_control.borrow_mut().rfe(),
+            0b10000 => self.system_control.rfe(),
             _ => panic!("{:08X} Unsupported cop op: {:06b}..{:05b}", instruction, instruction.op(), cop_instruction)
         }
     }
@@ -15,7 +15,7 @@ impl CPU {
         let rt = instruction.rt();
         let rd = instruction.rd();
         
-        let value = self.system_control.borrow().read_register(rd);
+        let value = self.system_control.read_register(rd);
         self.schedule_write(rt, value);
     }
 
@@ -23,11 +23,12 @@ impl CPU {
         let rt = instruction.rt();
         let rd = instruction.rd();
 
-        self.system_control.borrow_mut().write_register(rd, self.R[rt]);
+        self.system_control.write_register(rd, self.R[rt]);
     }
 
     pub fn cop2(&mut self, instruction: u32) {
         // println!("COP2 instruction: {instruction:08X}");
+        self.wait_for_gte();
         if instruction & const {1 << 25} != 0 {
             self.gte.issue_command(instruction);
         } else {
@@ -85,7 +86,7 @@ impl CPU {
         }
     }
 
-    pub fn lwc2(&mut self, instruction: u32) {
+    pub fn lwc2(&mut self, instruction: u32, bus: &mut Bus) {
         // println!("LWC2 instruction: {instruction:08X}");
         let rs = instruction.rs();
         let rt = instruction.rt();
@@ -97,11 +98,12 @@ impl CPU {
             return;
         }
 
-        let value = self.read32(addr);
+        let value = self.read32(addr, bus);
+        self.wait_for_gte();
         self.gte.write_data_register(rt, value);
     }
 
-    pub fn swc2(&mut self, instruction: u32) {
+    pub fn swc2(&mut self, instruction: u32, bus: &mut Bus) {
         // println!("SWC2 instruction: {instruction:08X}");
         let rs = instruction.rs();
         let rt = instruction.rt();
@@ -113,7 +115,8 @@ impl CPU {
             return;
         }
 
+        self.wait_for_gte();
         let value = self.gte.read_data_register(rt);
-        self.write32(addr, value);
+        self.write32(addr, value, bus);
     }
 }
\ No newline at end of file
@@ -1,20 +1,20 @@
 use std::hint::unreachable_unchecked;
 
-use crate::cpu::{decoder::{Cause, Instruction}, CPU};
+use crate::{bus::interface::Bus, cpu::{decoder::{Cause, Instruction}, CPU}};
 
 impl CPU {
-    pub fn lb(&mut self, instruction: u32) {
+    pub fn lb(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
 
         let addr = self.R[rs].wrapping_add(offset);
-        let value = self.read8(addr) as i8 as u32;
+        let value = self.read8(addr, bus) as i8 as u32;
 
         self.schedule_write(rt, value);
     }
 
-    pub fn lh(&mut self, instruction: u32) {
+    pub fn lh(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -25,11 +25,11 @@ impl CPU {
             return;
         }
 
-        let value = self.read16(addr) as i16 as u32;
+        let value = self.read16(addr, bus) as i16 as u32;
         self.schedule_write(rt, value);
     }
 
-    pub fn lwl(&mut self, instruction: u32) {
+    pub fn lwl(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -41,7 +41,7 @@ impl CPU {
             if r == rt {current_value = v}
         }
 
-        let aligned_word = self.read32(addr & !0b11);
+        let aligned_word = self.read32(addr & !0b11, bus);
 
         let value = match addr & 3 {
             0 => (current_value & 0x00FF_FFFF) | (aligned_word << 24),
@@ -54,7 +54,7 @@ impl CPU {
         self.schedule_write(rt, value);
     }
 
-    pub fn lw(&mut self, instruction: u32) {
+    pub fn lw(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -65,22 +65,22 @@ impl CPU {
             return;
         }
 
-        let value = self.read32(addr);
+        let value = self.read32(addr, bus);
         self.schedule_write(rt, value);
     }
 
-    pub fn lbu(&mut self, instruction: u32) {
+    pub fn lbu(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
 
         let addr = self.R[rs].wrapping_add(offset);
-        let value = self.read8(addr) as u32;
+        let value = self.read8(addr, bus) as u32;
 
         self.schedule_write(rt, value);
     }
 
-    pub fn lhu(&mut self, instruction: u32) {
+    pub fn lhu(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -91,11 +91,11 @@ impl CPU {
             return;
         }
 
-        let value = self.read16(addr) as u32;
+        let value = self.read16(addr, bus) as u32;
         self.schedule_write(rt, value);
     }
 
-    pub fn lwr(&mut self, instruction: u32) {
+    pub fn lwr(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -107,7 +107,7 @@ impl CPU {
             if r == rt {current_value = v}
         }
 
-        let aligned_word = self.read32(addr & !0b11);
+        let aligned_word = self.read32(addr & !0b11, bus);
 
         let value = match addr & 3 {
             0 => (current_value & 0x0000_0000) | (aligned_word >>  0),
@@ -120,7 +120,7 @@ impl CPU {
         self.schedule_write(rt, value);
     }
 
-    pub fn sb(&mut self, instruction: u32) {
+    pub fn sb(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -128,10 +128,10 @@ impl CPU {
         let addr = self.R[rs].wrapping_add(offset);
         let value = self.R[rt] as u8;
 
-        self.write8(addr, value);
+        self.write8(addr, value, bus);
     }
 
-    pub fn sh(&mut self, instruction: u32) {
+    pub fn sh(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -143,10 +143,10 @@ impl CPU {
         }
 
         let value = self.R[rt] as u16;
-        self.write16(addr, value);
+        self.write16(addr, value, bus);
     }
 
-    pub fn swl(&mut self, instruction: u32) {
+    pub fn swl(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -155,7 +155,7 @@ impl CPU {
         let value = self.R[rt];
 
         let aligned_addr = addr & !0b11;
-        let current_mem = self.read32(aligned_addr);
+        let current_mem = self.read32(aligned_addr, bus);
 
         let mem = match addr & 3 {
             0 => (current_mem & 0xFFFF_FF00) | (value >> 24),
@@ -165,10 +165,10 @@ impl CPU {
             _ => unsafe { unreachable_unchecked() }
         };
 
-        self.write32(aligned_addr, mem);
+        self.write32(aligned_addr, mem, bus);
     }
 
-    pub fn sw(&mut self, instruction: u32) {
+    pub fn sw(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -180,10 +180,10 @@ impl CPU {
         }
 
         let value = self.R[rt];
-        self.write32(addr, value);
+        self.write32(addr, value, bus);
     }
 
-    pub fn swr(&mut self, instruction: u32) {
+    pub fn swr(&mut self, instruction: u32, bus: &mut Bus) {
         let rs = instruction.rs();
         let rt = instruction.rt();
         let offset = instruction.imm_se();
@@ -192,7 +192,7 @@ impl CPU {
         let value = self.R[rt];
 
         let aligned_addr = addr & !0b11;
-        let current_mem = self.read32(aligned_addr);
+        let current_mem = self.read32(aligned_addr, bus);
 
         let mem = match addr & 3 {
             0 => (current_mem & 0x0000_0000) | (value <<  0),
@@ -202,6 +202,6 @@ impl CPU {
             _ => unsafe { unreachable_unchecked() }
         };
 
-        self.write32(aligned_addr, mem);
+        self.write32(aligned_addr, mem, bus);
     }
 }
\ No newline at end of file
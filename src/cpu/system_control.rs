@@ -1,4 +1,4 @@
-use crate::Registers;
+use crate::{state::{Savestate, StateReader, StateWriter}, Registers};
 
 #[derive(Debug)]
 pub struct SystemControl {
@@ -73,4 +73,15 @@ impl SystemControl {
         self.R[12] |= mode >> 2;
         self.R[12] |= old;
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for SystemControl {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.R);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.R = r.read()?;
+        Ok(())
+    }
+}
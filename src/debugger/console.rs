@@ -0,0 +1,234 @@
+use std::{io::BufRead, sync::mpsc::{self, Receiver, TryRecvError}};
+
+use anyhow::anyhow;
+
+use crate::{cpu::disassembler::REGISTER_NAMES, debugger::{Access, Debugger, Frontend, Resume, Stop, Watchpoint}, System};
+
+const COP0_REGISTERS: [(&str, u32); 7] = [
+    ("bpc", 3), ("bda", 5), ("dcic", 7), ("badvaddr", 8), ("sr", 12), ("cause", 13), ("epc", 14),
+];
+
+const HELP: &str = "\
+c, continue            run until a breakpoint or watchpoint
+s, step [n]            run n instructions
+n, next                step over calls
+finish                 run until the current function returns
+b, break <addr>        set a breakpoint
+d, delete <addr>       remove a breakpoint
+w, watch <addr> [len] [r|w|rw]
+                       set a watchpoint, on writes by default
+unwatch <addr>         remove a watchpoint
+i, info                list breakpoints and watchpoints
+r, regs                dump the CPU registers
+cop0                   dump the COP0 registers
+x <addr> [len]         hex dump memory
+dis [addr] [count]     disassemble count instructions, from the PC by default
+q, quit                exit the emulator";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Stay,
+    Resume,
+    Quit,
+}
+
+// Command line front end for the debugger. Lines are read from stdin on a separate thread, so
+// the emulator keeps running while the prompt waits, and any line typed meanwhile pauses it.
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn stdin() -> Self {
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {break}
+            }
+        });
+
+        Self { lines }
+    }
+
+    fn execute_line(&mut self, debugger: &mut Debugger, system: &mut System, line: &str) -> Outcome {
+        execute(debugger, system, line).unwrap_or_else(|err| {
+            println!("{err}");
+            Outcome::Stay
+        })
+    }
+}
+
+impl Frontend for Console {
+    // Runs one frame under the debugger, or handles commands while it's paused
+    fn frame(&mut self, debugger: &mut Debugger, system: &mut System) -> anyhow::Result<bool> {
+        match self.lines.try_recv() {
+            Ok(line) => {
+                if !debugger.paused() {
+                    debugger.pause();
+                    println!("Paused at {:08X}", system.cpu().pc);
+                }
+                if self.execute_line(debugger, system, &line) == Outcome::Quit {
+                    return Ok(false);
+                }
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) if debugger.paused() => return Ok(false),
+            Err(TryRecvError::Disconnected) => {}
+        }
+
+        while debugger.paused() {
+            let Ok(line) = self.lines.recv() else { return Ok(false) };
+            if self.execute_line(debugger, system, &line) == Outcome::Quit {
+                return Ok(false);
+            }
+        }
+
+        if let Some(stop) = debugger.run_frame(system) {
+            print_stop(stop);
+            print_disassembly(system, system.cpu().pc, 1);
+        }
+
+        Ok(true)
+    }
+}
+
+fn print_stop(stop: Stop) {
+    match stop {
+        Stop::Breakpoint(pc) => println!("Breakpoint at {pc:08X}"),
+        Stop::Watchpoint(hit) => {
+            let access = match hit.access {
+                Access::Read => "Read",
+                Access::Write => "Write",
+            };
+            println!("{access} of {:08X} at {:08X}", hit.addr, hit.pc);
+        }
+        Stop::Step(pc) => println!("Stopped at {pc:08X}"),
+    }
+}
+
+pub fn execute(debugger: &mut Debugger, system: &mut System, line: &str) -> anyhow::Result<Outcome> {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else { return Ok(Outcome::Stay) };
+    let args: Vec<&str> = words.collect();
+
+    match command {
+        "c" | "continue" => debugger.resume(system, Resume::Continue),
+        "s" | "step" => {
+            let steps = args.first().map(|steps| steps.parse()).transpose()?.unwrap_or(1);
+            debugger.resume(system, Resume::StepIn(steps));
+        }
+        "n" | "next" => debugger.resume(system, Resume::StepOver),
+        "finish" => debugger.resume(system, Resume::Finish),
+        "b" | "break" => {
+            let addr = parse_addr(args.first())?;
+            debugger.add_breakpoint(addr);
+            println!("Breakpoint at {addr:08X}");
+        }
+        "d" | "delete" => {
+            let addr = parse_addr(args.first())?;
+            if !debugger.remove_breakpoint(addr) {
+                return Err(anyhow!("No breakpoint at {addr:08X}"));
+            }
+        }
+        "w" | "watch" => {
+            let addr = parse_addr(args.first())?;
+            let (len, mode) = match args.get(1) {
+                Some(&mode @ ("r" | "w" | "rw")) => (4, mode),
+                Some(len) => (len.parse()?, args.get(2).copied().unwrap_or("w")),
+                None => (4, "w"),
+            };
+            let (read, write) = match mode {
+                "r" => (true, false),
+                "w" => (false, true),
+                "rw" => (true, true),
+                _ => return Err(anyhow!("Unknown watchpoint mode {mode}")),
+            };
+            system.set_watchpoint(Watchpoint { addr, len, read, write });
+            println!("Watching {len} bytes at {addr:08X}");
+        }
+        "unwatch" => {
+            let addr = parse_addr(args.first())?;
+            if !system.remove_watchpoint(addr) {
+                return Err(anyhow!("No watchpoint at {addr:08X}"));
+            }
+        }
+        "i" | "info" => {
+            for addr in debugger.breakpoints() {
+                println!("Breakpoint {addr:08X}");
+            }
+            for watchpoint in system.watchpoints() {
+                let mode = match (watchpoint.read, watchpoint.write) {
+                    (true, true) => "rw",
+                    (true, false) => "r",
+                    _ => "w",
+                };
+                println!("Watchpoint {:08X} {} {mode}", watchpoint.addr, watchpoint.len);
+            }
+        }
+        "r" | "regs" => print_registers(system),
+        "cop0" => print_cop0(system),
+        "x" => {
+            let addr = parse_addr(args.first())?;
+            let len = args.get(1).map(|len| len.parse()).transpose()?.unwrap_or(64);
+            print_memory(system, addr, len);
+        }
+        "dis" => {
+            let addr = match args.first() {
+                Some(_) => parse_addr(args.first())?,
+                None => system.cpu().pc,
+            };
+            let count = args.get(1).map(|count| count.parse()).transpose()?.unwrap_or(8);
+            print_disassembly(system, addr, count);
+        }
+        "q" | "quit" => return Ok(Outcome::Quit),
+        "h" | "help" => println!("{HELP}"),
+        _ => return Err(anyhow!("Unknown command {command}, try help")),
+    }
+
+    Ok(if debugger.paused() {Outcome::Stay} else {Outcome::Resume})
+}
+
+fn parse_addr(arg: Option<&&str>) -> anyhow::Result<u32> {
+    let arg = arg.ok_or_else(|| anyhow!("Missing address"))?;
+    Ok(u32::from_str_radix(arg.trim_start_matches("0x"), 16)?)
+}
+
+fn print_registers(system: &System) {
+    let cpu = system.cpu();
+    for row in 0..8 {
+        let line: Vec<String> = (0..4)
+            .map(|column| row * 4 + column)
+            .map(|register| format!("{:>4} {:08X}", REGISTER_NAMES[register as usize], cpu.R[register]))
+            .collect();
+        println!("{}", line.join("  "));
+    }
+    println!("  hi {:08X}    lo {:08X}    pc {:08X}", cpu.hi(), cpu.lo(), cpu.pc);
+}
+
+fn print_cop0(system: &System) {
+    for (name, register) in COP0_REGISTERS {
+        println!("{name:>8} {:08X}", system.cop0_register(register));
+    }
+}
+
+// I/O registers can have side effects when read, so only memory is shown
+fn print_memory(system: &System, addr: u32, len: u32) {
+    let end = addr.saturating_add(len);
+    for line in (addr..end).step_by(16) {
+        let bytes: Vec<Option<u8>> = (line..line.saturating_add(16).min(end)).map(|addr| system.peek8(addr)).collect();
+        let hex: Vec<String> = bytes.iter().map(|byte| byte.map_or(String::from("??"), |byte| format!("{byte:02X}"))).collect();
+        let ascii: String = bytes.iter().map(|byte| match byte {
+            Some(byte @ 0x20..=0x7E) => *byte as char,
+            _ => '.',
+        }).collect();
+        println!("{line:08X}: {:<47}  {ascii}", hex.join(" "));
+    }
+}
+
+fn print_disassembly(system: &System, addr: u32, count: u32) {
+    let end = addr.saturating_add(count.saturating_mul(4));
+    for line in system.disassemble(addr & !0b11..end) {
+        println!("{line}");
+    }
+}
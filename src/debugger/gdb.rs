@@ -0,0 +1,345 @@
+use std::{io::{ErrorKind, Read, Write}, net::{TcpListener, TcpStream}};
+
+use anyhow::anyhow;
+
+use crate::{debugger::{Access, Debugger, Frontend, Resume, Stop, Watchpoint}, System};
+
+// GPRs, sr, lo, hi, badvaddr, cause, pc, then the FPU registers the R3000A doesn't have. gdb's
+// MIPS layout has no room for EPC, so it comes after them as register 72, read-only with p48.
+const REGISTER_COUNT: usize = 72;
+const EPC: usize = 72;
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Packet(String),
+    Resumed,
+    Detach,
+    Kill,
+}
+
+// Remote serial protocol server for gdb-multiarch, one client at a time on localhost. It
+// drives the same debugger as the console: the first frame waits for a client to attach, and
+// the target is paused whenever gdb is in control.
+pub struct GdbStub {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+}
+
+impl GdbStub {
+    pub fn listen(port: u16) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on port {port}");
+
+        Ok(Self { listener, stream: None })
+    }
+
+    fn accept(&mut self, blocking: bool) -> anyhow::Result<bool> {
+        self.listener.set_nonblocking(!blocking)?;
+        match self.listener.accept() {
+            Ok((stream, addr)) => {
+                println!("GDB connected from {addr}");
+                stream.set_nodelay(true)?;
+                self.stream = Some(stream);
+                Ok(true)
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // None once the client has gone away
+    fn read_byte(&mut self, blocking: bool) -> anyhow::Result<Option<u8>> {
+        let Some(stream) = &mut self.stream else { return Ok(None) };
+        stream.set_nonblocking(!blocking)?;
+
+        let mut byte = [0];
+        match stream.read(&mut byte) {
+            Ok(0) => {
+                println!("GDB disconnected");
+                self.stream = None;
+                Ok(None)
+            }
+            Ok(_) => Ok(Some(byte[0])),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn read_packet(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            // Acks and interrupts while already stopped are skipped
+            loop {
+                match self.read_byte(true)? {
+                    Some(b'$') => break,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte(true)? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            for digit in &mut checksum {
+                let Some(byte) = self.read_byte(true)? else { return Ok(None) };
+                *digit = byte;
+            }
+
+            let valid = u8::from_str_radix(std::str::from_utf8(&checksum)?, 16).ok() == Some(checksum_of(&data));
+            self.write(if valid {b"+"} else {b"-"})?;
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> anyhow::Result<()> {
+        self.write(frame_packet(data).as_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        if let Some(stream) = &mut self.stream {
+            stream.set_nonblocking(false)?;
+            stream.write_all(bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl Frontend for GdbStub {
+    fn frame(&mut self, debugger: &mut Debugger, system: &mut System) -> anyhow::Result<bool> {
+        if self.stream.is_none() && self.accept(debugger.paused())? {
+            debugger.pause();
+        }
+
+        if !debugger.paused() && self.read_byte(false)? == Some(INTERRUPT) {
+            debugger.pause();
+            self.send("S02")?;
+        }
+
+        while debugger.paused() && self.stream.is_some() {
+            let Some(packet) = self.read_packet()? else { break };
+            match handle(&packet, debugger, system) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Resumed => {}
+                Reply::Detach => {
+                    self.send("OK")?;
+                    self.stream = None;
+                    debugger.resume(system, Resume::Continue);
+                }
+                Reply::Kill => return Ok(false),
+            }
+        }
+
+        if let Some(stop) = debugger.run_frame(system) {
+            let reply = stop_reply(stop, system);
+            self.send(&reply)?;
+        }
+
+        Ok(true)
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn frame_packet(data: &str) -> String {
+    format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+}
+
+fn stop_reply(stop: Stop, system: &System) -> String {
+    match stop {
+        Stop::Breakpoint(_) | Stop::Step(_) => String::from("S05"),
+        Stop::Watchpoint(hit) => {
+            // gdb wants the address it set the watchpoint on, not the physical one that was hit
+            let watchpoint = system.watchpoints().iter().find(|watchpoint| watchpoint.hit(hit.addr, 1, hit.access));
+            let kind = match (hit.access, watchpoint) {
+                (_, Some(Watchpoint { read: true, write: true, .. })) => "awatch",
+                (Access::Read, _) => "rwatch",
+                (Access::Write, _) => "watch",
+            };
+            format!("T05{kind}:{:x};", watchpoint.map_or(hit.addr, |watchpoint| watchpoint.addr))
+        }
+    }
+}
+
+fn handle(packet: &str, debugger: &mut Debugger, system: &mut System) -> Reply {
+    match execute(packet, debugger, system) {
+        Ok(reply) => reply,
+        Err(_) => Reply::Packet(String::from("E01")),
+    }
+}
+
+fn execute(packet: &str, debugger: &mut Debugger, system: &mut System) -> anyhow::Result<Reply> {
+    let Some(command) = packet.chars().next() else { return Ok(Reply::Packet(String::new())) };
+    let args = &packet[1..];
+
+    let reply = match command {
+        '?' => String::from("S05"),
+        'g' => (0..REGISTER_COUNT).map(|register| hex_word(read_register(system, register))).collect(),
+        'G' => {
+            for (register, word) in args.as_bytes().chunks(8).enumerate().take(REGISTER_COUNT) {
+                write_register(system, register, parse_word(std::str::from_utf8(word)?)?);
+            }
+            String::from("OK")
+        }
+        'p' => hex_word(read_register(system, usize::from_str_radix(args, 16)?)),
+        'P' => {
+            let (register, value) = args.split_once('=').ok_or_else(|| anyhow!("Malformed P packet"))?;
+            write_register(system, usize::from_str_radix(register, 16)?, parse_word(value)?);
+            String::from("OK")
+        }
+        'm' => {
+            let (addr, len) = parse_range(args)?;
+            (0..len)
+                .map(|offset| system.peek8(addr.wrapping_add(offset)).map(|byte| format!("{byte:02x}")))
+                .collect::<Option<String>>()
+                .ok_or_else(|| anyhow!("Unreadable memory"))?
+        }
+        'M' => {
+            let (range, data) = args.split_once(':').ok_or_else(|| anyhow!("Malformed M packet"))?;
+            let (addr, len) = parse_range(range)?;
+            let bytes = (0..len as usize)
+                .map(|offset| Ok(u8::from_str_radix(data.get(offset * 2..offset * 2 + 2).ok_or_else(|| anyhow!("Short M packet"))?, 16)?))
+                .collect::<anyhow::Result<Vec<u8>>>()?;
+            // Nothing is written unless all of it is memory, I/O registers are left alone
+            if (0..len).any(|offset| system.peek8(addr.wrapping_add(offset)).is_none()) {
+                return Err(anyhow!("Unwritable memory"));
+            }
+            for (offset, byte) in bytes.into_iter().enumerate() {
+                system.poke8(addr.wrapping_add(offset as u32), byte);
+            }
+            String::from("OK")
+        }
+        'c' | 's' => {
+            if !args.is_empty() {
+                system.cpu_mut().set_pc(u32::from_str_radix(args, 16)?);
+            }
+            debugger.resume(system, if command == 'c' {Resume::Continue} else {Resume::StepIn(1)});
+            return Ok(Reply::Resumed);
+        }
+        'Z' | 'z' => {
+            let mut fields = args.split(',');
+            let kind = fields.next().ok_or_else(|| anyhow!("Malformed breakpoint packet"))?;
+            let addr = u32::from_str_radix(fields.next().ok_or_else(|| anyhow!("Missing address"))?, 16)?;
+            let len = u32::from_str_radix(fields.next().unwrap_or("4"), 16)?;
+            let insert = command == 'Z';
+
+            match (kind, insert) {
+                ("0" | "1", true) => {debugger.add_breakpoint(addr);}
+                ("0" | "1", false) => {debugger.remove_breakpoint(addr);}
+                ("2" | "3" | "4", true) => {
+                    let (read, write) = match kind {
+                        "2" => (false, true),
+                        "3" => (true, false),
+                        _ => (true, true),
+                    };
+                    system.set_watchpoint(Watchpoint { addr, len, read, write });
+                }
+                ("2" | "3" | "4", false) => {system.remove_watchpoint(addr);}
+                _ => return Ok(Reply::Packet(String::new())),
+            }
+            String::from("OK")
+        }
+        'D' => return Ok(Reply::Detach),
+        'k' => return Ok(Reply::Kill),
+        'H' => String::from("OK"),
+        'q' => match args.split(':').next().unwrap_or_default() {
+            "Supported" => String::from("PacketSize=4000"),
+            "Attached" => String::from("1"),
+            "C" => String::from("QC1"),
+            "fThreadInfo" => String::from("m1"),
+            "sThreadInfo" => String::from("l"),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    };
+
+    Ok(Reply::Packet(reply))
+}
+
+fn read_register(system: &System, register: usize) -> u32 {
+    let cpu = system.cpu();
+    match register {
+        0..=31 => cpu.R[register as u32],
+        32 => system.cop0_register(12),
+        33 => cpu.lo(),
+        34 => cpu.hi(),
+        35 => system.cop0_register(8),
+        36 => system.cop0_register(13),
+        37 => cpu.pc,
+        EPC => system.cop0_register(14),
+        _ => 0,
+    }
+}
+
+fn write_register(system: &mut System, register: usize, value: u32) {
+    let cpu = system.cpu_mut();
+    match register {
+        1..=31 => cpu.R[register as u32] = value,
+        32 => cpu.system_control.write_register(12, value),
+        33 => cpu.set_lo(value),
+        34 => cpu.set_hi(value),
+        36 => cpu.system_control.write_register(13, value),
+        37 if value != cpu.pc => cpu.set_pc(value),
+        _ => {}
+    }
+}
+
+// Registers go over the wire in target byte order
+fn hex_word(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_word(hex: &str) -> anyhow::Result<u32> {
+    Ok(u32::from_str_radix(hex, 16)?.swap_bytes())
+}
+
+fn parse_range(args: &str) -> anyhow::Result<(u32, u32)> {
+    let (addr, len) = args.split_once(',').ok_or_else(|| anyhow!("Malformed memory packet"))?;
+    Ok((u32::from_str_radix(addr, 16)?, u32::from_str_radix(len, 16)?))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{debugger::{gdb::{execute, frame_packet, handle, stop_reply, Reply}, Debugger}, System};
+
+    #[test]
+    fn packets() {
+        let mut system = System::with_bios_image(vec![0; 512 * 1024]);
+        let mut debugger = Debugger::new();
+        let mut packet = |packet: &str, system: &mut System| execute(packet, &mut debugger, system).unwrap();
+
+        assert_eq!(frame_packet("OK"), "$OK#9a");
+
+        assert_eq!(packet("P4=78563412", &mut system), Reply::Packet(String::from("OK")));
+        assert_eq!(system.cpu().R[4], 0x1234_5678);
+        let Reply::Packet(registers) = packet("g", &mut system) else { panic!() };
+        assert_eq!(registers.len(), 72 * 8);
+        assert_eq!(&registers[4 * 8..5 * 8], "78563412");
+        assert_eq!(&registers[37 * 8..38 * 8], "0000c0bf");
+
+        assert_eq!(packet("M80001000,4:0a0b0c0d", &mut system), Reply::Packet(String::from("OK")));
+        assert_eq!(packet("m80001001,2", &mut system), Reply::Packet(String::from("0b0c")));
+        assert_eq!(handle("m1f801070,4", &mut Debugger::new(), &mut system), Reply::Packet(String::from("E01")));
+        assert_eq!(handle("M1f801800,1:0a", &mut Debugger::new(), &mut system), Reply::Packet(String::from("E01")));
+        // Half of this is RAM, the other half isn't memory
+        assert_eq!(handle("M807ffffe,4:0a0b0c0d", &mut Debugger::new(), &mut system), Reply::Packet(String::from("E01")));
+        assert_eq!(packet("m807ffffe,2", &mut system), Reply::Packet(String::from("0000")));
+        assert_eq!(packet("p48", &mut system), Reply::Packet(String::from("00000000")));
+
+        assert_eq!(packet("Z0,bfc00010,4", &mut system), Reply::Packet(String::from("OK")));
+        assert_eq!(packet("c", &mut system), Reply::Resumed);
+        let stop = debugger.run_frame(&mut system).unwrap();
+        assert_eq!(stop_reply(stop, &system), "S05");
+        assert_eq!(system.cpu().pc, 0xBFC0_0010);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeSet;
+
+use crate::{bus::interface::mask_region, cpu::decoder::Instruction, System};
+
+pub mod console;
+pub mod gdb;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Watched addresses are compared physically, so every mirror of a watched word triggers it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    // Takes the physical address of the access
+    pub fn hit(&self, addr: u32, bytes: u32, access: Access) -> bool {
+        let enabled = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+
+        let start = mask_region(self.addr) as u64;
+        enabled && (addr as u64) < start + self.len as u64 && start < addr as u64 + bytes as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u32,
+    pub access: Access,
+    pub pc: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u32),
+    Watchpoint(WatchHit),
+    Step(u32),
+}
+
+// Something that feeds the debugger commands, polled once per frame. Returns false once the
+// user quits.
+pub trait Frontend {
+    fn frame(&mut self, debugger: &mut Debugger, system: &mut System) -> anyhow::Result<bool>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    StepIn(usize),
+    StepOver,
+    Finish,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Continue,
+    Steps(usize),
+    Address(u32),
+}
+
+// Runs the system one interpreted instruction at a time so it can stop on any PC. It starts
+// out paused, and pauses again whenever a breakpoint, watchpoint or step target is reached.
+pub struct Debugger {
+    breakpoints: BTreeSet<u32>,
+    target: Option<Target>,
+    // The instruction a resume starts on runs even if it has a breakpoint
+    resuming: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            target: None,
+            resuming: false,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.target.is_none()
+    }
+
+    pub fn pause(&mut self) {
+        self.target = None;
+    }
+
+    pub fn resume(&mut self, system: &System, resume: Resume) {
+        let pc = system.cpu().pc;
+        self.target = Some(match resume {
+            Resume::Continue => Target::Continue,
+            Resume::StepIn(steps) => Target::Steps(steps.max(1)),
+            Resume::StepOver if system.peek32(pc).is_some_and(is_call) => Target::Address(pc.wrapping_add(8)),
+            Resume::StepOver => Target::Steps(1),
+            Resume::Finish => Target::Address(system.cpu().R[31]),
+        });
+        self.resuming = true;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Runs until the end of the frame, or until something makes the debugger pause. Does
+    // nothing while paused.
+    pub fn run_frame(&mut self, system: &mut System) -> Option<Stop> {
+        while let Some(target) = self.target {
+            let pc = system.cpu().pc;
+            if !self.resuming {
+                if self.breakpoints.contains(&pc) {
+                    return self.stop(Stop::Breakpoint(pc));
+                }
+                if let Target::Address(addr) = target {
+                    if addr == pc {
+                        return self.stop(Stop::Step(pc));
+                    }
+                }
+            }
+
+            let frame_done = system.step_interpreted();
+            let next_pc = system.cpu().pc;
+            // A stalled CPU doesn't move, and there's no way for an instruction to jump to itself
+            self.resuming &= next_pc == pc;
+
+            if let Some(hit) = system.take_watch_hit() {
+                return self.stop(Stop::Watchpoint(hit));
+            }
+            if let Target::Steps(steps) = target {
+                if next_pc != pc {
+                    if steps == 1 {
+                        return self.stop(Stop::Step(next_pc));
+                    }
+                    self.target = Some(Target::Steps(steps - 1));
+                }
+            }
+
+            if frame_done {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    fn stop(&mut self, stop: Stop) -> Option<Stop> {
+        self.target = None;
+        self.resuming = false;
+        Some(stop)
+    }
+}
+
+// jal, jalr, bltzal and bgezal
+fn is_call(instruction: u32) -> bool {
+    match instruction.op() {
+        0b000011 => true,
+        0b000000 => instruction.funct() == 0b001001,
+        0b000001 => instruction.rt() & 0x1E == 0x10,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{debugger::{Access, Debugger, Resume, Stop, Watchpoint}, System};
+
+    const BASE: u32 = 0x8000_1000;
+
+    fn system() -> System {
+        let mut system = System::with_bios_image(vec![0; 512 * 1024]);
+
+        let program = [
+            0x3401_2000, // ori r1, r0, 0x2000
+            0x0C00_0440, // jal 0x80001100
+            0x0000_0000, // nop
+            0xAC21_0000, // sw r1, 0(r1)
+            0x0000_0000, // nop
+        ];
+        for (index, word) in program.into_iter().enumerate() {
+            system.write32(BASE + index as u32 * 4, word);
+        }
+        // func: jr r31, addiu r2, r2, 1
+        system.write32(0x8000_1100, 0x03E0_0008);
+        system.write32(0x8000_1104, 0x2442_0001);
+
+        system.cpu_mut().pc = BASE;
+        system.cpu_mut().next_pc = BASE + 4;
+        system
+    }
+
+    #[test]
+    fn step_over_and_watch() {
+        let mut system = system();
+        let mut debugger = Debugger::new();
+        assert!(debugger.paused());
+        assert_eq!(debugger.run_frame(&mut system), None);
+
+        debugger.resume(&system, Resume::StepIn(1));
+        assert_eq!(debugger.run_frame(&mut system), Some(Stop::Step(BASE + 4)));
+
+        debugger.resume(&system, Resume::StepOver);
+        assert_eq!(debugger.run_frame(&mut system), Some(Stop::Step(BASE + 12)));
+        assert_eq!(system.cpu().R[2], 1);
+
+        system.set_watchpoint(Watchpoint { addr: 0xA000_2000, len: 4, read: false, write: true });
+        debugger.resume(&system, Resume::Continue);
+        let Some(Stop::Watchpoint(hit)) = debugger.run_frame(&mut system) else { panic!("Watchpoint missed") };
+        assert_eq!((hit.addr, hit.access, hit.pc), (0x2000, Access::Write, BASE + 12));
+    }
+
+    #[test]
+    fn breakpoints_and_finish() {
+        let mut system = system();
+        let mut debugger = Debugger::new();
+
+        debugger.add_breakpoint(0x8000_1100);
+        debugger.resume(&system, Resume::Continue);
+        assert_eq!(debugger.run_frame(&mut system), Some(Stop::Breakpoint(0x8000_1100)));
+
+        debugger.resume(&system, Resume::Finish);
+        assert_eq!(debugger.run_frame(&mut system), Some(Stop::Step(BASE + 12)));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{bus::interrupt::IRQ, gpu::{primitives::vertex::Vertex, GP0_State, GPU}};
+use crate::{bus::interrupt::{Interrupt, IRQ}, gpu::{primitives::vertex::Vertex, GP0_State, GPU}};
 
 impl GPU {
     pub fn quick_fill(&mut self, word: u32) -> GP0_State {
@@ -18,11 +18,11 @@ impl GPU {
         GP0_State::CommandStart
     }
 
-    pub fn irq(&mut self) -> GP0_State {
+    pub fn irq(&mut self, interrupt: &mut Interrupt) -> GP0_State {
         let old_irq = self.gpu_status.interrupt_request() != 0;
         if !old_irq {
             self.gpu_status.set_interrupt_request(1);
-            self.interrupt.borrow_mut().request(IRQ::GPU);
+            interrupt.request(IRQ::GPU);
         }
 
         GP0_State::CommandStart
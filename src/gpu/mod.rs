@@ -1,11 +1,15 @@
-use std::{cell::RefCell, collections::VecDeque, hint::unreachable_unchecked, rc::Rc};
+use std::{collections::VecDeque, hint::unreachable_unchecked};
 
 use glam::u8vec3;
 use modular_bitfield::{bitfield, prelude::*};
 
-use crate::{bus::{interrupt::{Interrupt, IRQ}, timer::Timer}, gpu::primitives::{color::Color, vertex::Vertex}, ram::RAM};
+use crate::{bus::interrupt::{Interrupt, IRQ}, gpu::primitives::{color::Color, vertex::Vertex}, ram::RAM, state::{Savestate, StateReader, StateValue, StateWriter}};
 
 const VRAM_SIZE: usize = 1024 * 1024;
+pub const VRAM_WIDTH: u32 = 1024;
+pub const VRAM_HEIGHT: u32 = 512;
+pub const FRAME_CYCLES: u64 = 566_203;
+pub const FIRST_VBLANK: u64 = 516_687;
 
 pub mod primitives;
 mod commands;
@@ -96,15 +100,11 @@ pub struct GPU {
 
     tex_window: u32,
 
-    cycle: usize,
     even_odd_frame: bool,
-
-    interrupt: Rc<RefCell<Interrupt>>,
-    timer: Rc<RefCell<Timer>>,
 }
 
 impl GPU {
-    pub fn new(interrupt: Rc<RefCell<Interrupt>>, timer: Rc<RefCell<Timer>>) -> Self {
+    pub fn new() -> Self {
         let vram = RAM::new(VRAM_SIZE);
 
         Self {
@@ -124,27 +124,13 @@ impl GPU {
 
             tex_window: 0,
 
-            cycle: 566_203 - 516_687,
             even_odd_frame: false,
-
-            interrupt,
-            timer,
         }
     }
 
-    pub fn tick(&mut self) -> bool {
-        self.cycle += 1;
-        if self.cycle == 566_203 {
-            self.cycle = 0;
-
-            self.even_odd_frame = !self.even_odd_frame;
-            self.interrupt.borrow_mut().request(IRQ::VBLANK);
-            self.timer.borrow_mut().enter_vblank();
-
-            return true;
-        }
-
-        return false;
+    pub fn vblank(&mut self, interrupt: &mut Interrupt) {
+        self.even_odd_frame = !self.even_odd_frame;
+        interrupt.request(IRQ::VBLANK);
     }
 
     pub fn read_gp0(&mut self) -> u32 {
@@ -170,7 +156,7 @@ impl GPU {
         u32::from_le_bytes(self.gpu_status.bytes)
     }
 
-    pub fn write_gp0(&mut self, word: u32) {
+    pub fn write_gp0(&mut self, word: u32, interrupt: &mut Interrupt) {
         // println!("GP0 {word:08X}");
         self.gp0_mode = match self.gp0_mode {
             GP0_State::CommandStart => {
@@ -191,7 +177,7 @@ impl GPU {
 
                         0x01 => {/* TODO: Flush texture cache */ GP0_State::CommandStart}
                         0x02 => GP0_State::ReceivingParameters {idx: 1, expected: 2, command: ParametrizedCommand::Fill(word)},
-                        0x1F => self.irq(),
+                        0x1F => self.irq(interrupt),
                         0xE1 => self.set_texpage(word),
                         0xE2 => self.set_tex_window(word),
                         0xE3 => self.set_drawing_area_top_left(word),
@@ -363,6 +349,10 @@ impl GPU {
         self.vram.write16(vram_addr, blended);
     }
 
+    pub fn vram(&self) -> &[u8] {
+        &self.vram.data
+    }
+
     pub fn render_vram(&self) -> Box<[Color; 512 * 1024]> {
         let mut output = Box::new([Color::default(); 512 * 1024]);
         if self.gpu_status.display_area_color_depth() == 0 {
@@ -390,4 +380,152 @@ impl GPU {
 
         output
     }
-}
\ No newline at end of file
+
+    pub fn display_resolution(&self) -> (u32, u32) {
+        let width = if self.gpu_status.horizontal_resolution_2() != 0 {
+            368
+        } else {
+            [256, 320, 512, 640][self.gpu_status.horizontal_resolution_1() as usize]
+        };
+        let height = 240 << (self.gpu_status.vertical_resolution() & self.gpu_status.vertical_interlace());
+
+        (width, height)
+    }
+
+    pub fn render_display_area(&self) -> (u32, u32, Vec<Color>) {
+        let (width, height) = self.display_resolution();
+        let vram = self.render_vram();
+
+        let mut start_x = self.display_area_start.coords.x as u32;
+        let start_y = self.display_area_start.coords.y as u32;
+        if self.gpu_status.display_area_color_depth() != 0 {
+            start_x = start_x * 2 / 3;
+        }
+
+        let mut output = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let vram_x = (start_x + x) & 0x3FF;
+                let vram_y = (start_y + y) & 0x1FF;
+                output.push(vram[((vram_y << 10) + vram_x) as usize]);
+            }
+        }
+
+        (width, height, output)
+    }
+}
+
+impl Savestate for GPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.vram.save_state(w);
+        w.write(&self.gp0_mode);
+        w.write(&self.gpu_read_transfer);
+        w.write(&self.gp0_parameters);
+        w.write(&self.gpu_read);
+        w.write(&self.gpu_status.bytes);
+        w.write(&self.drawing_area);
+        w.write(&self.drawing_offset);
+        w.write(&self.display_range);
+        w.write(&self.display_area_start);
+        w.write(&self.tex_window);
+        w.write(&self.even_odd_frame);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.vram.load_state(r)?;
+        self.gp0_mode = r.read()?;
+        self.gpu_read_transfer = r.read()?;
+        self.gp0_parameters = r.read()?;
+        self.gpu_read = r.read()?;
+        self.gpu_status = GPUSTAT::from_bytes(r.read()?);
+        self.drawing_area = r.read()?;
+        self.drawing_offset = r.read()?;
+        self.display_range = r.read()?;
+        self.display_area_start = r.read()?;
+        self.tex_window = r.read()?;
+        self.even_odd_frame = r.read()?;
+        Ok(())
+    }
+}
+
+impl StateValue for BlitFields {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&[self.vram_x, self.vram_y, self.width, self.height, self.current_row, self.current_col]);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let [vram_x, vram_y, width, height, current_row, current_col] = r.read()?;
+        Ok(Self { vram_x, vram_y, width, height, current_row, current_col })
+    }
+}
+
+impl StateValue for ParametrizedCommand {
+    fn write(&self, w: &mut StateWriter) {
+        let (tag, word): (u8, u32) = match *self {
+            ParametrizedCommand::Fill(word) => (0, word),
+            ParametrizedCommand::VRAM_VRAM_Copy => (1, 0),
+            ParametrizedCommand::CPU_VRAM_Copy => (2, 0),
+            ParametrizedCommand::VRAM_CPU_Copy => (3, 0),
+            ParametrizedCommand::Polygon(word) => (4, word),
+            ParametrizedCommand::Line(word) => (5, word),
+            ParametrizedCommand::Rectangle(word) => (6, word),
+        };
+        w.write(&tag);
+        w.write(&word);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let tag: u8 = r.read()?;
+        let word: u32 = r.read()?;
+        Ok(match tag {
+            0 => ParametrizedCommand::Fill(word),
+            1 => ParametrizedCommand::VRAM_VRAM_Copy,
+            2 => ParametrizedCommand::CPU_VRAM_Copy,
+            3 => ParametrizedCommand::VRAM_CPU_Copy,
+            4 => ParametrizedCommand::Polygon(word),
+            5 => ParametrizedCommand::Line(word),
+            6 => ParametrizedCommand::Rectangle(word),
+            _ => return Err(anyhow::anyhow!("Invalid GP0 command in save state: {tag}")),
+        })
+    }
+}
+
+impl StateValue for GP0_State {
+    fn write(&self, w: &mut StateWriter) {
+        match *self {
+            GP0_State::CommandStart => w.write(&0u8),
+            GP0_State::ReceivingParameters { idx, expected, command } => {
+                w.write(&1u8);
+                w.write(&idx);
+                w.write(&expected);
+                w.write(&command);
+            }
+            GP0_State::ReceivingPolyLineParameters { color_word, gouraud, command } => {
+                w.write(&2u8);
+                w.write(&color_word);
+                w.write(&gouraud);
+                w.write(&command);
+            }
+            GP0_State::ReceivingData(fields) => {
+                w.write(&3u8);
+                w.write(&fields);
+            }
+            GP0_State::SendingData(fields) => {
+                w.write(&4u8);
+                w.write(&fields);
+            }
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let tag: u8 = r.read()?;
+        Ok(match tag {
+            0 => GP0_State::CommandStart,
+            1 => GP0_State::ReceivingParameters { idx: r.read()?, expected: r.read()?, command: r.read()? },
+            2 => GP0_State::ReceivingPolyLineParameters { color_word: r.read()?, gouraud: r.read()?, command: r.read()? },
+            3 => GP0_State::ReceivingData(r.read()?),
+            4 => GP0_State::SendingData(r.read()?),
+            _ => return Err(anyhow::anyhow!("Invalid GP0 state in save state: {tag}")),
+        })
+    }
+}
@@ -1,6 +1,6 @@
 use glam::{DVec3, IVec2, IVec4, Vec4Swizzles};
 
-use crate::gpu::primitives::color::Color;
+use crate::{gpu::primitives::color::Color, state::{StateReader, StateValue, StateWriter}};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default)]
@@ -199,4 +199,15 @@ impl Vertex {
 
         return IVec4::from((min, max)).xzyw();
     }
-}
\ No newline at end of file
+}
+
+impl StateValue for Vertex {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.coords.x);
+        w.write(&self.coords.y);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(Self { coords: IVec2::new(r.read()?, r.read()?) })
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}};
+
+use anyhow::anyhow;
+
+use crate::{gpu::{primitives::color::Color, VRAM_HEIGHT, VRAM_WIDTH}, movie::{Movie, MoviePlayer}, System};
+
+#[derive(Default)]
+pub struct HeadlessConfig {
+    pub frames: usize,
+    pub break_pc: Option<u32>,
+    pub vram_out: Option<PathBuf>,
+    pub display_out: Option<PathBuf>,
+    pub movie: Option<Movie>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopReason {
+    FrameLimit,
+    BreakPC(u32),
+    Breakpoint(u32),
+    Exit(u32),
+}
+
+pub fn run(system: &mut System, config: &HeadlessConfig) -> anyhow::Result<StopReason> {
+    let reason = run_until(system, config)?;
+
+    if let Some(path) = &config.vram_out {
+        let vram = system.render_vram();
+        write_image(path, VRAM_WIDTH, VRAM_HEIGHT, &vram[..])?;
+    }
+
+    if let Some(path) = &config.display_out {
+        let (width, height, display) = system.render_display_area();
+        write_image(path, width, height, &display)?;
+    }
+
+    Ok(reason)
+}
+
+fn run_until(system: &mut System, config: &HeadlessConfig) -> anyhow::Result<StopReason> {
+    let mut player = config.movie.as_ref().map(MoviePlayer::new);
+    let mut frame = 0;
+    let mut frame_start = true;
+    while frame < config.frames {
+        if frame_start {
+            if let Some(player) = &mut player {
+                player.apply_frame(system)?;
+            }
+        }
+
+        let pc = system.cpu().pc;
+        if config.break_pc == Some(pc) {
+            return Ok(StopReason::BreakPC(pc));
+        }
+        if let Some(code) = system.exit_code() {
+            return Ok(StopReason::Exit(code));
+        }
+        if system.take_debug_break() {
+            return Ok(StopReason::Breakpoint(pc));
+        }
+
+        // The dynarec runs whole blocks, which could carry the PC straight past the break address
+        frame_start = if config.break_pc.is_some() {system.step_interpreted()} else {system.step_instruction()};
+        frame += frame_start as usize;
+    }
+
+    Ok(StopReason::FrameLimit)
+}
+
+pub fn write_image(path: &Path, width: u32, height: u32, pixels: &[Color]) -> anyhow::Result<()> {
+    let rgb: Vec<u8> = pixels.iter().flat_map(|color| color.rgb.to_array()).collect();
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => write_png(path, width, height, &rgb),
+        Some("ppm") => write_ppm(path, width, height, &rgb),
+        _ => Err(anyhow!("Unsupported image format: {}", path.display())),
+    }
+}
+
+fn write_png(path: &Path, width: u32, height: u32, rgb: &[u8]) -> anyhow::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+
+    Ok(())
+}
+
+fn write_ppm(path: &Path, width: u32, height: u32, rgb: &[u8]) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{width} {height}\n255\n")?;
+    file.write_all(rgb)?;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "dynarec"))]
+mod test {
+    use crate::{headless::{run, HeadlessConfig, StopReason}, System};
+
+    #[test]
+    fn break_pc_inside_a_dynarec_block() {
+        const BASE: u32 = 0x8000_1000;
+        let mut system = System::with_bios_image(vec![0; 512 * 1024]);
+        system.cpu_mut().set_dynarec(true).unwrap();
+        let program = [
+            0x2421_0001, // addiu r1, r1, 1
+            0x2421_0001, // addiu r1, r1, 1
+            0x2421_0001, // addiu r1, r1, 1
+            0x0800_0400, // j 0x80001000
+            0x0000_0000, // nop
+        ];
+        for (index, word) in program.into_iter().enumerate() {
+            system.write32(BASE + index as u32 * 4, word);
+        }
+        system.cpu_mut().pc = BASE;
+        system.cpu_mut().next_pc = BASE + 4;
+
+        let config = HeadlessConfig { frames: 1, break_pc: Some(BASE + 8), ..Default::default() };
+        assert_eq!(run(&mut system, &config).unwrap(), StopReason::BreakPC(BASE + 8));
+        assert_eq!(system.cpu().R[1], 2);
+    }
+}
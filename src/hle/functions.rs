@@ -0,0 +1,802 @@
+use crate::{bus::interface::Bus, cpu::CPU, hle::{call_guest, copy_to_ram, install_vectors, read_path, read_string, restore_jump_buffer, write_bytes, Frame, Heap, Hle, OpenFile, Then, B_TABLE, C_TABLE, EXCB, IDLE, I_MASK, I_STAT, PCB, ROM_BASE, TCB_SIZE, TCB_USED, WAIT_EVENT}};
+
+const GP0: u32 = 0x1F80_1810;
+const GP1: u32 = 0x1F80_1814;
+const TIMERS: u32 = 0x1F80_1100;
+
+const EVENT_FREE: u32 = 0x0000;
+const EVENT_DISABLED: u32 = 0x1000;
+const EVENT_ENABLED: u32 = 0x2000;
+const EVENT_READY: u32 = 0x4000;
+const MODE_CALLBACK: u32 = 0x1000;
+const MODE_READY: u32 = 0x2000;
+
+// Memory card accesses fail with a timeout, as if no card were inserted
+const CARD_CLASSES: [u32; 2] = [0xF400_0001, 0xF000_0011];
+const CARD_TIMEOUT: u32 = 0x0100;
+
+const ENOENT: u32 = 2;
+const EBADF: u32 = 9;
+const ERROR: u32 = 0xFFFF_FFFF;
+
+pub(super) enum Outcome {
+    Return(u32),
+    // Returns once the callbacks of the events it delivered have run
+    Deliver(u32, Vec<u32>),
+    // The function has moved the PC itself
+    Jump,
+}
+
+use Outcome::{Jump, Return};
+
+impl Hle {
+    pub(super) fn a_function(&mut self, number: u32, cpu: &mut CPU, bus: &mut Bus) -> Outcome {
+        let [a0, a1, a2, a3] = [cpu.R[4], cpu.R[5], cpu.R[6], cpu.R[7]];
+        match number {
+            0x00 => Return(self.open(bus, a0)),
+            0x01 => Return(self.lseek(a0, a1, a2)),
+            0x02 => Return(self.read(bus, a0, a1, a2)),
+            0x03 => Return(self.write(bus, a0, a1, a2)),
+            0x04 => Return(self.close(a0)),
+            0x05 => Return(0),
+            0x06 | 0x3A | 0xA1 => {
+                println!("HLE BIOS: program exited with {a0:08X}");
+                cpu.set_pc(ROM_BASE + IDLE);
+                Jump
+            }
+            0x07 => Return((a0 < 2) as u32),
+            0x08 | 0x3B => Return(0),
+            0x09 => {
+                if a1 == 1 {bus.debug_port.putchar(a0 as u8)}
+                Return(a0)
+            }
+            0x0A => Return(match a0 as u8 {
+                digit @ b'0'..=b'9' => (digit - b'0') as u32,
+                letter @ (b'a'..=b'z' | b'A'..=b'Z') => (letter.to_ascii_lowercase() - b'a') as u32 + 10,
+                _ => 9_999_999,
+            }),
+            0x0C => Return(strtol(bus, a0, a1, a2)),
+            0x0D => Return(strtol(bus, a0, a1, a2)),
+            0x0E | 0x0F => Return((a0 as i32).unsigned_abs()),
+            0x10 | 0x11 => Return(strtol(bus, a0, 0, 10)),
+            0x12 => {
+                let value = strtol(bus, a0, 0, 10);
+                bus.write32(a1, value);
+                Return(value)
+            }
+            0x13 => {
+                let words = [cpu.R[31], cpu.R[29], cpu.R[30], cpu.R[16], cpu.R[17], cpu.R[18], cpu.R[19], cpu.R[20], cpu.R[21], cpu.R[22], cpu.R[23], cpu.R[28]];
+                for (index, word) in words.into_iter().enumerate() {
+                    bus.write32(a0 + index as u32 * 4, word);
+                }
+                Return(0)
+            }
+            0x14 => {
+                restore_jump_buffer(cpu, bus, a0, a1);
+                Jump
+            }
+            0x15 => {
+                if a0 == 0 || a1 == 0 {return Return(0)}
+                let end = a0 + read_string(bus, a0).len() as u32;
+                let mut src = read_string(bus, a1);
+                src.push(0);
+                write_bytes(bus, end, &src);
+                Return(a0)
+            }
+            0x16 => {
+                if a0 == 0 || a1 == 0 {return Return(0)}
+                let end = a0 + read_string(bus, a0).len() as u32;
+                let mut src = read_string(bus, a1);
+                src.truncate(a2 as usize);
+                src.push(0);
+                write_bytes(bus, end, &src);
+                Return(a0)
+            }
+            0x17 => Return(compare(&read_string(bus, a0), &read_string(bus, a1))),
+            0x18 => {
+                let (mut left, mut right) = (read_string(bus, a0), read_string(bus, a1));
+                left.truncate(a2 as usize);
+                right.truncate(a2 as usize);
+                Return(compare(&left, &right))
+            }
+            0x19 => {
+                if a0 == 0 || a1 == 0 {return Return(0)}
+                let mut src = read_string(bus, a1);
+                src.push(0);
+                write_bytes(bus, a0, &src);
+                Return(a0)
+            }
+            0x1A => {
+                if a0 == 0 || a1 == 0 {return Return(0)}
+                let mut src = read_string(bus, a1);
+                src.resize(a2 as usize, 0);
+                write_bytes(bus, a0, &src);
+                Return(a0)
+            }
+            0x1B => Return(if a0 == 0 {0} else {read_string(bus, a0).len() as u32}),
+            0x1C | 0x1E => Return(find(&read_string(bus, a0), |byte| byte == a1 as u8).map_or(0, |index| a0 + index)),
+            0x1D | 0x1F => Return(read_string(bus, a0).iter().rposition(|&byte| byte == a1 as u8).map_or(0, |index| a0 + index as u32)),
+            0x20 => {
+                let set = read_string(bus, a1);
+                Return(find(&read_string(bus, a0), |byte| set.contains(&byte)).map_or(0, |index| a0 + index))
+            }
+            0x21 => {
+                let set = read_string(bus, a1);
+                Return(read_string(bus, a0).iter().take_while(|byte| set.contains(byte)).count() as u32)
+            }
+            0x22 => {
+                let set = read_string(bus, a1);
+                Return(read_string(bus, a0).iter().take_while(|byte| !set.contains(byte)).count() as u32)
+            }
+            0x23 => Return(self.strtok(bus, a0, a1)),
+            0x24 => {
+                let (haystack, needle) = (read_string(bus, a0), read_string(bus, a1));
+                let found = (0..=haystack.len().saturating_sub(needle.len())).find(|&index| haystack[index..].starts_with(&needle));
+                Return(found.filter(|_| haystack.len() >= needle.len()).map_or(0, |index| a0 + index as u32))
+            }
+            0x25 => Return((a0 as u8).to_ascii_uppercase() as u32),
+            0x26 => Return((a0 as u8).to_ascii_lowercase() as u32),
+            0x27 => {
+                copy(bus, a1, a0, a2);
+                Return(a1)
+            }
+            0x28 => {
+                fill(bus, a0, 0, a1);
+                Return(a0)
+            }
+            0x29 | 0x2D => {
+                let (left, right) = (read_bytes(bus, a0, a2), read_bytes(bus, a1, a2));
+                Return(compare(&left, &right))
+            }
+            0x2A | 0x2C => {
+                copy(bus, a0, a1, a2);
+                Return(a0)
+            }
+            0x2B => {
+                fill(bus, a0, a1 as u8, a2);
+                Return(a0)
+            }
+            0x2E => Return(find(&read_bytes(bus, a0, a2), |byte| byte == a1 as u8).map_or(0, |index| a0 + index)),
+            0x2F => {
+                self.rand_seed = self.rand_seed.wrapping_mul(0x41C6_4E6D).wrapping_add(0x3039);
+                Return((self.rand_seed >> 16) & 0x7FFF)
+            }
+            0x30 => {
+                self.rand_seed = a0;
+                Return(0)
+            }
+            0x33 => Return(self.heap.alloc(a0).unwrap_or(0)),
+            0x34 => {
+                self.heap.free(a0);
+                Return(0)
+            }
+            0x37 => {
+                let Some(size) = a0.checked_mul(a1) else { return Return(0) };
+                let addr = self.heap.alloc(size).unwrap_or(0);
+                if addr != 0 {fill(bus, addr, 0, size)}
+                Return(addr)
+            }
+            0x38 => Return(self.realloc(bus, a0, a1)),
+            0x39 => {
+                self.heap = Heap::new((a0, a0.saturating_add(a1)));
+                Return(0)
+            }
+            0x3C => Return(a0),
+            0x3D => Return(0),
+            0x3E => {
+                for byte in read_string(bus, a0).into_iter().chain([b'\n']) {
+                    bus.debug_port.putchar(byte);
+                }
+                Return(1)
+            }
+            0x3F => {
+                let text = printf(cpu, bus);
+                for byte in &text {
+                    bus.debug_port.putchar(*byte);
+                }
+                Return(text.len() as u32)
+            }
+            0x41 | 0x42 => {
+                let path = read_path(bus, a0);
+                let Some(exe) = bus.cd_rom.read_file(&path) else { return Return(0) };
+                if exe.len() < 0x800 || !exe.starts_with(b"PS-X EXE") {return Return(0)}
+
+                write_bytes(bus, a1, &exe[0x10..0x4C]);
+                if number == 0x42 {
+                    let text_addr = u32::from_le_bytes(*exe[0x18..].first_chunk().unwrap());
+                    let text_size = u32::from_le_bytes(*exe[0x1C..].first_chunk().unwrap()) as usize;
+                    copy_to_ram(cpu, bus, text_addr, &exe[0x800..(0x800 + text_size).min(exe.len())]);
+                }
+                Return(1)
+            }
+            0x43 => self.exec(cpu, bus, a0, a1, a2),
+            0x44 => Return(0),
+            0x45 => {
+                install_vectors(bus);
+                Return(0)
+            }
+            0x46 | 0x47 => {
+                let src = bus.read32(cpu.R[29] + 0x10);
+                bus.write32(GP0, 0xA000_0000);
+                bus.write32(GP0, (a1 << 16) | (a0 & 0xFFFF));
+                bus.write32(GP0, (a3 << 16) | (a2 & 0xFFFF));
+                for index in 0..(a2 * a3).div_ceil(2) {
+                    let word = bus.read32(src + index * 4);
+                    bus.write32(GP0, word);
+                }
+                Return(0)
+            }
+            0x48 => {
+                bus.write32(GP1, a0);
+                Return(0)
+            }
+            0x49 => {
+                bus.write32(GP0, a0);
+                Return(0)
+            }
+            0x4A => {
+                for index in 0..a1 {
+                    let word = bus.read32(a0 + index * 4);
+                    bus.write32(GP0, word);
+                }
+                Return(0)
+            }
+            0x4B => {
+                let mut addr = a0 & 0x1F_FFFC;
+                for _ in 0..0x10_0000 {
+                    let header = bus.read32(addr);
+                    for index in 1..=header >> 24 {
+                        let word = bus.read32(addr + index * 4);
+                        bus.write32(GP0, word);
+                    }
+                    if header & 0xFF_FFFF == 0xFF_FFFF {break}
+                    addr = header & 0x1F_FFFC;
+                }
+                Return(0)
+            }
+            0x4C | 0x4E => Return(0),
+            0x4D => Return(bus.read32(GP1)),
+            0x51 => {
+                let path = read_path(bus, a0);
+                if self.load_exec(cpu, bus, &path, a1, a2) {Jump} else {Return(0)}
+            }
+            0x54..=0x56 | 0x5B | 0x70..=0x72 | 0x95..=0x99 | 0x9F | 0xA2 | 0xA3 | 0xA6 | 0xAD => Return(0),
+            0x9C => {
+                self.set_conf(bus, a0, a1, a2);
+                Return(0)
+            }
+            0x9D => {
+                bus.write32(a0, self.event_count);
+                bus.write32(a1, self.tcb_count);
+                bus.write32(a2, self.stack_top);
+                Return(0)
+            }
+            0xA4 => {
+                let path = read_path(bus, a0);
+                Return(bus.cd_rom.find_file(&path).map_or(ERROR, |file| file.lba))
+            }
+            0xA5 => {
+                for sector in 0..a0 {
+                    let Some(data) = bus.cd_rom.data_sector(a1 + sector) else { return Return(ERROR) };
+                    copy_to_ram(cpu, bus, a2 + sector * 0x800, &data);
+                }
+                Return(a0)
+            }
+            0xAB | 0xAC => Return(1).deliver_card(self, bus),
+            _ => self.unimplemented("A", number, cpu),
+        }
+    }
+
+    pub(super) fn b_function(&mut self, number: u32, cpu: &mut CPU, bus: &mut Bus) -> Outcome {
+        let [a0, a1, a2, a3] = [cpu.R[4], cpu.R[5], cpu.R[6], cpu.R[7]];
+        match number {
+            0x00 => Return(self.kernel_heap.alloc(a0).unwrap_or(0)),
+            0x01 => {
+                self.kernel_heap.free(a0);
+                Return(0)
+            }
+            0x02 => {
+                if a0 < 3 {
+                    let mut mode = 0;
+                    if a2 & 0x1000 != 0 {mode |= 0x050}
+                    if a2 & 0x0100 != 0 {mode |= 0x008}
+                    if a2 & 0x0010 != 0 {mode |= 0x001}
+                    if a2 & 0x0001 != 0 {mode |= if a0 == 2 {0x200} else {0x100}}
+                    bus.write32(TIMERS + a0 * 0x10 + 8, a1);
+                    bus.write32(TIMERS + a0 * 0x10 + 4, mode);
+                }
+                Return(1)
+            }
+            0x03 => Return(if a0 < 3 {bus.read32(TIMERS + a0 * 0x10) & 0xFFFF} else {0}),
+            0x04 | 0x05 if a0 < 4 => {
+                let bit = if a0 == 3 {1} else {0x10 << a0};
+                let mask = bus.read32(I_MASK);
+                bus.write32(I_MASK, if number == 0x04 {mask | bit} else {mask & !bit});
+                Return(1)
+            }
+            0x04 | 0x05 => Return(0),
+            0x06 => {
+                if a0 < 3 {bus.write32(TIMERS + a0 * 0x10, 0)}
+                Return(1)
+            }
+            0x07 => Outcome::Deliver(0, self.deliver_event(bus, a0, a1)),
+            0x08 => Return(self.open_event(bus, a0, a1, a2, a3)),
+            0x09 => Return(self.set_event_status(bus, a0, |_| Some(EVENT_FREE))),
+            0x0A => {
+                let Some(event) = self.event_handle(a0) else { return Return(0) };
+                match bus.read32(event + 4) {
+                    EVENT_READY => {
+                        bus.write32(event + 4, EVENT_ENABLED);
+                        Return(1)
+                    }
+                    EVENT_ENABLED if bus.read32(event + 0xC) != MODE_CALLBACK => {
+                        cpu.set_pc(ROM_BASE + WAIT_EVENT);
+                        Jump
+                    }
+                    _ => Return(0),
+                }
+            }
+            0x0B => {
+                let Some(event) = self.event_handle(a0) else { return Return(0) };
+                let ready = bus.read32(event + 4) == EVENT_READY;
+                if ready {bus.write32(event + 4, EVENT_ENABLED)}
+                Return(ready as u32)
+            }
+            0x0C => Return(self.set_event_status(bus, a0, |status| (status != EVENT_FREE).then_some(EVENT_ENABLED))),
+            0x0D => Return(self.set_event_status(bus, a0, |status| (status != EVENT_FREE).then_some(EVENT_DISABLED))),
+            0x0E => Return(self.open_thread(bus, a0, a1, a2)),
+            0x0F => {
+                if let Some(tcb) = self.thread_handle(a0) {bus.write32(tcb, 0x1000)}
+                Return(1)
+            }
+            0x10 => {
+                let Some(tcb) = self.thread_handle(a0) else { return Return(ERROR) };
+                cpu.R[2] = 1;
+                self.save_context(cpu, bus, cpu.R[31]);
+                bus.write32(PCB, tcb);
+                let pc = self.restore_context(cpu, bus);
+                cpu.set_pc(pc);
+                Jump
+            }
+            0x12 => {
+                self.pad_buffers = [a0, a2];
+                Return(2)
+            }
+            0x13 => {
+                self.pad_started = true;
+                let mask = bus.read32(I_MASK);
+                bus.write32(I_MASK, mask | 1);
+                Return(1)
+            }
+            0x14 => {
+                self.pad_started = false;
+                Return(1)
+            }
+            0x17 => {
+                self.return_from_exception(cpu, bus);
+                Jump
+            }
+            0x18 => {
+                self.custom_exit = 0;
+                Return(0)
+            }
+            0x19 => {
+                self.custom_exit = a0;
+                Return(0)
+            }
+            0x20 => {
+                for index in 0..self.event_count {
+                    let event = self.event(index);
+                    if bus.read32(event) == a0 && bus.read32(event + 8) == a1 && bus.read32(event + 4) == EVENT_READY && bus.read32(event + 0xC) == MODE_READY {
+                        bus.write32(event + 4, EVENT_ENABLED);
+                    }
+                }
+                Return(0)
+            }
+            0x32..=0x3F => {
+                self.a_function(number - 0x32, cpu, bus)
+            }
+            0x40..=0x46 | 0x51 | 0x5C | 0x5D => Return(0),
+            0x47..=0x4C | 0x50 => Return(1),
+            0x4E | 0x4F => Return(1).deliver_card(self, bus),
+            0x54 | 0x55 => Return(self.last_error),
+            0x56 => Return(0x8000_0000 | C_TABLE),
+            0x57 => Return(0x8000_0000 | B_TABLE),
+            0x5B => {
+                self.clear_pad = a0 != 0;
+                Return(0)
+            }
+            _ => self.unimplemented("B", number, cpu),
+        }
+    }
+
+    pub(super) fn c_function(&mut self, number: u32, cpu: &mut CPU, bus: &mut Bus) -> Outcome {
+        let [a0, a1] = [cpu.R[4], cpu.R[5]];
+        match number {
+            0x00 | 0x01 | 0x0C | 0x12 | 0x1C => Return(0),
+            0x02 => {
+                if a0 < 4 && a1 != 0 {
+                    let head = bus.read32(EXCB + a0 * 8);
+                    bus.write32(a1, head);
+                    bus.write32(EXCB + a0 * 8, a1);
+                }
+                Return(0)
+            }
+            0x03 => {
+                if a0 < 4 {
+                    let mut link = EXCB + a0 * 8;
+                    for _ in 0..32 {
+                        let entry = bus.read32(link);
+                        if entry == 0 {break}
+                        if entry == a1 {
+                            let next = bus.read32(entry);
+                            bus.write32(link, next);
+                            break;
+                        }
+                        link = entry;
+                    }
+                }
+                Return(0)
+            }
+            0x07 => {
+                install_vectors(bus);
+                Return(0)
+            }
+            0x08 => {
+                self.kernel_heap = Heap::new((a0, a0.saturating_add(a1)));
+                Return(0)
+            }
+            0x0A if a0 < 4 => Return(std::mem::replace(&mut self.clear_root_counter[a0 as usize], a1 != 0) as u32),
+            _ => self.unimplemented("C", number, cpu),
+        }
+    }
+
+    fn unimplemented(&self, table: &str, number: u32, cpu: &CPU) -> Outcome {
+        println!("HLE BIOS: unimplemented function {table}({number:02X}) called from {:08X}", cpu.R[31]);
+        Return(0)
+    }
+
+    // Ready-mode events become ready, while callback-mode events return their callback
+    pub(super) fn deliver_event(&mut self, bus: &mut Bus, class: u32, spec: u32) -> Vec<u32> {
+        let mut callbacks = Vec::new();
+        for index in 0..self.event_count {
+            let event = self.event(index);
+            if bus.read32(event) != class || bus.read32(event + 8) != spec || bus.read32(event + 4) != EVENT_ENABLED {continue}
+
+            match bus.read32(event + 0xC) {
+                MODE_CALLBACK => {
+                    let func = bus.read32(event + 0x10);
+                    if func != 0 {callbacks.push(func)}
+                }
+                MODE_READY => bus.write32(event + 4, EVENT_READY),
+                _ => {}
+            }
+        }
+
+        callbacks
+    }
+
+    fn open_event(&mut self, bus: &mut Bus, class: u32, spec: u32, mode: u32, func: u32) -> u32 {
+        let Some(index) = (0..self.event_count).find(|&index| bus.read32(self.event(index) + 4) == EVENT_FREE) else { return ERROR };
+        let event = self.event(index);
+        for (offset, value) in [class, EVENT_DISABLED, spec, mode, func].into_iter().enumerate() {
+            bus.write32(event + offset as u32 * 4, value);
+        }
+
+        0xF100_0000 | index
+    }
+
+    fn event_handle(&self, handle: u32) -> Option<u32> {
+        let index = handle & 0xFFFF;
+        (index < self.event_count).then(|| self.event(index))
+    }
+
+    // Applies the new status if update returns one. Returns 1 for valid handles.
+    fn set_event_status(&mut self, bus: &mut Bus, handle: u32, update: impl Fn(u32) -> Option<u32>) -> u32 {
+        let Some(event) = self.event_handle(handle) else { return 0 };
+        if let Some(status) = update(bus.read32(event + 4)) {
+            bus.write32(event + 4, status);
+        }
+
+        1
+    }
+
+    fn open_thread(&mut self, bus: &mut Bus, pc: u32, sp: u32, gp: u32) -> u32 {
+        let Some(index) = (0..self.tcb_count).find(|&index| bus.read32(self.tcb(index)) != TCB_USED) else { return ERROR };
+        let tcb = self.tcb(index);
+        for offset in (4..TCB_SIZE).step_by(4) {
+            bus.write32(tcb + offset, 0);
+        }
+        bus.write32(tcb, TCB_USED);
+        bus.write32(tcb + 8 + 28 * 4, gp);
+        bus.write32(tcb + 8 + 29 * 4, sp);
+        bus.write32(tcb + 8 + 30 * 4, sp);
+        bus.write32(tcb + 0x88, pc);
+        bus.write32(tcb + 0x94, 0x0000_0404);
+
+        0xFF00_0000 | index
+    }
+
+    fn thread_handle(&self, handle: u32) -> Option<u32> {
+        let index = handle & 0xFFFF;
+        (index < self.tcb_count).then(|| self.tcb(index))
+    }
+
+    // Native handlers that run before the interrupt chains: the pad buffers get refreshed on
+    // vblank and the root counter events delivered. Returns the callbacks to run.
+    pub(super) fn kernel_interrupts(&mut self, bus: &mut Bus) -> Vec<u32> {
+        let pending = bus.read32(I_STAT) & bus.read32(I_MASK);
+        let mut callbacks = Vec::new();
+        for (counter, irq) in [4, 5, 6, 0].into_iter().enumerate() {
+            if pending & (1 << irq) == 0 {continue}
+            callbacks.extend(self.deliver_event(bus, 0xF200_0000 + counter as u32, 2));
+            if self.clear_root_counter[counter] {bus.write32(I_STAT, !(1 << irq))}
+        }
+
+        if pending & 1 != 0 && self.pad_started {
+            for (port, buffer) in self.pad_buffers.into_iter().enumerate() {
+                if buffer == 0 {continue}
+                match bus.sio0.device(port).map(|pad| pad.switches().bits()) {
+                    Some(switches) => write_bytes(bus, buffer, &[0x00, 0x41, switches as u8, (switches >> 8) as u8]),
+                    None => write_bytes(bus, buffer, &[0xFF]),
+                }
+            }
+            if self.clear_pad {bus.write32(I_STAT, !1)}
+        }
+
+        callbacks
+    }
+
+    fn open(&mut self, bus: &mut Bus, name: u32) -> u32 {
+        let path = read_path(bus, name);
+        let Some(fd) = (2..self.files.len()).find(|&fd| self.files[fd].is_none()) else {
+            self.last_error = EBADF;
+            return ERROR;
+        };
+
+        let is_cdrom = path.get(..6).is_some_and(|device| device.eq_ignore_ascii_case("cdrom:"));
+        match bus.cd_rom.find_file(&path).filter(|_| is_cdrom) {
+            Some(file) => {
+                self.files[fd] = Some(OpenFile { file, pos: 0 });
+                fd as u32
+            }
+            None => {
+                self.last_error = ENOENT;
+                ERROR
+            }
+        }
+    }
+
+    fn lseek(&mut self, fd: u32, offset: u32, whence: u32) -> u32 {
+        let Some(Some(open)) = self.files.get_mut(fd as usize) else { return ERROR };
+        open.pos = match whence {
+            0 => offset,
+            1 => open.pos.wrapping_add(offset),
+            _ => return ERROR,
+        };
+
+        open.pos
+    }
+
+    fn read(&mut self, bus: &mut Bus, fd: u32, dst: u32, len: u32) -> u32 {
+        let Some(Some(open)) = self.files.get_mut(fd as usize) else { return ERROR };
+        let Some(data) = bus.cd_rom.read_file_range(open.file, open.pos, len) else { return ERROR };
+        open.pos += data.len() as u32;
+        write_bytes(bus, dst, &data);
+
+        data.len() as u32
+    }
+
+    fn write(&mut self, bus: &mut Bus, fd: u32, src: u32, len: u32) -> u32 {
+        if fd != 1 {return ERROR}
+        for addr in src..src.saturating_add(len) {
+            let byte = bus.read8(addr);
+            bus.debug_port.putchar(byte);
+        }
+
+        len
+    }
+
+    fn close(&mut self, fd: u32) -> u32 {
+        match self.files.get_mut(fd as usize) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                fd
+            }
+            _ => ERROR,
+        }
+    }
+
+    // Later calls with a null string carry on from where the last one stopped
+    fn strtok(&mut self, bus: &mut Bus, src: u32, delimiters: u32) -> u32 {
+        let start = if src != 0 {src} else {self.strtok};
+        if start == 0 {return 0}
+
+        let set = read_string(bus, delimiters);
+        let text = read_string(bus, start);
+        let Some(first) = text.iter().position(|byte| !set.contains(byte)) else {
+            self.strtok = 0;
+            return 0;
+        };
+
+        let token = start + first as u32;
+        self.strtok = match text[first..].iter().position(|byte| set.contains(byte)) {
+            Some(len) => {
+                bus.write8(token + len as u32, 0);
+                token + len as u32 + 1
+            }
+            None => 0,
+        };
+
+        token
+    }
+
+    fn realloc(&mut self, bus: &mut Bus, addr: u32, size: u32) -> u32 {
+        if addr == 0 {return self.heap.alloc(size).unwrap_or(0)}
+        if size == 0 {
+            self.heap.free(addr);
+            return 0;
+        }
+
+        let Some(new) = self.heap.alloc(size) else { return 0 };
+        let old_size = self.heap.free(addr).unwrap_or(0);
+        copy(bus, new, addr, old_size.min(size));
+        new
+    }
+
+    // Runs a loaded executable from its header, returning 1 if it ever returns
+    fn exec(&mut self, cpu: &mut CPU, bus: &mut Bus, header: u32, argc: u32, argv: u32) -> Outcome {
+        let [pc, gp, _, _, _, _, bss_addr, bss_size, stack_addr, stack_size] = std::array::from_fn(|index| bus.read32(header + index as u32 * 4));
+        fill(bus, bss_addr, 0, bss_size);
+        cpu.flush_block_cache(bus);
+
+        cpu.R[28] = gp;
+        if stack_addr != 0 {
+            cpu.R[29] = stack_addr.wrapping_add(stack_size);
+            cpu.R[30] = cpu.R[29];
+        }
+
+        let ra = cpu.R[31];
+        self.frames.push(Frame { work: Default::default(), chain_entry: None, then: Then::Return(ra, 1) });
+        call_guest(cpu, pc, argc);
+        cpu.R[5] = argv;
+        Jump
+    }
+}
+
+impl Outcome {
+    // Memory card functions succeed, but the access then times out
+    fn deliver_card(self, hle: &mut Hle, bus: &mut Bus) -> Outcome {
+        let Return(v0) = self else { return self };
+        let callbacks = CARD_CLASSES.into_iter().flat_map(|class| hle.deliver_event(bus, class, CARD_TIMEOUT)).collect();
+        Outcome::Deliver(v0, callbacks)
+    }
+}
+
+fn read_bytes(bus: &mut Bus, addr: u32, len: u32) -> Vec<u8> {
+    (0..len.min(0x20_0000)).map(|index| bus.read8(addr.wrapping_add(index))).collect()
+}
+
+fn copy(bus: &mut Bus, dst: u32, src: u32, len: u32) {
+    if dst == 0 || src == 0 {return}
+    let bytes = read_bytes(bus, src, len);
+    write_bytes(bus, dst, &bytes);
+}
+
+fn fill(bus: &mut Bus, dst: u32, value: u8, len: u32) {
+    if dst == 0 {return}
+    for index in 0..len.min(0x20_0000) {
+        bus.write8(dst.wrapping_add(index), value);
+    }
+}
+
+fn find(bytes: &[u8], predicate: impl Fn(u8) -> bool) -> Option<u32> {
+    bytes.iter().position(|&byte| predicate(byte)).map(|index| index as u32)
+}
+
+fn compare(left: &[u8], right: &[u8]) -> u32 {
+    let index = left.iter().zip(right).position(|(l, r)| l != r).unwrap_or(left.len().min(right.len()));
+    let (l, r) = (left.get(index).copied().unwrap_or(0), right.get(index).copied().unwrap_or(0));
+    (l as i32 - r as i32) as u32
+}
+
+// Base 0 picks the base from a 0x or 0 prefix. The end pointer is written when not null.
+fn strtol(bus: &mut Bus, src: u32, end: u32, base: u32) -> u32 {
+    let text = read_string(bus, src);
+    let mut index = text.iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+    let negative = text.get(index) == Some(&b'-');
+    if matches!(text.get(index), Some(b'-' | b'+')) {index += 1}
+
+    let hex_prefix = text.get(index) == Some(&b'0') && matches!(text.get(index + 1), Some(b'x' | b'X'));
+    let base = match base {
+        0 if hex_prefix => 16,
+        0 if text.get(index) == Some(&b'0') => 8,
+        0 => 10,
+        base => base.clamp(2, 36),
+    };
+    if base == 16 && hex_prefix {index += 2}
+
+    let mut value: u32 = 0;
+    while let Some(digit) = text.get(index).and_then(|&byte| (byte as char).to_digit(base)) {
+        value = value.wrapping_mul(base).wrapping_add(digit);
+        index += 1;
+    }
+
+    if end != 0 {bus.write32(end, src + index as u32)}
+    if negative {value.wrapping_neg()} else {value}
+}
+
+// The format string comes in a0, and the arguments in a1 to a3 and then on the stack, past the
+// space the caller reserves for the registers
+fn printf(cpu: &CPU, bus: &mut Bus) -> Vec<u8> {
+    let format = read_string(bus, cpu.R[4]);
+    let mut arg_index = 1;
+    let mut next_arg = |bus: &mut Bus| {
+        let arg = if arg_index < 4 {cpu.R[4 + arg_index]} else {bus.read32(cpu.R[29] + arg_index * 4)};
+        arg_index += 1;
+        arg
+    };
+
+    let mut out = Vec::new();
+    let mut chars = format.into_iter().peekable();
+    while let Some(byte) = chars.next() {
+        if byte != b'%' {
+            out.push(byte);
+            continue;
+        }
+
+        let (mut left, mut zero) = (false, false);
+        while let Some(&flag @ (b'-' | b'0' | b'+' | b' ' | b'#')) = chars.peek() {
+            left |= flag == b'-';
+            zero |= flag == b'0';
+            chars.next();
+        }
+        let number = |chars: &mut std::iter::Peekable<std::vec::IntoIter<u8>>| {
+            let mut value = None;
+            while let Some(digit @ b'0'..=b'9') = chars.peek().copied() {
+                value = Some(value.unwrap_or(0) * 10 + (digit - b'0') as usize);
+                chars.next();
+            }
+            value
+        };
+        let width = number(&mut chars).unwrap_or(0);
+        let precision = if chars.peek() == Some(&b'.') {
+            chars.next();
+            Some(number(&mut chars).unwrap_or(0))
+        } else {None};
+        while matches!(chars.peek(), Some(b'l' | b'h')) {chars.next();}
+
+        let text = match chars.next() {
+            Some(b'd' | b'i') => (next_arg(bus) as i32).to_string().into_bytes(),
+            Some(b'u') => next_arg(bus).to_string().into_bytes(),
+            Some(b'x') => format!("{:x}", next_arg(bus)).into_bytes(),
+            Some(b'X') => format!("{:X}", next_arg(bus)).into_bytes(),
+            Some(b'p') => format!("{:08x}", next_arg(bus)).into_bytes(),
+            Some(b'o') => format!("{:o}", next_arg(bus)).into_bytes(),
+            Some(b'c') => vec![next_arg(bus) as u8],
+            Some(b's') => {
+                let src = next_arg(bus);
+                let mut text = read_string(bus, src);
+                if let Some(precision) = precision {text.truncate(precision)}
+                text
+            }
+            Some(other) => vec![other],
+            None => break,
+        };
+
+        let padding = width.saturating_sub(text.len());
+        if left {
+            out.extend(text);
+            out.extend(std::iter::repeat_n(b' ', padding));
+        } else if zero && text.first() == Some(&b'-') {
+            out.push(b'-');
+            out.extend(std::iter::repeat_n(b'0', padding));
+            out.extend(&text[1..]);
+        } else {
+            out.extend(std::iter::repeat_n(if zero {b'0'} else {b' '}, padding));
+            out.extend(text);
+        }
+    }
+
+    out
+}
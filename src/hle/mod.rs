@@ -0,0 +1,673 @@
+use std::collections::VecDeque;
+
+use crate::{bus::interface::Bus, cd_rom::iso9660::{IsoFile, SystemCnf}, cpu::CPU, state::{Savestate, StateReader, StateValue, StateWriter}};
+
+mod functions;
+
+pub const ROM_SIZE: usize = 512 * 1024;
+const ROM_BASE: u32 = 0xBFC0_0000;
+
+// Execution reaching one of these ROM offsets runs the native kernel instead. The RAM vectors
+// jump here, so programs that install their own vectors still get them.
+const RESET: u32 = 0x000;
+const EXCEPTION: u32 = 0x180;
+const A_FUNCTIONS: u32 = 0x200;
+const B_FUNCTIONS: u32 = 0x210;
+const C_FUNCTIONS: u32 = 0x220;
+const GUEST_RETURN: u32 = 0x230;
+// Every function gets its own trap, 0x400 bytes per table, so programs can also call the
+// addresses they read from the tables
+const FUNCTIONS: u32 = 0x1000;
+// Real code: calls WaitEvent again until the event is ready, so interrupts can deliver it
+const WAIT_EVENT: u32 = 0x300;
+const IDLE: u32 = 0x340;
+
+// Kernel structures in the first 64KB of RAM, laid out like the real kernel's where programs
+// are known to look at them
+const TABLE_OF_TABLES: u32 = 0x100;
+const A_TABLE: u32 = 0x200;
+const C_TABLE: u32 = 0x674;
+const B_TABLE: u32 = 0x874;
+const TABLE_SIZES: [u32; 3] = [0xC0, 0x60, 0x20];
+const EXCB: u32 = 0x6000;
+const PCB: u32 = 0x6020;
+const KERNEL_AREA: (u32, u32) = (0x6100, 0xC000);
+const KERNEL_HEAP: (u32, u32) = (0x8000_C000, 0x8000_E000);
+
+const TCB_SIZE: u32 = 0xC0;
+const EVCB_SIZE: u32 = 0x1C;
+const TCB_USED: u32 = 0x4000;
+
+const I_STAT: u32 = 0x1F80_1070;
+const I_MASK: u32 = 0x1F80_1074;
+const CACHE_CONTROL: u32 = 0xFFFE_0130;
+const SHELL_ENTRY: u32 = 0x8003_0000;
+const DEFAULT_STACK: u32 = 0x801F_FF00;
+
+// Guest code the kernel is waiting on, and what to do once it has all run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Work {
+    // A function and its argument
+    Call(u32, u32),
+    // An interrupt chain entry. Its second function gets called with the first one's result,
+    // if that is nonzero.
+    Chain(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Then {
+    ExitInterrupt,
+    Return(u32, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Frame {
+    work: VecDeque<Work>,
+    chain_entry: Option<u32>,
+    then: Then,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OpenFile {
+    file: IsoFile,
+    pos: u32,
+}
+
+// First fit over a range of RAM, with the bookkeeping kept outside of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Heap {
+    start: u32,
+    end: u32,
+    blocks: Vec<(u32, u32)>,
+}
+
+impl Heap {
+    fn new((start, end): (u32, u32)) -> Self {
+        Self { start, end, blocks: Vec::new() }
+    }
+
+    fn alloc(&mut self, size: u32) -> Option<u32> {
+        let size = size.max(1).checked_next_multiple_of(4)?;
+        let mut cursor = self.start;
+        for (index, &(addr, len)) in self.blocks.iter().enumerate() {
+            if addr - cursor >= size {
+                self.blocks.insert(index, (cursor, size));
+                return Some(cursor);
+            }
+            cursor = addr + len;
+        }
+
+        if self.end.checked_sub(cursor)? < size {
+            return None;
+        }
+        self.blocks.push((cursor, size));
+        Some(cursor)
+    }
+
+    fn free(&mut self, addr: u32) -> Option<u32> {
+        let index = self.blocks.iter().position(|&(block, _)| block == addr)?;
+        Some(self.blocks.remove(index).1)
+    }
+}
+
+// A high level replacement for the BIOS, for running programs without a BIOS image. The A0h,
+// B0h and C0h functions, the exception handler and disc boot run natively, while events,
+// threads and interrupt chains live in RAM where programs expect them. Memory cards always
+// read as missing.
+pub struct Hle {
+    boot_exe: bool,
+    frames: Vec<Frame>,
+    heap: Heap,
+    kernel_heap: Heap,
+    files: [Option<OpenFile>; 16],
+    last_error: u32,
+    rand_seed: u32,
+    strtok: u32,
+
+    tcb_count: u32,
+    event_count: u32,
+    stack_top: u32,
+
+    pad_buffers: [u32; 2],
+    pad_started: bool,
+    clear_pad: bool,
+    clear_root_counter: [bool; 4],
+    custom_exit: u32,
+}
+
+impl Hle {
+    // With boot_exe set, boot jumps to the shell entry point for the EXE to be sideloaded
+    pub fn new(boot_exe: bool) -> Self {
+        Self {
+            boot_exe,
+            frames: Vec::new(),
+            heap: Heap::new((0, 0)),
+            kernel_heap: Heap::new(KERNEL_HEAP),
+            files: [None; 16],
+            last_error: 0,
+            rand_seed: 0,
+            strtok: 0,
+
+            tcb_count: 0,
+            event_count: 0,
+            stack_top: DEFAULT_STACK,
+
+            pad_buffers: [0; 2],
+            pad_started: false,
+            clear_pad: true,
+            clear_root_counter: [true; 4],
+            custom_exit: 0,
+        }
+    }
+
+    // The ROM image mapped in place of the BIOS. Besides the traps, it only holds the few
+    // routines that have to run as guest code.
+    pub fn rom() -> Vec<u8> {
+        let mut rom = vec![0; ROM_SIZE];
+        let mut write = |offset: u32, code: &[u32]| {
+            for (index, word) in code.iter().enumerate() {
+                let offset = offset as usize + index * 4;
+                rom[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+            }
+        };
+
+        // addiu t1, zero, 0Ah; lui t0, BFC0h; ori t0, t0, B_FUNCTIONS; jr t0; nop
+        write(WAIT_EVENT, &[0x2409_000A, 0x3C08_BFC0, 0x3508_0000 | B_FUNCTIONS, 0x0100_0008, 0]);
+        // b IDLE; nop
+        write(IDLE, &[0x1000_FFFF, 0]);
+        let version = b"HLE BIOS";
+        rom[0x108..0x108 + version.len()].copy_from_slice(version);
+
+        rom
+    }
+
+    // Runs the native kernel if the CPU is about to execute one of the traps. Returns false
+    // when there's nothing to do and the instruction should run as normal.
+    pub fn trap(&mut self, cpu: &mut CPU, bus: &mut Bus) -> bool {
+        if cpu.pc & 0x1FFF_0000 != 0x1FC0_0000 {return false}
+        let offset = cpu.pc & 0xFFFF;
+        let function = offset.checked_sub(FUNCTIONS).filter(|function| function & 3 == 0 && function / 0x400 < 3);
+        if function.is_none() && !matches!(offset, RESET | EXCEPTION | A_FUNCTIONS | B_FUNCTIONS | C_FUNCTIONS | GUEST_RETURN) {
+            return false;
+        }
+
+        cpu.flush_load_delay();
+        match offset {
+            RESET => self.reset(cpu, bus),
+            EXCEPTION => self.exception(cpu, bus),
+            GUEST_RETURN => self.guest_returned(cpu, bus),
+            A_FUNCTIONS | B_FUNCTIONS | C_FUNCTIONS => self.vector(cpu, bus, (offset - A_FUNCTIONS) / 0x10),
+            _ => {
+                let function = function.unwrap();
+                self.dispatch(function / 0x400, (function % 0x400) / 4, cpu, bus);
+            }
+        }
+
+        true
+    }
+
+    fn reset(&mut self, cpu: &mut CPU, bus: &mut Bus) {
+        *self = Self::new(self.boot_exe);
+        bus.write32(CACHE_CONTROL, 0x0001_E988);
+        bus.write32(I_MASK, 0);
+        bus.write32(I_STAT, 0);
+        for addr in (0..0x1_0000).step_by(4) {
+            bus.write32(addr, 0);
+        }
+
+        install_vectors(bus);
+        for (table, (base, size)) in [A_TABLE, B_TABLE, C_TABLE].into_iter().zip(TABLE_SIZES).enumerate() {
+            for number in 0..size {
+                bus.write32(base + number * 4, function_address(table as u32, number));
+            }
+        }
+        self.set_conf(bus, 16, 4, DEFAULT_STACK);
+        cpu.system_control.write_register(12, 0x0000_0401);
+
+        if self.boot_exe {
+            cpu.set_pc(SHELL_ENTRY);
+            return;
+        }
+
+        match SystemCnf::from_disc(&mut bus.cd_rom) {
+            Some(cnf) => {
+                self.set_conf(bus, cnf.event, cnf.tcb, cnf.stack);
+                if !self.load_exec(cpu, bus, &cnf.boot, cnf.stack, 0) {
+                    println!("HLE BIOS: could not load {}", cnf.boot);
+                    cpu.set_pc(ROM_BASE + IDLE);
+                }
+            }
+            None => {
+                println!("HLE BIOS: no disc or EXE to boot");
+                cpu.set_pc(ROM_BASE + IDLE);
+            }
+        }
+    }
+
+    // Thread and event control blocks are carved out of the kernel area, and wiped
+    fn set_conf(&mut self, bus: &mut Bus, event_count: u32, tcb_count: u32, stack_top: u32) {
+        let (start, end) = KERNEL_AREA;
+        self.tcb_count = tcb_count.clamp(1, (end - start) / 2 / TCB_SIZE);
+        self.event_count = event_count.min((end - start) / 2 / EVCB_SIZE);
+        self.stack_top = stack_top;
+
+        for addr in (start..end).step_by(4) {
+            bus.write32(addr, 0);
+        }
+        for (index, (ptr, size)) in [
+            (EXCB, 0x20),
+            (PCB, 4),
+            (self.tcb(0), self.tcb_count * TCB_SIZE),
+            (0, 0),
+            (self.event(0), self.event_count * EVCB_SIZE),
+        ].into_iter().enumerate() {
+            bus.write32(TABLE_OF_TABLES + index as u32 * 8, ptr);
+            bus.write32(TABLE_OF_TABLES + index as u32 * 8 + 4, size);
+        }
+
+        bus.write32(self.tcb(0), TCB_USED);
+        bus.write32(PCB, self.tcb(0));
+    }
+
+    fn tcb(&self, index: u32) -> u32 {
+        0x8000_0000 | (KERNEL_AREA.0 + index * TCB_SIZE)
+    }
+
+    fn event(&self, index: u32) -> u32 {
+        0x8000_0000 | (KERNEL_AREA.0 + self.tcb_count * TCB_SIZE + index * EVCB_SIZE)
+    }
+
+    // Calls through the vectors go wherever the table entry points, in case a program has
+    // replaced it
+    fn vector(&mut self, cpu: &mut CPU, bus: &mut Bus, table: u32) {
+        let number = cpu.R[9];
+        if number < TABLE_SIZES[table as usize] {
+            let base = [A_TABLE, B_TABLE, C_TABLE][table as usize];
+            let entry = bus.read32(base + number * 4);
+            cpu.set_pc(entry);
+        } else {
+            self.dispatch(table, number, cpu, bus);
+        }
+    }
+
+    fn dispatch(&mut self, table: u32, number: u32, cpu: &mut CPU, bus: &mut Bus) {
+        let ra = cpu.R[31];
+        let outcome = match table {
+            0 => self.a_function(number, cpu, bus),
+            1 => self.b_function(number, cpu, bus),
+            _ => self.c_function(number, cpu, bus),
+        };
+
+        match outcome {
+            functions::Outcome::Return(v0) => {
+                cpu.R[2] = v0;
+                cpu.set_pc(ra);
+            }
+            functions::Outcome::Deliver(v0, callbacks) => {
+                let work = callbacks.into_iter().map(|func| Work::Call(func, 0)).collect();
+                self.frames.push(Frame { work, chain_entry: None, then: Then::Return(ra, v0) });
+                self.resume(cpu, bus);
+            }
+            functions::Outcome::Jump => {}
+        }
+    }
+
+    fn exception(&mut self, cpu: &mut CPU, bus: &mut Bus) {
+        let cause = cpu.system_control.read_register(13);
+        let epc = cpu.system_control.read_register(14);
+        match (cause >> 2) & 0x1F {
+            0 => {
+                self.save_context(cpu, bus, epc);
+
+                // The kernel's own handlers come first, so they see the IRQs before anything
+                // in the chains acknowledges them
+                let mut work: VecDeque<Work> = self.kernel_interrupts(bus).into_iter().map(|func| Work::Call(func, 0)).collect();
+                for priority in 0..4 {
+                    let mut entry = bus.read32(EXCB + priority * 8);
+                    for _ in 0..32 {
+                        if entry == 0 {break}
+                        work.push_back(Work::Chain(entry));
+                        entry = bus.read32(entry);
+                    }
+                }
+
+                self.frames.push(Frame { work, chain_entry: None, then: Then::ExitInterrupt });
+                self.resume(cpu, bus);
+            }
+            8 => self.syscall(cpu, bus, epc),
+            code => {
+                println!("HLE BIOS: unresolved exception {code} at {epc:08X}");
+                cpu.set_pc(ROM_BASE + IDLE);
+            }
+        }
+    }
+
+    fn syscall(&mut self, cpu: &mut CPU, bus: &mut Bus, epc: u32) {
+        let sr = cpu.system_control.read_register(12);
+        let mut resume = epc.wrapping_add(4);
+        match cpu.R[4] {
+            // EnterCriticalSection, clearing the interrupt enable that rfe restores
+            1 => {
+                cpu.R[2] = (sr & 0x404 == 0x404) as u32;
+                cpu.system_control.write_register(12, sr & !0x404);
+            }
+            // ExitCriticalSection
+            2 => cpu.system_control.write_register(12, sr | 0x404),
+            // ChangeThreadSubFunction
+            3 => {
+                cpu.R[2] = 1;
+                self.save_context(cpu, bus, resume);
+                bus.write32(PCB, cpu.R[5]);
+                resume = self.restore_context(cpu, bus);
+            }
+            _ => {}
+        }
+
+        cpu.system_control.rfe();
+        cpu.set_pc(resume);
+    }
+
+    fn save_context(&self, cpu: &CPU, bus: &mut Bus, pc: u32) {
+        let tcb = bus.read32(PCB);
+        for register in 0..32 {
+            bus.write32(tcb + 8 + register * 4, cpu.R[register]);
+        }
+        bus.write32(tcb + 0x88, pc);
+        bus.write32(tcb + 0x8C, cpu.hi());
+        bus.write32(tcb + 0x90, cpu.lo());
+        bus.write32(tcb + 0x94, cpu.system_control.read_register(12));
+        bus.write32(tcb + 0x98, cpu.system_control.read_register(13));
+    }
+
+    // Returns the PC to resume at
+    fn restore_context(&self, cpu: &mut CPU, bus: &mut Bus) -> u32 {
+        let tcb = bus.read32(PCB);
+        for register in 1..32 {
+            cpu.R[register] = bus.read32(tcb + 8 + register * 4);
+        }
+        cpu.set_hi(bus.read32(tcb + 0x8C));
+        cpu.set_lo(bus.read32(tcb + 0x90));
+        bus.read32(tcb + 0x88)
+    }
+
+    fn return_from_exception(&mut self, cpu: &mut CPU, bus: &mut Bus) {
+        if let Some(index) = self.frames.iter().rposition(|frame| frame.then == Then::ExitInterrupt) {
+            self.frames.truncate(index);
+        }
+
+        let pc = self.restore_context(cpu, bus);
+        cpu.system_control.rfe();
+        cpu.set_pc(pc);
+    }
+
+    // Runs the current frame's work until guest code has to be called, or the frame is done
+    fn resume(&mut self, cpu: &mut CPU, bus: &mut Bus) {
+        let Some(frame) = self.frames.last_mut() else { return };
+        loop {
+            match frame.work.pop_front() {
+                Some(Work::Call(func, arg)) => {
+                    call_guest(cpu, func, arg);
+                    return;
+                }
+                Some(Work::Chain(entry)) => {
+                    let func = bus.read32(entry + 8);
+                    if func != 0 {
+                        frame.chain_entry = Some(entry);
+                        call_guest(cpu, func, 0);
+                        return;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        match self.frames.pop().map(|frame| frame.then) {
+            Some(Then::ExitInterrupt) if self.custom_exit != 0 => {
+                // Like longjmp, into code that will return from the exception itself
+                restore_jump_buffer(cpu, bus, self.custom_exit, 1);
+            }
+            Some(Then::ExitInterrupt) => self.return_from_exception(cpu, bus),
+            Some(Then::Return(ra, v0)) => {
+                cpu.R[2] = v0;
+                cpu.set_pc(ra);
+            }
+            None => {}
+        }
+    }
+
+    fn guest_returned(&mut self, cpu: &mut CPU, bus: &mut Bus) {
+        let Some(frame) = self.frames.last_mut() else {
+            println!("HLE BIOS: unexpected return from guest code");
+            cpu.set_pc(ROM_BASE + IDLE);
+            return;
+        };
+
+        if let Some(entry) = frame.chain_entry.take() {
+            let func = bus.read32(entry + 4);
+            if cpu.R[2] != 0 && func != 0 {
+                frame.work.push_front(Work::Call(func, cpu.R[2]));
+            }
+        }
+        self.resume(cpu, bus);
+    }
+
+    // Loads a PS-EXE from the disc and starts it, never to return
+    fn load_exec(&mut self, cpu: &mut CPU, bus: &mut Bus, path: &str, stack_base: u32, stack_offset: u32) -> bool {
+        let Some(exe) = bus.cd_rom.read_file(path) else { return false };
+        if exe.len() < 0x800 || !exe.starts_with(b"PS-X EXE") {
+            return false;
+        }
+
+        let header: Vec<u32> = exe[0x10..0x38].chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+        let [pc, gp, text_addr, text_size, _, _, bss_addr, bss_size, stack_addr, stack_size] = header[..] else { return false };
+        let text_end = (0x800 + text_size as usize).min(exe.len());
+        copy_to_ram(cpu, bus, text_addr, &exe[0x800..text_end]);
+        copy_to_ram(cpu, bus, bss_addr, &vec![0; bss_size.min(0x20_0000) as usize]);
+
+        let sp = match (stack_base, stack_addr) {
+            (0, 0) => self.stack_top,
+            (0, _) => stack_addr.wrapping_add(stack_size),
+            _ => stack_base.wrapping_add(stack_offset),
+        };
+        cpu.R[28] = gp;
+        cpu.R[29] = sp;
+        cpu.R[30] = sp;
+        cpu.R[4] = 0;
+        cpu.R[5] = 0;
+        cpu.set_pc(pc);
+
+        true
+    }
+}
+
+fn install_vectors(bus: &mut Bus) {
+    // lui k0, BFC0h; ori k0, k0, EXCEPTION; jr k0; nop
+    for (index, word) in [0x3C1A_BFC0, 0x375A_0000 | EXCEPTION, 0x0340_0008, 0].into_iter().enumerate() {
+        bus.write32(0x80 + index as u32 * 4, word);
+    }
+    // lui t0, BFC0h; ori t0, t0, table; jr t0; nop
+    for (vector, trap) in [(0xA0, A_FUNCTIONS), (0xB0, B_FUNCTIONS), (0xC0, C_FUNCTIONS)] {
+        for (index, word) in [0x3C08_BFC0, 0x3508_0000 | trap, 0x0100_0008, 0].into_iter().enumerate() {
+            bus.write32(vector + index as u32 * 4, word);
+        }
+    }
+}
+
+fn function_address(table: u32, number: u32) -> u32 {
+    ROM_BASE + FUNCTIONS + table * 0x400 + number * 4
+}
+
+fn call_guest(cpu: &mut CPU, func: u32, arg: u32) {
+    cpu.R[4] = arg;
+    cpu.R[31] = ROM_BASE + GUEST_RETURN;
+    cpu.set_pc(func);
+}
+
+// The setjmp buffer layout: ra, sp, fp, s0 to s7 and gp
+fn restore_jump_buffer(cpu: &mut CPU, bus: &mut Bus, buffer: u32, v0: u32) {
+    let ra = bus.read32(buffer);
+    cpu.R[29] = bus.read32(buffer + 4);
+    cpu.R[30] = bus.read32(buffer + 8);
+    for register in 0..8 {
+        cpu.R[16 + register] = bus.read32(buffer + 0xC + register * 4);
+    }
+    cpu.R[28] = bus.read32(buffer + 0x2C);
+    cpu.R[2] = v0;
+    cpu.set_pc(ra);
+}
+
+// Writes straight to DRAM, so any code compiled from it has to go
+fn copy_to_ram(cpu: &mut CPU, bus: &mut Bus, addr: u32, bytes: &[u8]) {
+    let ram = &mut bus.dram.data;
+    for (index, byte) in bytes.iter().enumerate() {
+        let offset = (addr as usize + index) & (ram.len() - 1);
+        ram[offset] = *byte;
+    }
+    cpu.flush_block_cache(bus);
+}
+
+fn read_string(bus: &mut Bus, addr: u32) -> Vec<u8> {
+    (addr..addr.saturating_add(0x1000)).map(|addr| bus.read8(addr)).take_while(|&byte| byte != 0).collect()
+}
+
+fn read_path(bus: &mut Bus, addr: u32) -> String {
+    String::from_utf8_lossy(&read_string(bus, addr)).into_owned()
+}
+
+fn write_bytes(bus: &mut Bus, addr: u32, bytes: &[u8]) {
+    for (index, byte) in bytes.iter().enumerate() {
+        bus.write8(addr.wrapping_add(index as u32), *byte);
+    }
+}
+
+impl StateValue for Work {
+    fn write(&self, w: &mut StateWriter) {
+        match *self {
+            Work::Call(func, arg) => w.write(&(0u8, (func, arg))),
+            Work::Chain(entry) => w.write(&(1u8, (entry, 0u32))),
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let (tag, (a, b)): (u8, (u32, u32)) = r.read()?;
+        Ok(if tag == 0 {Work::Call(a, b)} else {Work::Chain(a)})
+    }
+}
+
+impl StateValue for Frame {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.work);
+        w.write(&self.chain_entry);
+        match self.then {
+            Then::ExitInterrupt => w.write(&None::<(u32, u32)>),
+            Then::Return(ra, v0) => w.write(&Some((ra, v0))),
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let work = r.read()?;
+        let chain_entry = r.read()?;
+        let then = match r.read::<Option<(u32, u32)>>()? {
+            Some((ra, v0)) => Then::Return(ra, v0),
+            None => Then::ExitInterrupt,
+        };
+        Ok(Frame { work, chain_entry, then })
+    }
+}
+
+impl StateValue for OpenFile {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&[self.file.lba, self.file.size, self.pos]);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let [lba, size, pos] = r.read()?;
+        Ok(OpenFile { file: IsoFile { lba, size }, pos })
+    }
+}
+
+impl StateValue for Heap {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(self.start, self.end));
+        w.write(&self.blocks);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let (start, end) = r.read()?;
+        Ok(Heap { start, end, blocks: r.read()? })
+    }
+}
+
+impl Savestate for Hle {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.frames);
+        w.write(&self.heap);
+        w.write(&self.kernel_heap);
+        w.write(&self.files);
+        w.write(&[self.last_error, self.rand_seed, self.strtok, self.tcb_count, self.event_count, self.stack_top, self.custom_exit]);
+        w.write(&self.pad_buffers);
+        w.write(&[self.pad_started, self.clear_pad]);
+        w.write(&self.clear_root_counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.frames = r.read()?;
+        self.heap = r.read()?;
+        self.kernel_heap = r.read()?;
+        self.files = r.read()?;
+        [self.last_error, self.rand_seed, self.strtok, self.tcb_count, self.event_count, self.stack_top, self.custom_exit] = r.read()?;
+        self.pad_buffers = r.read()?;
+        [self.pad_started, self.clear_pad] = r.read()?;
+        self.clear_root_counter = r.read()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Config, System};
+
+    #[test]
+    fn vblank_event_and_printf() {
+        let program: [u32; 35] = [
+            // OpenEvent(RCntCNT3, EvSpINT, EvMdNOINTR, 0)
+            0x3C04_F200, 0x3484_0003, 0x2405_0002, 0x2406_2000, 0x2407_0000,
+            0x240A_00B0, 0x0140_F809, 0x2409_0008,
+            0x0040_8021, // addu s0, v0, zero
+            // EnableEvent(s0)
+            0x0200_2021, 0x240A_00B0, 0x0140_F809, 0x2409_000C,
+            // enable_timer_irq(3)
+            0x2404_0003, 0x240A_00B0, 0x0140_F809, 0x2409_0004,
+            // ExitCriticalSection
+            0x2404_0002, 0x0000_000C,
+            // WaitEvent(s0)
+            0x0200_2021, 0x240A_00B0, 0x0140_F809, 0x2409_000A,
+            // printf("hle %d\n", v0)
+            0x3C04_8001, 0x3484_0100, 0x0040_2821, 0x240A_00A0, 0x0140_F809, 0x2409_003F,
+            // b .
+            0x1000_FFFF, 0x0000_0000,
+            0, 0, 0, 0,
+        ];
+
+        let mut exe = vec![0; 0x1000];
+        exe[..8].copy_from_slice(b"PS-X EXE");
+        for (offset, value) in [(0x10, 0x8001_0000u32), (0x18, 0x8001_0000), (0x1C, 0x800), (0x30, 0x801F_FF00)] {
+            exe[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        for (index, word) in program.into_iter().enumerate() {
+            exe[0x800 + index * 4..0x800 + index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        exe[0x900..0x908].copy_from_slice(b"hle %d\n\0");
+
+        let path = std::env::temp_dir().join("psx_hle_test.exe");
+        std::fs::write(&path, exe).unwrap();
+        let mut system = System::new(Config { hle_bios: true, exe: Some(path.clone()), ..Default::default() }).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        system.set_tty_echo(false);
+        for _ in 0..3 {
+            system.run_frame();
+        }
+        assert_eq!(system.tty_output(), "hle 1\n");
+    }
+}
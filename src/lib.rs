@@ -0,0 +1,41 @@
+#![allow(non_snake_case, non_camel_case_types, clippy::new_without_default)]
+
+use std::ops::{Index, IndexMut};
+
+pub mod bus;
+mod bios;
+pub mod cpu;
+pub mod debugger;
+pub mod gpu;
+pub mod headless;
+pub mod hle;
+pub mod movie;
+pub mod ram;
+pub mod rewind;
+pub mod scheduler;
+pub mod cd_rom;
+pub mod peripheral;
+pub mod spu;
+pub mod state;
+pub mod system;
+
+pub use system::{Config, System};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Registers<const N: usize> {
+    R: [u32; N],
+}
+
+impl<const N: usize> Index<u32> for Registers<N> {
+    type Output = u32;
+
+    fn index(&self, index: u32) -> &Self::Output {
+        &self.R[index as usize]
+    }
+}
+
+impl<const N: usize> IndexMut<u32> for Registers<N> {
+    fn index_mut(&mut self, index: u32) -> &mut Self::Output {
+        &mut self.R[index as usize]
+    }
+}
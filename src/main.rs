@@ -1,37 +1,189 @@
-#![allow(non_snake_case, non_camel_case_types)]
+use std::path::PathBuf;
+
+use clap::Parser;
+use psx::{debugger::{console::Console, gdb::GdbStub, Debugger, Frontend}, headless::{self, HeadlessConfig}, movie::Movie, rewind::Rewind, Config, System};
+
+#[derive(Parser)]
+#[command(version, about = "PlayStation emulator")]
+struct Args {
+    /// BIOS image to boot, ignored with --hle-bios
+    #[arg(long, default_value = "SCPH1001.bin")]
+    bios: PathBuf,
+
+    /// Emulate the BIOS instead of booting an image of it
+    #[arg(long)]
+    hle_bios: bool,
+
+    /// Disc image (.bin, .cue, .iso, .chd, .ecm or .pbp) to insert. Pick a disc of a multi-disc EBOOT with game.pbp#2
+    #[arg(long)]
+    disc: Option<PathBuf>,
+
+    /// PS-EXE to sideload once the BIOS reaches the shell
+    #[arg(long)]
+    exe: Option<PathBuf>,
+
+    /// Arguments passed to the sideloaded PS-EXE
+    #[arg(long = "arg", requires = "exe")]
+    exe_args: Vec<String>,
+
+    /// Skip the BIOS intro and boot the disc's executable directly
+    #[arg(long)]
+    fast_boot: bool,
+
+    /// Run without a window
+    #[arg(long)]
+    headless: bool,
+
+    /// Number of frames to run in headless mode [default: 60, or the movie length]
+    #[arg(long)]
+    frames: Option<usize>,
+
+    /// Stop headless mode when the PC reaches this address (hex)
+    #[arg(long, value_parser = parse_hex)]
+    break_pc: Option<u32>,
+
+    /// Write the whole VRAM to a .png or .ppm file after a headless run
+    #[arg(long)]
+    vram_out: Option<PathBuf>,
+
+    /// Write the display area to a .png or .ppm file after a headless run
+    #[arg(long)]
+    display_out: Option<PathBuf>,
+
+    /// Print every executed instruction
+    #[arg(long)]
+    trace: bool,
+
+    /// Log BIOS A/B/C function calls and their return values. Takes an optional filter such as
+    /// "B,open,A(3Fh),-putchar"
+    #[arg(long, num_args = 0..=1, default_missing_value = "")]
+    trace_kernel: Option<String>,
+
+    /// Run blocks through the x86-64 dynamic recompiler (needs the dynarec feature)
+    #[arg(long)]
+    dynarec: bool,
+
+    /// Start paused in the debugger, which takes commands on stdin
+    #[arg(long)]
+    debug: bool,
+
+    /// Start paused and wait for gdb to attach on this localhost port
+    #[arg(long, conflicts_with = "debug")]
+    gdb: Option<u16>,
+
+    /// Window scale factor
+    #[arg(long, default_value_t = 1)]
+    scale: u32,
+
+    /// Frames between rewind snapshots
+    #[arg(long, default_value_t = 5)]
+    rewind_interval: usize,
+
+    /// Number of rewind snapshots to keep, 0 disables rewinding
+    #[arg(long, default_value_t = 360)]
+    rewind_snapshots: usize,
+
+    /// Record pad input, resets and disc swaps to a movie file
+    #[arg(long, conflicts_with = "play_movie")]
+    record_movie: Option<PathBuf>,
+
+    /// Play back a movie file instead of reading the keyboard
+    #[arg(long)]
+    play_movie: Option<PathBuf>,
+}
 
-use std::{collections::HashMap, env};
-#[allow(unused_imports)]
-use std::{cell::RefCell, ops::{Index, IndexMut}, path::Path, rc::Rc, time::{Duration, Instant}};
+struct WindowConfig {
+    scale: u32,
+    debugger: Option<(Debugger, Box<dyn Frontend>)>,
+    state_name: String,
+    rewind: Option<Rewind>,
+    record_movie: Option<PathBuf>,
+    movie: Option<Movie>,
+}
 
-use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum};
+fn parse_hex(value: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(value.trim_start_matches("0x"), 16)
+}
 
-use crate::{bus::{dma::DMA, interface::Interface, interrupt::Interrupt, timer::Timer}, cd_rom::CD_ROM, cpu::{system_control::SystemControl, CPU}, peripheral::{devices::{digital_pad::DigitalPad, Device, DigitalSwitch}, ports::sio0::SIO0}, spu::SPU};
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    let state_name = args.disc.as_ref().or(args.exe.as_ref())
+        .and_then(|path| path.file_stem())
+        .map_or_else(|| String::from("bios"), |stem| stem.to_string_lossy().into_owned());
+
+    let mut system = System::new(Config {
+        bios: args.bios,
+        hle_bios: args.hle_bios,
+        disc: args.disc,
+        exe: args.exe,
+        exe_args: args.exe_args,
+        fast_boot: args.fast_boot,
+        trace: args.trace,
+        trace_kernel: args.trace_kernel,
+        dynarec: args.dynarec,
+    })?;
+
+    let movie = args.play_movie.as_deref().map(Movie::from_file).transpose()?;
+
+    let frontend: Option<Box<dyn Frontend>> = match (args.debug, args.gdb) {
+        (true, _) => Some(Box::new(Console::stdin())),
+        (false, Some(port)) => Some(Box::new(GdbStub::listen(port)?)),
+        (false, None) => None,
+    };
+    let mut debugger = frontend.map(|frontend| (Debugger::new(), frontend));
+
+    if let (true, Some((debugger, frontend))) = (args.headless, &mut debugger) {
+        while frontend.frame(debugger, &mut system)? {}
+        return Ok(());
+    }
 
-mod bus;
-mod bios;
-mod cpu;
-mod gpu;
-mod ram;
-mod cd_rom;
-mod peripheral;
-mod spu;
+    if args.headless {
+        let config = HeadlessConfig {
+            frames: args.frames.unwrap_or(movie.as_ref().map_or(60, Movie::len)),
+            break_pc: args.break_pc,
+            vram_out: args.vram_out,
+            display_out: args.display_out,
+            movie,
+        };
+        let reason = headless::run(&mut system, &config)?;
+        println!("Stopped: {reason:?}");
+        return Ok(());
+    }
 
-const VRAM_WIDTH: u32 = 1024;
-const VRAM_HEIGHT: u32 = 512;
+    // Rewinding would desync a movie from the frames it was recorded on
+    let movie_active = movie.is_some() || args.record_movie.is_some();
+    let rewind = (args.rewind_snapshots > 0 && !movie_active).then(|| Rewind::new(args.rewind_interval, args.rewind_snapshots));
+
+    run_window(system, WindowConfig {
+        scale: args.scale,
+        debugger,
+        state_name,
+        rewind,
+        record_movie: args.record_movie,
+        movie,
+    })
+}
 
-const NTSC_FRAME_TIME: Duration = Duration::from_nanos(16_866_250);
+#[cfg(not(feature = "sdl"))]
+fn run_window(_system: System, _config: WindowConfig) -> Result<(), anyhow::Error> {
+    use anyhow::anyhow;
 
-fn main() -> Result<(), anyhow::Error> {
-    let args: Vec<_> = env::args().collect();
-    let disk = &args[1];
-    // let exe_binding = std::fs::read("RenderTexturePolygon15BPPDither.exe").unwrap();
-    // let exe = exe_binding.as_slice();
+    Err(anyhow!("Built without SDL support, run with --headless"))
+}
+
+#[cfg(feature = "sdl")]
+fn run_window(mut system: System, config: WindowConfig) -> Result<(), anyhow::Error> {
+    use std::{collections::HashMap, path::Path, time::{Duration, Instant}};
+
+    use psx::{gpu::{VRAM_HEIGHT, VRAM_WIDTH}, movie::{MovieEvent, MoviePlayer, MovieRecorder}, peripheral::devices::DigitalSwitch};
+    use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum};
+
+    const NTSC_FRAME_TIME: Duration = Duration::from_nanos(16_866_250);
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window("PSX", VRAM_WIDTH, VRAM_HEIGHT)
+        .window("PSX", VRAM_WIDTH * config.scale, VRAM_HEIGHT * config.scale)
         .position_centered()
         .build()?;
 
@@ -40,24 +192,6 @@ fn main() -> Result<(), anyhow::Error> {
     let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, VRAM_WIDTH, VRAM_HEIGHT)?;
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let spu = Rc::new(RefCell::new(SPU::new()));
-    let system_control = Rc::new(RefCell::new(SystemControl::new()));
-    let interrupt = Rc::new(RefCell::new(Interrupt::new(system_control.clone())));
-    let sio0 = Rc::new(RefCell::new(SIO0::new([const { None }; 2], interrupt.clone())));
-    let pad1 = Rc::new(RefCell::new(Box::new(DigitalPad::new(Rc::downgrade(&sio0))) as Box<dyn Device>));
-    let pad2 = Rc::new(RefCell::new(Box::new(DigitalPad::new(Rc::downgrade(&sio0))) as Box<dyn Device>));
-    sio0.borrow_mut().connect_device(pad1.clone(), 0);
-    sio0.borrow_mut().connect_device(pad2.clone(), 1);
-    let timer = Rc::new(RefCell::new(Timer::new(interrupt.clone())));
-    let cd_rom = Rc::new(RefCell::new(CD_ROM::new(interrupt.clone(), disk)?));
-    let interface = Rc::new(RefCell::new(Interface::new(Path::new("SCPH1001.bin"), interrupt, cd_rom.clone(), timer.clone(), sio0.clone(), spu.clone())?));
-    let dma_running = Rc::new(RefCell::new(false));
-    let dma = Rc::new(RefCell::new(DMA::new(interface.clone(), interface.borrow_mut().interrupt.clone(), dma_running.clone())));
-    interface.borrow_mut().dma = Rc::downgrade(&dma);
-    let mut cpu = CPU::new(interface.clone(), dma_running, system_control);
-
-    let mut instruction = true;
-
     let mut frame_start = Instant::now();
 
     let mut key_map = HashMap::new();
@@ -76,116 +210,118 @@ fn main() -> Result<(), anyhow::Error> {
     key_map.insert(Keycode::RETURN, DigitalSwitch::START);
     key_map.insert(Keycode::BACKSPACE, DigitalSwitch::SELECT);
 
+    let WindowConfig { mut rewind, record_movie, movie, mut debugger, .. } = config;
+    let mut recorder = record_movie.as_ref().map(|_| MovieRecorder::new());
+    let mut player = movie.as_ref().map(MoviePlayer::new);
+    let disc = system.disc().map(Path::to_path_buf);
+
+    let mut state_slot = 0;
+    let mut rewinding = false;
+
     loop {
-        if instruction {
-            // sideload_exe(&mut cpu, interface.clone(), exe);
-            cpu.tick();
+        if let Some(recorder) = &mut recorder {
+            recorder.record_frame(&system);
         }
-        timer.borrow_mut().tick();
-        dma.borrow_mut().tick();
-        cd_rom.borrow_mut().tick();
-        sio0.borrow_mut().tick();
-        pad1.borrow_mut().transfer_rx();
-        pad2.borrow_mut().transfer_rx();
-        if interface.borrow_mut().gpu.tick() {
-            let frame: Vec<_> = interface.borrow().gpu.render_vram().iter().flat_map(|color| color.rgb.to_array()).collect();
-            texture.update(None, &frame[..], VRAM_WIDTH as usize * 3)?;
-
-            canvas.clear();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
-
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit { .. } | Event::KeyDown {keycode: Some(Keycode::Escape), ..} => return Ok(()),
-                    Event::KeyDown { keycode, .. } => {
-                        if let Some(key) = keycode {
-                            if let Some(switch) = key_map.get(&key) {
-                                pad1.borrow_mut().set_switch(*switch, false);
-                            }
-                        }
-                    }
-                    Event::KeyUp { keycode, .. } => {
-                        if let Some(key) = keycode {
-                            if let Some(switch) = key_map.get(&key) {
-                                pad1.borrow_mut().set_switch(*switch, true);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+        if let Some(movie_player) = &mut player {
+            if !movie_player.apply_frame(&mut system)? {
+                println!("Movie finished");
+                player = None;
             }
-
-            let frame_time = frame_start.elapsed();
-            std::thread::sleep(NTSC_FRAME_TIME.saturating_sub(frame_time));
-            frame_start = Instant::now();
         }
-        instruction = !instruction;
-    }
-}
-
-#[allow(unused)]
-fn sideload_exe(cpu: &mut CPU, interface: Rc<RefCell<Interface>>, exe: &[u8]) {
-    if cpu.pc != 0x80030000 {return}
-
-    let initial_pc = u32::from_le_bytes(*exe[0x10..].first_chunk().unwrap());
-    let initial_r28 = u32::from_le_bytes(*exe[0x14..].first_chunk().unwrap());
-    let exe_ram_addr = u32::from_le_bytes(*exe[0x18..].first_chunk().unwrap()) & 0x001F_FFFF;
-    let exe_size_2kb = u32::from_le_bytes(*exe[0x1C..].first_chunk().unwrap());
-    let initial_sp = u32::from_le_bytes(*exe[0x30..].first_chunk().unwrap());
-
-    let exe_size = exe_size_2kb;
-    interface.borrow_mut().dram.data[exe_ram_addr as usize..(exe_ram_addr + exe_size) as usize]
-        .copy_from_slice(&exe[2048..2048 + exe_size as usize]);
-
-    cpu.R[28] = initial_r28;
-    if initial_sp != 0 {
-        cpu.R[29] = initial_sp;
-        cpu.R[30] = initial_sp;
-    }
-
-    cpu.next_pc = initial_pc;
-
-    // amidog_args(interface);
-}
-
-#[allow(unused)]
-fn amidog_args(interface: Rc<RefCell<Interface>>) {
-    let args = ["console\0"];
-    let arglen = 1;
-    let mut len = 0;
 
-    for i in 0..arglen {
-        interface.borrow_mut().write32(0x1F80_0004 + (i * 4), 0x1F80_0044 + len);
-
-        let mut x = 0;
-        let n = args[i as usize].len() as u32;
-        for j in len..(len + n) {
-            interface.borrow_mut().write8(0x1F80_0044 + j, args[i as usize].as_bytes()[(j - len) as usize]);
-            x = j;
+        match (&mut rewind, &mut debugger) {
+            (_, Some((debugger, frontend))) => {
+                if !frontend.frame(debugger, &mut system)? {
+                    return Ok(());
+                }
+            }
+            (Some(rewind), None) if rewinding => {
+                if let Err(err) = rewind.step_back(&mut system) {
+                    println!("Failed to rewind: {err}");
+                    rewind.clear();
+                }
+            }
+            (Some(rewind), None) => {
+                system.run_frame();
+                rewind.on_frame(&system);
+            }
+            (None, None) => system.run_frame(),
         }
 
-        len = x;
-    }
-
-    interface.borrow_mut().write32(0x1F80_0000, arglen);
-}
+        let frame: Vec<_> = system.render_vram().iter().flat_map(|color| color.rgb.to_array()).collect();
+        texture.update(None, &frame[..], VRAM_WIDTH as usize * 3)?;
 
-#[derive(Debug, Clone, Copy)]
-pub struct Registers<const N: usize> {
-    R: [u32; N],
-}
+        canvas.clear();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
 
-impl<const N: usize> Index<u32> for Registers<N> {
-    type Output = u32;
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
+                    if let (Some(recorder), Some(path)) = (&recorder, &record_movie) {
+                        recorder.movie().save_to_file(path)?;
+                        println!("Saved movie to {}", path.display());
+                    }
+                    return Ok(());
+                }
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    let path = PathBuf::from(format!("{}.ss{state_slot}", config.state_name));
+                    match system.save_state_to_file(&path) {
+                        Ok(()) => println!("Saved state to {}", path.display()),
+                        Err(err) => println!("Failed to save state: {err}"),
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+                    state_slot = (state_slot + 1) % 10;
+                    println!("Selected save state slot {state_slot}");
+                }
+                Event::KeyDown { keycode: Some(Keycode::F7), .. } if recorder.is_some() || player.is_some() => {
+                    println!("Cannot load states while a movie is active");
+                }
+                Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                    let path = PathBuf::from(format!("{}.ss{state_slot}", config.state_name));
+                    match system.load_state_from_file(&path) {
+                        Ok(()) => {
+                            println!("Loaded state from {}", path.display());
+                            if let Some(rewind) = &mut rewind {rewind.clear()}
+                        }
+                        Err(err) => println!("Failed to load state: {err}"),
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } if player.is_none() => {
+                    let event = MovieEvent::Reset;
+                    event.apply(&mut system)?;
+                    if let Some(recorder) = &mut recorder {recorder.record_event(event)}
+                    if let Some(rewind) = &mut rewind {rewind.clear()}
+                }
+                Event::KeyDown { keycode: Some(Keycode::F10), .. } if player.is_none() => {
+                    let event = MovieEvent::SwapDisc(if system.disc().is_some() {None} else {disc.clone()});
+                    event.apply(&mut system)?;
+                    println!("Disc {}", if system.disc().is_some() {"inserted"} else {"ejected"});
+                    if let Some(recorder) = &mut recorder {recorder.record_event(event)}
+                }
+                Event::KeyDown { keycode: Some(Keycode::R), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::R), .. } => rewinding = false,
+                Event::KeyDown { keycode, .. } if player.is_none() => {
+                    if let Some(key) = keycode {
+                        if let Some(switch) = key_map.get(&key) {
+                            system.set_switch(0, *switch, false);
+                        }
+                    }
+                }
+                Event::KeyUp { keycode, .. } if player.is_none() => {
+                    if let Some(key) = keycode {
+                        if let Some(switch) = key_map.get(&key) {
+                            system.set_switch(0, *switch, true);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
 
-    fn index(&self, index: u32) -> &Self::Output {
-        &self.R[index as usize]
+        let frame_time = frame_start.elapsed();
+        std::thread::sleep(NTSC_FRAME_TIME.saturating_sub(frame_time));
+        frame_start = Instant::now();
     }
 }
-
-impl<const N: usize> IndexMut<u32> for Registers<N> {
-    fn index_mut(&mut self, index: u32) -> &mut Self::Output {
-        &mut self.R[index as usize]
-    }
-}
\ No newline at end of file
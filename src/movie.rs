@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::{peripheral::devices::DigitalSwitch, state::{StateReader, StateValue, StateWriter}, System};
+
+const MOVIE_MAGIC: [u8; 4] = *b"PSXM";
+const MOVIE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieEvent {
+    Reset,
+    SwapDisc(Option<PathBuf>),
+}
+
+impl MovieEvent {
+    pub fn apply(&self, system: &mut System) -> anyhow::Result<()> {
+        match self {
+            MovieEvent::Reset => system.reset(),
+            MovieEvent::SwapDisc(disc) => system.swap_disc(disc.as_deref()),
+        }
+    }
+}
+
+// Events are applied at the start of the frame, before the pads are set to `pressed`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub events: Vec<MovieEvent>,
+    pub pressed: [u16; 2],
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_bytes(&MOVIE_MAGIC);
+        w.write(&MOVIE_VERSION);
+        w.write(&self.frames);
+
+        w.into_inner()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let mut r = StateReader::new(data);
+        if r.read_bytes(4)? != MOVIE_MAGIC {
+            return Err(anyhow!("Not a movie file"));
+        }
+        let version: u32 = r.read()?;
+        if version != MOVIE_VERSION {
+            return Err(anyhow!("Unsupported movie version {version}, expected {MOVIE_VERSION}"));
+        }
+
+        let frames = r.read()?;
+        if !r.is_empty() {
+            return Err(anyhow!("Trailing data in movie file"));
+        }
+
+        Ok(Self { frames })
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+#[derive(Default)]
+pub struct MovieRecorder {
+    movie: Movie,
+    events: Vec<MovieEvent>,
+}
+
+impl MovieRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_event(&mut self, event: MovieEvent) {
+        self.events.push(event);
+    }
+
+    pub fn record_frame(&mut self, system: &System) {
+        self.movie.frames.push(MovieFrame {
+            events: std::mem::take(&mut self.events),
+            pressed: [0, 1].map(|port| system.pressed_switches(port).bits()),
+        });
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+pub struct MoviePlayer<'a> {
+    movie: &'a Movie,
+    frame: usize,
+}
+
+impl<'a> MoviePlayer<'a> {
+    pub fn new(movie: &'a Movie) -> Self {
+        Self { movie, frame: 0 }
+    }
+
+    pub fn apply_frame(&mut self, system: &mut System) -> anyhow::Result<bool> {
+        let Some(frame) = self.movie.frames.get(self.frame) else {return Ok(false)};
+
+        for event in &frame.events {
+            event.apply(system)?;
+        }
+        for (port, pressed) in frame.pressed.into_iter().enumerate() {
+            system.set_pressed_switches(port, DigitalSwitch::from_bits_truncate(pressed));
+        }
+
+        self.frame += 1;
+        Ok(true)
+    }
+}
+
+impl StateValue for MovieEvent {
+    fn write(&self, w: &mut StateWriter) {
+        match self {
+            MovieEvent::Reset => w.write(&0u8),
+            MovieEvent::SwapDisc(disc) => {
+                w.write(&1u8);
+                w.write(&disc.as_ref().map(|disc| disc.to_string_lossy().into_owned()));
+            }
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        match r.read::<u8>()? {
+            0 => Ok(MovieEvent::Reset),
+            1 => Ok(MovieEvent::SwapDisc(r.read::<Option<String>>()?.map(PathBuf::from))),
+            tag => Err(anyhow!("Invalid movie event {tag}")),
+        }
+    }
+}
+
+impl StateValue for MovieFrame {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.events);
+        w.write(&self.pressed);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(Self { events: r.read()?, pressed: r.read()? })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::movie::{Movie, MovieEvent, MovieFrame};
+
+    #[test]
+    fn round_trip() {
+        let movie = Movie {
+            frames: vec![
+                MovieFrame { events: vec![], pressed: [0x0008, 0] },
+                MovieFrame { events: vec![MovieEvent::SwapDisc(Some(PathBuf::from("disc2.bin"))), MovieEvent::Reset], pressed: [0, 0x4000] },
+                MovieFrame { events: vec![MovieEvent::SwapDisc(None)], pressed: [0; 2] },
+            ],
+        };
+
+        let data = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&data).unwrap(), movie);
+        assert!(Movie::from_bytes(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -1,6 +1,4 @@
-use std::{cell::RefCell, rc::Weak};
-
-use crate::peripheral::{devices::{Device, DigitalSwitch}, ports::sio0::SIO0};
+use crate::{peripheral::devices::{Device, DigitalSwitch}, state::{Savestate, StateReader, StateWriter}};
 
 const DIGITAL_CONTROLLER_INFO: u16 = 0x5A41;
 
@@ -10,18 +8,16 @@ pub struct DigitalPad {
     addressing_controller: bool,
     
     rx: Option<u8>,
-    sio: Weak<RefCell<SIO0>>,
 }
 
 impl DigitalPad {
-    pub fn new(sio: Weak<RefCell<SIO0>>) -> Self {
+    pub fn new() -> Self {
         Self {
             switches: DigitalSwitch::from_bits_truncate(0xFFFF),
             step: 0,
             addressing_controller: false,
             
             rx: None,
-            sio,
         }
     }
 }
@@ -48,10 +44,28 @@ impl Device for DigitalPad {
         self.switches.set(switch, released);
     }
 
-    fn transfer_rx(&mut self) {
-        if let Some(rx) = self.rx {
-            self.sio.upgrade().unwrap().borrow_mut().rx_receive(rx);
-            self.rx = None;
-        }
+    fn switches(&self) -> DigitalSwitch {
+        self.switches
+    }
+
+    fn transfer_rx(&mut self) -> Option<u8> {
+        self.rx.take()
+    }
+}
+
+impl Savestate for DigitalPad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.switches.bits());
+        w.write(&self.step);
+        w.write(&self.addressing_controller);
+        w.write(&self.rx);
     }
-}
\ No newline at end of file
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.switches = DigitalSwitch::from_bits_truncate(r.read()?);
+        self.step = r.read()?;
+        self.addressing_controller = r.read()?;
+        self.rx = r.read()?;
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 use bitflags::bitflags;
 
+use crate::state::Savestate;
+
 pub mod digital_pad;
 
 bitflags! {
@@ -24,8 +26,9 @@ bitflags! {
     }
 }
 
-pub trait Device {
+pub trait Device: Savestate {
     fn send(&mut self, data: u8);
     fn set_switch(&mut self, switch: DigitalSwitch, released: bool);
-    fn transfer_rx(&mut self);
+    fn switches(&self) -> DigitalSwitch;
+    fn transfer_rx(&mut self) -> Option<u8>;
 }
\ No newline at end of file
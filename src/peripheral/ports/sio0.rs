@@ -1,7 +1,5 @@
-use std::{cell::RefCell, rc::Rc};
-
-use crate::{bus::interrupt::{Interrupt, IRQ}, peripheral::{devices::Device, ports::{SIO_CTRL, SIO_MODE, SIO_STAT}}};
-const ACK_IRQ_DELAY: usize = 1088;
+use crate::{bus::interrupt::{Interrupt, IRQ}, peripheral::{devices::Device, ports::{SIO_CTRL, SIO_MODE, SIO_STAT}}, scheduler::{Event, Scheduler}, state::{Savestate, StateReader, StateWriter}};
+const ACK_IRQ_DELAY: u64 = 1088;
 
 pub struct SIO0 {
     TX_DATA: u32,
@@ -13,16 +11,15 @@ pub struct SIO0 {
 
     SIO_BAUD: u16,
 
-    devices: [Option<Rc<RefCell<Box<dyn Device>>>>; 2],
+    devices: [Option<Box<dyn Device>>; 2],
 
-    interrupt: Rc<RefCell<Interrupt>>,
     bits_sent: usize,
-    irq_delay: usize,
-    irq_pending: bool,
+    baud_reload_time: u64,
+    tx_time: u64,
 }
 
 impl SIO0 {
-    pub fn new(devices: [Option<Rc<RefCell<Box<dyn Device>>>>; 2], interrupt: Rc<RefCell<Interrupt>>) -> Self {
+    pub fn new(devices: [Option<Box<dyn Device>>; 2]) -> Self {
         Self {
             TX_DATA: 0,
             RX_DATA: 0,
@@ -35,55 +32,83 @@ impl SIO0 {
             
             devices,
             
-            interrupt,
             bits_sent: 0,
-            irq_delay: 0,
-            irq_pending: false,
+            baud_reload_time: 0,
+            tx_time: 0,
         }
     }
 
-    pub fn tick(&mut self) {
-        let current_timer = self.SIO_STAT.baudrate_timer();
-        if current_timer != 0 {
-            self.SIO_STAT.set_baudrate_timer(current_timer - 1);
-        } else {
-            if self.SIO_STAT.tx_fifo_not_full() == 0 {
-                self.bits_sent += 1;
-                if self.bits_sent == 8 {
-                    if let Some(device) = &mut self.devices[self.SIO_CTRL.sio0_port_select() as usize] {
-                        device.borrow_mut().send(self.TX_DATA as u8);
-                    }
-                    self.irq_pending = true;
-                    self.irq_delay = ACK_IRQ_DELAY;
-                    self.TX_DATA = 0;
-                    self.SIO_STAT.set_tx_fifo_not_full(1);
-                    self.SIO_STAT.set_tx_idle(1);
-                    // println!("TX FIFO empty");
-                    self.bits_sent = 0;
-                }
-                // println!("SIO0 bits_sent {}", self.bits_sent);
+    pub fn transfer(&mut self, scheduler: &mut Scheduler) {
+        if let Some(device) = &mut self.devices[self.SIO_CTRL.sio0_port_select() as usize] {
+            device.send(self.TX_DATA as u8);
+        }
+        for device in self.devices.iter_mut().flatten() {
+            if let Some(rx) = device.transfer_rx() {
+                self.RX_DATA = rx as u32;
             }
-
-            self.reload_timer();
         }
+        scheduler.schedule(Event::SioAck, ACK_IRQ_DELAY - 1);
+        self.TX_DATA = 0;
+        self.SIO_STAT.set_tx_fifo_not_full(1);
+        self.SIO_STAT.set_tx_idle(1);
+        self.bits_sent = 0;
+    }
 
-        if self.irq_pending {
-            self.irq_delay -= 1;
-            if self.irq_delay == 0 {
-                // println!("byte received");
-                self.irq_pending = false;
-                self.interrupt.borrow_mut().request(IRQ::BYTE_RECEIVED);
-                self.SIO_STAT.set_irq(1);
-                self.SIO_STAT.set_rx_fifo_not_empty(1);
-            }
+    pub fn acknowledge(&mut self, interrupt: &mut Interrupt) {
+        interrupt.request(IRQ::BYTE_RECEIVED);
+        self.SIO_STAT.set_irq(1);
+        self.SIO_STAT.set_rx_fifo_not_empty(1);
+    }
+
+    fn write_tx(&mut self, value: u8, scheduler: &mut Scheduler) {
+        self.TX_DATA = value as u32;
+        self.SIO_STAT.set_tx_fifo_not_full(0);
+        self.SIO_STAT.set_tx_idle(0);
+
+        if !scheduler.is_scheduled(Event::SioTransfer) {
+            self.tx_time = scheduler.now();
+            self.schedule_transfer(scheduler);
         }
     }
 
-    pub fn connect_device(&mut self, device: Rc<RefCell<Box<dyn Device>>>, port: usize) {
+    // A bit goes out every time the baudrate timer wraps, the byte is sent after 8 of them
+    fn schedule_transfer(&self, scheduler: &mut Scheduler) {
+        let period = self.SIO_STAT.baudrate_timer() as u64 + 1;
+        let elapsed = self.tx_time.max(self.baud_reload_time) - self.baud_reload_time;
+        let first_bit = self.baud_reload_time + (elapsed / period + 1) * period;
+        let remaining_bits = 8usize.saturating_sub(self.bits_sent + 1) as u64;
+
+        scheduler.schedule_at(Event::SioTransfer, first_bit + remaining_bits * period);
+    }
+
+    fn bits_since(&self, time: u64, now: u64) -> usize {
+        let period = self.SIO_STAT.baudrate_timer() as u64 + 1;
+        let start = time.max(self.baud_reload_time) - self.baud_reload_time;
+        ((now - self.baud_reload_time) / period - start / period) as usize
+    }
+
+    fn current_stat(&self, now: u64) -> u32 {
+        let mut stat = SIO_STAT::from_bytes(self.SIO_STAT.into_bytes());
+        let period = self.SIO_STAT.baudrate_timer() as u64 + 1;
+        let elapsed = now - self.baud_reload_time;
+        stat.set_baudrate_timer(self.SIO_STAT.baudrate_timer() - (elapsed % period) as u32);
+
+        u32::from_le_bytes(stat.into_bytes())
+    }
+
+    pub fn connect_device(&mut self, device: Box<dyn Device>, port: usize) {
         self.devices[port] = Some(device);
     }
 
-    pub fn read32(&mut self, offset: u32) -> u32 {
+    pub fn device(&self, port: usize) -> Option<&dyn Device> {
+        self.devices[port].as_deref()
+    }
+
+    pub fn device_mut(&mut self, port: usize) -> Option<&mut (dyn Device + 'static)> {
+        self.devices[port].as_deref_mut()
+    }
+
+    pub fn read32(&mut self, offset: u32, scheduler: &Scheduler) -> u32 {
         let offset = offset & 0xF;
         match offset {
             0x00 => {
@@ -94,21 +119,17 @@ impl SIO0 {
 
                 data
             }
-            0x04 => u32::from_le_bytes(self.SIO_STAT.into_bytes()),
+            0x04 => self.current_stat(scheduler.now()),
             0x08 => u16::from_le_bytes(self.SIO_MODE.into_bytes()) as u32 | ((u16::from_le_bytes(self.SIO_CTRL.into_bytes()) as u32) << 16),
             0x0C => (self.SIO_BAUD as u32) << 16,
             _ => unreachable!()
         }
     }
 
-    pub fn write32(&mut self, offset: u32, value: u32) {
+    pub fn write32(&mut self, offset: u32, value: u32, scheduler: &mut Scheduler) {
         let offset = offset & 0xF;
         match offset {
-            0x00 => {
-                self.TX_DATA = value & 0xFF;
-                self.SIO_STAT.set_tx_fifo_not_full(0);
-                self.SIO_STAT.set_tx_idle(0);
-            }
+            0x00 => self.write_tx(value as u8, scheduler),
             0x04 => self.SIO_MODE = SIO_MODE::from_bytes(((value >> 16) as u16 & 0x013F).to_le_bytes()),
             0x08 => {
                 self.SIO_MODE = SIO_MODE::from_bytes((value as u16 & 0x013F).to_le_bytes());
@@ -120,14 +141,14 @@ impl SIO0 {
             }
             0x0C => {
                 self.SIO_BAUD = (value >> 16) as u16;
-                self.reload_timer();
+                self.reload_timer(scheduler);
             }
             _ => unreachable!()
         }
     }
 
-    pub fn read16(&mut self, offset: u32) -> u16 {
-        // println!("SI0 read 16-bit offset: {offset:02X}, SIO0_STAT: {:08X}", u32::from_le_bytes(self.SIO_STAT.into_bytes()));
+    pub fn read16(&mut self, offset: u32, scheduler: &Scheduler) -> u16 {
+        // println!("SI0 read 16-bit offset: {offset:02X}, SIO0_STAT: {:08X}", self.current_stat());
         let offset = offset & 0xF;
         match offset {
             0x00 => {
@@ -139,9 +160,9 @@ impl SIO0 {
                 data
             }
             0x02 => (self.RX_DATA >> 16) as u16,
-            0x04 => u32::from_le_bytes(self.SIO_STAT.into_bytes()) as u16,
+            0x04 => self.current_stat(scheduler.now()) as u16,
             // 0x04 => 0xFF,
-            0x06 => (u32::from_le_bytes(self.SIO_STAT.into_bytes()) >> 16) as u16,
+            0x06 => (self.current_stat(scheduler.now()) >> 16) as u16,
             0x08 => u16::from_le_bytes(self.SIO_MODE.into_bytes()),
             0x0A => u16::from_le_bytes(self.SIO_CTRL.into_bytes()),
             0x0C => 0,
@@ -150,14 +171,10 @@ impl SIO0 {
         }
     }
 
-    pub fn write16(&mut self, offset: u32, value: u16) {
+    pub fn write16(&mut self, offset: u32, value: u16, scheduler: &mut Scheduler) {
         let offset = offset & 0xF;
         match offset {
-            0x00 => {
-                self.TX_DATA = value as u32 & 0xFF;
-                self.SIO_STAT.set_tx_fifo_not_full(0);
-                self.SIO_STAT.set_tx_idle(0);
-            }
+            0x00 => self.write_tx(value as u8, scheduler),
             0x02 => {}
             0x04 => {}
             0x06 => {}
@@ -166,13 +183,13 @@ impl SIO0 {
             0x0C => {}
             0x0E => {
                 self.SIO_BAUD = value;
-                self.reload_timer();
+                self.reload_timer(scheduler);
             }
             _ => unreachable!()
         }
     }
 
-    pub fn read8(&mut self, offset: u32) -> u8 {
+    pub fn read8(&mut self, offset: u32, scheduler: &Scheduler) -> u8 {
         let offset = offset & 0xF;
         match offset {
             0x00 => {
@@ -186,10 +203,10 @@ impl SIO0 {
             0x01 => (self.RX_DATA >> 8) as u8,
             0x02 => (self.RX_DATA >> 16) as u8,
             0x03 => (self.RX_DATA >> 24) as u8,
-            0x04 => u32::from_le_bytes(self.SIO_STAT.into_bytes()) as u8,
-            0x05 => (u32::from_le_bytes(self.SIO_STAT.into_bytes()) >> 8) as u8,
-            0x06 => (u32::from_le_bytes(self.SIO_STAT.into_bytes()) >> 16) as u8,
-            0x07 => (u32::from_le_bytes(self.SIO_STAT.into_bytes()) >> 24) as u8,
+            0x04 => self.current_stat(scheduler.now()) as u8,
+            0x05 => (self.current_stat(scheduler.now()) >> 8) as u8,
+            0x06 => (self.current_stat(scheduler.now()) >> 16) as u8,
+            0x07 => (self.current_stat(scheduler.now()) >> 24) as u8,
             0x08 => u16::from_le_bytes(self.SIO_MODE.into_bytes()) as u8,
             0x09 => (u16::from_le_bytes(self.SIO_MODE.into_bytes()) >> 8) as u8,
             0x0A => u16::from_le_bytes(self.SIO_CTRL.into_bytes()) as u8,
@@ -202,14 +219,10 @@ impl SIO0 {
         }
     }
 
-    pub fn write8(&mut self, offset: u32, value: u8) {
+    pub fn write8(&mut self, offset: u32, value: u8, scheduler: &mut Scheduler) {
         let offset = offset & 0xF;
         match offset {
-            0x00 => {
-                self.TX_DATA = value as u32 & 0xFF;
-                self.SIO_STAT.set_tx_fifo_not_full(0);
-                self.SIO_STAT.set_tx_idle(0);
-            }
+            0x00 => self.write_tx(value, scheduler),
             0x01..=0x03 => {}
             0x04..=0x07 => {}
             0x08 => self.SIO_MODE = SIO_MODE::from_bytes((((self.SIO_MODE.sio0_clock_polarity() as u16) << 8) | value as u16).to_le_bytes()),
@@ -219,17 +232,17 @@ impl SIO0 {
             0x0C..=0x0D => {}
             0x0E => {
                 self.SIO_BAUD = (self.SIO_BAUD & 0xFF00) | (value as u16);
-                self.reload_timer();
+                self.reload_timer(scheduler);
             }
             0x0F => {
                 self.SIO_BAUD = (self.SIO_BAUD & 0x00FF) | ((value as u16) << 8);
-                self.reload_timer();
+                self.reload_timer(scheduler);
             }
             _ => unreachable!()
         }
     }
 
-    fn reload_timer(&mut self) {
+    fn reload_timer(&mut self, scheduler: &mut Scheduler) {
         let baud_factor: u32 = match self.SIO_MODE.baudrate_reload_factor() {
             0 | 1 => 0, // MUL1
             2 => 4,     // MUL16
@@ -237,11 +250,54 @@ impl SIO0 {
             _ => unreachable!()
         };
 
+        let now = scheduler.now();
+        let transferring = scheduler.is_scheduled(Event::SioTransfer);
+        if transferring {
+            self.bits_sent += self.bits_since(self.tx_time, now);
+            self.tx_time = now;
+        }
+
         let reload = ((self.SIO_BAUD as u32) << baud_factor) >> 1;
         self.SIO_STAT.set_baudrate_timer(reload);
+        self.baud_reload_time = now;
+
+        if transferring {
+            self.schedule_transfer(scheduler);
+        }
     }
+}
+
+impl Savestate for SIO0 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.TX_DATA);
+        w.write(&self.RX_DATA);
+        w.write(&self.SIO_STAT.into_bytes());
+        w.write(&self.SIO_CTRL.into_bytes());
+        w.write(&self.SIO_MODE.into_bytes());
+        w.write(&self.SIO_BAUD);
+        w.write(&self.bits_sent);
+        w.write(&self.baud_reload_time);
+        w.write(&self.tx_time);
 
-    pub fn rx_receive(&mut self, data: u8) {
-        self.RX_DATA = data as u32;
+        for device in self.devices.iter().flatten() {
+            device.save_state(w);
+        }
     }
-}
\ No newline at end of file
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.TX_DATA = r.read()?;
+        self.RX_DATA = r.read()?;
+        self.SIO_STAT = SIO_STAT::from_bytes(r.read()?);
+        self.SIO_CTRL = SIO_CTRL::from_bytes(r.read()?);
+        self.SIO_MODE = SIO_MODE::from_bytes(r.read()?);
+        self.SIO_BAUD = r.read()?;
+        self.bits_sent = r.read()?;
+        self.baud_reload_time = r.read()?;
+        self.tx_time = r.read()?;
+
+        for device in self.devices.iter_mut().flatten() {
+            device.load_state(r)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::state::{Savestate, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct RAM {
     pub data: Vec<u8>,
@@ -38,4 +40,13 @@ impl RAM {
     pub fn write8(&mut self, offset: u32, value: u8) {
         self.data[offset as usize] = value;
     }
-}
\ No newline at end of file
+}
+impl Savestate for RAM {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.read_bytes_into(&mut self.data)
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+
+use crate::System;
+
+// Keeps the newest snapshot in full and every older one as a compressed delta against the
+// snapshot that followed it, so stepping back only ever has to undo one delta.
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frames_since_snapshot: usize,
+
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(interval: usize, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity,
+            frames_since_snapshot: 0,
+
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn on_frame(&mut self, system: &System) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.interval {return}
+        self.frames_since_snapshot = 0;
+
+        self.push(system.save_state());
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(previous) = self.newest.replace(snapshot) {
+            self.deltas.push_back(encode_delta(&previous, self.newest.as_ref().unwrap()));
+            if self.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+    }
+
+    pub fn step_back(&mut self, system: &mut System) -> anyhow::Result<bool> {
+        let Some(newest) = self.newest.take() else {return Ok(false)};
+
+        system.load_state(&newest)?;
+        self.newest = self.deltas.pop_back().map(|delta| decode_delta(&newest, &delta)).transpose()?;
+        self.frames_since_snapshot = 0;
+
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.newest.is_some() as usize + self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.frames_since_snapshot = 0;
+    }
+}
+
+// Delta format: the previous snapshot's length, followed by the XOR of both snapshots
+// run-length encoded as (zero run, literal length, literal bytes) triples.
+fn encode_delta(previous: &[u8], next: &[u8]) -> Vec<u8> {
+    let len = previous.len().max(next.len());
+    let xor = (0..len).map(|i| previous.get(i).unwrap_or(&0) ^ next.get(i).unwrap_or(&0));
+
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(previous.len() as u32).to_le_bytes());
+
+    let mut zeros = 0;
+    let mut literal = Vec::new();
+    for byte in xor {
+        if byte == 0 {
+            if !literal.is_empty() {
+                flush_run(&mut delta, &mut zeros, &mut literal);
+            }
+            zeros += 1;
+        } else {
+            literal.push(byte);
+        }
+    }
+    flush_run(&mut delta, &mut zeros, &mut literal);
+
+    delta
+}
+
+fn flush_run(delta: &mut Vec<u8>, zeros: &mut usize, literal: &mut Vec<u8>) {
+    write_varint(delta, *zeros);
+    write_varint(delta, literal.len());
+    delta.append(literal);
+    *zeros = 0;
+}
+
+fn decode_delta(next: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let truncated = || anyhow!("Rewind delta is truncated");
+
+    let previous_len = u32::from_le_bytes(*delta.first_chunk().ok_or_else(truncated)?) as usize;
+    let mut previous = next.to_vec();
+    previous.resize(previous_len.max(next.len()), 0);
+
+    let mut pos = 4;
+    let mut offset = 0;
+    while pos < delta.len() {
+        offset += read_varint(delta, &mut pos).ok_or_else(truncated)?;
+        let literal_len = read_varint(delta, &mut pos).ok_or_else(truncated)?;
+        let literal = delta.get(pos..pos + literal_len).ok_or_else(truncated)?;
+        let target = previous.get_mut(offset..offset + literal_len).ok_or_else(truncated)?;
+
+        for (byte, xor) in target.iter_mut().zip(literal) {
+            *byte ^= xor;
+        }
+
+        pos += literal_len;
+        offset += literal_len;
+    }
+
+    previous.truncate(previous_len);
+    Ok(previous)
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {return Some(value)}
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rewind::{decode_delta, encode_delta};
+
+    #[test]
+    fn delta_round_trip() {
+        let previous: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut next = previous.clone();
+        next[3] = 0xFF;
+        next[500..520].fill(0xAA);
+        next.extend_from_slice(&[1, 2, 3]);
+
+        let delta = encode_delta(&previous, &next);
+        assert!(delta.len() < 64);
+        assert_eq!(decode_delta(&next, &delta).unwrap(), previous);
+
+        let shorter = &previous[..900];
+        let delta = encode_delta(&next, shorter);
+        assert_eq!(decode_delta(shorter, &delta).unwrap(), next);
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::anyhow;
+
+use crate::state::{Savestate, StateReader, StateValue, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    VBlank,
+    CdRomInterrupt,
+    SioTransfer,
+    SioAck,
+    Timer,
+    DmaStart,
+    DmaComplete,
+}
+
+const EVENTS: [Event; 7] = [
+    Event::VBlank,
+    Event::CdRomInterrupt,
+    Event::SioTransfer,
+    Event::SioAck,
+    Event::Timer,
+    Event::DmaStart,
+    Event::DmaComplete,
+];
+
+// Each kind of event is pending at most once, scheduling it again moves it.
+#[derive(Debug)]
+pub struct Scheduler {
+    now: u64,
+    events: Vec<(u64, Event)>,
+    next: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            now: 0,
+            events: Vec::with_capacity(EVENTS.len()),
+            next: u64::MAX,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    pub fn cycles_until_next_event(&self) -> u64 {
+        self.next.saturating_sub(self.now)
+    }
+
+    pub fn skip_to_next_event(&mut self) {
+        if self.next != u64::MAX {
+            self.now = self.now.max(self.next);
+        }
+    }
+
+    pub fn schedule(&mut self, event: Event, delay: u64) {
+        self.schedule_at(event, self.now + delay);
+    }
+
+    pub fn schedule_at(&mut self, event: Event, time: u64) {
+        self.events.retain(|(_, pending)| *pending != event);
+        self.events.push((time, event));
+        // The event may have been the next one and now come later
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|(_, pending)| *pending != event);
+        self.update_next();
+    }
+
+    pub fn is_scheduled(&self, event: Event) -> bool {
+        self.events.iter().any(|(_, pending)| *pending == event)
+    }
+
+    pub fn pop_due(&mut self) -> Option<Event> {
+        if self.next > self.now {return None}
+
+        let idx = self.events.iter().enumerate().min_by_key(|(_, (time, _))| *time).map(|(idx, _)| idx)?;
+        let (_, event) = self.events.swap_remove(idx);
+        self.update_next();
+
+        Some(event)
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.events.iter().map(|(time, _)| *time).min().unwrap_or(u64::MAX);
+    }
+}
+
+impl Savestate for Scheduler {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write(&self.now);
+        w.write(&self.events);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.now = r.read()?;
+        self.events = r.read()?;
+        self.update_next();
+        Ok(())
+    }
+}
+
+impl StateValue for Event {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(EVENTS.iter().position(|event| event == self).unwrap() as u8));
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let tag: u8 = r.read()?;
+        EVENTS.get(tag as usize).copied().ok_or_else(|| anyhow!("Invalid scheduler event {tag}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::scheduler::{Event, Scheduler};
+
+    #[test]
+    fn events_fire_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timer, 10);
+        scheduler.schedule(Event::VBlank, 5);
+        scheduler.schedule(Event::SioAck, 20);
+        scheduler.schedule(Event::Timer, 15);
+        scheduler.cancel(Event::SioAck);
+
+        scheduler.advance(4);
+        assert_eq!(scheduler.pop_due(), None);
+        scheduler.advance(1);
+        assert_eq!(scheduler.pop_due(), Some(Event::VBlank));
+        assert_eq!(scheduler.pop_due(), None);
+
+        scheduler.skip_to_next_event();
+        assert_eq!(scheduler.now(), 15);
+        assert_eq!(scheduler.pop_due(), Some(Event::Timer));
+        assert!(!scheduler.is_scheduled(Event::SioAck));
+    }
+
+    #[test]
+    fn rescheduling_later_delays_the_event() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Timer, 10);
+        scheduler.schedule(Event::Timer, 100);
+
+        scheduler.advance(10);
+        assert_eq!(scheduler.pop_due(), None);
+        assert_eq!(scheduler.cycles_until_next_event(), 90);
+        scheduler.advance(90);
+        assert_eq!(scheduler.pop_due(), Some(Event::Timer));
+    }
+}
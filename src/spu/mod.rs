@@ -1,3 +1,5 @@
+use crate::state::{Savestate, StateReader, StateWriter};
+
 pub struct SPU {
     voice: [u8; 0x180],
     control: [u8; 0x40],
@@ -84,4 +86,18 @@ impl SPU {
     pub fn write_reverb8(&mut self, addr: u32, value: u8) {
         self.control[addr as usize] = value;
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for SPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.voice);
+        w.write_bytes(&self.control);
+        w.write_bytes(&self.reverb);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.read_bytes_into(&mut self.voice)?;
+        r.read_bytes_into(&mut self.control)?;
+        r.read_bytes_into(&mut self.reverb)
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+
+use crate::Registers;
+
+pub const STATE_MAGIC: [u8; 4] = *b"PSXS";
+pub const STATE_VERSION: u32 = 7;
+
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()>;
+}
+
+pub trait StateValue: Sized {
+    fn write(&self, w: &mut StateWriter);
+    fn read(r: &mut StateReader) -> anyhow::Result<Self>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write<T: StateValue>(&mut self, value: &T) {
+        value.write(self);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn read<T: StateValue>(&mut self) -> anyhow::Result<T> {
+        T::read(self)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self.pos.checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| anyhow!("Save state is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_bytes_into(&mut self, dest: &mut [u8]) -> anyhow::Result<()> {
+        dest.copy_from_slice(self.read_bytes(dest.len())?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+macro_rules! impl_state_value_int {
+    ($($ty:ty),*) => {$(
+        impl StateValue for $ty {
+            fn write(&self, w: &mut StateWriter) {
+                w.write_bytes(&self.to_le_bytes());
+            }
+
+            fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+                Ok(<$ty>::from_le_bytes(*r.read_bytes(size_of::<$ty>())?.first_chunk().unwrap()))
+            }
+        }
+    )*};
+}
+
+impl_state_value_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl StateValue for usize {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u64));
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(r.read::<u64>()? as usize)
+    }
+}
+
+impl StateValue for bool {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&(*self as u8));
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(r.read::<u8>()? != 0)
+    }
+}
+
+impl<T: StateValue, const N: usize> StateValue for [T; N] {
+    fn write(&self, w: &mut StateWriter) {
+        for value in self {
+            w.write(value);
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let values = (0..N).map(|_| r.read()).collect::<anyhow::Result<Vec<T>>>()?;
+        Ok(values.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+}
+
+impl<T: StateValue> StateValue for Option<T> {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.is_some());
+        if let Some(value) = self {
+            w.write(value);
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(if r.read::<bool>()? {Some(r.read()?)} else {None})
+    }
+}
+
+impl<T: StateValue> StateValue for VecDeque<T> {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.len());
+        for value in self {
+            w.write(value);
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let len: usize = r.read()?;
+        (0..len).map(|_| r.read()).collect()
+    }
+}
+
+impl<T: StateValue> StateValue for Vec<T> {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.len());
+        for value in self {
+            w.write(value);
+        }
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let len: usize = r.read()?;
+        (0..len).map(|_| r.read()).collect()
+    }
+}
+
+impl StateValue for String {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.len());
+        w.write_bytes(self.as_bytes());
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        let len: usize = r.read()?;
+        Ok(String::from_utf8(r.read_bytes(len)?.to_vec())?)
+    }
+}
+
+impl<A: StateValue, B: StateValue> StateValue for (A, B) {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.0);
+        w.write(&self.1);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok((r.read()?, r.read()?))
+    }
+}
+
+impl<const N: usize> StateValue for Registers<N> {
+    fn write(&self, w: &mut StateWriter) {
+        w.write(&self.R);
+    }
+
+    fn read(r: &mut StateReader) -> anyhow::Result<Self> {
+        Ok(Registers { R: r.read()? })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use crate::state::{StateReader, StateWriter};
+
+    #[test]
+    fn round_trip() {
+        let queue: VecDeque<u32> = VecDeque::from([1, 2, 3]);
+        let pending: [Option<(u32, u32)>; 2] = [Some((4, 0xDEAD_BEEF)), None];
+
+        let mut w = StateWriter::new();
+        w.write(&queue);
+        w.write(&pending);
+        w.write(&true);
+        w.write(&usize::MAX);
+        let data = w.into_inner();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.read::<VecDeque<u32>>().unwrap(), queue);
+        assert_eq!(r.read::<[Option<(u32, u32)>; 2]>().unwrap(), pending);
+        assert!(r.read::<bool>().unwrap());
+        assert_eq!(r.read::<usize>().unwrap(), usize::MAX);
+        assert!(r.is_empty());
+        assert!(r.read::<u8>().is_err());
+
+        // A corrupt length can't run the position past the end
+        let mut r = StateReader::new(&data);
+        r.read::<u8>().unwrap();
+        assert!(r.read_bytes(usize::MAX).is_err());
+    }
+}
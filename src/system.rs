@@ -0,0 +1,494 @@
+use std::{ops::Range, path::{Path, PathBuf}};
+
+use anyhow::anyhow;
+
+use crate::{bios::BIOS, bus::interface::Bus, cd_rom::{iso9660::SystemCnf, CD_ROM}, hle::Hle, cpu::{disassembler::disassemble, kernel::KernelTracer, CPU}, debugger::{WatchHit, Watchpoint}, gpu::primitives::color::Color, peripheral::{devices::{digital_pad::DigitalPad, DigitalSwitch}, ports::sio0::SIO0}, state::{Savestate, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION}};
+
+// What a native kernel call costs, roughly the length of a short BIOS function
+const HLE_CALL_CYCLES: u64 = 20;
+
+#[derive(Clone)]
+pub struct Config {
+    pub bios: PathBuf,
+    // Runs on the HLE BIOS, without reading the BIOS image
+    pub hle_bios: bool,
+    pub disc: Option<PathBuf>,
+    pub exe: Option<PathBuf>,
+    pub exe_args: Vec<String>,
+    // Boots the disc's executable as soon as the BIOS reaches the shell, skipping the intro
+    pub fast_boot: bool,
+    pub trace: bool,
+    // Filter for the kernel call tracer, which is off when None
+    pub trace_kernel: Option<String>,
+    pub dynarec: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { bios: PathBuf::from("SCPH1001.bin"), hle_bios: false, disc: None, exe: None, exe_args: Vec::new(), fast_boot: false, trace: false, trace_kernel: None, dynarec: false }
+    }
+}
+
+pub struct System {
+    cpu: CPU,
+    bus: Bus,
+    hle: Option<Hle>,
+
+    config: Config,
+    exe: Option<Vec<u8>>,
+    exe_args: Vec<String>,
+    fast_boot: bool,
+}
+
+impl System {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let mut sio0 = SIO0::new([const { None }; 2]);
+        sio0.connect_device(Box::new(DigitalPad::new()), 0);
+        sio0.connect_device(Box::new(DigitalPad::new()), 1);
+        let cd_rom = CD_ROM::new(config.disc.as_deref())?;
+        let bus = if config.hle_bios {
+            Bus::with_bios(BIOS::from_image(Hle::rom())?, cd_rom, sio0)
+        } else {
+            Bus::new(&config.bios, cd_rom, sio0)?
+        };
+        Self::with_bus(config, bus)
+    }
+
+    // The default machine running the given BIOS image, with no disc or pads
+    #[cfg(test)]
+    pub(crate) fn with_bios_image(image: Vec<u8>) -> Self {
+        Self::with_bus(Config::default(), Bus::with_bios_image(image)).unwrap()
+    }
+
+    fn with_bus(config: Config, bus: Bus) -> anyhow::Result<Self> {
+        let mut cpu = CPU::new();
+        cpu.trace = config.trace;
+        cpu.kernel_tracer = config.trace_kernel.as_deref().map(KernelTracer::new).transpose()?;
+        cpu.set_dynarec(config.dynarec)?;
+
+        let exe = config.exe.as_ref().map(std::fs::read).transpose()?;
+        if let Some(exe) = &exe {
+            check_exe(exe, bus.dram.data.len())?;
+        }
+
+        Ok(Self {
+            cpu,
+            bus,
+            hle: config.hle_bios.then(|| Hle::new(config.exe.is_some())),
+
+            exe,
+            exe_args: config.exe_args.clone(),
+            fast_boot: config.fast_boot,
+            config,
+        })
+    }
+
+    // The CPU runs an instruction every other cycle, plus whatever the fetch stalls for. While
+    // DMA has it stalled, time skips straight to the next event. The dynarec may run several
+    // instructions in one step, but never past an event.
+    fn tick(&mut self, native: bool) -> bool {
+        if self.bus.dma.running() {
+            self.bus.scheduler.skip_to_next_event();
+        } else {
+            if self.cpu.pc == 0x80030000 {
+                if let Some(exe) = self.exe.take() {
+                    self.sideload_exe(&exe);
+                } else if std::mem::take(&mut self.fast_boot) {
+                    self.boot_disc();
+                }
+            }
+            let trapped = self.hle.as_mut().is_some_and(|hle| hle.trap(&mut self.cpu, &mut self.bus));
+            let cycles = if trapped {
+                HLE_CALL_CYCLES
+            } else if native {
+                self.cpu.step(&mut self.bus)
+            } else {
+                self.cpu.tick(&mut self.bus)
+            };
+            self.bus.scheduler.advance(cycles);
+        }
+
+        self.bus.run_events()
+    }
+
+    pub fn run_frame(&mut self) {
+        while !self.tick(true) {}
+    }
+
+    pub fn step_instruction(&mut self) -> bool {
+        self.tick(true)
+    }
+
+    // A single instruction even with the dynarec enabled, so the debugger sees every PC
+    pub fn step_interpreted(&mut self) -> bool {
+        self.tick(false)
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.bus.scheduler.now()
+    }
+
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        let echo = self.bus.debug_port.echo;
+        *self = System::new(self.config.clone())?;
+        self.set_tty_echo(echo);
+
+        Ok(())
+    }
+
+    pub fn swap_disc(&mut self, disc: Option<&Path>) -> anyhow::Result<()> {
+        self.bus.cd_rom.swap_disk(disc)?;
+        self.config.disc = disc.map(Path::to_path_buf);
+
+        Ok(())
+    }
+
+    pub fn disc(&self) -> Option<&Path> {
+        self.config.disc.as_deref()
+    }
+
+    pub fn set_switch(&mut self, port: usize, switch: DigitalSwitch, released: bool) {
+        if let Some(pad) = self.bus.sio0.device_mut(port) {
+            pad.set_switch(switch, released);
+        }
+    }
+
+    pub fn pressed_switches(&self, port: usize) -> DigitalSwitch {
+        self.bus.sio0.device(port).map_or(DigitalSwitch::empty(), |pad| pad.switches().complement())
+    }
+
+    pub fn set_pressed_switches(&mut self, port: usize, pressed: DigitalSwitch) {
+        if let Some(pad) = self.bus.sio0.device_mut(port) {
+            pad.set_switch(pressed, false);
+            pad.set_switch(pressed.complement(), true);
+        }
+    }
+
+    pub fn render_vram(&self) -> Box<[Color; 512 * 1024]> {
+        self.bus.gpu.render_vram()
+    }
+
+    pub fn render_display_area(&self) -> (u32, u32, Vec<Color>) {
+        self.bus.gpu.render_display_area()
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        self.bus.gpu.vram()
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.bus.dram.data
+    }
+
+    pub fn scratchpad(&self) -> &[u8] {
+        &self.bus.scratchpad.data
+    }
+
+    pub fn tty_output(&self) -> String {
+        self.bus.debug_port.tty().to_string()
+    }
+
+    pub fn exit_code(&self) -> Option<u32> {
+        self.bus.debug_port.exit_code()
+    }
+
+    pub fn take_debug_break(&mut self) -> bool {
+        self.bus.debug_port.take_break()
+    }
+
+    pub fn set_tty_echo(&mut self, echo: bool) {
+        self.bus.debug_port.echo = echo;
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn cop0_register(&self, register: u32) -> u32 {
+        self.cpu.system_control.read_register(register)
+    }
+
+    pub fn read32(&mut self, addr: u32) -> u32 {
+        self.bus.read32(addr)
+    }
+
+    pub fn write32(&mut self, addr: u32, value: u32) {
+        self.bus.write32(addr, value);
+    }
+
+    pub fn write8(&mut self, addr: u32, value: u8) {
+        self.bus.write8(addr, value);
+    }
+
+    // Reads that never touch I/O, for inspecting memory without side effects
+    pub fn peek8(&self, addr: u32) -> Option<u8> {
+        self.bus.read_memory8(addr)
+    }
+
+    // Writes that never touch I/O either. The BIOS is read-only, so writes to it go nowhere.
+    pub fn poke8(&mut self, addr: u32, value: u8) -> bool {
+        if self.peek8(addr).is_none() {return false}
+
+        self.bus.write_memory8(addr, value);
+        true
+    }
+
+    pub fn peek32(&self, addr: u32) -> Option<u32> {
+        if addr & 0b11 != 0 {return None}
+        self.bus.read_memory32(addr)
+    }
+
+    // One line per word in the range, with unmapped words shown as ??
+    pub fn disassemble(&self, range: Range<u32>) -> Vec<String> {
+        range.step_by(4).map(|addr| match self.peek32(addr) {
+            Some(instruction) => format!("{addr:08X}: {instruction:08X}  {}", disassemble(instruction, addr)),
+            None => format!("{addr:08X}: ??"),
+        }).collect()
+    }
+
+    pub fn set_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.set_watchpoint(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u32) -> bool {
+        self.bus.remove_watchpoint(addr)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.bus.watchpoints()
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.bus.take_watch_hit()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_bytes(&STATE_MAGIC);
+        w.write(&STATE_VERSION);
+
+        self.cpu.save_state(&mut w);
+        self.bus.save_state(&mut w);
+        w.write(&self.hle.is_some());
+        if let Some(hle) = &self.hle {
+            hle.save_state(&mut w);
+        }
+
+        w.into_inner()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut r = StateReader::new(data);
+        if r.read_bytes(4)? != STATE_MAGIC {
+            return Err(anyhow!("Not a save state"));
+        }
+        let version: u32 = r.read()?;
+        if version != STATE_VERSION {
+            return Err(anyhow!("Unsupported save state version {version}, expected {STATE_VERSION}"));
+        }
+
+        let backup = self.save_state();
+        if let Err(err) = self.load_components(&mut r) {
+            self.load_components(&mut StateReader::new(&backup[8..]))?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn load_components(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.cpu.load_state(r)?;
+        self.bus.load_state(r)?;
+        if r.read::<bool>()? != self.hle.is_some() {
+            return Err(anyhow!("Save state was made with a different BIOS"));
+        }
+        if let Some(hle) = &mut self.hle {
+            hle.load_state(r)?;
+        }
+        self.cpu.flush_block_cache(&mut self.bus);
+
+        if !r.is_empty() {
+            return Err(anyhow!("Trailing data in save state"));
+        }
+
+        Ok(())
+    }
+
+    pub fn save_state_to_file(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.save_state())?;
+        Ok(())
+    }
+
+    pub fn load_state_from_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.load_state(&std::fs::read(path)?)
+    }
+
+    fn sideload_exe(&mut self, exe: &[u8]) {
+        let initial_pc = u32::from_le_bytes(*exe[0x10..].first_chunk().unwrap());
+        let initial_r28 = u32::from_le_bytes(*exe[0x14..].first_chunk().unwrap());
+        let exe_ram_addr = u32::from_le_bytes(*exe[0x18..].first_chunk().unwrap()) & 0x001F_FFFF;
+        let exe_size_2kb = u32::from_le_bytes(*exe[0x1C..].first_chunk().unwrap());
+        let initial_sp = u32::from_le_bytes(*exe[0x30..].first_chunk().unwrap());
+
+        let (exe_ram_addr, exe_size) = (exe_ram_addr as usize, (exe_size_2kb as usize).min(exe.len() - 2048));
+        self.bus.dram.data[exe_ram_addr..exe_ram_addr + exe_size]
+            .copy_from_slice(&exe[2048..2048 + exe_size]);
+
+        self.cpu.R[28] = initial_r28;
+        if initial_sp != 0 {
+            self.cpu.R[29] = initial_sp;
+            self.cpu.R[30] = initial_sp;
+        }
+
+        self.cpu.next_pc = initial_pc;
+        self.cpu.flush_block_cache(&mut self.bus);
+
+        if !self.exe_args.is_empty() {
+            self.write_exe_args();
+        }
+    }
+
+    // Does what the shell would once the intro is over: loads the executable SYSTEM.CNF names,
+    // with the stack it asks for unless the executable has its own. Without a bootable disc,
+    // the shell carries on as normal.
+    fn boot_disc(&mut self) {
+        let Some(cnf) = SystemCnf::from_disc(&mut self.bus.cd_rom) else {
+            println!("Fast boot: no SYSTEM.CNF or PSX.EXE on the disc");
+            return;
+        };
+
+        let Some(exe) = self.bus.cd_rom.read_file(&cnf.boot) else {
+            println!("Fast boot: could not load {}", cnf.boot);
+            return;
+        };
+        if let Err(err) = check_exe(&exe, self.bus.dram.data.len()) {
+            println!("Fast boot: refusing {}: {err}", cnf.boot);
+            return;
+        }
+
+        self.sideload_exe(&exe);
+        if u32::from_le_bytes(*exe[0x30..].first_chunk().unwrap()) == 0 {
+            self.cpu.R[29] = cnf.stack;
+            self.cpu.R[30] = cnf.stack;
+        }
+    }
+
+    // Arguments are passed the way the PSX shell does it: argc at 0x1F800000, the argv
+    // pointer table right after it and the strings themselves from 0x1F800044 onwards.
+    fn write_exe_args(&mut self) {
+        const ARGC_ADDR: u32 = 0x1F80_0000;
+        const ARGV_ADDR: u32 = 0x1F80_0004;
+        const STRINGS_ADDR: u32 = 0x1F80_0044;
+        const STRINGS_END: u32 = 0x1F80_0400;
+
+        let mut string_addr = STRINGS_ADDR;
+        let mut argc = 0;
+
+        for arg in &self.exe_args {
+            let bytes = arg.as_bytes();
+            if ARGV_ADDR + argc * 4 >= STRINGS_ADDR || string_addr + bytes.len() as u32 + 1 > STRINGS_END {
+                println!("Too many EXE arguments, ignoring {arg}");
+                break;
+            }
+
+            self.bus.write32(ARGV_ADDR + argc * 4, string_addr);
+            for byte in bytes.iter().copied().chain([0]) {
+                self.bus.write8(string_addr, byte);
+                string_addr += 1;
+            }
+
+            argc += 1;
+        }
+
+        self.bus.write32(ARGC_ADDR, argc);
+        self.cpu.R[4] = argc;
+        self.cpu.R[5] = ARGV_ADDR;
+    }
+}
+
+// Whatever the header says is loaded has to fit in RAM. Files cut short of the size in the header
+// are loaded as far as they go.
+fn check_exe(exe: &[u8], ram_size: usize) -> anyhow::Result<()> {
+    if exe.len() < 0x800 || !exe.starts_with(b"PS-X EXE") {
+        return Err(anyhow!("Invalid PS-EXE header"));
+    }
+
+    let ram_addr = (u32::from_le_bytes(*exe[0x18..].first_chunk().unwrap()) & 0x001F_FFFF) as usize;
+    let size = u32::from_le_bytes(*exe[0x1C..].first_chunk().unwrap()) as usize;
+    if ram_addr + size > ram_size {
+        return Err(anyhow!("PS-EXE of {size:#X} bytes doesn't fit in RAM at {ram_addr:#08X}"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{cd_rom::iso9660, system::check_exe, Config, System};
+
+    const ENTRY: u32 = 0x8001_0000;
+
+    // Boots a BIOS that jumps straight to the shell, with a disc holding the files
+    fn fast_boot(files: &[(&str, &[u8])]) -> System {
+        let directory = std::env::temp_dir().join("psx_fast_boot_test");
+        std::fs::create_dir_all(&directory).unwrap();
+        // lui t0, 0x8003; jr t0; nop
+        let mut bios = [0x3C08_8003u32, 0x0100_0008, 0].iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>();
+        bios.resize(512 * 1024, 0);
+        std::fs::write(directory.join("bios.bin"), bios).unwrap();
+        std::fs::write(directory.join("game.iso"), iso9660::test::image(files)).unwrap();
+
+        let config = Config { bios: directory.join("bios.bin"), disc: Some(directory.join("game.iso")), fast_boot: true, ..Default::default() };
+        let mut system = System::new(config).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // Stops at the executable's entry point, or wherever the BIOS got to without it
+        for _ in 0..16 {
+            if system.cpu().pc == ENTRY {break}
+            system.step_interpreted();
+        }
+        system
+    }
+
+    fn exe(sp: u32) -> Vec<u8> {
+        let mut exe = vec![0; 0x1000];
+        exe[..8].copy_from_slice(b"PS-X EXE");
+        for (offset, value) in [(0x10, ENTRY), (0x14, 0x8002_0000), (0x18, 0x8001_0000), (0x1C, 0x800), (0x30, sp)] {
+            exe[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        exe
+    }
+
+    #[test]
+    fn fast_boot_follows_system_cnf() {
+        let registers = |system: &System| (system.cpu().pc, system.cpu().R[28], system.cpu().R[29]);
+
+        // STACK only applies when the executable leaves its stack to the BIOS
+        let cnf: &[u8] = b"BOOT = cdrom:\\GAME.EXE;1\r\nSTACK = 801FFFF0\r\n";
+        let system = fast_boot(&[("SYSTEM.CNF;1", cnf), ("GAME.EXE;1", &exe(0))]);
+        assert_eq!(registers(&system), (ENTRY, 0x8002_0000, 0x801F_FFF0));
+        let system = fast_boot(&[("SYSTEM.CNF;1", cnf), ("GAME.EXE;1", &exe(0x801F_0000))]);
+        assert_eq!(registers(&system), (ENTRY, 0x8002_0000, 0x801F_0000));
+
+        // Without SYSTEM.CNF, PSX.EXE boots with the default stack
+        let system = fast_boot(&[("PSX.EXE;1", &exe(0))]);
+        assert_eq!(registers(&system), (ENTRY, 0x8002_0000, 0x801F_FF00));
+    }
+
+    #[test]
+    fn exe_has_to_fit_in_ram() {
+        let mut exe = vec![0; 0x1000];
+        exe[..8].copy_from_slice(b"PS-X EXE");
+        exe[0x1C..0x20].copy_from_slice(&0x800u32.to_le_bytes());
+
+        exe[0x18..0x1C].copy_from_slice(&0x801F_F800u32.to_le_bytes());
+        assert!(check_exe(&exe, 0x20_0000).is_ok());
+        exe[0x18..0x1C].copy_from_slice(&0x801F_FC00u32.to_le_bytes());
+        assert!(check_exe(&exe, 0x20_0000).is_err());
+        assert!(check_exe(&exe[..0x7FF], 0x20_0000).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+// Boots PS-EXE test programs headlessly and checks their exit code and TTY output.
+//
+// The BIOS and test programs are not distributed with the emulator. Point PSX_BIOS at a
+// BIOS image and PSX_TEST_ROMS at a directory containing the test suites, laid out as below,
+// then run them with `cargo test -- --ignored`.
+
+use std::{env, path::PathBuf};
+
+use psx::{headless::{self, HeadlessConfig, StopReason}, Config, System};
+
+// How a suite says it passed. Running out of frames without saying so is a failure.
+enum Pass {
+    Exit(u32),
+    // A line the suite only prints once everything has passed
+    Tty(&'static str),
+}
+
+struct TestRom {
+    path: &'static str,
+    args: &'static [&'static str],
+    frames: usize,
+    pass: Pass,
+}
+
+fn bios_path() -> PathBuf {
+    env::var_os("PSX_BIOS").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("SCPH1001.bin"))
+}
+
+fn rom_dir() -> PathBuf {
+    env::var_os("PSX_TEST_ROMS").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("test_roms"))
+}
+
+fn boot(rom: &TestRom, dynarec: bool) -> (System, StopReason) {
+    let bios = bios_path();
+    let exe = rom_dir().join(rom.path);
+    assert!(exe.exists(), "Missing {}", exe.display());
+
+    // Without a BIOS image, the suites run on the HLE BIOS
+    let hle_bios = !bios.exists();
+    let exe_args = rom.args.iter().map(|arg| arg.to_string()).collect();
+    let mut system = System::new(Config { bios, hle_bios, exe: Some(exe), exe_args, dynarec, ..Default::default() }).unwrap();
+    system.set_tty_echo(false);
+
+    let reason = headless::run(&mut system, &HeadlessConfig { frames: rom.frames, ..Default::default() }).unwrap();
+    (system, reason)
+}
+
+fn run_test_rom(rom: TestRom) {
+    let (system, reason) = boot(&rom, false);
+    let tty = system.tty_output();
+
+    match rom.pass {
+        Pass::Exit(code) => {
+            assert_eq!(reason, StopReason::Exit(code), "{} TTY output:\n{tty}", rom.path);
+        }
+        // The summary itself counts the failures
+        Pass::Tty(marker) => {
+            assert!(!matches!(reason, StopReason::Breakpoint(_)), "{} hit a breakpoint. TTY output:\n{tty}", rom.path);
+            assert!(tty.contains(marker), "{} did not print {marker:?}. TTY output:\n{tty}", rom.path);
+        }
+    }
+}
+
+// The dynarec has to leave the machine just as the interpreter does, down to the cycle
+#[cfg(feature = "dynarec")]
+fn compare_dynarec(rom: TestRom) {
+    let (interpreter, interpreter_reason) = boot(&rom, false);
+    let (dynarec, dynarec_reason) = boot(&rom, true);
+
+    let registers = |system: &System| {
+        let cpu = system.cpu();
+        ((0..32).map(|index| cpu.R[index]).collect::<Vec<u32>>(), cpu.pc, cpu.hi(), cpu.lo())
+    };
+    assert_eq!(dynarec_reason, interpreter_reason, "{} stopped differently", rom.path);
+    assert_eq!(dynarec.cycles(), interpreter.cycles(), "{} ran for a different time", rom.path);
+    assert_eq!(registers(&dynarec), registers(&interpreter), "{} registers differ", rom.path);
+    assert!(dynarec.ram() == interpreter.ram(), "{} RAM differs", rom.path);
+    assert_eq!(dynarec.tty_output(), interpreter.tty_output(), "{} TTY output differs", rom.path);
+}
+
+// The console argument sends the results to the TTY instead of the screen
+const AMIDOG_CPU: TestRom = TestRom { path: "amidog/psxtest_cpu.exe", args: &["console"], frames: 3000, pass: Pass::Tty("Failed: 0") };
+const PS1_TESTS_GTE: TestRom = TestRom { path: "ps1-tests/gte/test-all/test-all.exe", args: &[], frames: 600, pass: Pass::Exit(0) };
+const PS1_TESTS_CPU_ACCESS_TIME: TestRom = TestRom { path: "ps1-tests/cpu/access-time/access-time.exe", args: &[], frames: 600, pass: Pass::Exit(0) };
+const PS1_TESTS_TIMERS: TestRom = TestRom { path: "ps1-tests/timers/timers.exe", args: &[], frames: 600, pass: Pass::Exit(0) };
+const PS1_TESTS_GPU_QUAD: TestRom = TestRom { path: "ps1-tests/gpu/quad/quad.exe", args: &[], frames: 300, pass: Pass::Exit(0) };
+const PCSX_REDUX_EXIT_CODE: TestRom = TestRom { path: "pcsx-redux/exit-code.exe", args: &[], frames: 600, pass: Pass::Exit(0) };
+
+#[test]
+#[ignore = "needs the test ROMs"]
+fn amidog_cpu() {
+    run_test_rom(AMIDOG_CPU);
+}
+
+#[test]
+#[ignore = "needs the test ROMs"]
+fn ps1_tests_gte() {
+    run_test_rom(PS1_TESTS_GTE);
+}
+
+#[test]
+#[ignore = "needs the test ROMs"]
+fn ps1_tests_cpu_access_time() {
+    run_test_rom(PS1_TESTS_CPU_ACCESS_TIME);
+}
+
+#[test]
+#[ignore = "needs the test ROMs"]
+fn ps1_tests_timers() {
+    run_test_rom(PS1_TESTS_TIMERS);
+}
+
+#[test]
+#[ignore = "needs the test ROMs"]
+fn ps1_tests_gpu_quad() {
+    run_test_rom(PS1_TESTS_GPU_QUAD);
+}
+
+#[test]
+#[ignore = "needs the test ROMs"]
+fn pcsx_redux_exit_code() {
+    run_test_rom(PCSX_REDUX_EXIT_CODE);
+}
+
+#[cfg(feature = "dynarec")]
+mod dynarec {
+    use crate::{compare_dynarec, AMIDOG_CPU, PCSX_REDUX_EXIT_CODE, PS1_TESTS_CPU_ACCESS_TIME, PS1_TESTS_GPU_QUAD, PS1_TESTS_GTE, PS1_TESTS_TIMERS};
+
+    #[test]
+    #[ignore = "needs the test ROMs"]
+    fn amidog_cpu() {
+        compare_dynarec(AMIDOG_CPU);
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs"]
+    fn ps1_tests_gte() {
+        compare_dynarec(PS1_TESTS_GTE);
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs"]
+    fn ps1_tests_cpu_access_time() {
+        compare_dynarec(PS1_TESTS_CPU_ACCESS_TIME);
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs"]
+    fn ps1_tests_timers() {
+        compare_dynarec(PS1_TESTS_TIMERS);
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs"]
+    fn ps1_tests_gpu_quad() {
+        compare_dynarec(PS1_TESTS_GPU_QUAD);
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs"]
+    fn pcsx_redux_exit_code() {
+        compare_dynarec(PCSX_REDUX_EXIT_CODE);
+    }
+}